@@ -0,0 +1,62 @@
+//! Compare the throughput of the account state stores on a high fan-out workload.
+//!
+//! Run with `cargo bench --bench stores`, optionally giving the number of transactions and clients,
+//! e.g. `cargo bench --bench stores -- 1000000 65535`.
+
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Runs of the binary per store, the fastest one is reported.
+const RUNS: usize = 3;
+
+/// Run the binary over `path` with the given store, returning how long it took.
+fn run(path: &std::path::Path, store: &str) -> Duration {
+    let start = Instant::now();
+    let status = Command::new(env!("CARGO_BIN_EXE_transaction-processing"))
+        .arg(path)
+        .args(["--store", store])
+        .env("RUST_LOG", "off")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    start.elapsed()
+}
+
+fn main() {
+    // Arguments of cargo itself, e.g. `--bench`, are passed through
+    let mut args = std::env::args()
+        .skip(1)
+        .filter_map(|arg| arg.parse::<u32>().ok());
+    let transactions = args.next().unwrap_or(1_000_000);
+    let clients = args
+        .next()
+        .unwrap_or(u16::MAX as u32)
+        .clamp(1, u16::MAX as u32);
+
+    // Deposits spread over all clients, in round-robin
+    let mut data = String::from("type,client,tx,amount\n");
+    for tx in 0..transactions {
+        data.push_str(&format!("deposit,{},{tx},1.0\n", tx % clients));
+    }
+    let path = std::env::temp_dir().join(format!(
+        "transaction-processing-bench-stores-{}.csv",
+        std::process::id()
+    ));
+    std::fs::write(&path, data).unwrap();
+
+    println!(
+        "{transactions} transactions over {clients} clients, {} cores",
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+    for store in ["dashmap", "sharded"] {
+        let elapsed = (0..RUNS).map(|_| run(&path, store)).min().unwrap();
+        println!(
+            "{store}: {elapsed:.2?} ({:.0} transactions/s)",
+            transactions as f64 / elapsed.as_secs_f64()
+        );
+    }
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,63 @@
+//! Compare the peak memory of writing accounts out with and without `--stream`.
+//!
+//! Run with `cargo bench --bench stream`, optionally giving the number of clients, e.g.
+//! `cargo bench --bench stream -- 65535`. Client ids are `u16`, thus there are at most 65535
+//! accounts.
+
+use std::process::{Command, Stdio};
+
+/// Run the binary over `path` with the extra `args`, returning its peak resident set size in KiB.
+fn peak_rss(path: &std::path::Path, args: &[&str]) -> i64 {
+    let child = Command::new(env!("CARGO_BIN_EXE_transaction-processing"))
+        .arg(path)
+        .args(args)
+        .env("RUST_LOG", "off")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+        .id() as libc::pid_t;
+
+    // The standard library does not expose the resource usage of a child, reap it ourselves
+    let mut status = 0;
+    // SAFETY: `rusage` is plain old data, which zeroes are valid for
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    // SAFETY: the child was not waited for yet and both pointers are valid for the whole call
+    let pid = unsafe { libc::wait4(child, &mut status, 0, &mut usage) };
+    assert_eq!(pid, child);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+    // Kilobytes on Linux
+    usage.ru_maxrss
+}
+
+fn main() {
+    // Arguments of cargo itself, e.g. `--bench`, are passed through
+    let max_clients = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse::<u32>().ok())
+        .unwrap_or(u16::MAX as u32)
+        .clamp(1, u16::MAX as u32);
+
+    println!("clients | collected | streamed");
+    for clients in [max_clients / 16, max_clients / 4, max_clients]
+        .into_iter()
+        .filter(|&clients| clients > 0)
+    {
+        // One deposit per client, so the accounts make up most of the state
+        let mut data = String::from("type,client,tx,amount\n");
+        for client in 0..clients {
+            data.push_str(&format!("deposit,{client},{client},1.0\n"));
+        }
+        let path = std::env::temp_dir().join(format!(
+            "transaction-processing-bench-stream-{}.csv",
+            std::process::id()
+        ));
+        std::fs::write(&path, data).unwrap();
+
+        let collected = peak_rss(&path, &[]);
+        let streamed = peak_rss(&path, &["--stream"]);
+        println!("{clients} | {collected} KiB | {streamed} KiB");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,12 +1,559 @@
+pub mod applied;
+pub mod chaos;
 pub mod handler;
+pub mod metrics;
+pub mod persistence;
 pub mod server;
 pub mod state;
+pub mod store;
 
 use tokio::sync::mpsc::Receiver;
+use tokio_util::sync::CancellationToken;
 
-/// Run the engine.
-pub async fn run(rx: Receiver<server::Command>) {
-    let mut listener = server::Listener::new(rx);
+/// Options of an engine run, see `run`.
+#[derive(Clone)]
+pub struct Options {
+    /// How transactions are applied.
+    pub config: state::Config,
+    /// Failures to inject, if any.
+    pub chaos: Option<chaos::Config>,
+    /// Thresholds up to which transactions are executed inline, if any.
+    pub adaptive: Option<server::Adaptive>,
+    /// Number of transactions each handler queues, see
+    /// `server::Listener::with_channel_capacity`.
+    pub channel_capacity: usize,
+    /// Database each handler upserts its account into on commit, if any.
+    pub persistence: Option<std::sync::Arc<persistence::AccountsDb>>,
+    /// Storage of the client states.
+    pub store: store::Kind,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            config: state::Config::default(),
+            chaos: None,
+            adaptive: None,
+            channel_capacity: server::DEFAULT_CHANNEL_CAPACITY,
+            persistence: None,
+            store: store::Kind::default(),
+        }
+    }
+}
+
+/// Run the engine with `options`.
+///
+/// Returns a summary of the transactions executed once all command senders are dropped or `token`
+/// is cancelled. Commands sent before cancellation are not lost, see `server::Listener::run`.
+pub async fn run(
+    rx: Receiver<server::Command>,
+    options: Options,
+    token: CancellationToken,
+) -> server::RunSummary {
+    match options.store {
+        store::Kind::Dashmap => {
+            let listener = server::Listener::new(rx, options.config);
+            run_listener(listener, options, token).await
+        }
+        store::Kind::Sharded => {
+            let store = std::sync::Arc::new(store::ShardedStore::default());
+            let listener = server::Listener::with_store(rx, options.config, store);
+            run_listener(listener, options, token).await
+        }
+    }
+}
+
+/// Run `listener` configured with `options`.
+async fn run_listener<S: store::Store>(
+    listener: server::Listener<S>,
+    options: Options,
+    token: CancellationToken,
+) -> server::RunSummary {
+    let mut listener = listener
+        .with_chaos(options.chaos)
+        .with_adaptive(options.adaptive)
+        .with_channel_capacity(options.channel_capacity)
+        .with_persistence(options.persistence);
+
+    listener.run(token).await
+}
+
+/// Handle to a running engine, see `run`.
+#[derive(Clone)]
+pub struct Engine {
+    tx: tokio::sync::mpsc::Sender<server::Command>,
+}
+
+impl Engine {
+    /// Create a handle sending commands to the engine listening on the other end of `tx`.
+    pub fn new(tx: tokio::sync::mpsc::Sender<server::Command>) -> Self {
+        Self { tx }
+    }
+
+    /// Execute `record` and wait until it is either applied or rejected.
+    ///
+    /// Returns `state::Error::NotExecuted` if the engine stopped before executing the transaction.
+    pub async fn process_result(
+        &self,
+        record: crate::model::transaction::TransactionRecord,
+    ) -> Result<(), state::Error> {
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        self.tx
+            .send(server::Command::ExecuteTransactionWithResult(
+                record, resp_tx,
+            ))
+            .await
+            .map_err(|_| state::Error::NotExecuted)?;
+
+        resp_rx.await.map_err(|_| state::Error::NotExecuted)?
+    }
+
+    /// All accounts once the transactions sent so far are executed, sorted in ascending order by
+    /// client id.
+    ///
+    /// The order is part of the contract of `Engine`, whatever the store or the number of
+    /// handlers. Returns `None` if the engine stopped before answering.
+    pub async fn accounts(&self) -> Option<Vec<crate::model::account::Account>> {
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        self.tx
+            .send(server::Command::GetAccountsState(resp_tx))
+            .await
+            .ok()?;
+
+        resp_rx.await.ok()
+    }
+}
+
+/// Run `records`, in order, through a fresh engine configured with `config` and return the
+/// resulting accounts, sorted by client id.
+///
+/// Once all records are executed, interest is accrued at the given rate under the given
+/// transaction id, if any. Used as a reference to cross check the accounts of a run.
+pub async fn replay(
+    records: &[crate::model::transaction::TransactionRecord],
+    config: state::Config,
+    interest: Option<(crate::model::amount::Amount, crate::model::transaction::Id)>,
+) -> Vec<crate::model::account::Account> {
+    let options = Options {
+        config,
+        ..Options::default()
+    };
+    run_records(records, options, interest).await
+}
+
+/// Execute `records` and accrue `interest` on an engine run with `options`, then return the
+/// resulting accounts state.
+async fn run_records(
+    records: &[crate::model::transaction::TransactionRecord],
+    options: Options,
+    interest: Option<(crate::model::amount::Amount, crate::model::transaction::Id)>,
+) -> Vec<crate::model::account::Account> {
+    let (tx, rx) = tokio::sync::mpsc::channel(options.channel_capacity);
+    let handle = tokio::spawn(run(rx, options, CancellationToken::new()));
+
+    for record in records {
+        if tx
+            .send(server::Command::ExecuteTransaction(record.clone()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    if let Some((rate, interest_tx)) = interest {
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        let _ = tx
+            .send(server::Command::AccrueInterest {
+                rate,
+                tx: interest_tx,
+                resp: resp_tx,
+            })
+            .await;
+        let _ = resp_rx.await;
+    }
+
+    let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+    let _ = tx.send(server::Command::GetAccountsState(resp_tx)).await;
+    let accounts = resp_rx.await.unwrap_or_default();
+
+    drop(tx);
+    let _ = handle.await;
+
+    accounts
+}
+
+/// Run the engine over `records`, in order, and return the resulting accounts state.
+///
+/// Test helper which drives the engine end-to-end through its command channel without any file or
+/// CSV handling.
+#[cfg(test)]
+pub async fn process(
+    records: &[crate::model::transaction::TransactionRecord],
+) -> Vec<crate::model::account::Account> {
+    process_with_capacity(records, server::DEFAULT_CHANNEL_CAPACITY).await
+}
+
+/// Same as `process`, with channels of the given capacity.
+#[cfg(test)]
+pub async fn process_with_capacity(
+    records: &[crate::model::transaction::TransactionRecord],
+    channel_capacity: usize,
+) -> Vec<crate::model::account::Account> {
+    let options = Options {
+        channel_capacity,
+        ..Options::default()
+    };
+    run_records(records, options, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::amount::Amount;
+    use crate::model::transaction::{TransactionRecord, TransactionType};
+
+    fn record(
+        transaction_type: TransactionType,
+        client: u16,
+        id: u32,
+        amount: Option<f64>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type,
+            client,
+            id,
+            amount: amount.and_then(Amount::from_f64).map(Ok),
+            tag: None,
+            counterparty: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispute_flow() {
+        let accounts = process(&[
+            record(TransactionType::Deposit, 1, 1, Some(10.0)),
+            record(TransactionType::Deposit, 1, 2, Some(5.0)),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::ChargeBack, 1, 1, None),
+            record(TransactionType::Deposit, 2, 3, Some(3.0)),
+            record(TransactionType::Dispute, 2, 3, None),
+            record(TransactionType::Resolve, 2, 3, None),
+            record(TransactionType::Deposit, 3, 4, Some(7.0)),
+            record(TransactionType::Dispute, 3, 4, None),
+        ])
+        .await;
+
+        assert_eq!(accounts.len(), 3);
+
+        assert_eq!(accounts[0].available(), Amount::from_f64(5.0).unwrap());
+        assert_eq!(accounts[0].held(), Amount::ZERO);
+        assert_eq!(accounts[0].total(), Amount::from_f64(5.0).unwrap());
+        assert!(accounts[0].locked());
+
+        assert_eq!(accounts[1].available(), Amount::from_f64(3.0).unwrap());
+        assert_eq!(accounts[1].held(), Amount::ZERO);
+        assert_eq!(accounts[1].total(), Amount::from_f64(3.0).unwrap());
+        assert!(!accounts[1].locked());
+
+        assert_eq!(accounts[2].available(), Amount::ZERO);
+        assert_eq!(accounts[2].held(), Amount::from_f64(7.0).unwrap());
+        assert_eq!(accounts[2].total(), Amount::from_f64(7.0).unwrap());
+        assert!(!accounts[2].locked());
+    }
+
+    #[tokio::test]
+    async fn test_process_result() {
+        let (tx, rx) = tokio::sync::mpsc::channel(32);
+        let handle = tokio::spawn(run(rx, Options::default(), CancellationToken::new()));
+        let engine = Engine::new(tx);
+
+        engine
+            .process_result(record(TransactionType::Deposit, 1, 1, Some(10.0)))
+            .await
+            .unwrap();
+        assert_eq!(
+            engine
+                .process_result(record(TransactionType::Withdrawal, 1, 2, Some(15.0)))
+                .await,
+            Err(state::Error::Account(
+                crate::model::account::Error::InsufficientFunds
+            ))
+        );
+        assert_eq!(
+            engine
+                .process_result(record(TransactionType::Deposit, 1, 3, None))
+                .await,
+            Err(state::Error::Deposit)
+        );
+        assert_eq!(
+            engine
+                .process_result(record(TransactionType::Deposit, 1, 1, Some(1.0)))
+                .await,
+            Err(state::Error::DuplicateTransactionId)
+        );
+        engine
+            .process_result(record(TransactionType::Withdrawal, 1, 4, Some(10.0)))
+            .await
+            .unwrap();
+
+        drop(engine);
+        let summary = handle.await.unwrap();
+        assert_eq!(summary.processed, 2);
+        assert_eq!(summary.rejected, 3);
+    }
+
+    #[tokio::test]
+    async fn test_accounts_sorted() {
+        for store in [store::Kind::Dashmap, store::Kind::Sharded] {
+            let (tx, rx) = tokio::sync::mpsc::channel(32);
+            let options = Options {
+                store,
+                ..Options::default()
+            };
+            let token = CancellationToken::new();
+            let handle = tokio::spawn(run(rx, options, token.clone()));
+            let engine = Engine::new(tx.clone());
+
+            // Clients show up in no particular order
+            for id in 0..500u32 {
+                let client = (id * 7919 % 500) as u16;
+                tx.send(server::Command::ExecuteTransaction(record(
+                    TransactionType::Deposit,
+                    client,
+                    id,
+                    Some(1.0),
+                )))
+                .await
+                .unwrap();
+            }
+            let accounts = engine.accounts().await.unwrap();
+            assert_eq!(accounts.len(), 500, "{store:?}");
+            assert!(
+                accounts.windows(2).all(|pair| pair[0].id() < pair[1].id()),
+                "{store:?}"
+            );
+
+            token.cancel();
+            drop(tx);
+            handle.await.unwrap();
+            assert!(engine.accounts().await.is_none(), "{store:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let records = [
+            record(TransactionType::Deposit, 2, 1, Some(10.0)),
+            record(TransactionType::Deposit, 1, 2, Some(5.0)),
+            record(TransactionType::Withdrawal, 2, 3, Some(20.0)),
+            record(TransactionType::Dispute, 2, 1, None),
+            record(TransactionType::ChargeBack, 2, 1, None),
+            record(TransactionType::Withdrawal, 1, 4, Some(1.5)),
+            record(TransactionType::Deposit, 3, 5, None),
+        ];
+
+        let accounts = replay(&records, state::Config::default(), None).await;
+
+        assert_eq!(accounts, process(&records).await);
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[0].total(), Amount::from_f64(3.5).unwrap());
+        assert!(accounts[1].locked());
+
+        // Interest is accrued once all records are executed, the locked account is skipped
+        let interest = Some(("0.1".parse().unwrap(), 6));
+        let accounts = replay(&records, state::Config::default(), interest).await;
+        assert_eq!(accounts[0].total(), Amount::from_f64(3.85).unwrap());
+        assert_eq!(accounts[1].total(), Amount::ZERO);
+        assert_eq!(accounts[2].total(), Amount::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_channel_capacity_one() {
+        let records = (1..=500)
+            .flat_map(|id| {
+                let client = (id % 7) as u16;
+                [
+                    record(TransactionType::Deposit, client, id * 2, Some(2.0)),
+                    record(TransactionType::Withdrawal, client, id * 2 + 1, Some(1.5)),
+                ]
+            })
+            .chain([
+                record(TransactionType::Dispute, 3, 6, None),
+                record(TransactionType::ChargeBack, 3, 6, None),
+                record(TransactionType::Dispute, 4, 8, None),
+            ])
+            .collect::<Vec<_>>();
+
+        let accounts = process_with_capacity(&records, 1).await;
+
+        assert_eq!(
+            accounts,
+            replay(&records, state::Config::default(), None).await
+        );
+        assert_eq!(accounts.len(), 7);
+        assert!(accounts[3].locked());
+        assert_eq!(accounts[4].held(), Amount::from_f64(2.0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_restore_snapshot() {
+        let day1 = [
+            record(TransactionType::Deposit, 1, 1, Some(10.0)),
+            record(TransactionType::Deposit, 1, 2, Some(5.5)),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Deposit, 2, 3, Some(3.25)),
+            record(TransactionType::Deposit, 2, 4, Some(1.0)),
+            record(TransactionType::Deposit, 3, 5, Some(2.0)),
+            record(TransactionType::Dispute, 3, 5, None),
+            record(TransactionType::ChargeBack, 3, 5, None),
+        ];
+        let day2 = [
+            // Disputes referring to deposits of the first day
+            record(TransactionType::Resolve, 1, 1, None),
+            record(TransactionType::Dispute, 2, 3, None),
+            record(TransactionType::ChargeBack, 2, 3, None),
+            record(TransactionType::Withdrawal, 1, 6, Some(12.0)),
+            record(TransactionType::Deposit, 3, 7, Some(1.0)),
+            record(TransactionType::Deposit, 4, 8, Some(4.0)),
+        ];
+        let run_day = |snapshots: Option<Vec<state::Snapshot>>, records: Vec<TransactionRecord>| async move {
+            let (tx, rx) = tokio::sync::mpsc::channel(32);
+            let handle = tokio::spawn(run(rx, Options::default(), CancellationToken::new()));
+            if let Some(snapshots) = snapshots {
+                let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+                tx.send(server::Command::Restore(snapshots, resp_tx))
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap().unwrap();
+            }
+            for record in records {
+                tx.send(server::Command::ExecuteTransaction(record))
+                    .await
+                    .unwrap();
+            }
+            let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+            tx.send(server::Command::GetAccountsState(resp_tx))
+                .await
+                .unwrap();
+            let accounts = resp_rx.await.unwrap();
+            let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+            tx.send(server::Command::GetSnapshot(resp_tx))
+                .await
+                .unwrap();
+            let snapshots = resp_rx.await.unwrap();
+            drop(tx);
+            handle.await.unwrap();
+
+            (accounts, snapshots)
+        };
+
+        let (_, snapshots) = run_day(None, day1.to_vec()).await;
+        // Go through the serialized form, as when resuming from a file
+        let snapshots = serde_json::from_slice(&serde_json::to_vec(&snapshots).unwrap()).unwrap();
+        let (accounts, _) = run_day(Some(snapshots), day2.to_vec()).await;
+
+        let expected = process(&[&day1[..], &day2[..]].concat()).await;
+        assert_eq!(accounts, expected);
+        assert_eq!(accounts.len(), 4);
+        assert_eq!(accounts[0].available(), Amount::from_f64(3.5).unwrap());
+        assert_eq!(accounts[0].held(), Amount::ZERO);
+        assert!(accounts[1].locked());
+        assert!(accounts[2].locked());
+        assert_eq!(accounts[2].total(), Amount::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_restore_existing_client() {
+        let snapshots = [1, 2]
+            .map(|client| {
+                let mut state = state::State::new(client);
+                state::Transaction::try_from(&record(
+                    TransactionType::Deposit,
+                    client,
+                    client.into(),
+                    Some(1.0),
+                ))
+                .unwrap()
+                .apply(&mut state)
+                .unwrap();
+                state.snapshot()
+            })
+            .to_vec();
+        let (tx, rx) = tokio::sync::mpsc::channel(32);
+        let handle = tokio::spawn(run(rx, Options::default(), CancellationToken::new()));
+        tx.send(server::Command::ExecuteTransaction(record(
+            TransactionType::Deposit,
+            1,
+            3,
+            Some(5.0),
+        )))
+        .await
+        .unwrap();
+
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        tx.send(server::Command::Restore(snapshots, resp_tx))
+            .await
+            .unwrap();
+        assert!(matches!(
+            resp_rx.await.unwrap(),
+            Err(state::SnapshotError::ClientExists(1))
+        ));
+        // Nothing is restored, not even the clients without a state
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        tx.send(server::Command::GetAccountsState(resp_tx))
+            .await
+            .unwrap();
+        let accounts = resp_rx.await.unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].total(), Amount::from_f64(5.0).unwrap());
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tags() {
+        let mut first = record(TransactionType::Deposit, 1, 1, Some(1.0));
+        first.tag = Some("eu".to_string());
+        let mut conflicting = record(TransactionType::Deposit, 1, 2, Some(1.0));
+        conflicting.tag = Some("us".to_string());
+        let mut late = record(TransactionType::Deposit, 2, 4, Some(1.0));
+        late.tag = Some("us".to_string());
+
+        let accounts = process(&[
+            first,
+            conflicting,
+            record(TransactionType::Deposit, 2, 3, Some(1.0)),
+            late,
+            record(TransactionType::Deposit, 3, 5, Some(1.0)),
+        ])
+        .await;
+
+        assert_eq!(accounts[0].tag(), Some("eu"));
+        assert_eq!(accounts[0].total(), Amount::from_f64(2.0).unwrap());
+        // The first transaction of client 2 is untagged, so the later tag is ignored
+        assert_eq!(accounts[1].tag(), None);
+        assert_eq!(accounts[1].total(), Amount::from_f64(2.0).unwrap());
+        assert_eq!(accounts[2].tag(), None);
+    }
+
+    #[tokio::test]
+    async fn test_same_tx_id_different_clients() {
+        let accounts = process(&[
+            record(TransactionType::Deposit, 1, 1, Some(10.0)),
+            record(TransactionType::Deposit, 2, 1, Some(20.0)),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 2, 1, None),
+            record(TransactionType::Resolve, 1, 1, None),
+            record(TransactionType::ChargeBack, 2, 1, None),
+        ])
+        .await;
+
+        assert_eq!(accounts[0].available(), Amount::from_f64(10.0).unwrap());
+        assert_eq!(accounts[0].held(), Amount::ZERO);
+        assert!(!accounts[0].locked());
 
-    listener.run().await
+        assert_eq!(accounts[1].total(), Amount::ZERO);
+        assert_eq!(accounts[1].held(), Amount::ZERO);
+        assert!(accounts[1].locked());
+    }
 }
@@ -0,0 +1,127 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use dashmap::DashMap;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::model::account::Id as ClientId;
+use crate::model::transaction::{Id as TransactionId, TxKey};
+
+/// Ids of the deposits, withdrawals, unlocks and transfers applied so far, by client, kept across
+/// runs so that re-processing an input skips the transactions it already applied.
+///
+/// Along with the snapshot of the same run, see `State::snapshot`, re-running an input is a no-op:
+/// balances are restored from the snapshot and the transactions of the input are skipped rather
+/// than applied twice. Disputes, resolves and charge backs refer to existing ids, thus are applied
+/// again, but the restored dispute flags reject them without touching the balances.
+#[derive(Debug, Default)]
+pub struct AppliedIds(DashMap<ClientId, HashSet<TransactionId>>);
+
+impl AppliedIds {
+    /// Whether the transaction identified by `key` was applied.
+    pub fn contains(&self, key: TxKey) -> bool {
+        self.0
+            .get(&key.client)
+            .is_some_and(|ids| ids.contains(&key.tx))
+    }
+
+    /// Record the transaction identified by `key` as applied.
+    ///
+    /// Returns whether it was not recorded yet.
+    pub fn insert(&self, key: TxKey) -> bool {
+        self.0.entry(key.client).or_default().insert(key.tx)
+    }
+
+    /// Parse ids saved by `AppliedIds::to_json`.
+    pub fn from_json(json: &[u8]) -> serde_json::Result<Self> {
+        let ids = serde_json::from_slice::<BTreeMap<ClientId, Vec<TransactionId>>>(json)?;
+        Ok(Self(
+            ids.into_iter()
+                .map(|(client, ids)| (client, ids.into_iter().collect()))
+                .collect(),
+        ))
+    }
+
+    /// Save the ids as a JSON object mapping each client to its ids, both sorted.
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        let ids = self
+            .0
+            .iter()
+            .map(|entry| {
+                let mut ids = entry.value().iter().copied().collect::<Vec<_>>();
+                ids.sort_unstable();
+                (*entry.key(), ids)
+            })
+            .collect::<BTreeMap<_, _>>();
+        serde_json::to_vec(&ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::handler::{Handler, Outcome};
+    use crate::engine::state::State;
+    use crate::model::amount::Amount;
+    use crate::model::transaction::{TransactionRecord, TransactionType};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_json() {
+        let applied = AppliedIds::default();
+        for (client, tx) in [(2, 7), (1, 3), (2, 1)] {
+            assert!(applied.insert(TxKey { client, tx }));
+        }
+        assert!(!applied.insert(TxKey { client: 2, tx: 7 }));
+
+        let json = applied.to_json().unwrap();
+        assert_eq!(json, br#"{"1":[3],"2":[1,7]}"#);
+        let parsed = AppliedIds::from_json(&json).unwrap();
+        assert!(parsed.contains(TxKey { client: 2, tx: 1 }));
+        assert!(!parsed.contains(TxKey { client: 1, tx: 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_replay() {
+        let applied = Arc::new(AppliedIds::default());
+        let deposit = TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            id: 1,
+            amount: Amount::from_f64(5.0).map(Ok),
+            tag: None,
+            counterparty: None,
+        };
+
+        // A first run applies the deposit and saves its state, a second run resumes from it
+        let first = Arc::new(DashMap::new());
+        first.insert(1, State::new(1));
+        let mut handler = Handler {
+            state: first.clone(),
+            account_id: 1,
+            chaos: None,
+            rejections: None,
+            persistence: None,
+            metrics: Default::default(),
+            applied: Some(applied.clone()),
+        };
+        assert_eq!(handler.execute(deposit.clone()), Ok(Ok(Outcome::Applied)));
+        let snapshot = first.get(&1).unwrap().snapshot();
+
+        let second = Arc::new(DashMap::new());
+        second.insert(
+            1,
+            State::restore(snapshot, crate::engine::state::Config::default()).unwrap(),
+        );
+        let mut handler = Handler {
+            state: second.clone(),
+            ..handler
+        };
+        // Skipped rather than rejected as a duplicate
+        assert_eq!(handler.execute(deposit), Ok(Ok(Outcome::Skipped)));
+
+        let account = second.get(&1).unwrap().account.clone();
+        assert_eq!(account.total(), Amount::from_f64(5.0).unwrap());
+        assert_eq!(account.available(), Amount::from_f64(5.0).unwrap());
+    }
+}
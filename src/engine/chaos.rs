@@ -0,0 +1,154 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use std::time::Duration;
+
+use crate::model::account::Id as AccountId;
+
+/// How long a handler is delayed by `Fault::DelayHandler`.
+pub const DELAY: Duration = Duration::from_millis(1);
+
+/// Failures which can be injected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Drop a transaction instead of applying it.
+    DropTransaction,
+    /// Delay a handler before it executes a command.
+    DelayHandler,
+    /// Fail a commit.
+    FailCommit,
+}
+
+/// Settings of the failure injection mode, used for resilience testing only.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Config {
+    /// Probability, between 0 and 1, of injecting a failure at every injection point.
+    pub rate: f64,
+    /// Seed of the pseudo-random generator; runs with the same seed inject the same failures.
+    pub seed: u64,
+}
+
+/// Injects failures of a single handler at random, following `Config`.
+///
+/// Uses splitmix64 rather than a generic random generator so a seed keeps producing the same
+/// failures across dependency upgrades.
+#[derive(Debug)]
+pub struct Chaos {
+    rate: f64,
+    state: u64,
+}
+
+impl Chaos {
+    /// Create the injector of the handler of `client`.
+    ///
+    /// The client id is mixed into the seed so the failures injected for a client do not depend on
+    /// how handlers interleave.
+    pub fn new(config: Config, client: AccountId) -> Self {
+        Self {
+            rate: config.rate,
+            state: config.seed ^ u64::from(client).wrapping_mul(0x9e37_79b9_7f4a_7c15),
+        }
+    }
+
+    /// Next pseudo-random number, uniformly distributed in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Whether to inject `fault` at this point.
+    pub fn inject(&mut self, fault: Fault) -> bool {
+        let inject = self.next_f64() < self.rate;
+        if inject {
+            tracing::warn!(?fault, "chaos: injecting failure");
+        }
+
+        inject
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::server::{Command, Listener};
+    use crate::engine::state::Config as StateConfig;
+    use crate::model::amount::Amount;
+    use crate::model::transaction::{TransactionRecord, TransactionType};
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn test_inject() {
+        let config = Config {
+            rate: 0.5,
+            seed: 42,
+        };
+        let faults = |client| {
+            let mut chaos = Chaos::new(config, client);
+            (0..1000)
+                .map(|_| chaos.inject(Fault::DropTransaction))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(faults(1), faults(1));
+        assert_ne!(faults(1), faults(2));
+        let injected = faults(1).into_iter().filter(|inject| *inject).count();
+        assert!((400..600).contains(&injected));
+
+        let mut chaos = Chaos::new(
+            Config {
+                rate: 0.0,
+                seed: 42,
+            },
+            1,
+        );
+        assert!((0..1000).all(|_| !chaos.inject(Fault::FailCommit)));
+        let mut chaos = Chaos::new(
+            Config {
+                rate: 1.0,
+                seed: 42,
+            },
+            1,
+        );
+        assert!((0..1000).all(|_| chaos.inject(Fault::FailCommit)));
+    }
+
+    #[tokio::test]
+    async fn test_reproducible() {
+        let chaos = Config { rate: 0.2, seed: 7 };
+        let run = || async {
+            let (tx, rx) = mpsc::channel(32);
+            let mut listener = Listener::new(rx, StateConfig::default()).with_chaos(Some(chaos));
+            let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+
+            for id in 0..1000 {
+                tx.send(Command::ExecuteTransaction(TransactionRecord {
+                    transaction_type: TransactionType::Deposit,
+                    client: (id % 10) as u16,
+                    id,
+                    amount: Amount::from_f64(1.0).map(Ok),
+                    tag: None,
+                    counterparty: None,
+                }))
+                .await
+                .unwrap();
+            }
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
+            let accounts = resp_rx.await.unwrap();
+            drop(tx);
+
+            (accounts, handle.await.unwrap())
+        };
+
+        let (accounts, summary) = run().await;
+        assert!(summary.rejected > 0);
+        assert_eq!(summary.processed + summary.rejected, 1000);
+        assert_eq!(run().await, (accounts, summary));
+    }
+}
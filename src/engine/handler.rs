@@ -3,94 +3,312 @@
 
 use dashmap::DashMap;
 use std::sync::Arc;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::engine::state::{State, Transaction};
+use crate::engine::applied::AppliedIds;
+use crate::engine::chaos::{self, Chaos, Fault};
+use crate::engine::metrics::EngineMetrics;
+use crate::engine::persistence::AccountsDb;
+use crate::engine::state::{Drift, Error as StateError, Result as StateResult, State, Transaction};
+use crate::engine::store::Store;
 use crate::model::account::Id as AccountId;
-use crate::model::transaction::TransactionRecord;
+use crate::model::transaction::{TransactionRecord, TransactionType, TxKey};
 
 /// Error conditions that may arise when creating a new `Handler` object.
-#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
 pub enum Error {
     /// The state of the handler is invalid.
     #[error("Invalid state")]
     InvalidState,
+    /// Failure injected by the chaos mode.
+    #[error("Injected failure")]
+    InjectedFailure,
+    /// Failure to persist the account.
+    #[error("Failed to persist account: {0}")]
+    Persistence(String),
 }
 
 /// Result of account operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Outcome of the transactions executed by a `Handler`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Summary {
+    /// Number of transactions successfully applied.
+    pub processed: u64,
+    /// Number of transactions rejected, either invalid or failing to apply.
+    pub rejected: u64,
+    /// Number of transactions skipped as already applied, see `Handler::applied`.
+    pub skipped: u64,
+}
+
+/// Outcome of a transaction which was not rejected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Applied to the state of the client.
+    Applied,
+    /// Left out as already applied, see `Handler::applied`.
+    Skipped,
+}
+
+/// Transaction which could not be applied, as reported to a rejection feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// Client and id of the transaction.
+    pub key: TxKey,
+    /// Type of the transaction.
+    pub transaction_type: TransactionType,
+    /// Reason of the rejection.
+    pub error: StateError,
+}
+
 /// Commands received by the Handler from the Listener.
 #[derive(Debug)]
 pub enum Command {
-    /// Execute a transaction.
-    ExecuteTransaction(TransactionRecord),
+    /// Execute a transaction, sending its outcome back if a channel is given.
+    ExecuteTransaction(
+        TransactionRecord,
+        Option<tokio::sync::oneshot::Sender<StateResult<()>>>,
+    ),
     /// Finish executing pending transactions and return.
     Commit(tokio::sync::oneshot::Sender<Result<()>>),
+    /// Respond once the transactions received before are executed, keeping the handler running.
+    Flush(tokio::sync::oneshot::Sender<()>),
+    /// Report the transactions rejected from now on to the given feed.
+    SubscribeRejections(Sender<Rejection>),
+    /// Compare the account against its transaction history, repairing it if the flag is set.
+    CheckBalance(bool, tokio::sync::oneshot::Sender<Option<Drift>>),
+    /// Skip the transactions whose id is in the given set from now on, recording the applied ones.
+    TrackAppliedIds(Arc<AppliedIds>),
 }
 
 /// Handles transactions on a single account.
-pub struct Handler {
+pub struct Handler<S = DashMap<AccountId, State>> {
     /// Sharded state of a single account.
-    pub state: Arc<DashMap<AccountId, State>>,
+    pub state: Arc<S>,
     /// Account id of this handler.
     pub account_id: AccountId,
+    /// Failure injector, only set in chaos mode.
+    pub chaos: Option<Chaos>,
+    /// Feed of the rejected transactions, if any.
+    pub rejections: Option<Sender<Rejection>>,
+    /// Durable copy of the account, upserted on commit, if any.
+    pub persistence: Option<Arc<AccountsDb>>,
+    /// Counters of the transactions applied and rejected, shared with the other handlers.
+    pub metrics: Arc<EngineMetrics>,
+    /// Ids of the deposits, withdrawals and unlocks already applied, skipped if received again,
+    /// if tracked.
+    pub applied: Option<Arc<AppliedIds>>,
 }
 
-impl Handler {
+impl<S: Store> Handler<S> {
+    /// Whether to inject `fault`, always false outside of chaos mode.
+    fn inject(&mut self, fault: Fault) -> bool {
+        self.chaos.as_mut().is_some_and(|chaos| chaos.inject(fault))
+    }
+
+    /// Upsert the account into the durable copy, if any.
+    pub fn persist(&self) -> Result<()> {
+        let Some(persistence) = &self.persistence else {
+            return Ok(());
+        };
+        let account = self
+            .state
+            .update(self.account_id, |state| state.account.clone())
+            .ok_or(Error::InvalidState)?;
+
+        persistence
+            .upsert(&account)
+            .map_err(|e| Error::Persistence(e.to_string()))
+    }
+
+    /// Execute a single transaction and return its outcome.
+    ///
+    /// Fails only if the state of the handler is invalid.
+    pub fn execute(
+        &mut self,
+        transaction_record: TransactionRecord,
+    ) -> Result<StateResult<Outcome>> {
+        let key = transaction_record.key();
+        if key.client != self.account_id {
+            tracing::error! {
+                %key, %self.account_id,
+                "received transaction for another endpoint"
+            };
+            return Ok(Err(StateError::InvalidAccountId));
+        }
+        if self.inject(Fault::DropTransaction) {
+            tracing::warn!(%key, "chaos: dropping transaction");
+            return Ok(Err(StateError::NotExecuted));
+        }
+        let transaction = match Transaction::try_from(&transaction_record) {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                tracing::warn! {
+                    %transaction_record, %e,
+                    "invalid transaction record"
+                };
+                return Ok(Err(e));
+            }
+        };
+        // Disputes, resolves and charge backs share the id of the transaction they refer to
+        let applied = match transaction {
+            Transaction::Deposit(..) | Transaction::Withdrawal(..) | Transaction::Unlock(..) => {
+                self.applied.as_ref()
+            }
+            _ => None,
+        };
+        if applied.is_some_and(|applied| applied.contains(key)) {
+            tracing::debug!(%key, "skipping transaction already applied");
+            return Ok(Ok(Outcome::Skipped));
+        }
+
+        let result = self
+            .state
+            .update(key.client, {
+                let tag = transaction_record.tag.clone();
+                move |state| {
+                    if let Err(e) = state.set_tag(tag.as_deref()) {
+                        tracing::warn! {
+                            %key.client, tag = tag.as_deref(), %e,
+                            "keeping existing tag"
+                        };
+                    }
+
+                    transaction.apply(state)
+                }
+            })
+            .ok_or(Error::InvalidState)?;
+        match &result {
+            Ok(_) => {
+                if let Some(applied) = applied {
+                    applied.insert(key);
+                }
+                tracing::debug! {
+                    %key.client, %transaction,
+                    "success"
+                };
+            }
+            Err(e) => {
+                tracing::warn! {
+                    %key.client, %transaction, %e,
+                    "failure"
+                };
+            }
+        }
+
+        Ok(result.map(|_| Outcome::Applied))
+    }
+
+    /// Execute a single transaction like `execute`, counting it in the metrics and reporting it to
+    /// the rejection feed if it is rejected.
+    ///
+    /// Waits for room in the feed, thus the feed must be consumed while transactions are executed.
+    pub async fn process(
+        &mut self,
+        transaction_record: TransactionRecord,
+    ) -> Result<StateResult<Outcome>> {
+        let (key, transaction_type) = (
+            transaction_record.key(),
+            transaction_record.transaction_type,
+        );
+        let result = self.execute(transaction_record)?;
+        // Skipped transactions were counted by the run which applied them
+        if result != Ok(Outcome::Skipped) {
+            self.metrics
+                .record(transaction_type, &result.clone().map(|_| ()));
+        }
+        if let (Err(error), Some(rejections)) = (&result, &self.rejections) {
+            let rejection = Rejection {
+                key,
+                transaction_type,
+                error: error.clone(),
+            };
+            if rejections.send(rejection).await.is_err() {
+                tracing::debug!(%key, "rejection feed closed");
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Execute commands from `rx` until a commit is received.
+    ///
+    /// Commands queued before the commit are still executed, the commit is answered once
+    /// the channel is drained.
     #[tracing::instrument(name = "Handler::run", skip_all)]
-    pub async fn run(&mut self, rx: &mut Receiver<Command>) -> Result<()> {
+    pub async fn run(&mut self, rx: &mut Receiver<Command>) -> Result<Summary> {
+        let mut summary = Summary::default();
+        let mut commits = Vec::new();
         while let Some(cmd) = rx.recv().await {
+            if self.inject(Fault::DelayHandler) {
+                tokio::time::sleep(chaos::DELAY).await;
+            }
             match cmd {
-                Command::ExecuteTransaction(transaction_record) => {
-                    if transaction_record.client != self.account_id {
-                        tracing::error! {
-                            %transaction_record.client, %self.account_id,
-                            "received transaction for another endpoint"
-                        };
-                        continue;
+                Command::ExecuteTransaction(transaction_record, ack) => {
+                    let result = self.process(transaction_record).await?;
+                    match result {
+                        Ok(Outcome::Applied) => summary.processed += 1,
+                        Ok(Outcome::Skipped) => summary.skipped += 1,
+                        Err(_) => summary.rejected += 1,
                     }
-                    match Transaction::try_from(transaction_record) {
-                        Ok(transaction) => {
-                            let mut state = self
-                                .state
-                                .get_mut(&transaction_record.client)
-                                .ok_or(Error::InvalidState)?;
-
-                            match transaction.apply(state.value_mut()) {
-                                Ok(_) => {
-                                    tracing::debug! {
-                                        %transaction_record.client, %transaction,
-                                        "success"
-                                    };
-                                }
-                                Err(e) => {
-                                    tracing::warn! {
-                                        %transaction_record.client, %transaction, %e,
-                                        "failure"
-                                    };
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            tracing::warn! {
-                                %transaction_record, %e,
-                                "invalid transaction record"
-                            };
+                    if let Some(ack) = ack {
+                        if ack.send(result.map(|_| ())).is_err() {
+                            tracing::debug!("transaction outcome no longer awaited");
                         }
                     }
                 }
+                Command::SubscribeRejections(rejections) => {
+                    self.rejections = Some(rejections);
+                }
+                Command::TrackAppliedIds(applied) => {
+                    self.applied = Some(applied);
+                }
+                Command::CheckBalance(repair, resp) => {
+                    let drift = self
+                        .state
+                        .update(self.account_id, move |state| state.check_balance(repair))
+                        .ok_or(Error::InvalidState)?;
+                    if let Some(drift) = &drift {
+                        tracing::warn! {
+                            %self.account_id, ?drift, repair,
+                            "account balance drifted from transaction history"
+                        };
+                    }
+                    if let Err(e) = resp.send(drift) {
+                        tracing::error!("unable to send balance check response, err: {:?}", e);
+                    }
+                }
+                Command::Flush(resp) => {
+                    if resp.send(()).is_err() {
+                        tracing::debug!("flush no longer awaited");
+                    }
+                }
                 Command::Commit(resp) => {
                     tracing::debug!("received commit");
-                    if let Err(e) = resp.send(Ok(())) {
-                        tracing::error!("unable to send commit response, err: {:?}", e);
-                    }
+                    commits.push(resp);
                     rx.close();
                 }
             }
         }
 
-        Ok(())
+        let persisted = if commits.is_empty() {
+            Ok(())
+        } else {
+            self.persist()
+        };
+        for resp in commits {
+            let result = if self.inject(Fault::FailCommit) {
+                Err(Error::InjectedFailure)
+            } else {
+                persisted.clone()
+            };
+            if let Err(e) = resp.send(result) {
+                tracing::error!("unable to send commit response, err: {:?}", e);
+            }
+        }
+
+        Ok(summary)
     }
 }
 
@@ -105,6 +323,11 @@ mod tests {
     use tokio::sync::oneshot;
 
     #[tokio::test]
+    #[allow(
+        clippy::vec_init_then_push,
+        clippy::let_unit_value,
+        clippy::bool_assert_comparison
+    )]
     async fn test_handler() {
         let client_id = 1;
         let state: Arc<DashMap<AccountId, State>> = Arc::new(DashMap::new());
@@ -114,18 +337,23 @@ mod tests {
         let mut handler = Handler {
             state: state.clone(),
             account_id: client_id,
+            chaos: None,
+            rejections: None,
+            persistence: None,
+            metrics: Default::default(),
+            applied: None,
         };
 
-        let handle = tokio::spawn(async move {
-            handler.run(&mut rx).await.unwrap();
-        });
+        let handle = tokio::spawn(async move { handler.run(&mut rx).await.unwrap() });
         let mut transactions = Vec::new();
         // Invalid account id
         transactions.push(TransactionRecord {
             transaction_type: TransactionType::Deposit,
             client: client_id + 1,
             id: 1,
-            amount: Some(1.0),
+            amount: Amount::from_f64(1.0).map(Ok),
+            tag: None,
+            counterparty: None,
         });
         // Invalid deposit transaction
         transactions.push(TransactionRecord {
@@ -133,16 +361,20 @@ mod tests {
             client: client_id,
             id: 2,
             amount: None,
+            tag: None,
+            counterparty: None,
         });
         // Valid deposit
         transactions.push(TransactionRecord {
             transaction_type: TransactionType::Deposit,
             client: client_id,
             id: 3,
-            amount: Some(12.34),
+            amount: Amount::from_f64(12.34).map(Ok),
+            tag: None,
+            counterparty: None,
         });
         for transaction in transactions {
-            tx.send(Command::ExecuteTransaction(transaction))
+            tx.send(Command::ExecuteTransaction(transaction, None))
                 .await
                 .unwrap();
         }
@@ -150,7 +382,14 @@ mod tests {
         tx.send(Command::Commit(resp_tx)).await.unwrap();
         let result = resp_rx.await.unwrap();
         let _ = result.unwrap();
-        handle.await.unwrap();
+        assert_eq!(
+            handle.await.unwrap(),
+            Summary {
+                processed: 1,
+                rejected: 2,
+                skipped: 0,
+            }
+        );
 
         assert_eq!(
             state.get(&client_id).unwrap().account.available(),
@@ -178,4 +417,113 @@ mod tests {
             )
         );
     }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let client_id = 1;
+        let state: Arc<DashMap<AccountId, State>> = Arc::new(DashMap::new());
+        state.insert(client_id, State::new(client_id));
+
+        let (tx, mut rx) = mpsc::channel(32);
+        let mut handler = Handler {
+            state: state.clone(),
+            account_id: client_id,
+            chaos: None,
+            rejections: None,
+            persistence: None,
+            metrics: Default::default(),
+            applied: None,
+        };
+        let handle = tokio::spawn(async move { handler.run(&mut rx).await.unwrap() });
+
+        for id in 1..=3 {
+            for _ in 0..2 {
+                tx.send(Command::ExecuteTransaction(
+                    TransactionRecord {
+                        transaction_type: TransactionType::Deposit,
+                        client: client_id,
+                        id,
+                        amount: Amount::from_f64(1.0).map(Ok),
+                        tag: None,
+                        counterparty: None,
+                    },
+                    None,
+                ))
+                .await
+                .unwrap();
+            }
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::Flush(resp_tx)).await.unwrap();
+            resp_rx.await.unwrap();
+            // Duplicate ids are rejected, the handler keeps running after each flush
+            assert_eq!(
+                state.get(&client_id).unwrap().account.total(),
+                Amount::from_int(id.into()).unwrap()
+            );
+        }
+
+        drop(tx);
+        assert_eq!(
+            handle.await.unwrap(),
+            Summary {
+                processed: 3,
+                rejected: 3,
+                skipped: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commit_drains_queue() {
+        let client_id = 1;
+        let state: Arc<DashMap<AccountId, State>> = Arc::new(DashMap::new());
+        state.insert(client_id, State::new(client_id));
+
+        // Queue everything before the handler starts running
+        let (tx, mut rx) = mpsc::channel(32);
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::Commit(resp_tx)).await.unwrap();
+        for id in 1..=10 {
+            tx.send(Command::ExecuteTransaction(
+                TransactionRecord {
+                    transaction_type: TransactionType::Deposit,
+                    client: client_id,
+                    id,
+                    amount: Amount::from_f64(1.0).map(Ok),
+                    tag: None,
+                    counterparty: None,
+                },
+                None,
+            ))
+            .await
+            .unwrap();
+        }
+
+        let mut handler = Handler {
+            state: state.clone(),
+            account_id: client_id,
+            chaos: None,
+            rejections: None,
+            persistence: None,
+            metrics: Default::default(),
+            applied: None,
+        };
+        let handle = tokio::spawn(async move { handler.run(&mut rx).await.unwrap() });
+
+        resp_rx.await.unwrap().unwrap();
+        // All queued transactions were applied by the time the commit is answered
+        assert_eq!(
+            state.get(&client_id).unwrap().account.total(),
+            Amount::from_int(10).unwrap()
+        );
+        assert_eq!(
+            handle.await.unwrap(),
+            Summary {
+                processed: 10,
+                rejected: 0,
+                skipped: 0,
+            }
+        );
+        assert!(tx.is_closed());
+    }
 }
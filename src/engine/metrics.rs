@@ -0,0 +1,119 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::engine::state::{Error as StateError, Result as StateResult};
+use crate::model::transaction::TransactionType;
+
+/// Counters of the transactions applied and rejected by the engine, shared by the listener and all
+/// handlers.
+#[derive(Debug, Default)]
+pub struct EngineMetrics {
+    deposits_applied: AtomicU64,
+    withdrawals_applied: AtomicU64,
+    disputes: AtomicU64,
+    resolves: AtomicU64,
+    chargebacks: AtomicU64,
+    unlocks: AtomicU64,
+    transfers: AtomicU64,
+    /// Number of rejected transactions by reason.
+    rejections: DashMap<String, u64>,
+}
+
+impl EngineMetrics {
+    /// Count the outcome of a transaction of type `transaction_type`.
+    pub fn record(&self, transaction_type: TransactionType, result: &StateResult<()>) {
+        let counter = match (transaction_type, result) {
+            (_, Err(e)) => return self.reject(e),
+            (TransactionType::Deposit, Ok(_)) => &self.deposits_applied,
+            (TransactionType::Withdrawal, Ok(_)) => &self.withdrawals_applied,
+            (TransactionType::Dispute, Ok(_)) => &self.disputes,
+            (TransactionType::Resolve, Ok(_)) => &self.resolves,
+            (TransactionType::ChargeBack, Ok(_)) => &self.chargebacks,
+            (TransactionType::Unlock, Ok(_)) => &self.unlocks,
+            (TransactionType::Transfer, Ok(_)) => &self.transfers,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a transaction rejected because of `error`.
+    ///
+    /// Account errors are counted by their own reason, e.g. insufficient funds apart from locked
+    /// accounts, while negative amounts are counted together whatever their value.
+    pub fn reject(&self, error: &StateError) {
+        let reason = match error {
+            StateError::NegativeAmount(_) => "Negative amount".to_string(),
+            e => e.reason(),
+        };
+        *self.rejections.entry(reason).or_default() += 1;
+    }
+
+    /// Copy the current value of the counters.
+    ///
+    /// Transactions being executed meanwhile may or may not be counted.
+    pub fn snapshot(&self) -> EngineMetricsSnapshot {
+        EngineMetricsSnapshot {
+            deposits_applied: self.deposits_applied.load(Ordering::Relaxed),
+            withdrawals_applied: self.withdrawals_applied.load(Ordering::Relaxed),
+            disputes: self.disputes.load(Ordering::Relaxed),
+            resolves: self.resolves.load(Ordering::Relaxed),
+            chargebacks: self.chargebacks.load(Ordering::Relaxed),
+            unlocks: self.unlocks.load(Ordering::Relaxed),
+            transfers: self.transfers.load(Ordering::Relaxed),
+            rejections: self
+                .rejections
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+        }
+    }
+}
+
+/// Value of the `EngineMetrics` counters at some point, see `Command::GetMetrics`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EngineMetricsSnapshot {
+    /// Number of deposits applied.
+    pub deposits_applied: u64,
+    /// Number of withdrawals applied.
+    pub withdrawals_applied: u64,
+    /// Number of disputes applied.
+    pub disputes: u64,
+    /// Number of resolves applied.
+    pub resolves: u64,
+    /// Number of charge backs applied.
+    pub chargebacks: u64,
+    /// Number of unlocks applied.
+    pub unlocks: u64,
+    /// Number of transfers applied.
+    pub transfers: u64,
+    /// Number of rejected transactions by reason, sorted by reason.
+    pub rejections: BTreeMap<String, u64>,
+}
+
+impl EngineMetricsSnapshot {
+    /// Total number of rejected transactions.
+    pub fn rejected(&self) -> u64 {
+        self.rejections.values().sum()
+    }
+}
+
+impl std::fmt::Display for EngineMetricsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "deposits applied: {}", self.deposits_applied)?;
+        writeln!(f, "withdrawals applied: {}", self.withdrawals_applied)?;
+        writeln!(f, "disputes: {}", self.disputes)?;
+        writeln!(f, "resolves: {}", self.resolves)?;
+        writeln!(f, "charge backs: {}", self.chargebacks)?;
+        writeln!(f, "unlocks: {}", self.unlocks)?;
+        writeln!(f, "transfers: {}", self.transfers)?;
+        writeln!(f, "rejected: {}", self.rejected())?;
+        for (reason, count) in &self.rejections {
+            writeln!(f, "  {reason}: {count}")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::model::account::Account;
+
+/// Error conditions that may arise when persisting accounts.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Failure to open or set up the database.
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    /// The thread writing into the database is gone.
+    #[error("database writer stopped")]
+    Stopped,
+}
+
+/// Result of persistence operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Request to the thread writing into the database.
+enum Write {
+    /// Upsert the row of the account.
+    Upsert(Account),
+    /// Respond with the number of upserts which failed since the previous flush, once all the
+    /// previous upserts are done.
+    Flush(oneshot::Sender<u64>),
+}
+
+/// Durable copy of the account balances in the `accounts` table of a SQLite database, upserted by
+/// the handlers as they commit.
+///
+/// Rows are written by a dedicated thread, so handlers never block on SQLite. Amounts are stored as
+/// decimal strings with all their digits, rows of other clients are left untouched.
+pub struct AccountsDb {
+    writes: mpsc::UnboundedSender<Write>,
+}
+
+impl AccountsDb {
+    /// Open the database at `path`, creating it if needed.
+    pub fn open(path: &std::path::Path) -> Result<Self> {
+        Self::new(rusqlite::Connection::open(path)?)
+    }
+
+    /// Persist accounts through `conn`, creating the `accounts` table if it does not exist.
+    pub fn new(conn: rusqlite::Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS accounts (
+                 client INTEGER PRIMARY KEY,
+                 available TEXT NOT NULL,
+                 held TEXT NOT NULL,
+                 total TEXT NOT NULL,
+                 locked INTEGER NOT NULL
+             );",
+        )?;
+        let (writes, rx) = mpsc::unbounded_channel();
+        std::thread::spawn(move || write(conn, rx));
+
+        Ok(Self { writes })
+    }
+
+    /// Queue the row of `account` to be inserted, replacing the previous one of the same client if
+    /// any.
+    ///
+    /// Failures to write it are reported by `flush`.
+    pub fn upsert(&self, account: &Account) -> Result<()> {
+        self.writes
+            .send(Write::Upsert(account.clone()))
+            .map_err(|_| Error::Stopped)
+    }
+
+    /// Wait for the rows queued so far to be written.
+    ///
+    /// Returns the number of rows which failed to be written since the previous flush.
+    pub async fn flush(&self) -> Result<u64> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.writes
+            .send(Write::Flush(resp_tx))
+            .map_err(|_| Error::Stopped)?;
+
+        resp_rx.await.map_err(|_| Error::Stopped)
+    }
+}
+
+/// Execute the `writes` through `conn` until the `AccountsDb` is dropped.
+fn write(conn: rusqlite::Connection, mut writes: mpsc::UnboundedReceiver<Write>) {
+    let mut failures = 0;
+    while let Some(write) = writes.blocking_recv() {
+        match write {
+            Write::Upsert(account) => {
+                if let Err(e) = upsert(&conn, &account) {
+                    tracing::error!(
+                        client = account.id(),
+                        "unable to persist account, err: {:?}",
+                        e
+                    );
+                    failures += 1;
+                }
+            }
+            Write::Flush(resp) => {
+                if resp.send(std::mem::take(&mut failures)).is_err() {
+                    tracing::debug!("flush no longer awaited");
+                }
+            }
+        }
+    }
+}
+
+/// Insert the row of `account`, replacing the previous one of the same client if any.
+fn upsert(conn: &rusqlite::Connection, account: &Account) -> rusqlite::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO accounts (client, available, held, total, locked)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (client) DO UPDATE SET
+             available = excluded.available,
+             held = excluded.held,
+             total = excluded.total,
+             locked = excluded.locked",
+    )?
+    .execute(rusqlite::params![
+        account.id(),
+        account.available().to_string(),
+        account.held().to_string(),
+        account.total().to_string(),
+        account.locked(),
+    ])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::server::{Adaptive, Command, Listener, RunSummary};
+    use crate::engine::state::Config;
+    use crate::model::transaction::{TransactionRecord, TransactionType};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    /// Rows of the `accounts` table of the database at `path`, sorted by client.
+    fn rows(path: &std::path::Path) -> Vec<(u16, String, String, String, bool)> {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        let mut statement = conn
+            .prepare("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+            .unwrap();
+        statement
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    /// Deposit records of `(client, id, amount)`.
+    fn deposits(deposits: &[(u16, u32, &str)]) -> Vec<TransactionRecord> {
+        deposits
+            .iter()
+            .map(|&(client, id, amount)| TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client,
+                id,
+                amount: amount.parse().ok().map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+            .collect()
+    }
+
+    /// Run a listener persisting into `db` over `records`.
+    async fn run(
+        db: AccountsDb,
+        adaptive: Option<Adaptive>,
+        records: Vec<TransactionRecord>,
+    ) -> RunSummary {
+        let (tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::new(rx, Config::default())
+            .with_adaptive(adaptive)
+            .with_persistence(Some(Arc::new(db)));
+        let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+        for record in records {
+            tx.send(Command::ExecuteTransaction(record)).await.unwrap();
+        }
+        drop(tx);
+
+        handle.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_persist_on_commit() {
+        for adaptive in [
+            None,
+            Some(Adaptive {
+                max_clients: 1,
+                max_client_transactions: None,
+            }),
+        ] {
+            let path = std::env::temp_dir().join(format!(
+                "persist-on-commit-{}-{}.db",
+                std::process::id(),
+                adaptive.is_some()
+            ));
+            let db = AccountsDb::open(&path).unwrap();
+            // Rows of clients absent from the run are kept
+            db.upsert(&Account::new(9)).unwrap();
+
+            let records = deposits(&[(1, 1, "1.5"), (2, 2, "2.25"), (1, 3, "0.00001")]);
+            let summary = run(db, adaptive, records).await;
+            let rows = rows(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            let row = |client, amount: &str| {
+                (
+                    client,
+                    amount.to_string(),
+                    "0".to_string(),
+                    amount.to_string(),
+                    false,
+                )
+            };
+            assert_eq!(
+                rows,
+                vec![row(1, "1.50001"), row(2, "2.25"), row(9, "0")],
+                "{adaptive:?}"
+            );
+            assert_eq!(summary.persistence_failures, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persistence_failures() {
+        // Rows of unlocked accounts violate the constraint
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE accounts (
+                 client INTEGER PRIMARY KEY,
+                 available TEXT NOT NULL,
+                 held TEXT NOT NULL,
+                 total TEXT NOT NULL,
+                 locked INTEGER NOT NULL CHECK (locked)
+             );",
+        )
+        .unwrap();
+
+        let records = deposits(&[(1, 1, "1.5"), (2, 2, "2.25")]);
+        let summary = run(AccountsDb::new(conn).unwrap(), None, records).await;
+
+        assert_eq!(summary.processed, 2);
+        assert_eq!(summary.persistence_failures, 2);
+    }
+}
@@ -2,171 +2,2113 @@
 #![deny(warnings)]
 
 use dashmap::DashMap;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::sync::Arc;
 use tokio::sync::mpsc::{self, Receiver};
 use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 
-use crate::engine::handler::{Command as HandlerCommand, Handler};
-use crate::engine::state::State;
+use crate::engine::applied::AppliedIds;
+use crate::engine::chaos::{self, Chaos};
+use crate::engine::handler::{
+    Command as HandlerCommand, Handler, Outcome, Rejection, Result as HandlerResult,
+    Summary as HandlerSummary,
+};
+use crate::engine::metrics::{EngineMetrics, EngineMetricsSnapshot};
+use crate::engine::persistence::AccountsDb;
+use crate::engine::state::{
+    Config, Drift, Error as StateError, Flows, Result as StateResult, Snapshot, SnapshotError,
+    SnapshotTransaction, State, Transaction,
+};
+use crate::engine::store::Store;
 use crate::model::account::{Account, Id as ClientId};
-use crate::model::transaction::TransactionRecord;
+use crate::model::amount::Amount;
+use crate::model::transaction::{Id as TransactionId, TransactionRecord, TransactionType};
+
+/// Default capacity of the channels carrying commands to the listener and to each handler.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 32;
 
 /// Commands accepted by the Listener.
 #[derive(Debug)]
 pub enum Command {
     /// Execute a transaction.
     ExecuteTransaction(TransactionRecord),
-    /// Get a view of all accounts.
+    /// Execute a transaction and send back its outcome once applied or rejected.
+    ExecuteTransactionWithResult(TransactionRecord, oneshot::Sender<StateResult<()>>),
+    /// Get a view of all accounts, sorted in ascending order by client id.
     GetAccountsState(tokio::sync::oneshot::Sender<Vec<Account>>),
+    /// Send all accounts one by one, in ascending order by client id, without collecting them
+    /// first.
+    StreamAccountsState(mpsc::Sender<Account>),
+    /// Get a view of the accounts of the given clients, sorted in ascending order by client id.
+    ///
+    /// Unknown clients are skipped.
+    GetAccountsFiltered(Vec<ClientId>, oneshot::Sender<Vec<Account>>),
+    /// Report every transaction rejected from now on to the given feed, replacing the previous one.
+    ///
+    /// The feed must be consumed while transactions are executed, the engine waits for room in it.
+    SubscribeRejections(mpsc::Sender<Rejection>),
+    /// Skip deposits, withdrawals and unlocks whose id is in the given set from now on, recording
+    /// the ids of those applied into it, see `AppliedIds`.
+    ///
+    /// Skipped transactions succeed without being executed, whatever `Config::duplicate_policy`.
+    TrackAppliedIds(Arc<AppliedIds>),
+    /// Get the totals of all accounts and the funds moved by all transactions, added up.
+    ///
+    /// Responds with `None` if they do not fit in an `Amount`.
+    GetLedger(oneshot::Sender<Option<Ledger>>),
+    /// Restore client states saved by `GetSnapshot`, before any of their transactions.
+    ///
+    /// Responds with an error, restoring none of them, if any snapshot is corrupt or of a client
+    /// which already has a state.
+    Restore(Vec<Snapshot>, oneshot::Sender<Result<(), SnapshotError>>),
+    /// Get the ids of all clients in the order they first appeared, restored clients first.
+    GetClientOrder(oneshot::Sender<Vec<ClientId>>),
+    /// Get snapshots of all client states, sorted in ascending order by client id.
+    GetSnapshot(oneshot::Sender<Vec<Snapshot>>),
+    /// Recompute the account of `client` from its transaction history once its pending
+    /// transactions are executed, replacing the stored account if `repair` is set.
+    ///
+    /// Responds with the drift found, `None` if there is none or the client is unknown.
+    CheckBalance {
+        /// Client whose account is checked.
+        client: ClientId,
+        /// Replace the stored account with the recomputed one.
+        repair: bool,
+        /// Channel receiving the drift.
+        resp: oneshot::Sender<Option<Drift>>,
+    },
+    /// Check that the available and held funds of every account add up to its total once all
+    /// pending transactions are executed.
+    ///
+    /// Responds with the violation of the client with the lowest id, if any.
+    VerifyInvariants(oneshot::Sender<Result<(), InvariantViolation>>),
+    /// Get the counters of the transactions applied by type and rejected by reason, once all
+    /// pending transactions are executed.
+    GetMetrics(oneshot::Sender<EngineMetricsSnapshot>),
+    /// Credit interest on the available funds of every unlocked account once all pending
+    /// transactions are executed, see `State::accrue_interest`.
+    ///
+    /// Responds with the interest credited to every account, zero for locked ones, or the reason
+    /// it could not be, in client order.
+    AccrueInterest {
+        /// Interest rate, e.g. 0.05 for 5%.
+        rate: Amount,
+        /// Id of the interest transaction recorded in the history of each account credited.
+        tx: TransactionId,
+        /// Channel receiving the interest credited to each client.
+        resp: oneshot::Sender<Vec<(ClientId, StateResult<Amount>)>>,
+    },
+}
+
+/// Outcome of a `Listener` run.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    /// Number of transactions successfully applied.
+    pub processed: u64,
+    /// Number of transactions rejected, either invalid or failing to apply.
+    pub rejected: u64,
+    /// Number of transactions skipped as already applied, see `Command::TrackAppliedIds`.
+    pub skipped: u64,
+    /// Number of client accounts created.
+    pub accounts_created: u64,
+    /// Highest number of handlers alive at the same time.
+    pub peak_handlers: u64,
+    /// Number of account updates which could not be persisted, see `Listener::with_persistence`.
+    pub persistence_failures: u64,
+}
+
+/// Totals of all accounts along with the funds moved by all transactions, see
+/// `Command::GetLedger`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Ledger {
+    /// Funds moved by all transactions.
+    pub flows: Flows,
+    /// Totals of all accounts.
+    pub total: Amount,
+}
+
+impl Ledger {
+    /// Whether the account totals add up to the funds moved by the transactions.
+    pub fn is_balanced(&self) -> bool {
+        self.flows.total() == Some(self.total)
+    }
+}
+
+/// Account whose available and held funds do not add up to its total, see
+/// `Command::VerifyInvariants`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("client {client}: available {available} + held {held} != total {total}")]
+pub struct InvariantViolation {
+    /// Client owning the account.
+    pub client: ClientId,
+    /// Available funds of the account.
+    pub available: Amount,
+    /// Held funds of the account.
+    pub held: Amount,
+    /// Total funds of the account.
+    pub total: Amount,
+}
+
+impl InvariantViolation {
+    /// Check that the available and held funds of `account` add up to its total.
+    fn check(account: &Account) -> Result<(), Self> {
+        if account.available().checked_add(account.held()) == Some(account.total()) {
+            return Ok(());
+        }
+
+        Err(Self {
+            client: account.id(),
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+        })
+    }
+}
+
+/// Thresholds of the adaptive strategy, executing transactions inline in the listener until
+/// spawning a handler per client pays off.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Adaptive {
+    /// Spawn handlers once transactions of more than `max_clients` clients were received.
+    pub max_clients: usize,
+    /// Spawn handlers once a client received more than `max_client_transactions` transactions, if
+    /// set.
+    pub max_client_transactions: Option<u64>,
+}
+
+/// Client claiming each transaction id, for feeds whose ids are unique across clients.
+///
+/// Ids are claimed when their transaction is routed, before it is applied, thus a claim only holds
+/// once the state of its client is found to hold the transaction, see `State::has_transaction`.
+#[derive(Debug)]
+pub struct TxOwners {
+    owners: HashMap<TransactionId, ClientId>,
+    /// Number of claims beyond which those of transactions no longer held are pruned.
+    prune_at: usize,
+}
+
+impl Default for TxOwners {
+    fn default() -> Self {
+        Self {
+            owners: HashMap::new(),
+            prune_at: TX_OWNERS_PRUNE_AT,
+        }
+    }
+}
+
+/// Number of claims `TxOwners` holds before first pruning them.
+const TX_OWNERS_PRUNE_AT: usize = 1 << 16;
+
+impl TxOwners {
+    /// Claim the id of `transaction` for its client if it is the id of a new deposit, withdrawal,
+    /// unlock or transfer.
+    ///
+    /// Returns the other client which claimed the id first, if any, in which case the id is left
+    /// claimed by that client. Disputes, resolves and charge backs refer to existing ids, thus only
+    /// need to match their owner.
+    pub fn claim(&mut self, transaction: &TransactionRecord) -> Option<ClientId> {
+        let key = transaction.key();
+        match self.owners.get(&key.tx) {
+            Some(owner) if *owner != key.client => Some(*owner),
+            Some(_) => None,
+            None => {
+                if !matches!(
+                    transaction.transaction_type,
+                    TransactionType::Dispute
+                        | TransactionType::Resolve
+                        | TransactionType::ChargeBack
+                ) {
+                    self.owners.insert(key.tx, key.client);
+                }
+                None
+            }
+        }
+    }
+
+    /// Drop the claim on `tx`, e.g. once its client rejected the transaction.
+    pub fn release(&mut self, tx: TransactionId) {
+        self.owners.remove(&tx);
+    }
+
+    /// Record `client` as the owner of `tx`, e.g. for transactions restored from a snapshot.
+    fn insert(&mut self, tx: TransactionId, client: ClientId) {
+        self.owners.insert(tx, client);
+    }
+
+    /// Whether enough ids were claimed since the last pruning to prune them again.
+    fn should_prune(&self) -> bool {
+        self.owners.len() > self.prune_at
+    }
+
+    /// Keep the claims of the ids `held` returns for each client, e.g. dropping those of
+    /// transactions rejected or evicted from the history of their client.
+    ///
+    /// The next pruning happens once the claims kept doubled, so that pruning takes amortized
+    /// constant time per claim.
+    fn prune(&mut self, mut held: impl FnMut(ClientId, Vec<TransactionId>) -> Vec<TransactionId>) {
+        let mut by_client = HashMap::<ClientId, Vec<TransactionId>>::new();
+        for (tx, client) in self.owners.drain() {
+            by_client.entry(client).or_default().push(tx);
+        }
+        for (client, txs) in by_client {
+            self.owners
+                .extend(held(client, txs).into_iter().map(|tx| (tx, client)));
+        }
+        self.prune_at = TX_OWNERS_PRUNE_AT.max(self.owners.len() * 2);
+    }
+}
+
+/// Clients whose transactions are executed inline, with the number of transactions each received.
+struct Inline<S> {
+    thresholds: Adaptive,
+    clients: HashMap<ClientId, (Handler<S>, u64)>,
+}
+
+impl<S> Inline<S> {
+    /// Whether one more transaction of `client` stays within the thresholds.
+    fn admits(&self, client: ClientId) -> bool {
+        match self.clients.get(&client) {
+            Some((_, count)) => self
+                .thresholds
+                .max_client_transactions
+                .is_none_or(|max| *count < max),
+            None => self.clients.len() < self.thresholds.max_clients,
+        }
+    }
+}
+
+impl<S: Store> Inline<S> {
+    /// Persist the accounts of the clients executed inline, which have no handler task to commit.
+    ///
+    /// Done once no more transactions are executed inline; later transactions of these clients go
+    /// through handlers which persist them again on commit.
+    fn persist(&self) {
+        for (handler, _) in self.clients.values() {
+            if let Err(e) = handler.persist() {
+                tracing::error!("unable to persist account, err: {:?}", e);
+            }
+        }
+    }
 }
 
 /// Waits for commands and dispatches them to handlers.
-pub struct Listener {
-    accounts: Arc<DashMap<ClientId, State>>,
+///
+/// With an adaptive strategy, transactions are first executed inline by the listener itself; once
+/// a threshold is crossed, the listener switches to a handler per client for good.
+pub struct Listener<S = DashMap<ClientId, State>> {
+    accounts: Arc<S>,
     tx_handlers: HashMap<ClientId, mpsc::Sender<HandlerCommand>>,
+    handlers: JoinSet<HandlerResult<HandlerSummary>>,
+    inline: Option<Inline<S>>,
+    /// Clients in the order their state was created.
+    first_seen: Vec<ClientId>,
+    /// Feed of the rejected transactions, if any.
+    rejections: Option<mpsc::Sender<Rejection>>,
+    /// Owners of the transaction ids, only tracked if `Config::unique_tx_ids` is set.
+    tx_owners: TxOwners,
     rx: Receiver<Command>,
+    config: Config,
+    chaos: Option<chaos::Config>,
+    /// Capacity of the channel of each handler.
+    channel_capacity: usize,
+    /// Durable copy of the accounts, if any.
+    persistence: Option<Arc<AccountsDb>>,
+    /// Counters of the transactions applied and rejected, shared with the handlers.
+    metrics: Arc<EngineMetrics>,
+    /// Ids of the transactions already applied, if tracked.
+    applied: Option<Arc<AppliedIds>>,
+    summary: RunSummary,
 }
 
 impl Listener {
-    pub fn new(rx: Receiver<Command>) -> Self {
+    pub fn new(rx: Receiver<Command>, config: Config) -> Self {
+        Self::with_store(rx, config, Arc::new(DashMap::new()))
+    }
+}
+
+impl<S: Store> Listener<S> {
+    /// Create a listener keeping account states in `accounts`.
+    pub fn with_store(rx: Receiver<Command>, config: Config, accounts: Arc<S>) -> Self {
         Self {
-            accounts: Arc::new(DashMap::new()),
+            accounts,
             tx_handlers: HashMap::new(),
+            handlers: JoinSet::new(),
+            inline: None,
+            first_seen: Vec::new(),
+            rejections: None,
+            tx_owners: TxOwners::default(),
             rx,
+            config,
+            chaos: None,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            persistence: None,
+            metrics: Arc::new(EngineMetrics::default()),
+            applied: None,
+            summary: RunSummary::default(),
         }
     }
 
-    /// Run the listener
-    #[tracing::instrument(name = "Listener::run", skip_all)]
-    pub async fn run(&mut self) {
-        while let Some(cmd) = self.rx.recv().await {
-            tracing::debug!("received cmd {:?}", cmd,);
-            match cmd {
-                Command::ExecuteTransaction(transaction) => {
-                    if let std::collections::hash_map::Entry::Vacant(e) =
-                        self.tx_handlers.entry(transaction.client)
-                    {
-                        let (tx, mut rx) = mpsc::channel(32);
-
-                        e.insert(tx);
-                        self.accounts
-                            .entry(transaction.client)
-                            .or_insert(State::new(transaction.client));
-
-                        let mut handler = Handler {
-                            state: self.accounts.clone(),
-                            account_id: transaction.client,
-                        };
-
-                        tracing::debug!("spawning new handler for client {}", transaction.client);
-                        tokio::spawn(async move {
-                            if let Err(err) = handler.run(&mut rx).await {
-                                tracing::error!("handler error: {:?}", err);
-                            }
-                        });
+    /// Inject failures in the handlers according to `chaos`, if given.
+    pub fn with_chaos(mut self, chaos: Option<chaos::Config>) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    /// Queue up to `capacity` commands per handler before routing blocks, `DEFAULT_CHANNEL_CAPACITY`
+    /// by default.
+    ///
+    /// A low capacity increases backpressure on the sender, a high one the memory held by queued
+    /// transactions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_channel_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "channel capacity must be positive");
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Upsert each account into `persistence`, if given, once its transactions are committed.
+    ///
+    /// Accounts which fail to be persisted are counted in `RunSummary::persistence_failures`.
+    pub fn with_persistence(mut self, persistence: Option<Arc<AccountsDb>>) -> Self {
+        self.persistence = persistence;
+        self
+    }
+
+    /// Execute transactions inline according to `adaptive`, if given, instead of spawning a handler
+    /// per client right away.
+    pub fn with_adaptive(mut self, adaptive: Option<Adaptive>) -> Self {
+        self.inline = adaptive.map(|thresholds| Inline {
+            thresholds,
+            clients: HashMap::new(),
+        });
+        self
+    }
+
+    /// Whether transactions are still executed inline by the listener.
+    #[cfg(test)]
+    pub fn is_inline(&self) -> bool {
+        self.inline.is_some()
+    }
+
+    /// Wait for all handlers whose channel was dropped to finish and add up their outcome.
+    async fn join_handlers(&mut self) {
+        while let Some(result) = self.handlers.join_next().await {
+            match result {
+                Ok(Ok(summary)) => {
+                    self.summary.processed += summary.processed;
+                    self.summary.rejected += summary.rejected;
+                    self.summary.skipped += summary.skipped;
+                }
+                Ok(Err(err)) => {
+                    tracing::error!("handler error: {:?}", err);
+                }
+                Err(err) => {
+                    tracing::error!("unable to join handler, err: {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Number of handlers alive, i.e. spawned and not joined yet.
+    pub fn handler_count(&self) -> usize {
+        self.handlers.len()
+    }
+
+    /// Clients whose handler stopped, e.g. on an internal error, while still registered.
+    fn dead_handlers(&self) -> Vec<ClientId> {
+        let mut dead: Vec<_> = self
+            .tx_handlers
+            .iter()
+            .filter(|(_, sender)| sender.is_closed())
+            .map(|(client, _)| *client)
+            .collect();
+        dead.sort_unstable();
+        dead
+    }
+
+    /// Check that every registered handler is still running.
+    ///
+    /// Transactions routed to a dead handler would be lost and its state never committed; it is a
+    /// bug, thus debug builds panic.
+    fn check_handlers(&self) {
+        let dead = self.dead_handlers();
+        if !dead.is_empty() {
+            tracing::error!(?dead, "handlers stopped before being committed");
+        }
+        debug_assert!(
+            dead.is_empty(),
+            "handlers {dead:?} stopped before being committed"
+        );
+    }
+
+    /// Execute `transaction` in the listener, unless it crosses a threshold of the adaptive
+    /// strategy.
+    ///
+    /// Returns the transaction back if it was not executed, i.e. it must go through a handler.
+    async fn execute_inline(
+        &mut self,
+        transaction: TransactionRecord,
+        ack: Option<oneshot::Sender<StateResult<()>>>,
+    ) -> Option<(TransactionRecord, Option<oneshot::Sender<StateResult<()>>>)> {
+        let Some(inline) = &mut self.inline else {
+            return Some((transaction, ack));
+        };
+        let client = transaction.key().client;
+        if !inline.admits(client) {
+            tracing::info!(
+                clients = inline.clients.len(),
+                "switching to per-client handlers"
+            );
+            inline.persist();
+            self.inline = None;
+            return Some((transaction, ack));
+        }
+
+        let (handler, count) = inline.clients.entry(client).or_insert_with(|| {
+            if self
+                .accounts
+                .insert(client, State::with_config(client, self.config))
+            {
+                self.summary.accounts_created += 1;
+                self.first_seen.push(client);
+            }
+            let handler = Handler {
+                state: self.accounts.clone(),
+                account_id: client,
+                chaos: self.chaos.map(|chaos| Chaos::new(chaos, client)),
+                rejections: self.rejections.clone(),
+                persistence: self.persistence.clone(),
+                metrics: self.metrics.clone(),
+                applied: self.applied.clone(),
+            };
+            (handler, 0)
+        });
+        *count += 1;
+        let result = handler.process(transaction).await.unwrap_or_else(|e| {
+            tracing::error!("unable to execute transaction inline, err: {:?}", e);
+            Err(StateError::NotExecuted)
+        });
+        match result {
+            Ok(Outcome::Applied) => self.summary.processed += 1,
+            Ok(Outcome::Skipped) => self.summary.skipped += 1,
+            Err(_) => self.summary.rejected += 1,
+        }
+        if let Some(ack) = ack {
+            if ack.send(result.map(|_| ())).is_err() {
+                tracing::debug!("transaction outcome no longer awaited");
+            }
+        }
+
+        None
+    }
+
+    /// Reject `transaction` without executing it, reporting it like the handlers do.
+    async fn reject(
+        &mut self,
+        transaction: TransactionRecord,
+        ack: Option<oneshot::Sender<StateResult<()>>>,
+        error: StateError,
+    ) {
+        let key = transaction.key();
+        tracing::debug!(%key, ?error, "transaction rejected by the listener");
+        self.summary.rejected += 1;
+        self.metrics.reject(&error);
+        if let Some(rejections) = &self.rejections {
+            let rejection = Rejection {
+                key,
+                transaction_type: transaction.transaction_type,
+                error: error.clone(),
+            };
+            if rejections.send(rejection).await.is_err() {
+                tracing::debug!(%key, "rejection feed closed");
+            }
+        }
+        if let Some(ack) = ack {
+            if ack.send(Err(error)).is_err() {
+                tracing::debug!("transaction outcome no longer awaited");
+            }
+        }
+    }
+
+    /// Send `transaction` to the handler of its client, spawning the handler if needed, or execute
+    /// it inline while the adaptive strategy allows it.
+    async fn route(
+        &mut self,
+        transaction: TransactionRecord,
+        ack: Option<oneshot::Sender<StateResult<()>>>,
+    ) {
+        // Handlers only see the transactions of their client, thus the listener is the one place
+        // seeing ids across clients.
+        if self.config.unique_tx_ids {
+            if let Some(owner) = self.tx_owners.claim(&transaction) {
+                let tx = transaction.key().tx;
+                self.flush_handler(owner).await;
+                if self
+                    .accounts
+                    .update(owner, move |state| state.has_transaction(tx))
+                    .unwrap_or(false)
+                {
+                    let error = StateError::TransactionClientMismatch;
+                    self.reject(transaction, ack, error).await;
+                    return;
+                }
+                // The transaction of the first client was rejected, thus never owned the id
+                self.tx_owners.release(tx);
+                self.tx_owners.claim(&transaction);
+            }
+            if self.tx_owners.should_prune() {
+                self.prune_tx_owners().await;
+            }
+        }
+        if transaction.transaction_type == TransactionType::Transfer {
+            self.transfer(transaction, ack).await;
+            return;
+        }
+        let Some((transaction, ack)) = self.execute_inline(transaction, ack).await else {
+            return;
+        };
+        // Transactions are routed by client, each handler owning the transaction ids of a single
+        // client.
+        let client = transaction.key().client;
+        if let std::collections::hash_map::Entry::Vacant(e) = self.tx_handlers.entry(client) {
+            let (tx, mut rx) = mpsc::channel(self.channel_capacity);
+
+            e.insert(tx);
+            if self
+                .accounts
+                .insert(client, State::with_config(client, self.config))
+            {
+                self.summary.accounts_created += 1;
+                self.first_seen.push(client);
+            }
+
+            let mut handler = Handler {
+                state: self.accounts.clone(),
+                account_id: client,
+                chaos: self.chaos.map(|chaos| Chaos::new(chaos, client)),
+                rejections: self.rejections.clone(),
+                persistence: self.persistence.clone(),
+                metrics: self.metrics.clone(),
+                applied: self.applied.clone(),
+            };
+
+            tracing::debug!("spawning new handler for client {}", client);
+            self.handlers
+                .spawn(async move { handler.run(&mut rx).await });
+            self.summary.peak_handlers =
+                self.summary.peak_handlers.max(self.handler_count() as u64);
+        }
+        if let Some(sender) = self.tx_handlers.get(&client) {
+            if let Err(e) = sender
+                .send(HandlerCommand::ExecuteTransaction(transaction, ack))
+                .await
+            {
+                tracing::error!("unable to send transaction {:?}, err: {}", e.0, e);
+            }
+        }
+    }
+
+    /// Apply transfer `transaction` to the states of both its clients at once.
+    ///
+    /// Transfers span two handlers, thus are applied by the listener itself once both handlers
+    /// executed the transactions sent to them so far.
+    async fn transfer(
+        &mut self,
+        transaction: TransactionRecord,
+        ack: Option<oneshot::Sender<StateResult<()>>>,
+    ) {
+        let key = transaction.key();
+        let transfer = match Transaction::try_from(&transaction) {
+            Ok(transfer) => transfer,
+            Err(error) => return self.reject(transaction, ack, error).await,
+        };
+        let Transaction::Transfer(_, receiver, _) = transfer else {
+            return self.reject(transaction, ack, StateError::Transfer).await;
+        };
+        if receiver == key.client {
+            return self.reject(transaction, ack, StateError::Transfer).await;
+        }
+        if self
+            .applied
+            .as_ref()
+            .is_some_and(|applied| applied.contains(key))
+        {
+            tracing::debug!(%key, "skipping transaction already applied");
+            self.summary.skipped += 1;
+            if let Some(ack) = ack {
+                if ack.send(Ok(())).is_err() {
+                    tracing::debug!("transaction outcome no longer awaited");
+                }
+            }
+            return;
+        }
+
+        for client in [key.client, receiver] {
+            self.flush_handler(client).await;
+        }
+
+        // Both states cannot be borrowed from the store at once, thus they are moved out while the
+        // transfer is applied. Clients without a state only get one if the transfer succeeds.
+        let [from, to] = [key.client, receiver].map(|client| {
+            match self.accounts.update(client, std::mem::take) {
+                Some(state) => (state, false),
+                None => (State::with_config(client, self.config), true),
+            }
+        });
+        let ((mut from, from_created), (mut to, to_created)) = (from, to);
+        let result = transfer.transfer(&mut from, &mut to);
+        for (client, state, created) in
+            [(key.client, from, from_created), (receiver, to, to_created)]
+        {
+            if !created {
+                self.accounts
+                    .update(client, move |current| *current = state);
+            } else if result.is_ok() && self.accounts.insert(client, state) {
+                self.summary.accounts_created += 1;
+                self.first_seen.push(client);
+            }
+        }
+        if let Err(error) = result {
+            return self.reject(transaction, ack, error).await;
+        }
+
+        tracing::debug!(%key, %transfer, "transfer applied");
+        self.summary.processed += 1;
+        self.metrics.record(TransactionType::Transfer, &Ok(()));
+        if let Some(applied) = &self.applied {
+            applied.insert(key);
+        }
+        if let Some(persistence) = &self.persistence {
+            for client in [key.client, receiver] {
+                let Some(account) = self.accounts.update(client, |state| state.account.clone())
+                else {
+                    continue;
+                };
+                if let Err(e) = persistence.upsert(&account) {
+                    tracing::error!("unable to persist account, err: {:?}", e);
+                }
+            }
+        }
+        if let Some(ack) = ack {
+            if ack.send(Ok(())).is_err() {
+                tracing::debug!("transaction outcome no longer awaited");
+            }
+        }
+    }
+
+    /// Wait for the handler of `client`, if any, to execute its pending transactions.
+    async fn flush_handler(&self, client: ClientId) {
+        let Some(handler) = self.tx_handlers.get(&client) else {
+            return;
+        };
+        let (resp_tx, resp_rx) = oneshot::channel();
+        match handler.send(HandlerCommand::Flush(resp_tx)).await {
+            Ok(_) => {
+                if let Err(e) = resp_rx.await {
+                    tracing::error!("unable to receive flush response, err: {:?}", e);
+                }
+            }
+            Err(e) => tracing::error!("unable to send flush, err: {:?}", e),
+        }
+    }
+
+    /// Drop the claims on transaction ids whose transaction was rejected or evicted from the
+    /// history of its client, once all pending transactions are executed.
+    async fn prune_tx_owners(&mut self) {
+        self.flush_handlers().await;
+        let accounts = self.accounts.clone();
+        self.tx_owners.prune(|client, mut txs| {
+            accounts
+                .update(client, move |state| {
+                    txs.retain(|tx| state.has_transaction(*tx));
+                    txs
+                })
+                .unwrap_or_default()
+        });
+    }
+
+    /// Wait for all handlers to execute their pending transactions, keeping them running.
+    async fn flush_handlers(&mut self) {
+        self.check_handlers();
+        let mut acks = Vec::with_capacity(self.tx_handlers.len());
+        for handler in self.tx_handlers.values() {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            match handler.send(HandlerCommand::Flush(resp_tx)).await {
+                Ok(_) => acks.push(resp_rx),
+                Err(e) => {
+                    tracing::error!("unable to send flush, err: {:?}", e);
+                }
+            }
+        }
+        for ack in acks {
+            if let Err(e) = ack.await {
+                tracing::error!("unable to receive flush response, err: {:?}", e);
+            }
+        }
+    }
+
+    /// Commit all handlers and wait for them to finish their pending transactions.
+    async fn commit_handlers(&mut self) {
+        self.check_handlers();
+        for handler in self.tx_handlers.values() {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            match handler.send(HandlerCommand::Commit(resp_tx)).await {
+                Ok(_) => match resp_rx.await {
+                    Ok(resp) => {
+                        if let Err(e) = resp {
+                            tracing::error!("handler did not successfully commit, err: {:?}", e);
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("unable to receive commit response, err: {:?}", e);
                     }
-                    if let Some(sender) = self.tx_handlers.get(&transaction.client) {
-                        if let Err(e) = sender
-                            .send(HandlerCommand::ExecuteTransaction(transaction))
+                },
+                Err(e) => {
+                    tracing::error!("unable to send commit, err: {:?}", e);
+                }
+            }
+        }
+        self.tx_handlers.clear();
+        self.join_handlers().await;
+    }
+
+    /// Execute a single command.
+    async fn execute(&mut self, cmd: Command) {
+        tracing::debug!("received cmd {:?}", cmd,);
+        match cmd {
+            Command::ExecuteTransaction(transaction) => self.route(transaction, None).await,
+            Command::ExecuteTransactionWithResult(transaction, resp) => {
+                self.route(transaction, Some(resp)).await
+            }
+            Command::CheckBalance {
+                client,
+                repair,
+                resp,
+            } => {
+                // Go through the handler, if any, so the check sees all the transactions received
+                // before it.
+                let drift = match self.tx_handlers.get(&client) {
+                    Some(handler) => {
+                        let (resp_tx, resp_rx) = oneshot::channel();
+                        match handler
+                            .send(HandlerCommand::CheckBalance(repair, resp_tx))
                             .await
                         {
-                            tracing::error!(
-                                "unable to send transaction {:?}, err: {}",
-                                transaction,
-                                e
-                            );
+                            Ok(_) => resp_rx.await.unwrap_or_else(|e| {
+                                tracing::error!(
+                                    "unable to receive balance check response, err: {:?}",
+                                    e
+                                );
+                                None
+                            }),
+                            Err(e) => {
+                                tracing::error!("unable to send balance check, err: {:?}", e);
+                                None
+                            }
                         }
                     }
+                    None => self
+                        .accounts
+                        .update(client, move |state| state.check_balance(repair))
+                        .flatten(),
+                };
+                if let Err(e) = resp.send(drift) {
+                    tracing::error!("unable to send balance check response, err: {:?}", e);
                 }
-                Command::GetAccountsState(resp) => {
-                    tracing::debug!("get accounts state");
-                    for handler in self.tx_handlers.values() {
-                        let (resp_tx, resp_rx) = oneshot::channel();
-                        match handler.send(HandlerCommand::Commit(resp_tx)).await {
-                            Ok(_) => match resp_rx.await {
-                                Ok(resp) => {
-                                    if let Err(e) = resp {
-                                        tracing::error!(
-                                            "handler did not successfully commit, err: {:?}",
-                                            e
-                                        );
-                                    }
-                                }
-                                Err(e) => {
-                                    tracing::error!(
-                                        "unable to receive commit response, err: {:?}",
-                                        e
-                                    );
-                                }
-                            },
-                            Err(e) => {
-                                tracing::error!("unable to send commit, err: {:?}", e);
+            }
+            Command::GetAccountsState(resp) => {
+                tracing::debug!("get accounts state");
+                self.flush_handlers().await;
+                let mut accounts = self.accounts.accounts();
+                accounts.sort_unstable_by_key(|account| account.id());
+                if let Err(e) = resp.send(accounts) {
+                    tracing::error!("unable to send accounts state, err: {:?}", e);
+                }
+            }
+            Command::StreamAccountsState(sender) => {
+                tracing::debug!("stream accounts state");
+                self.flush_handlers().await;
+                let mut clients = self.accounts.clients();
+                clients.sort_unstable();
+                for client in clients {
+                    let Some(account) = self.accounts.update(client, |state| state.account.clone())
+                    else {
+                        continue;
+                    };
+                    if sender.send(account).await.is_err() {
+                        tracing::error!("accounts stream closed early");
+                        break;
+                    }
+                }
+            }
+            Command::GetAccountsFiltered(mut clients, resp) => {
+                tracing::debug!("get accounts of {} clients", clients.len());
+                self.flush_handlers().await;
+                clients.sort_unstable();
+                clients.dedup();
+                let accounts = clients
+                    .into_iter()
+                    .filter_map(|client| {
+                        self.accounts.update(client, |state| state.account.clone())
+                    })
+                    .collect::<Vec<_>>();
+                if let Err(e) = resp.send(accounts) {
+                    tracing::error!("unable to send accounts state, err: {:?}", e);
+                }
+            }
+            Command::SubscribeRejections(rejections) => {
+                tracing::debug!("subscribe to rejections");
+                if let Some(inline) = &mut self.inline {
+                    for (handler, _) in inline.clients.values_mut() {
+                        handler.rejections = Some(rejections.clone());
+                    }
+                }
+                for handler in self.tx_handlers.values() {
+                    let cmd = HandlerCommand::SubscribeRejections(rejections.clone());
+                    if let Err(e) = handler.send(cmd).await {
+                        tracing::error!("unable to subscribe handler to rejections, err: {:?}", e);
+                    }
+                }
+                self.rejections = Some(rejections);
+            }
+            Command::TrackAppliedIds(applied) => {
+                tracing::debug!("track applied transaction ids");
+                if let Some(inline) = &mut self.inline {
+                    for (handler, _) in inline.clients.values_mut() {
+                        handler.applied = Some(applied.clone());
+                    }
+                }
+                for handler in self.tx_handlers.values() {
+                    let cmd = HandlerCommand::TrackAppliedIds(applied.clone());
+                    if let Err(e) = handler.send(cmd).await {
+                        tracing::error!("unable to track applied ids in handler, err: {:?}", e);
+                    }
+                }
+                self.applied = Some(applied);
+            }
+            Command::GetLedger(resp) => {
+                tracing::debug!("get ledger");
+                self.flush_handlers().await;
+                let ledger = self.accounts.clients().into_iter().try_fold(
+                    Ledger::default(),
+                    |ledger, client| {
+                        let (flows, total) = self
+                            .accounts
+                            .update(client, |state| (state.flows(), state.account.total()))?;
+                        Some(Ledger {
+                            flows: ledger.flows.checked_add(&flows?)?,
+                            total: ledger.total.checked_add(total)?,
+                        })
+                    },
+                );
+                if let Err(e) = resp.send(ledger) {
+                    tracing::error!("unable to send ledger, err: {:?}", e);
+                }
+            }
+            Command::VerifyInvariants(resp) => {
+                tracing::debug!("verify invariants");
+                self.flush_handlers().await;
+                let mut accounts = self.accounts.accounts();
+                accounts.sort_unstable_by_key(Account::id);
+                let result = accounts.iter().try_for_each(InvariantViolation::check);
+                if let Err(e) = resp.send(result) {
+                    tracing::error!("unable to send invariant check, err: {:?}", e);
+                }
+            }
+            Command::GetMetrics(resp) => {
+                tracing::debug!("get metrics");
+                self.flush_handlers().await;
+                if let Err(e) = resp.send(self.metrics.snapshot()) {
+                    tracing::error!("unable to send metrics, err: {:?}", e);
+                }
+            }
+            Command::AccrueInterest { rate, tx, resp } => {
+                tracing::debug!(%rate, tx, "accrue interest");
+                self.flush_handlers().await;
+                let mut clients = self.accounts.clients();
+                clients.sort_unstable();
+                let mut accruals = Vec::with_capacity(clients.len());
+                for client in clients {
+                    let result: Option<StateResult<_>> =
+                        self.accounts.update(client, move |state| {
+                            let interest = state.accrue_interest(tx, rate)?;
+                            Ok((interest, state.account.clone()))
+                        });
+                    let Some(result) = result else {
+                        continue;
+                    };
+                    if let (Ok((interest, account)), Some(persistence)) =
+                        (&result, &self.persistence)
+                    {
+                        if interest.is_positive() {
+                            if let Err(e) = persistence.upsert(account) {
+                                tracing::error!("unable to persist account, err: {:?}", e);
                             }
                         }
                     }
-                    self.tx_handlers.clear();
-                    if let Err(e) = resp.send(
-                        self.accounts
-                            .clone()
+                    accruals.push((client, result.map(|(interest, _)| interest)));
+                }
+                if let Err(e) = resp.send(accruals) {
+                    tracing::error!("unable to send interest accrual, err: {:?}", e);
+                }
+            }
+            Command::Restore(snapshots, resp) => {
+                // Nothing is restored unless every snapshot is valid
+                let states = snapshots
+                    .into_iter()
+                    .map(|snapshot| {
+                        let txs = snapshot
+                            .transactions
                             .iter()
-                            .map(|r| r.pair().1.account)
-                            .collect::<Vec<Account>>(),
-                    ) {
-                        tracing::error!("unable to send accounts state, err: {:?}", e);
+                            .map(SnapshotTransaction::tx)
+                            .collect::<Vec<_>>();
+                        State::restore(snapshot, self.config).map(|state| (state, txs))
+                    })
+                    .collect::<Result<Vec<_>, _>>();
+                let result = states.and_then(|states| {
+                    // A client with a state, or a handler about to create one, already received
+                    // transactions the snapshot would overwrite.
+                    let mut clients = HashSet::new();
+                    for (state, _) in &states {
+                        let client = state.account.id();
+                        if !clients.insert(client)
+                            || self.tx_handlers.contains_key(&client)
+                            || self.accounts.update(client, |_| ()).is_some()
+                        {
+                            return Err(SnapshotError::ClientExists(client));
+                        }
+                    }
+                    for (state, txs) in states {
+                        let client = state.account.id();
+                        self.accounts.insert(client, state);
+                        self.first_seen.push(client);
+                        if self.config.unique_tx_ids {
+                            for tx in txs {
+                                self.tx_owners.insert(tx, client);
+                            }
+                        }
                     }
+                    Ok(())
+                });
+                if let Err(e) = resp.send(result) {
+                    tracing::error!("unable to send restore result, err: {:?}", e);
+                }
+            }
+            Command::GetClientOrder(resp) => {
+                if let Err(e) = resp.send(self.first_seen.clone()) {
+                    tracing::error!("unable to send client order, err: {:?}", e);
+                }
+            }
+            Command::GetSnapshot(resp) => {
+                tracing::debug!("get snapshot");
+                self.flush_handlers().await;
+                let mut snapshots = self.accounts.snapshots();
+                snapshots.sort_unstable_by_key(|snapshot| snapshot.client);
+                if let Err(e) = resp.send(snapshots) {
+                    tracing::error!("unable to send snapshot, err: {:?}", e);
                 }
             }
         }
     }
+
+    /// Run the listener until all command senders are dropped or `token` is cancelled.
+    ///
+    /// Cancellation is safe with regards to commands: once cancelled no more commands are
+    /// accepted, but those already queued are still executed and all handlers finish their
+    /// pending transactions before returning.
+    ///
+    /// Returns a summary of all transactions executed.
+    #[tracing::instrument(name = "Listener::run", skip_all)]
+    pub async fn run(&mut self, token: CancellationToken) -> RunSummary {
+        loop {
+            tokio::select! {
+                cmd = self.rx.recv() => match cmd {
+                    Some(cmd) => self.execute(cmd).await,
+                    None => break,
+                },
+                _ = token.cancelled() => {
+                    tracing::debug!("cancelled, draining queued commands");
+                    // Closing rejects further sends, the queue is then drained without waiting on
+                    // senders which may never be dropped.
+                    self.rx.close();
+                    while let Ok(cmd) = self.rx.try_recv() {
+                        self.execute(cmd).await;
+                    }
+                    break;
+                }
+            }
+        }
+
+        self.commit_handlers().await;
+        if let Some(inline) = &self.inline {
+            inline.persist();
+        }
+        if let Some(persistence) = &self.persistence {
+            match persistence.flush().await {
+                Ok(failures) => self.summary.persistence_failures += failures,
+                Err(e) => {
+                    tracing::error!("unable to persist accounts, err: {:?}", e);
+                    self.summary.persistence_failures += 1;
+                }
+            }
+        }
+        tracing::debug!(
+            self.summary.processed,
+            self.summary.rejected,
+            self.summary.skipped,
+            self.summary.accounts_created,
+            self.summary.peak_handlers,
+            self.summary.persistence_failures,
+            "listener finished"
+        );
+
+        self.summary
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::model::amount::Amount;
-    use crate::model::transaction::{TransactionRecord, TransactionType};
-    use tokio::sync::mpsc;
-    use tokio::sync::oneshot;
+    use crate::engine::process;
+    use crate::model::account::Error as AccountError;
+    use crate::model::transaction::{TransactionRecord, TransactionType, TxKey};
 
     #[tokio::test]
     async fn test_engine() {
-        // Start server
+        let transactions = (1..10001)
+            .map(|i| TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client: i,
+                id: i as u32,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+            .collect::<Vec<_>>();
+
+        let result = process(&transactions).await;
+
+        assert_eq!(result.len(), 10000);
+        assert!(result
+            .iter()
+            .all(|acc| acc.available() == Amount::from_f64(1.0).unwrap()));
+        assert!(result
+            .iter()
+            .all(|acc| acc.total() == Amount::from_f64(1.0).unwrap()));
+        assert!(result.iter().all(|acc| acc.held() == Amount::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_accounts_state_sorted() {
+        let transactions = [5, 3, 9, 1, 7]
+            .into_iter()
+            .enumerate()
+            .map(|(id, client)| TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client,
+                id: id as u32,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+            .collect::<Vec<_>>();
+
+        let result = process(&transactions).await;
+
+        assert_eq!(
+            result.iter().map(|acc| acc.id()).collect::<Vec<_>>(),
+            vec![1, 3, 5, 7, 9]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accounts_filtered() {
         let (tx, rx) = mpsc::channel(32);
-        let mut listener = Listener::new(rx);
-        tokio::spawn(async move { listener.run().await });
+        let mut listener = Listener::new(rx, Config::default());
+        tokio::spawn(async move { listener.run(CancellationToken::new()).await });
 
-        // Send transactions
-        let mut transactions: Vec<TransactionRecord> = Vec::new();
+        for client in 1..=5 {
+            tx.send(Command::ExecuteTransaction(TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client,
+                id: client as u32,
+                amount: Amount::from_int(client.into()).map(Ok),
+                tag: None,
+                counterparty: None,
+            }))
+            .await
+            .unwrap();
+        }
 
-        for i in 1..10001 {
-            transactions.push(TransactionRecord {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::GetAccountsFiltered(vec![5, 42, 1, 3, 1], resp_tx))
+            .await
+            .unwrap();
+        let accounts = resp_rx.await.unwrap();
+
+        assert_eq!(
+            accounts.iter().map(|acc| acc.id()).collect::<Vec<_>>(),
+            vec![1, 3, 5]
+        );
+        // Transactions sent before the request are executed
+        assert!(accounts
+            .iter()
+            .all(|acc| acc.total() == Amount::from_int(acc.id().into()).unwrap()));
+    }
+
+    /// Run a listener with the `adaptive` strategy over deposits of 1 to `clients`, `per_client`
+    /// deposits each, round robin.
+    ///
+    /// Returns the summary, whether the listener is still inline and the accounts.
+    async fn run_adaptive(
+        adaptive: Adaptive,
+        clients: u16,
+        per_client: u32,
+    ) -> (RunSummary, bool, Vec<Account>) {
+        let (tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::new(rx, Config::default()).with_adaptive(Some(adaptive));
+        let handle = tokio::spawn(async move {
+            let summary = listener.run(CancellationToken::new()).await;
+            (summary, listener.is_inline())
+        });
+
+        for round in 0..per_client {
+            for client in 0..clients {
+                tx.send(Command::ExecuteTransaction(TransactionRecord {
+                    transaction_type: TransactionType::Deposit,
+                    client,
+                    id: round * clients as u32 + client as u32,
+                    amount: Amount::from_f64(1.0).map(Ok),
+                    tag: None,
+                    counterparty: None,
+                }))
+                .await
+                .unwrap();
+            }
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
+        let accounts = resp_rx.await.unwrap();
+        drop(tx);
+        let (summary, inline) = handle.await.unwrap();
+
+        (summary, inline, accounts)
+    }
+
+    #[tokio::test]
+    async fn test_adaptive() {
+        let per_client = Amount::from_int(3).unwrap();
+        let adaptive = Adaptive {
+            max_clients: 10,
+            max_client_transactions: None,
+        };
+
+        // Low cardinality stays inline
+        let (summary, inline, accounts) = run_adaptive(adaptive, 10, 3).await;
+        assert!(inline);
+        assert_eq!(summary.peak_handlers, 0);
+        assert_eq!(summary.processed, 30);
+        assert_eq!(summary.accounts_created, 10);
+        assert_eq!(accounts.len(), 10);
+        assert!(accounts.iter().all(|acc| acc.total() == per_client));
+
+        // High cardinality switches to handlers, without losing or reordering transactions
+        let (summary, inline, accounts) = run_adaptive(adaptive, 100, 3).await;
+        assert!(!inline);
+        assert!(summary.peak_handlers > 0);
+        assert_eq!(summary.processed, 300);
+        assert_eq!(summary.rejected, 0);
+        assert_eq!(summary.accounts_created, 100);
+        assert_eq!(accounts.len(), 100);
+        assert!(accounts.iter().all(|acc| acc.total() == per_client));
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_client_transactions() {
+        let adaptive = Adaptive {
+            max_clients: 10,
+            max_client_transactions: Some(5),
+        };
+
+        let (summary, inline, _) = run_adaptive(adaptive, 2, 5).await;
+        assert!(inline);
+        assert_eq!(summary.peak_handlers, 0);
+
+        let (summary, inline, accounts) = run_adaptive(adaptive, 2, 6).await;
+        assert!(!inline);
+        assert_eq!(summary.peak_handlers, 2);
+        assert_eq!(summary.processed, 12);
+        assert!(accounts
+            .iter()
+            .all(|acc| acc.total() == Amount::from_int(6).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_client_order() {
+        for adaptive in [
+            None,
+            Some(Adaptive {
+                max_clients: 2,
+                max_client_transactions: None,
+            }),
+        ] {
+            let (tx, rx) = mpsc::channel(32);
+            let mut listener = Listener::new(rx, Config::default()).with_adaptive(adaptive);
+            tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+
+            for (id, client) in [5, 3, 9, 3, 1, 5, 7].into_iter().enumerate() {
+                tx.send(Command::ExecuteTransaction(TransactionRecord {
+                    transaction_type: TransactionType::Deposit,
+                    client,
+                    id: id as u32,
+                    amount: Amount::from_f64(1.0).map(Ok),
+                    tag: None,
+                    counterparty: None,
+                }))
+                .await
+                .unwrap();
+            }
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::GetClientOrder(resp_tx)).await.unwrap();
+            assert_eq!(resp_rx.await.unwrap(), vec![5, 3, 9, 1, 7], "{adaptive:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_summary() {
+        let (tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::new(rx, Config::default());
+        let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+
+        let transactions = [
+            (TransactionType::Deposit, 1, 1, Some(1.0)),
+            // Duplicate transaction id
+            (TransactionType::Deposit, 1, 1, Some(1.0)),
+            // Insufficient funds
+            (TransactionType::Withdrawal, 2, 2, Some(1.0)),
+            // Invalid record
+            (TransactionType::Deposit, 3, 3, None),
+            (TransactionType::Dispute, 1, 1, None),
+        ];
+        for (transaction_type, client, id, amount) in transactions {
+            tx.send(Command::ExecuteTransaction(TransactionRecord {
+                transaction_type,
+                client,
+                id,
+                amount: amount.and_then(Amount::from_f64).map(Ok),
+                tag: None,
+                counterparty: None,
+            }))
+            .await
+            .unwrap();
+        }
+        drop(tx);
+
+        assert_eq!(
+            handle.await.unwrap(),
+            RunSummary {
+                processed: 2,
+                rejected: 3,
+                skipped: 0,
+                accounts_created: 3,
+                peak_handlers: 3,
+                persistence_failures: 0,
+            }
+        );
+    }
+
+    async fn check_balance(tx: &mpsc::Sender<Command>, repair: bool) -> Option<Drift> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::CheckBalance {
+            client: 1,
+            repair,
+            resp: resp_tx,
+        })
+        .await
+        .unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_check_balance() {
+        let (tx, rx) = mpsc::channel(32);
+        let accounts = Arc::new(DashMap::new());
+        let mut listener = Listener::with_store(rx, Config::default(), accounts.clone());
+        tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+
+        for id in 1..4 {
+            tx.send(Command::ExecuteTransaction(TransactionRecord {
                 transaction_type: TransactionType::Deposit,
-                client: i,
-                id: i as u32,
-                amount: Some(1.0),
+                client: 1,
+                id,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
+            }))
+            .await
+            .unwrap();
+        }
+        assert_eq!(check_balance(&tx, false).await, None);
+
+        // Corrupt the stored account
+        accounts.get_mut(&1).unwrap().account = Account::new(1);
+        let drift = check_balance(&tx, false).await.unwrap();
+        assert_eq!(drift.stored, Account::new(1));
+        assert_eq!(
+            drift.recomputed.unwrap().total(),
+            Amount::from_f64(3.0).unwrap()
+        );
+
+        assert!(check_balance(&tx, true).await.is_some());
+        assert_eq!(check_balance(&tx, false).await, None);
+        assert_eq!(
+            accounts.get(&1).unwrap().account.available(),
+            Amount::from_f64(3.0).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handler_count() {
+        let (_tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::new(rx, Config::default());
+        let deposit = |client, id| {
+            Command::ExecuteTransaction(TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client,
+                id,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
             })
+        };
+
+        for cycle in 0..3 {
+            for client in 0..5 {
+                listener.execute(deposit(client, cycle)).await;
+                listener.execute(deposit(client, cycle + 100)).await;
+            }
+            assert_eq!(listener.handler_count(), 5);
+            assert_eq!(listener.tx_handlers.len(), 5);
+
+            // Querying the accounts keeps the handlers running
+            let (resp_tx, resp_rx) = oneshot::channel();
+            listener.execute(Command::GetAccountsState(resp_tx)).await;
+            assert_eq!(resp_rx.await.unwrap().len(), 5);
+            assert_eq!(listener.handler_count(), 5);
+            assert_eq!(listener.tx_handlers.len(), 5);
         }
 
-        for transaction in transactions {
-            tx.send(Command::ExecuteTransaction(transaction))
+        assert!(listener.dead_handlers().is_empty());
+        listener.commit_handlers().await;
+        assert_eq!(listener.handler_count(), 0);
+        assert!(listener.tx_handlers.is_empty());
+        assert_eq!(listener.summary.peak_handlers, 5);
+        assert_eq!(listener.summary.accounts_created, 5);
+        assert_eq!(listener.summary.processed, 30);
+    }
+
+    #[tokio::test]
+    async fn test_dead_handlers() {
+        let (_tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::new(rx, Config::default());
+        let (alive, _alive_rx) = mpsc::channel(1);
+        let (dead, dead_rx) = mpsc::channel(1);
+        drop(dead_rx);
+        listener.tx_handlers.insert(1, alive);
+        listener.tx_handlers.insert(2, dead);
+
+        assert_eq!(listener.dead_handlers(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_accounts_state_between_transactions() {
+        let (tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::new(rx, Config::default());
+        let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+        let deposit = |id| {
+            Command::ExecuteTransaction(TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client: 1,
+                id,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+        };
+        let accounts = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
+            resp_rx.await.unwrap()
+        };
+
+        tx.send(deposit(1)).await.unwrap();
+        assert_eq!(accounts().await[0].total(), Amount::from_int(1).unwrap());
+        tx.send(deposit(2)).await.unwrap();
+        assert_eq!(accounts().await[0].total(), Amount::from_int(2).unwrap());
+        // The same handler executes all transactions, it still rejects a reused id
+        tx.send(deposit(2)).await.unwrap();
+        assert_eq!(accounts().await[0].total(), Amount::from_int(2).unwrap());
+
+        drop(tx);
+        let summary = handle.await.unwrap();
+        assert_eq!(summary.processed, 2);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.peak_handlers, 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_accounts_state() {
+        // Every possible client, streamed through a channel holding a single account at a time
+        let accounts = Arc::new(DashMap::new());
+        for client in 0..=ClientId::MAX {
+            let mut state = State::new(client);
+            state.account.deposit(Amount::from_int(1).unwrap()).unwrap();
+            accounts.insert(client, state);
+        }
+        let (tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::with_store(rx, Config::default(), accounts);
+        tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+
+        let (acc_tx, mut acc_rx) = mpsc::channel(1);
+        tx.send(Command::StreamAccountsState(acc_tx)).await.unwrap();
+        let mut count = 0;
+        while let Some(account) = acc_rx.recv().await {
+            assert_eq!(account.id() as usize, count);
+            assert_eq!(account.total(), Amount::from_int(1).unwrap());
+            count += 1;
+        }
+        assert_eq!(count, ClientId::MAX as usize + 1);
+
+        // Dropping the receiver early stops the stream, the listener keeps running
+        let (acc_tx, mut acc_rx) = mpsc::channel(1);
+        tx.send(Command::StreamAccountsState(acc_tx)).await.unwrap();
+        assert_eq!(acc_rx.recv().await.unwrap().id(), 0);
+        drop(acc_rx);
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::GetAccountsFiltered(vec![7], resp_tx))
+            .await
+            .unwrap();
+        assert_eq!(resp_rx.await.unwrap()[0].id(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_rejections() {
+        for adaptive in [
+            None,
+            Some(Adaptive {
+                max_clients: 1,
+                max_client_transactions: None,
+            }),
+        ] {
+            let (tx, rx) = mpsc::channel(32);
+            let mut listener = Listener::new(rx, Config::default()).with_adaptive(adaptive);
+            let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+            let deposit = |client, id| {
+                Command::ExecuteTransaction(TransactionRecord {
+                    transaction_type: TransactionType::Deposit,
+                    client,
+                    id,
+                    amount: Amount::from_f64(1.0).map(Ok),
+                    tag: None,
+                    counterparty: None,
+                })
+            };
+
+            // Client 1 has a running handler, or is executed inline, before subscribing
+            tx.send(deposit(1, 1)).await.unwrap();
+            let (rejections_tx, mut rejections_rx) = mpsc::channel(32);
+            tx.send(Command::SubscribeRejections(rejections_tx))
+                .await
+                .unwrap();
+            tx.send(deposit(1, 1)).await.unwrap();
+            tx.send(deposit(2, 2)).await.unwrap();
+            tx.send(deposit(2, 2)).await.unwrap();
+            tx.send(Command::ExecuteTransaction(TransactionRecord {
+                transaction_type: TransactionType::Dispute,
+                client: 2,
+                id: 9,
+                amount: None,
+                tag: None,
+                counterparty: None,
+            }))
+            .await
+            .unwrap();
+            drop(tx);
+            assert_eq!(handle.await.unwrap().rejected, 3);
+
+            let mut rejections = Vec::new();
+            while let Some(rejection) = rejections_rx.recv().await {
+                rejections.push(rejection);
+            }
+            rejections.sort_by_key(|rejection| rejection.key.tx);
+            let key = |client, tx| TxKey { client, tx };
+            assert_eq!(
+                rejections,
+                vec![
+                    Rejection {
+                        key: key(1, 1),
+                        transaction_type: TransactionType::Deposit,
+                        error: StateError::DuplicateTransactionId,
+                    },
+                    Rejection {
+                        key: key(2, 2),
+                        transaction_type: TransactionType::Deposit,
+                        error: StateError::DuplicateTransactionId,
+                    },
+                    Rejection {
+                        key: key(2, 9),
+                        transaction_type: TransactionType::Dispute,
+                        error: StateError::Dispute,
+                    },
+                ],
+                "{adaptive:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unique_tx_ids() {
+        for unique_tx_ids in [false, true] {
+            let (tx, rx) = mpsc::channel(32);
+            let config = Config {
+                unique_tx_ids,
+                ..Config::default()
+            };
+            let mut listener = Listener::new(rx, config);
+            let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+            let execute = |transaction_type, client, id, amount: Option<f64>| {
+                let tx = tx.clone();
+                async move {
+                    let (resp_tx, resp_rx) = oneshot::channel();
+                    tx.send(Command::ExecuteTransactionWithResult(
+                        TransactionRecord {
+                            transaction_type,
+                            client,
+                            id,
+                            amount: amount.and_then(Amount::from_f64).map(Ok),
+                            tag: None,
+                            counterparty: None,
+                        },
+                        resp_tx,
+                    ))
+                    .await
+                    .unwrap();
+                    resp_rx.await.unwrap()
+                }
+            };
+
+            execute(TransactionType::Deposit, 1, 1, Some(5.0))
+                .await
+                .unwrap();
+            execute(TransactionType::Deposit, 2, 2, Some(5.0))
+                .await
+                .unwrap();
+            // Dispute of a deposit of client 1 sent by client 2
+            let dispute = execute(TransactionType::Dispute, 2, 1, None).await;
+            // Deposit of client 2 reusing the id of a deposit of client 1
+            let deposit = execute(TransactionType::Deposit, 2, 1, Some(5.0)).await;
+            if unique_tx_ids {
+                assert_eq!(dispute, Err(StateError::TransactionClientMismatch));
+                assert_eq!(deposit, Err(StateError::TransactionClientMismatch));
+            } else {
+                assert_eq!(dispute, Err(StateError::Dispute));
+                assert_eq!(deposit, Ok(()));
+            }
+
+            // Disputes of the owner go through
+            execute(TransactionType::Dispute, 1, 1, None).await.unwrap();
+            execute(TransactionType::Dispute, 2, 2, None).await.unwrap();
+
+            // A rejected withdrawal does not own its id
+            assert_eq!(
+                execute(TransactionType::Withdrawal, 1, 3, Some(50.0)).await,
+                Err(StateError::Account(AccountError::InsufficientFunds))
+            );
+            execute(TransactionType::Deposit, 2, 3, Some(1.0))
+                .await
+                .unwrap();
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
+            let accounts = resp_rx.await.unwrap();
+            assert_eq!(accounts[0].held(), Amount::from_int(5).unwrap());
+            assert_eq!(
+                accounts[1].total(),
+                Amount::from_int(if unique_tx_ids { 6 } else { 11 }).unwrap()
+            );
+
+            drop(tx);
+            let summary = handle.await.unwrap();
+            assert_eq!(summary.rejected, if unique_tx_ids { 3 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn test_tx_owners_prune() {
+        let deposit = |client, id| TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client,
+            id,
+            amount: Amount::from_f64(1.0).map(Ok),
+            tag: None,
+            counterparty: None,
+        };
+        let mut owners = TxOwners::default();
+        for id in 0..=TX_OWNERS_PRUNE_AT as TransactionId {
+            assert_eq!(owners.claim(&deposit(1 + id as ClientId % 2, id)), None);
+        }
+        assert!(owners.should_prune());
+
+        // Client 1 only holds its last transaction
+        owners.prune(|client, mut txs| {
+            if client == 1 {
+                txs.retain(|tx| *tx == TX_OWNERS_PRUNE_AT as TransactionId);
+            }
+            txs
+        });
+        assert!(!owners.should_prune());
+        assert_eq!(owners.claim(&deposit(2, 0)), None);
+        assert_eq!(owners.claim(&deposit(1, 1)), Some(2));
+        assert_eq!(
+            owners.claim(&deposit(2, TX_OWNERS_PRUNE_AT as TransactionId)),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ledger() {
+        let (tx, rx) = mpsc::channel(32);
+        let accounts = Arc::new(DashMap::new());
+        let mut listener = Listener::with_store(rx, Config::default(), accounts.clone());
+        tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+        let record = |transaction_type, client, id, amount: Option<f64>| {
+            Command::ExecuteTransaction(TransactionRecord {
+                transaction_type,
+                client,
+                id,
+                amount: amount.and_then(Amount::from_f64).map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+        };
+        let get_ledger = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::GetLedger(resp_tx)).await.unwrap();
+            resp_rx.await.unwrap().unwrap()
+        };
+
+        for cmd in [
+            record(TransactionType::Deposit, 1, 1, Some(10.0)),
+            record(TransactionType::Deposit, 2, 2, Some(5.0)),
+            record(TransactionType::Withdrawal, 1, 3, Some(4.0)),
+            record(TransactionType::Withdrawal, 2, 4, Some(1.0)),
+            // Rejected, insufficient funds
+            record(TransactionType::Withdrawal, 2, 5, Some(100.0)),
+            record(TransactionType::Deposit, 1, 6, Some(2.0)),
+            record(TransactionType::Dispute, 1, 6, None),
+            record(TransactionType::ChargeBack, 1, 6, None),
+            record(TransactionType::Dispute, 2, 4, None),
+        ] {
+            tx.send(cmd).await.unwrap();
+        }
+        let ledger = get_ledger().await;
+        assert!(ledger.is_balanced());
+        assert_eq!(
+            ledger,
+            Ledger {
+                flows: Flows {
+                    opening: Amount::ZERO,
+                    deposited: Amount::from_f64(17.0).unwrap(),
+                    withdrawn: Amount::from_f64(5.0).unwrap(),
+                    charged_back: Amount::from_f64(2.0).unwrap(),
+                    reversed: Amount::from_f64(1.0).unwrap(),
+                    interest: Amount::ZERO,
+                },
+                total: Amount::from_f64(11.0).unwrap(),
+            }
+        );
+
+        // Corrupt an account behind the engine's back
+        accounts.get_mut(&2).unwrap().account = Account::with_balances(
+            2,
+            Amount::from_f64(9.0).unwrap(),
+            Amount::ZERO,
+            Amount::from_f64(9.0).unwrap(),
+            false,
+        );
+        let ledger = get_ledger().await;
+        assert!(!ledger.is_balanced());
+        assert_eq!(ledger.total, Amount::from_f64(15.0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_verify_invariants() {
+        let (tx, rx) = mpsc::channel(32);
+        let accounts = Arc::new(DashMap::new());
+        let mut listener = Listener::with_store(rx, Config::default(), accounts.clone());
+        let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+
+        for (transaction_type, client, id, amount) in [
+            (TransactionType::Deposit, 1, 1, Amount::from_f64(5.0)),
+            (TransactionType::Deposit, 2, 2, Amount::from_f64(3.0)),
+            (TransactionType::Dispute, 2, 2, None),
+            (TransactionType::Deposit, 3, 3, Amount::from_f64(1.0)),
+        ] {
+            tx.send(Command::ExecuteTransaction(TransactionRecord {
+                transaction_type,
+                client,
+                id,
+                amount: amount.map(Ok),
+                tag: None,
+                counterparty: None,
+            }))
+            .await
+            .unwrap();
+        }
+        let verify = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::VerifyInvariants(resp_tx)).await.unwrap();
+            resp_rx.await.unwrap()
+        };
+        assert_eq!(verify().await, Ok(()));
+
+        // Corrupt two accounts behind the engine's back, the lowest client id is reported
+        for client in [3, 2] {
+            accounts.get_mut(&client).unwrap().account = Account::with_balances(
+                client,
+                Amount::from_f64(1.0).unwrap(),
+                Amount::from_f64(3.0).unwrap(),
+                Amount::from_f64(3.0).unwrap(),
+                false,
+            );
+        }
+        assert_eq!(
+            verify().await,
+            Err(InvariantViolation {
+                client: 2,
+                available: Amount::from_f64(1.0).unwrap(),
+                held: Amount::from_f64(3.0).unwrap(),
+                total: Amount::from_f64(3.0).unwrap(),
+            })
+        );
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_skipped() {
+        for adaptive in [
+            None,
+            Some(Adaptive {
+                max_clients: 1,
+                max_client_transactions: None,
+            }),
+        ] {
+            let (tx, rx) = mpsc::channel(32);
+            let mut listener = Listener::new(rx, Config::default()).with_adaptive(adaptive);
+            let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+            let applied = Arc::new(AppliedIds::default());
+            applied.insert(TxKey { client: 1, tx: 1 });
+            applied.insert(TxKey { client: 1, tx: 2 });
+            tx.send(Command::TrackAppliedIds(applied)).await.unwrap();
+
+            for (transaction_type, id, counterparty) in [
+                (TransactionType::Deposit, 1, None),
+                (TransactionType::Transfer, 2, Some(2)),
+                (TransactionType::Deposit, 3, None),
+            ] {
+                tx.send(Command::ExecuteTransaction(TransactionRecord {
+                    transaction_type,
+                    client: 1,
+                    id,
+                    amount: Amount::from_f64(1.0).map(Ok),
+                    tag: None,
+                    counterparty,
+                }))
+                .await
+                .unwrap();
+            }
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::GetMetrics(resp_tx)).await.unwrap();
+            let metrics = resp_rx.await.unwrap();
+            drop(tx);
+            let summary = handle.await.unwrap();
+
+            // Skipped transactions are not counted as applied
+            assert_eq!(metrics.deposits_applied, 1, "{adaptive:?}");
+            assert_eq!(metrics.transfers, 0, "{adaptive:?}");
+            assert_eq!(summary.processed, 1, "{adaptive:?}");
+            assert_eq!(summary.skipped, 2, "{adaptive:?}");
+            assert_eq!(summary.rejected, 0, "{adaptive:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        for adaptive in [
+            None,
+            Some(Adaptive {
+                max_clients: 1,
+                max_client_transactions: None,
+            }),
+        ] {
+            let (tx, rx) = mpsc::channel(32);
+            let mut listener = Listener::new(rx, Config::default()).with_adaptive(adaptive);
+            let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+
+            for (transaction_type, client, id, amount) in [
+                (TransactionType::Deposit, 1, 1, Amount::from_f64(5.0)),
+                (TransactionType::Deposit, 1, 2, Amount::from_f64(3.0)),
+                (TransactionType::Withdrawal, 1, 3, Amount::from_f64(1.0)),
+                (TransactionType::Dispute, 1, 2, None),
+                (TransactionType::Resolve, 1, 2, None),
+                (TransactionType::Deposit, 2, 4, Amount::from_f64(2.0)),
+                (TransactionType::Dispute, 2, 4, None),
+                (TransactionType::ChargeBack, 2, 4, None),
+                // Insufficient funds
+                (TransactionType::Withdrawal, 1, 5, Amount::from_f64(100.0)),
+                // Missing amount
+                (TransactionType::Deposit, 1, 6, None),
+                // Locked account
+                (TransactionType::Deposit, 2, 7, Amount::from_f64(1.0)),
+                // Unknown transaction
+                (TransactionType::Dispute, 1, 99, None),
+            ] {
+                tx.send(Command::ExecuteTransaction(TransactionRecord {
+                    transaction_type,
+                    client,
+                    id,
+                    amount: amount.map(Ok),
+                    tag: None,
+                    counterparty: None,
+                }))
                 .await
                 .unwrap();
+            }
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::GetMetrics(resp_tx)).await.unwrap();
+            let metrics = resp_rx.await.unwrap();
+            drop(tx);
+            let summary = handle.await.unwrap();
+
+            assert_eq!(metrics.deposits_applied, 3, "{adaptive:?}");
+            assert_eq!(metrics.withdrawals_applied, 1, "{adaptive:?}");
+            assert_eq!(metrics.disputes, 2, "{adaptive:?}");
+            assert_eq!(metrics.resolves, 1, "{adaptive:?}");
+            assert_eq!(metrics.chargebacks, 1, "{adaptive:?}");
+            assert_eq!(metrics.unlocks, 0, "{adaptive:?}");
+            assert_eq!(metrics.rejected(), summary.rejected, "{adaptive:?}");
+            assert_eq!(metrics.rejections.len(), 4, "{metrics}");
+            assert_eq!(metrics.rejections[&StateError::Deposit.to_string()], 1);
+            assert_eq!(metrics.rejections[&StateError::Dispute.to_string()], 1);
+            assert_eq!(
+                metrics.rejections[&AccountError::InsufficientFunds.to_string()],
+                1
+            );
+            assert_eq!(metrics.rejections[&AccountError::Locked.to_string()], 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        for adaptive in [
+            None,
+            Some(Adaptive {
+                max_clients: 1,
+                max_client_transactions: None,
+            }),
+        ] {
+            let (tx, rx) = mpsc::channel(32);
+            let mut listener = Listener::new(rx, Config::default()).with_adaptive(adaptive);
+            let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+            let records = [
+                (TransactionType::Deposit, 1, 1, Some(10.0), None),
+                (TransactionType::Deposit, 2, 2, Some(1.0), None),
+                (TransactionType::Transfer, 1, 3, Some(4.0), Some(2)),
+                // Insufficient funds
+                (TransactionType::Transfer, 2, 4, Some(6.0), Some(1)),
+                // Missing counterparty
+                (TransactionType::Transfer, 1, 5, Some(1.0), None),
+                // Funds received through a transfer can be withdrawn
+                (TransactionType::Withdrawal, 2, 6, Some(5.0), None),
+                // Rejected transfers create no account on either side
+                (TransactionType::Transfer, 1, 7, Some(100.0), Some(99)),
+                (TransactionType::Transfer, 7, 8, Some(1.0), None),
+            ]
+            .map(
+                |(transaction_type, client, id, amount, counterparty)| TransactionRecord {
+                    transaction_type,
+                    client,
+                    id,
+                    amount: amount.and_then(Amount::from_f64).map(Ok),
+                    tag: None,
+                    counterparty,
+                },
+            );
+            let mut results = Vec::new();
+            for record in records.clone() {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                tx.send(Command::ExecuteTransactionWithResult(record, resp_tx))
+                    .await
+                    .unwrap();
+                results.push(resp_rx.await.unwrap());
+            }
+            assert_eq!(
+                results,
+                vec![
+                    Ok(()),
+                    Ok(()),
+                    Ok(()),
+                    Err(StateError::Account(AccountError::InsufficientFunds)),
+                    Err(StateError::Transfer),
+                    Ok(()),
+                    Err(StateError::Account(AccountError::InsufficientFunds)),
+                    Err(StateError::Transfer),
+                ],
+                "{adaptive:?}"
+            );
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
+            let accounts = resp_rx.await.unwrap();
+            assert_eq!(accounts.len(), 2, "{adaptive:?}");
+            assert_eq!(accounts[0].total(), Amount::from_int(6).unwrap());
+            assert_eq!(accounts[1].total(), Amount::ZERO);
+            assert_eq!(
+                accounts,
+                crate::engine::replay(&records, Config::default(), None).await
+            );
+
+            drop(tx);
+            let summary = handle.await.unwrap();
+            assert_eq!(summary.processed, 4, "{adaptive:?}");
+            assert_eq!(summary.rejected, 4, "{adaptive:?}");
+            assert_eq!(summary.accounts_created, 2, "{adaptive:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accrue_interest() {
+        let (tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::new(rx, Config::default());
+        let handle = tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+        for (transaction_type, client, id, amount) in [
+            (TransactionType::Deposit, 1, 1, Amount::from_int(100)),
+            (TransactionType::Deposit, 2, 2, Amount::from_int(100)),
+            (TransactionType::Dispute, 2, 2, None),
+            (TransactionType::ChargeBack, 2, 2, None),
+        ] {
+            tx.send(Command::ExecuteTransaction(TransactionRecord {
+                transaction_type,
+                client,
+                id,
+                amount: amount.map(Ok),
+                tag: None,
+                counterparty: None,
+            }))
+            .await
+            .unwrap();
         }
 
-        // Request the state of account balances
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::AccrueInterest {
+            rate: "0.05".parse().unwrap(),
+            tx: 3,
+            resp: resp_tx,
+        })
+        .await
+        .unwrap();
+        // The locked account is skipped
+        assert_eq!(
+            resp_rx.await.unwrap(),
+            vec![(1, Ok(Amount::from_int(5).unwrap())), (2, Ok(Amount::ZERO))]
+        );
+
+        // Failures are reported per client
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::AccrueInterest {
+            rate: "0.05".parse().unwrap(),
+            tx: 3,
+            resp: resp_tx,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            resp_rx.await.unwrap(),
+            vec![
+                (1, Err(StateError::DuplicateTransactionId)),
+                (2, Ok(Amount::ZERO))
+            ]
+        );
+
         let (resp_tx, resp_rx) = oneshot::channel();
         tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
-        let result = resp_rx.await.unwrap();
+        let accounts = resp_rx.await.unwrap();
+        assert_eq!(accounts[0].available(), Amount::from_int(105).unwrap());
+        assert_eq!(accounts[1].total(), Amount::ZERO);
 
-        assert_eq!(result.len(), 10000);
-        assert!(result
-            .iter()
-            .all(|&acc| acc.available() == Amount::from_f64(1.0).unwrap()));
-        assert!(result
-            .iter()
-            .all(|&acc| acc.total() == Amount::from_f64(1.0).unwrap()));
-        assert!(result.iter().all(|&acc| acc.held() == Amount::ZERO));
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::GetLedger(resp_tx)).await.unwrap();
+        assert!(resp_rx.await.unwrap().unwrap().is_balanced());
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_drains_commands() {
+        let (tx, rx) = mpsc::channel(32);
+        let accounts = Arc::new(DashMap::new());
+        let mut listener = Listener::with_store(rx, Config::default(), accounts.clone());
+        let token = CancellationToken::new();
+        let handle = tokio::spawn({
+            let token = token.clone();
+            async move { listener.run(token).await }
+        });
+
+        let mut sent = 0;
+        for id in 0..10000 {
+            if id == 5000 {
+                token.cancel();
+            }
+            let transaction = TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client: 1,
+                id,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
+            };
+            if tx
+                .send(Command::ExecuteTransaction(transaction))
+                .await
+                .is_err()
+            {
+                break;
+            }
+            sent += 1;
+        }
+        let summary = handle.await.unwrap();
+
+        // Every command accepted before cancellation is fully applied.
+        assert!(sent >= 5000);
+        assert_eq!(summary.processed, sent);
+        assert_eq!(summary.rejected, 0);
+        let state = accounts.get(&1).unwrap();
+        assert_eq!(
+            state.account.total(),
+            Amount::from_f64(sent as f64).unwrap()
+        );
+        assert_eq!(state.account.available(), state.account.total());
+        assert_eq!(state.account.held(), Amount::ZERO);
+        assert_eq!(state.transaction_history.len(), sent as usize);
     }
 }
@@ -3,12 +3,12 @@
 
 use crate::model::account::{Account, Id as AccountId};
 use crate::model::amount::Amount;
-use crate::model::transaction::{Id as TransactionId, TransactionRecord, TransactionType};
-use std::collections::HashMap;
+use crate::model::transaction::{Id as TransactionId, TransactionRecord, TransactionType, TxKey};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 
 /// Error conditions that may arise when using this module.
-#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
 pub enum Error {
     /// Invalid account operation.
     #[error("Failed to execute transaction due to account error")]
@@ -28,12 +28,59 @@ pub enum Error {
     /// Invalid charge back transaction.
     #[error("Invalid charge back")]
     ChargeBack,
+    /// Invalid unlock transaction.
+    #[error("Invalid unlock")]
+    Unlock,
+    /// Invalid transfer transaction.
+    #[error("Invalid transfer")]
+    Transfer,
+    /// Interest which cannot be credited, e.g. at a negative rate.
+    #[error("Invalid interest")]
+    Interest,
     /// Deposit/Withdrawal with same id.
     #[error("Duplicate transaction")]
     DuplicateTransactionId,
     /// Transaction for another account id.
     #[error("Invalid account id")]
     InvalidAccountId,
+    /// Account already has a different tag.
+    #[error("Conflicting account tag")]
+    TagConflict,
+    /// Transaction was dropped before being applied.
+    #[error("Transaction not executed")]
+    NotExecuted,
+    /// Deposit/Withdrawal with a lower id than a previous one of the same client.
+    #[error("Out of order transaction id")]
+    OutOfOrderTransactionId,
+    /// Dispute beyond the maximum number of disputes open on an account.
+    #[error("Too many open disputes")]
+    TooManyOpenDisputes,
+    /// Partial disputes holding more, added up, than the disputed transaction amount.
+    #[error("Disputed amount exceeds the original transaction amount")]
+    DisputeExceedsOriginal,
+    /// Dispute which would hold more funds than the account has.
+    #[error("Invalid dispute, held funds would exceed total funds")]
+    InvalidDispute,
+    /// Transaction id already used by a transaction of another client.
+    #[error("Transaction id belongs to another client")]
+    TransactionClientMismatch,
+    /// Deposit/Withdrawal of a negative amount.
+    #[error("Negative amount {0}")]
+    NegativeAmount(Amount),
+    /// Transaction whose amount is not a finite number.
+    #[error("Non-finite amount")]
+    NonFiniteAmount,
+}
+
+impl Error {
+    /// Reason reported for the rejection of a transaction, the account error itself for account
+    /// errors so that e.g. insufficient funds and locked accounts can be told apart.
+    pub fn reason(&self) -> String {
+        match self {
+            Self::Account(e) => e.to_string(),
+            e => e.to_string(),
+        }
+    }
 }
 
 /// Result of account operations.
@@ -43,30 +90,115 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct TransactionMetadata(pub TransactionId, pub AccountId);
 
+impl TransactionMetadata {
+    /// Identity of the transaction.
+    pub fn key(&self) -> TxKey {
+        TxKey {
+            client: self.1,
+            tx: self.0,
+        }
+    }
+}
+
 /// Internal data representation of a transaction.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Transaction {
     /// Deposit transaction.
     Deposit(TransactionMetadata, Amount, bool),
     /// Withdrawal transaction.
-    Withdrawal(TransactionMetadata, Amount),
-    /// Dispute transaction.
-    Dispute(TransactionMetadata),
+    Withdrawal(TransactionMetadata, Amount, bool),
+    /// Dispute transaction, of part of the disputed transaction amount if given.
+    Dispute(TransactionMetadata, Option<Amount>),
     /// Resolve transaction.
     Resolve(TransactionMetadata),
     /// Charge back transaction.
     ChargeBack(TransactionMetadata),
+    /// Unlock transaction, along with the number of charge backs of the account when applied.
+    ///
+    /// The account is locked again by any later charge back.
+    Unlock(TransactionMetadata, usize),
+    /// Transfer transaction, along with the client receiving the funds.
+    ///
+    /// Kept in the histories of both the sender and the receiver, see `Transaction::transfer`, but
+    /// cannot be disputed: disputing either side alone would credit funds the other side keeps.
+    Transfer(TransactionMetadata, AccountId, Amount),
+    /// Interest credited to the account by the engine rather than the input, see
+    /// `State::accrue_interest`.
+    ///
+    /// Kept in the history like a deposit, but cannot be disputed.
+    Interest(TransactionMetadata, Amount),
 }
 
 impl Transaction {
     pub fn apply(&self, state: &mut State) -> Result<()> {
-        match self {
+        if let Self::Deposit(md, _, _) | Self::Withdrawal(md, _, _) = self {
+            if state.config.monotonic_tx_ids
+                && state.highest_tx_id.is_some_and(|highest| md.0 < highest)
+            {
+                return Err(Error::OutOfOrderTransactionId);
+            }
+        }
+
+        let result = match self {
             Self::Deposit(_, _, _) => self.deposit(state),
-            Self::Withdrawal(_, _) => self.withdrawal(state),
-            Self::Dispute(_) => self.dispute(state),
+            Self::Withdrawal(_, _, _) => self.withdrawal(state),
+            Self::Dispute(_, _) => self.dispute(state),
             Self::Resolve(_) => self.resolve(state),
             Self::ChargeBack(_) => self.charge_back(state),
+            Self::Unlock(_, _) => self.unlock(state),
+            // Transfers involve the states of two clients
+            Self::Transfer(..) => Err(Error::Transfer),
+            // Interest is computed out of the state itself
+            Self::Interest(..) => Err(Error::Interest),
+        };
+        if result.is_ok() {
+            // Only transactions which applied move the high-water mark, a rejected id can be
+            // reused later on
+            if let Self::Deposit(md, _, _) | Self::Withdrawal(md, _, _) = self {
+                state.highest_tx_id = state.highest_tx_id.max(Some(md.0));
+            }
+            state.evict();
+        }
+        result
+    }
+
+    /// Move the funds of a transfer from the `sender` state to the `receiver` one at once.
+    ///
+    /// Either both sides are applied or none, e.g. if the sender has insufficient funds or either
+    /// account is locked. Transfers reusing the id of a deposit or withdrawal of either client are
+    /// rejected whatever the duplicate policy.
+    pub fn transfer(&self, sender: &mut State, receiver: &mut State) -> Result<()> {
+        let Self::Transfer(md, to, amount) = *self else {
+            return Err(Error::Transfer);
+        };
+        if sender.account.id() != md.1 || receiver.account.id() != to {
+            return Err(Error::InvalidAccountId);
+        }
+        if md.1 == to {
+            return Err(Error::Transfer);
         }
+        sender.check_new_transaction(md.0)?;
+        receiver.check_new_transaction(md.0)?;
+        // Work on copies of the accounts so a failure of either side leaves both as they were
+        let mut from = sender.account.clone();
+        from.withdrawal(amount).map_err(Error::Account)?;
+        let mut into = receiver.account.clone();
+        into.deposit(amount).map_err(Error::Account)?;
+
+        sender.account = from;
+        sender.record_transaction(*self);
+        sender.track(|flows| {
+            flows.withdrawn = flows.withdrawn.checked_add(amount)?;
+            Some(())
+        });
+        receiver.account = into;
+        receiver.record_transaction(*self);
+        receiver.track(|flows| {
+            flows.deposited = flows.deposited.checked_add(amount)?;
+            Some(())
+        });
+
+        Ok(())
     }
 
     fn deposit(&self, state: &mut State) -> Result<()> {
@@ -75,11 +207,20 @@ impl Transaction {
                 if state.account.id() != md.1 {
                     return Err(Error::InvalidAccountId);
                 }
-                if state.transaction_history.contains_key(&md.0) {
-                    return Err(Error::DuplicateTransactionId);
+                if let Some(existing) = state.transaction_history.get(&md.0).copied() {
+                    return self.duplicate(state, Some(existing));
+                }
+                if state.evicted.contains(&md.0) {
+                    return self.duplicate(state, None);
                 }
                 state.account.deposit(*amount).map_err(Error::Account)?;
-                state.transaction_history.insert(md.0, *self);
+                state.account.raise_last_tx_id(md.0);
+                state.push_history(md.0, *self);
+                state.track_order(md.0);
+                state.track(|flows| {
+                    flows.deposited = flows.deposited.checked_add(*amount)?;
+                    Some(())
+                });
 
                 Ok(())
             }
@@ -89,15 +230,24 @@ impl Transaction {
 
     fn withdrawal(&self, state: &mut State) -> Result<()> {
         match self {
-            Self::Withdrawal(md, amount) => {
+            Self::Withdrawal(md, amount, _) => {
                 if state.account.id() != md.1 {
                     return Err(Error::InvalidAccountId);
                 }
-                if state.transaction_history.contains_key(&md.0) {
-                    return Err(Error::DuplicateTransactionId);
-                };
+                if let Some(existing) = state.transaction_history.get(&md.0).copied() {
+                    return self.duplicate(state, Some(existing));
+                }
+                if state.evicted.contains(&md.0) {
+                    return self.duplicate(state, None);
+                }
                 state.account.withdrawal(*amount).map_err(Error::Account)?;
-                state.transaction_history.insert(md.0, *self);
+                state.account.raise_last_tx_id(md.0);
+                state.push_history(md.0, *self);
+                state.track_order(md.0);
+                state.track(|flows| {
+                    flows.withdrawn = flows.withdrawn.checked_add(*amount)?;
+                    Some(())
+                });
 
                 Ok(())
             }
@@ -105,30 +255,155 @@ impl Transaction {
         }
     }
 
+    /// Handle a deposit or withdrawal reusing the id of `existing`, following the duplicate policy.
+    fn duplicate(&self, state: &mut State, existing: Option<Transaction>) -> Result<()> {
+        match state.config.duplicate_policy {
+            DuplicatePolicy::Error => Err(Error::DuplicateTransactionId),
+            DuplicatePolicy::Ignore => Ok(()),
+            DuplicatePolicy::Overwrite => {
+                // Work on a copy so a failure to apply the new transaction leaves the account as
+                // it was.
+                let mut account = state.account.clone();
+                match existing {
+                    Some(Self::Deposit(md, amount, false))
+                        if !state.charge_backs.contains(&md.0) =>
+                    {
+                        account.withdrawal(amount)
+                    }
+                    Some(Self::Withdrawal(md, amount, false))
+                        if !state.charge_backs.contains(&md.0) =>
+                    {
+                        account.deposit(amount)
+                    }
+                    // Disputed and charged back transactions are settled by their dispute, evicted
+                    // ones are settled for good
+                    _ => return Err(Error::DuplicateTransactionId),
+                }
+                .map_err(Error::Account)?;
+                let md = match self {
+                    Self::Deposit(md, amount, _) => {
+                        account.deposit(*amount).map_err(Error::Account)?;
+                        md
+                    }
+                    Self::Withdrawal(md, amount, _) => {
+                        account.withdrawal(*amount).map_err(Error::Account)?;
+                        md
+                    }
+                    _ => return Err(Error::DuplicateTransactionId),
+                };
+
+                account.raise_last_tx_id(md.0);
+                state.account = account;
+                state.transaction_history.insert(md.0, *self);
+                state.track(|flows| {
+                    match existing {
+                        Some(Self::Deposit(_, amount, _)) => {
+                            flows.deposited = flows.deposited.checked_sub(amount)?
+                        }
+                        Some(Self::Withdrawal(_, amount, _)) => {
+                            flows.withdrawn = flows.withdrawn.checked_sub(amount)?
+                        }
+                        _ => {}
+                    }
+                    match self {
+                        Self::Deposit(_, amount, _) => {
+                            flows.deposited = flows.deposited.checked_add(*amount)?
+                        }
+                        Self::Withdrawal(_, amount, _) => {
+                            flows.withdrawn = flows.withdrawn.checked_add(*amount)?
+                        }
+                        _ => {}
+                    }
+                    Some(())
+                });
+
+                Ok(())
+            }
+        }
+    }
+
     fn dispute(&self, state: &mut State) -> Result<()> {
         match self {
-            Self::Dispute(md) => {
+            Self::Dispute(md, partial) => {
                 if state.account.id() != md.1 {
                     return Err(Error::InvalidAccountId);
                 }
+                // Amounts of dispute rows are ignored unless disputes can be partial
+                let partial = partial.filter(|_| state.config.partial_disputes);
 
                 let disputed_transaction =
                     state.transaction_history.get(&md.0).ok_or(Error::Dispute)?;
 
-                match disputed_transaction {
-                    Self::Deposit(md, amount, is_disputed) => {
-                        if *is_disputed {
-                            return Err(Error::Dispute);
-                        }
-                        state.account.dispute(*amount).map_err(Error::Account)?;
-                        state
-                            .transaction_history
-                            .insert(md.0, Self::Deposit(*md, *amount, true));
+                let (md, amount, is_disputed) = match *disputed_transaction {
+                    Self::Deposit(md, amount, is_disputed)
+                    | Self::Withdrawal(md, amount, is_disputed) => (md, amount, is_disputed),
+                    _ => return Err(Error::Dispute),
+                };
+                // Partial disputes of the same transaction add up, a whole one must come first
+                if is_disputed && partial.is_none() {
+                    return Err(Error::Dispute);
+                }
+                // A charged back withdrawal is already reversed
+                if state.charge_backs.contains(&md.0) {
+                    return Err(Error::Dispute);
+                }
+                if !is_disputed
+                    && state
+                        .config
+                        .max_open_disputes
+                        .is_some_and(|max| state.held.len() >= max)
+                {
+                    return Err(Error::TooManyOpenDisputes);
+                }
+                let disputed_amount = match partial {
+                    Some(partial) => Some(state.config.disputed_amount(partial))
+                        .filter(Amount::is_positive)
+                        .ok_or(Error::Dispute)?,
+                    None => state.config.disputed_amount(amount),
+                };
+                // Amounts held by the same transaction add up and must never exceed its amount
+                let held = state
+                    .held
+                    .get(&md.0)
+                    .copied()
+                    .unwrap_or(Amount::ZERO)
+                    .checked_add(disputed_amount)
+                    .filter(|held| *held <= amount)
+                    .ok_or(Error::DisputeExceedsOriginal)?;
+                let mut account = state.account.clone();
+                let disputed = match disputed_transaction {
+                    Self::Deposit(..) => {
+                        account.dispute(disputed_amount).map_err(Error::Account)?;
+                        Self::Deposit(md, amount, true)
                     }
                     _ => {
-                        return Err(Error::Dispute);
+                        account
+                            .dispute_withdrawal(disputed_amount)
+                            .map_err(Error::Account)?;
+                        Self::Withdrawal(md, amount, true)
                     }
+                };
+                // The available check above should make this impossible, unless the balances
+                // were corrupted
+                if account.held() > account.total() {
+                    return Err(Error::InvalidDispute);
                 }
+                state.account = account;
+                state.held.insert(md.0, held);
+                if let Self::Withdrawal(..) = disputed {
+                    state.track(|flows| {
+                        flows.reversed = flows.reversed.checked_add(disputed_amount)?;
+                        Some(())
+                    });
+                }
+                tracing::info!(
+                    name: "dispute_opened",
+                    client = md.1,
+                    tx = md.0,
+                    amount = %disputed_amount.display_dp(state.config.scale),
+                    "dispute opened"
+                );
+                state.transaction_history.insert(md.0, disputed);
 
                 Ok(())
             }
@@ -143,200 +418,2416 @@ impl Transaction {
                     return Err(Error::InvalidAccountId);
                 }
 
-                let disputed_transaction =
-                    state.transaction_history.get(&md.0).ok_or(Error::Resolve)?;
+                let disputed_transaction =
+                    state.transaction_history.get(&md.0).ok_or(Error::Resolve)?;
+
+                let resolved = match *disputed_transaction {
+                    Self::Deposit(md, amount, true) => Self::Deposit(md, amount, false),
+                    Self::Withdrawal(md, amount, true) => Self::Withdrawal(md, amount, false),
+                    _ => return Err(Error::Resolve),
+                };
+                let disputed_amount = *state.held.get(&md.0).ok_or(Error::Resolve)?;
+                match resolved {
+                    Self::Deposit(..) => state.account.resolve(disputed_amount),
+                    _ => state.account.resolve_withdrawal(disputed_amount),
+                }
+                .map_err(Error::Account)?;
+                state.held.remove(&md.0);
+                state.unpin(md.0);
+                if let Self::Withdrawal(..) = resolved {
+                    state.track(|flows| {
+                        flows.reversed = flows.reversed.checked_sub(disputed_amount)?;
+                        Some(())
+                    });
+                }
+                tracing::info!(
+                    name: "dispute_resolved",
+                    client = md.1,
+                    tx = md.0,
+                    amount = %disputed_amount.display_dp(state.config.scale),
+                    "dispute resolved"
+                );
+                state.transaction_history.insert(md.0, resolved);
+
+                Ok(())
+            }
+            _ => Err(Error::Resolve),
+        }
+    }
+
+    fn charge_back(&self, state: &mut State) -> Result<()> {
+        match self {
+            Self::ChargeBack(md) => {
+                if state.account.id() != md.1 {
+                    return Err(Error::InvalidAccountId);
+                }
+
+                let disputed_transaction = state
+                    .transaction_history
+                    .get(&md.0)
+                    .ok_or(Error::ChargeBack)?;
+
+                let charged_back = match *disputed_transaction {
+                    Self::Deposit(md, amount, true) => Self::Deposit(md, amount, false),
+                    Self::Withdrawal(md, amount, true) => Self::Withdrawal(md, amount, false),
+                    _ => return Err(Error::ChargeBack),
+                };
+                let disputed_amount = *state.held.get(&md.0).ok_or(Error::ChargeBack)?;
+                match charged_back {
+                    Self::Deposit(..) => state.account.charge_back(disputed_amount),
+                    _ => state.account.charge_back_withdrawal(disputed_amount),
+                }
+                .map_err(Error::Account)?;
+                state.held.remove(&md.0);
+                state.charge_backs.insert(md.0);
+                if let Self::Deposit(_, amount, _) | Self::Withdrawal(_, amount, _) = charged_back {
+                    if disputed_amount != state.config.disputed_amount(amount) {
+                        state.partial_charge_backs.insert(md.0, disputed_amount);
+                    }
+                }
+                // A charged back withdrawal stays reversed
+                if let Self::Deposit(..) = charged_back {
+                    state.track(|flows| {
+                        flows.charged_back = flows.charged_back.checked_add(disputed_amount)?;
+                        Some(())
+                    });
+                }
+                tracing::info!(
+                    name: "charged_back",
+                    client = md.1,
+                    tx = md.0,
+                    amount = %disputed_amount.display_dp(state.config.scale),
+                    "charged back"
+                );
+                state.transaction_history.insert(md.0, charged_back);
+                // Charged back transactions can no longer be disputed
+                if state.config.max_history.is_some() {
+                    state.evict_transaction(md.0);
+                }
+
+                Ok(())
+            }
+            _ => Err(Error::ChargeBack),
+        }
+    }
+
+    fn unlock(&self, state: &mut State) -> Result<()> {
+        match self {
+            Self::Unlock(md, _) => {
+                if state.account.id() != md.1 {
+                    return Err(Error::InvalidAccountId);
+                }
+                // Unlocks are kept in the history as well, thus need an id of their own
+                if state.transaction_history.contains_key(&md.0) {
+                    return Err(Error::DuplicateTransactionId);
+                }
+                state.account.unlock().map_err(Error::Account)?;
+                tracing::info!(
+                    name: "account_unlocked",
+                    client = md.1,
+                    tx = md.0,
+                    "account unlocked"
+                );
+                state.push_history(md.0, Self::Unlock(*md, state.charge_backs.len()));
+
+                Ok(())
+            }
+            _ => Err(Error::Unlock),
+        }
+    }
+}
+
+/// Reject a negative deposit or withdrawal `amount` as soon as it is parsed.
+///
+/// Zero amounts, `-0` included, are left to the account to reject as invalid input.
+fn non_negative(amount: Amount) -> Result<Amount> {
+    if amount.is_negative() {
+        Err(Error::NegativeAmount(amount))
+    } else {
+        Ok(amount)
+    }
+}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = crate::engine::state::Error;
+
+    fn try_from(tx: TransactionRecord) -> Result<Self> {
+        Self::try_from(&tx)
+    }
+}
+
+impl TryFrom<&TransactionRecord> for Transaction {
+    type Error = crate::engine::state::Error;
+
+    fn try_from(tx: &TransactionRecord) -> Result<Self> {
+        // Only amounts which are not finite numbers are kept invalid, others fail reading the record
+        let amount = tx
+            .amount
+            .clone()
+            .transpose()
+            .map_err(|_| Error::NonFiniteAmount)?;
+        match tx.transaction_type {
+            TransactionType::Deposit => Ok(Self::Deposit(
+                TransactionMetadata(tx.id, tx.client),
+                non_negative(amount.ok_or(Error::Deposit)?)?,
+                false,
+            )),
+            TransactionType::Withdrawal => Ok(Self::Withdrawal(
+                TransactionMetadata(tx.id, tx.client),
+                non_negative(amount.ok_or(Error::Withdrawal)?)?,
+                false,
+            )),
+            TransactionType::Dispute => {
+                Ok(Self::Dispute(TransactionMetadata(tx.id, tx.client), amount))
+            }
+            TransactionType::Resolve => Ok(Self::Resolve(TransactionMetadata(tx.id, tx.client))),
+            TransactionType::ChargeBack => {
+                Ok(Self::ChargeBack(TransactionMetadata(tx.id, tx.client)))
+            }
+            TransactionType::Unlock => Ok(Self::Unlock(TransactionMetadata(tx.id, tx.client), 0)),
+            TransactionType::Transfer => Ok(Self::Transfer(
+                TransactionMetadata(tx.id, tx.client),
+                tx.counterparty.ok_or(Error::Transfer)?,
+                non_negative(amount.ok_or(Error::Transfer)?)?,
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Transaction::Deposit(md, amount, is_disputed) => write!(
+                f,
+                "Deposit id {} client {} amount {} is_disputed {}",
+                md.0, md.1, amount, is_disputed
+            ),
+            Transaction::Withdrawal(md, amount, is_disputed) => write!(
+                f,
+                "Withdraw id {} client {} amount {} is_disputed {}",
+                md.0, md.1, amount, is_disputed
+            ),
+            Transaction::Dispute(md, None) => write!(f, "Dispute {}", md.key()),
+            Transaction::Dispute(md, Some(amount)) => {
+                write!(f, "Dispute {} amount {}", md.key(), amount)
+            }
+            Transaction::Resolve(md) => write!(f, "Resolve {}", md.key()),
+            Transaction::ChargeBack(md) => write!(f, "Charge back {}", md.key()),
+            Transaction::Unlock(md, _) => write!(f, "Unlock {}", md.key()),
+            Transaction::Interest(md, amount) => {
+                write!(f, "Interest {} amount {}", md.key(), amount)
+            }
+            Transaction::Transfer(md, to, amount) => write!(
+                f,
+                "Transfer id {} client {} to {} amount {}",
+                md.0, md.1, to, amount
+            ),
+        }
+    }
+}
+
+/// How to handle a deposit or withdrawal reusing the id of a previous one.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Reject the transaction.
+    #[default]
+    Error,
+    /// Treat the transaction as a no-op.
+    Ignore,
+    /// Replace the previous transaction, reversing it and applying the new one (corrections).
+    Overwrite,
+}
+
+/// Policies applied when executing transactions against a `State`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Config {
+    /// Number of decimal places amounts are output with, `Amount::PRECISION` by default.
+    pub scale: u32,
+    /// Truncate the amounts moved between available and held funds by disputes, resolves and
+    /// charge backs to `scale`, so the held funds never accumulate digits which are not output.
+    pub round_disputes: bool,
+    /// Handling of deposits and withdrawals reusing a transaction id.
+    pub duplicate_policy: DuplicatePolicy,
+    /// Reject deposits and withdrawals with a lower id than a previous one of the same client.
+    pub monotonic_tx_ids: bool,
+    /// Maximum number of disputes open at once on an account, unlimited if not set.
+    pub max_open_disputes: Option<usize>,
+    /// Floor withdrawals may not take the available funds of an account below, if any.
+    pub min_balance: Option<Amount>,
+    /// Treat transaction ids as unique across clients, rejecting transactions reusing the id of a
+    /// transaction of another client.
+    pub unique_tx_ids: bool,
+    /// Maximum number of deposits and withdrawals kept in the history of an account, unlimited if
+    /// not set.
+    ///
+    /// Beyond it, the oldest ones which are not under dispute are evicted, as are charged back
+    /// ones right away; evicted transactions can no longer be disputed, only their ids are kept
+    /// to reject duplicates.
+    pub max_history: Option<usize>,
+    /// Dispute only the amount given on dispute rows rather than the whole transaction.
+    ///
+    /// Off by default, as some feeds repeat the amount of the disputed transaction on dispute
+    /// rows.
+    pub partial_disputes: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scale: Amount::PRECISION,
+            round_disputes: false,
+            duplicate_policy: DuplicatePolicy::default(),
+            monotonic_tx_ids: false,
+            max_open_disputes: None,
+            min_balance: None,
+            unique_tx_ids: false,
+            max_history: None,
+            partial_disputes: false,
+        }
+    }
+}
+
+impl Config {
+    /// Amount moved between the available and held funds when disputing `amount`.
+    fn disputed_amount(&self, amount: Amount) -> Amount {
+        if self.round_disputes {
+            amount.trunc_dp(self.scale)
+        } else {
+            amount
+        }
+    }
+}
+
+/// Mismatch between a stored account and the one recomputed from its transaction history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    /// Account as it was stored.
+    pub stored: Account,
+    /// Account recomputed from the transaction history, `None` if the history does not add up to
+    /// an account, see `State::recompute_balance`.
+    pub recomputed: Option<Account>,
+}
+
+/// Funds moved into and out of an account by its transactions, which its total must add up to.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Flows {
+    /// Total of the account when restored from a snapshot.
+    pub opening: Amount,
+    /// Funds deposited.
+    pub deposited: Amount,
+    /// Funds withdrawn.
+    pub withdrawn: Amount,
+    /// Disputed deposit funds removed by charge backs.
+    pub charged_back: Amount,
+    /// Withdrawn funds credited back by disputes which were not resolved.
+    pub reversed: Amount,
+    /// Interest credited.
+    pub interest: Amount,
+}
+
+impl Flows {
+    /// Total the flows add up to, `None` on overflow.
+    pub fn total(&self) -> Option<Amount> {
+        self.opening
+            .checked_add(self.deposited)?
+            .checked_sub(self.withdrawn)?
+            .checked_sub(self.charged_back)?
+            .checked_add(self.reversed)?
+            .checked_add(self.interest)
+    }
+
+    /// Flows of both `self` and `other`, `None` on overflow.
+    pub fn checked_add(&self, other: &Self) -> Option<Self> {
+        Some(Self {
+            opening: self.opening.checked_add(other.opening)?,
+            deposited: self.deposited.checked_add(other.deposited)?,
+            withdrawn: self.withdrawn.checked_add(other.withdrawn)?,
+            charged_back: self.charged_back.checked_add(other.charged_back)?,
+            reversed: self.reversed.checked_add(other.reversed)?,
+            interest: self.interest.checked_add(other.interest)?,
+        })
+    }
+}
+
+/// Deposit or withdrawal saved in a `Snapshot`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SnapshotTransaction {
+    /// Deposit, along with whether it is under dispute.
+    Deposit {
+        /// Transaction id.
+        tx: TransactionId,
+        /// Deposited amount.
+        #[serde(with = "crate::model::amount::exact")]
+        amount: Amount,
+        /// Whether the deposit is under dispute.
+        disputed: bool,
+    },
+    /// Withdrawal, along with whether it is under dispute.
+    Withdrawal {
+        /// Transaction id.
+        tx: TransactionId,
+        /// Withdrawn amount.
+        #[serde(with = "crate::model::amount::exact")]
+        amount: Amount,
+        /// Whether the withdrawal is under dispute, missing from snapshots taken before
+        /// withdrawals could be disputed.
+        #[serde(default)]
+        disputed: bool,
+    },
+    /// Interest credited to the account.
+    Interest {
+        /// Transaction id.
+        tx: TransactionId,
+        /// Credited amount.
+        #[serde(with = "crate::model::amount::exact")]
+        amount: Amount,
+    },
+    /// Unlock of the account.
+    Unlock {
+        /// Transaction id.
+        tx: TransactionId,
+        /// Number of charge backs of the account when unlocked.
+        charge_backs: usize,
+    },
+    /// Transfer from or to the account.
+    Transfer {
+        /// Transaction id.
+        tx: TransactionId,
+        /// Client sending the funds.
+        sender: AccountId,
+        /// Client receiving the funds.
+        receiver: AccountId,
+        /// Transferred amount.
+        #[serde(with = "crate::model::amount::exact")]
+        amount: Amount,
+    },
+}
+
+impl SnapshotTransaction {
+    /// Transaction id.
+    pub fn tx(&self) -> TransactionId {
+        match self {
+            Self::Deposit { tx, .. }
+            | Self::Withdrawal { tx, .. }
+            | Self::Interest { tx, .. }
+            | Self::Unlock { tx, .. }
+            | Self::Transfer { tx, .. } => *tx,
+        }
+    }
+
+    /// Amount moved by the transaction, `None` for unlocks.
+    pub fn amount(&self) -> Option<Amount> {
+        match self {
+            Self::Deposit { amount, .. }
+            | Self::Withdrawal { amount, .. }
+            | Self::Interest { amount, .. }
+            | Self::Transfer { amount, .. } => Some(*amount),
+            Self::Unlock { .. } => None,
+        }
+    }
+}
+
+/// Amount held by a deposit or withdrawal disputed for part of its amount, or charged back after
+/// such a dispute, saved in a `Snapshot`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PartialAmount {
+    /// Transaction id.
+    pub tx: TransactionId,
+    /// Amount held or charged back.
+    #[serde(with = "crate::model::amount::exact")]
+    pub amount: Amount,
+}
+
+/// Serializable copy of a `State`, see `State::snapshot`.
+///
+/// Balances are not saved, they are rebuilt out of the transactions when restoring.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    /// Client id.
+    pub client: AccountId,
+    /// Tag of the account.
+    pub tag: Option<String>,
+    /// Id of the last deposit or withdrawal applied.
+    pub last_tx_id: Option<TransactionId>,
+    /// Highest id of the deposits and withdrawals seen.
+    pub highest_tx_id: Option<TransactionId>,
+    /// History of deposits and withdrawals, in the order they were applied.
+    pub transactions: Vec<SnapshotTransaction>,
+    /// Deposits and withdrawals which were charged back, sorted by id.
+    pub charge_backs: Vec<TransactionId>,
+    /// Net funds of the deposits and withdrawals evicted from the history, missing from snapshots
+    /// taken before histories could be capped.
+    #[serde(default, with = "crate::model::amount::exact")]
+    pub settled: Amount,
+    /// Amounts of the partial disputes and of the charge backs following them, sorted by id;
+    /// missing from snapshots taken before disputes could be partial.
+    #[serde(default)]
+    pub partial: Vec<PartialAmount>,
+    /// How far below zero withdrawals may take the account, missing from snapshots taken before
+    /// accounts had an overdraft limit.
+    #[serde(default, with = "crate::model::amount::exact")]
+    pub overdraft_limit: Amount,
+    /// Ids of the deposits and withdrawals evicted from the history, sorted; missing from
+    /// snapshots taken before evicted ids were kept.
+    #[serde(default)]
+    pub evicted: Vec<TransactionId>,
+}
+
+/// Version of the snapshots format, see `SnapshotV1`.
+pub const SNAPSHOT_VERSION: u64 = 1;
+
+/// Error conditions that may arise when loading snapshots.
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    /// Malformed snapshots.
+    #[error("invalid snapshot: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Snapshots saved in a format this version does not know.
+    #[error("unsupported snapshot version {0}, expected {SNAPSHOT_VERSION}")]
+    UnsupportedVersion(u64),
+    /// Snapshots whose transactions cannot have been applied, e.g. non-positive amounts or
+    /// balances which do not fit in an `Amount`.
+    #[error("corrupt snapshot of client {0}")]
+    Corrupt(AccountId),
+    /// Snapshot of a client which already has a state, or given twice.
+    #[error("client {0} already has a state")]
+    ClientExists(AccountId),
+}
+
+/// Client states saved by a run, in an envelope recording the version of their format so that
+/// future formats can be detected.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotV1 {
+    /// Version of the format, `SNAPSHOT_VERSION`.
+    pub version: u64,
+    /// Client states.
+    pub clients: Vec<Snapshot>,
+}
+
+impl SnapshotV1 {
+    /// Wrap `clients` in an envelope of the current version.
+    pub fn new(clients: Vec<Snapshot>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            clients,
+        }
+    }
+
+    /// Parse snapshots saved as JSON, including the bare list of client states saved before
+    /// snapshots were versioned.
+    pub fn from_json(json: &[u8]) -> std::result::Result<Self, SnapshotError> {
+        let value = serde_json::from_slice::<serde_json::Value>(json)?;
+        if value.is_array() {
+            return Ok(Self::new(serde_json::from_value(value)?));
+        }
+        match value.get("version").and_then(serde_json::Value::as_u64) {
+            Some(version) if version != SNAPSHOT_VERSION => {
+                Err(SnapshotError::UnsupportedVersion(version))
+            }
+            _ => Ok(serde_json::from_value(value)?),
+        }
+    }
+}
+
+/// State of all a client account.
+pub struct State {
+    /// Account
+    pub account: Account,
+    /// History of deposits and withdrawals.
+    pub transaction_history: HashMap<TransactionId, Transaction>,
+    /// Ids of the transactions of the history in the order they were applied, see
+    /// `State::applied`. Ids evicted since are skipped until the next compaction.
+    sequence: Vec<TransactionId>,
+    /// Deposits and withdrawals which were charged back.
+    pub charge_backs: HashSet<TransactionId>,
+    /// Amounts charged back from deposits and withdrawals only disputed in part.
+    partial_charge_backs: HashMap<TransactionId, Amount>,
+    /// Amounts held by each deposit or withdrawal under dispute, adding up to the held funds.
+    held: HashMap<TransactionId, Amount>,
+    /// Highest id of the deposits and withdrawals seen so far.
+    highest_tx_id: Option<TransactionId>,
+    /// Funds moved by the transactions applied, `None` once they overflow.
+    flows: Option<Flows>,
+    /// Ids of the deposits and withdrawals of the history in the order they were applied, only
+    /// tracked if `Config::max_history` is set.
+    ///
+    /// Ids evicted out of order, i.e. charged back ones, are only skipped once they reach the
+    /// front, and ids reaching the front while under dispute are moved to `pinned`.
+    order: VecDeque<TransactionId>,
+    /// Ids of the disputed deposits and withdrawals which would have been evicted otherwise, put
+    /// back at the front of `order` once resolved.
+    pinned: HashSet<TransactionId>,
+    /// Number of deposits and withdrawals in the history, only tracked if `Config::max_history` is
+    /// set.
+    tracked: usize,
+    /// Ids of the deposits and withdrawals evicted from the history, so that they are still
+    /// rejected as duplicates.
+    evicted: HashSet<TransactionId>,
+    /// Net funds of the deposits and withdrawals evicted from the history.
+    settled: Amount,
+    /// Whether the first transaction already established the tag, if any, see `State::set_tag`.
+    tag_established: bool,
+    /// Policies applied to transactions.
+    pub config: Config,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::with_config(AccountId::default(), Config::default())
+    }
+}
+
+impl State {
+    #[cfg(test)]
+    pub fn new(id: AccountId) -> Self {
+        Self::with_config(id, Config::default())
+    }
+
+    pub fn with_config(id: AccountId, config: Config) -> Self {
+        let mut account = Account::new(id);
+        account.set_min_balance(config.min_balance);
+        Self {
+            account,
+            transaction_history: HashMap::new(),
+            sequence: Vec::new(),
+            charge_backs: HashSet::new(),
+            partial_charge_backs: HashMap::new(),
+            held: HashMap::new(),
+            highest_tx_id: None,
+            flows: Some(Flows::default()),
+            order: VecDeque::new(),
+            pinned: HashSet::new(),
+            tracked: 0,
+            evicted: HashSet::new(),
+            settled: Amount::ZERO,
+            tag_established: false,
+            config,
+        }
+    }
+
+    /// Save the state so it can be restored later by `State::restore`, e.g. by another run.
+    pub fn snapshot(&self) -> Snapshot {
+        let transactions = self
+            .applied()
+            .filter_map(|transaction| match *transaction {
+                Transaction::Deposit(md, amount, disputed) => Some(SnapshotTransaction::Deposit {
+                    tx: md.0,
+                    amount,
+                    disputed,
+                }),
+                Transaction::Withdrawal(md, amount, disputed) => {
+                    Some(SnapshotTransaction::Withdrawal {
+                        tx: md.0,
+                        amount,
+                        disputed,
+                    })
+                }
+                Transaction::Interest(md, amount) => {
+                    Some(SnapshotTransaction::Interest { tx: md.0, amount })
+                }
+                Transaction::Transfer(md, receiver, amount) => {
+                    Some(SnapshotTransaction::Transfer {
+                        tx: md.0,
+                        sender: md.1,
+                        receiver,
+                        amount,
+                    })
+                }
+                Transaction::Unlock(md, charge_backs) => Some(SnapshotTransaction::Unlock {
+                    tx: md.0,
+                    charge_backs,
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut charge_backs = self.charge_backs.iter().copied().collect::<Vec<_>>();
+        charge_backs.sort_unstable();
+        let mut partial = self
+            .held
+            .iter()
+            .filter(|(tx, held)| match self.transaction_history.get(tx) {
+                Some(
+                    Transaction::Deposit(_, amount, _) | Transaction::Withdrawal(_, amount, _),
+                ) => **held != self.config.disputed_amount(*amount),
+                _ => false,
+            })
+            .chain(&self.partial_charge_backs)
+            .map(|(tx, amount)| PartialAmount {
+                tx: *tx,
+                amount: *amount,
+            })
+            .collect::<Vec<_>>();
+        partial.sort_unstable_by_key(|partial| partial.tx);
+        let mut evicted = self.evicted.iter().copied().collect::<Vec<_>>();
+        evicted.sort_unstable();
+
+        Snapshot {
+            client: self.account.id(),
+            tag: self.account.tag().map(str::to_string),
+            last_tx_id: self.account.last_tx_id(),
+            highest_tx_id: self.highest_tx_id,
+            transactions,
+            charge_backs,
+            settled: self.settled,
+            partial,
+            overdraft_limit: self.account.overdraft_limit(),
+            evicted,
+        }
+    }
+
+    /// Restore a state saved by `State::snapshot`, applying transactions according to `config`.
+    ///
+    /// The account is rebuilt out of the saved transactions, thus disputes are held according to
+    /// `config` rather than the configuration of the run which saved the snapshot.
+    ///
+    /// Fails if the saved transactions cannot have been applied, e.g. if they do not add up.
+    pub fn restore(snapshot: Snapshot, config: Config) -> std::result::Result<Self, SnapshotError> {
+        let client = snapshot.client;
+        let mut amounts = snapshot
+            .transactions
+            .iter()
+            .filter_map(SnapshotTransaction::amount)
+            .chain(snapshot.partial.iter().map(|partial| partial.amount));
+        if amounts.any(|amount| !amount.is_positive()) {
+            return Err(SnapshotError::Corrupt(client));
+        }
+        let mut state = Self::with_config(client, config);
+        let partial = snapshot
+            .partial
+            .into_iter()
+            .map(|partial| (partial.tx, partial.amount))
+            .collect::<HashMap<_, _>>();
+        let held = |tx, amount| {
+            partial
+                .get(&tx)
+                .copied()
+                .unwrap_or_else(|| config.disputed_amount(amount))
+        };
+        for transaction in snapshot.transactions {
+            let (tx, transaction) = match transaction {
+                SnapshotTransaction::Deposit {
+                    tx,
+                    amount,
+                    disputed,
+                } => {
+                    if disputed {
+                        state.held.insert(tx, held(tx, amount));
+                    }
+                    let md = TransactionMetadata(tx, client);
+                    (tx, Transaction::Deposit(md, amount, disputed))
+                }
+                SnapshotTransaction::Withdrawal {
+                    tx,
+                    amount,
+                    disputed,
+                } => {
+                    if disputed {
+                        state.held.insert(tx, held(tx, amount));
+                    }
+                    let md = TransactionMetadata(tx, client);
+                    (tx, Transaction::Withdrawal(md, amount, disputed))
+                }
+                SnapshotTransaction::Interest { tx, amount } => {
+                    let md = TransactionMetadata(tx, client);
+                    (tx, Transaction::Interest(md, amount))
+                }
+                SnapshotTransaction::Unlock { tx, charge_backs } => {
+                    let md = TransactionMetadata(tx, client);
+                    (tx, Transaction::Unlock(md, charge_backs))
+                }
+                SnapshotTransaction::Transfer {
+                    tx,
+                    sender,
+                    receiver,
+                    amount,
+                } => {
+                    if sender == receiver || (client != sender && client != receiver) {
+                        return Err(SnapshotError::Corrupt(client));
+                    }
+                    let md = TransactionMetadata(tx, sender);
+                    (tx, Transaction::Transfer(md, receiver, amount))
+                }
+            };
+            if let Transaction::Deposit(..)
+            | Transaction::Withdrawal(..)
+            | Transaction::Interest(..)
+            | Transaction::Transfer(..) = transaction
+            {
+                state.track_order(tx);
+            }
+            if state.transaction_history.contains_key(&tx) {
+                return Err(SnapshotError::Corrupt(client));
+            }
+            state.push_history(tx, transaction);
+        }
+        state.charge_backs = snapshot.charge_backs.into_iter().collect();
+        state.partial_charge_backs = partial
+            .into_iter()
+            .filter(|(tx, _)| state.charge_backs.contains(tx))
+            .collect();
+        state.settled = snapshot.settled;
+        state.evicted = snapshot.evicted.into_iter().collect();
+        // Saved transactions are in the order they were applied
+        state.evict();
+        state.highest_tx_id = snapshot.highest_tx_id;
+        state.account.set_tag(snapshot.tag);
+        state.tag_established = true;
+        state.account.set_last_tx_id(snapshot.last_tx_id);
+        state
+            .account
+            .set_overdraft_limit(snapshot.overdraft_limit)
+            .map_err(|_| SnapshotError::Corrupt(client))?;
+        state.account = state
+            .recompute_balance()
+            .ok_or(SnapshotError::Corrupt(client))?;
+        state.flows = Some(Flows {
+            opening: state.account.total(),
+            ..Flows::default()
+        });
+
+        Ok(state)
+    }
+
+    /// Funds moved by the transactions applied, `None` if they no longer fit in an `Amount`.
+    pub fn flows(&self) -> Option<Flows> {
+        self.flows
+    }
+
+    /// Update the flows with `f`, giving up on them once `f` overflows.
+    fn track(&mut self, f: impl FnOnce(&mut Flows) -> Option<()>) {
+        if let Some(mut flows) = self.flows {
+            self.flows = f(&mut flows).map(|_| flows);
+        }
+    }
+
+    /// Credit interest at `rate` on the available funds, recorded as interest transaction `tx`.
+    ///
+    /// The interest is computed with decimal arithmetic and rounded to `Config::scale` decimal
+    /// places. Returns the credited interest, zero if the account is locked or the
+    /// interest rounds to nothing, in which case the account is left untouched.
+    pub fn accrue_interest(&mut self, tx: TransactionId, rate: Amount) -> Result<Amount> {
+        if rate.is_negative() {
+            return Err(Error::Interest);
+        }
+        let interest = self
+            .account
+            .available()
+            .checked_mul(rate)
+            .ok_or(Error::Interest)?
+            .round_dp(self.config.scale);
+        if self.account.locked() || !interest.is_positive() {
+            return Ok(Amount::ZERO);
+        }
+        self.check_new_transaction(tx)?;
+        self.account.deposit(interest).map_err(Error::Account)?;
+        let md = TransactionMetadata(tx, self.account.id());
+        self.record_transaction(Transaction::Interest(md, interest));
+        self.track(|flows| {
+            flows.interest = flows.interest.checked_add(interest)?;
+            Some(())
+        });
+
+        Ok(interest)
+    }
+
+    /// Check that a transfer or interest `tx` applied outside of `Transaction::apply` neither goes
+    /// back in ids nor reuses one.
+    fn check_new_transaction(&self, tx: TransactionId) -> Result<()> {
+        if self.config.monotonic_tx_ids && self.highest_tx_id.is_some_and(|highest| tx < highest) {
+            return Err(Error::OutOfOrderTransactionId);
+        }
+        if self.transaction_history.contains_key(&tx) || self.evicted.contains(&tx) {
+            return Err(Error::DuplicateTransactionId);
+        }
+        Ok(())
+    }
+
+    /// Add transfer or interest `transaction`, already applied to the account, to the history.
+    fn record_transaction(&mut self, transaction: Transaction) {
+        if let Transaction::Transfer(md, _, _) | Transaction::Interest(md, _) = transaction {
+            self.highest_tx_id = self.highest_tx_id.max(Some(md.0));
+            self.account.raise_last_tx_id(md.0);
+            self.push_history(md.0, transaction);
+            self.track_order(md.0);
+            self.evict();
+        }
+    }
+
+    /// Add `transaction`, whose id `tx` is not in the history yet, after all the others.
+    fn push_history(&mut self, tx: TransactionId, transaction: Transaction) {
+        self.transaction_history.insert(tx, transaction);
+        self.sequence.push(tx);
+    }
+
+    /// Transactions of the history in the order they were applied, or saved for restored ones.
+    fn applied(&self) -> impl Iterator<Item = &Transaction> {
+        self.sequence
+            .iter()
+            .filter_map(|tx| self.transaction_history.get(tx))
+    }
+
+    /// Record that deposit or withdrawal `tx` was added to the history, if its size is capped.
+    fn track_order(&mut self, tx: TransactionId) {
+        if self.config.max_history.is_some() {
+            self.order.push_back(tx);
+            self.tracked += 1;
+        }
+    }
+
+    /// Make disputed transaction `tx` evictable again, e.g. once its dispute is resolved.
+    fn unpin(&mut self, tx: TransactionId) {
+        // Pinned transactions are older than all of those in `order`
+        if self.pinned.remove(&tx) {
+            self.order.push_front(tx);
+        }
+    }
+
+    /// Evict the oldest deposits and withdrawals beyond `Config::max_history`, if set.
+    ///
+    /// Transactions under dispute are kept whatever their age, so they can still be resolved or
+    /// charged back.
+    fn evict(&mut self) {
+        let Some(max) = self.config.max_history else {
+            return;
+        };
+        while self.tracked > max {
+            let Some(tx) = self.order.pop_front() else {
+                break;
+            };
+            if !self.transaction_history.contains_key(&tx) {
+                continue;
+            }
+            if self.held.contains_key(&tx) {
+                self.pinned.insert(tx);
+                continue;
+            }
+            self.evict_transaction(tx);
+        }
+    }
+
+    /// Remove deposit or withdrawal `tx` from the history, adding its funds to the settled ones.
+    fn evict_transaction(&mut self, tx: TransactionId) {
+        // Left in `order` if there, skipped once it reaches the front
+        self.pinned.remove(&tx);
+        let removed = self.transaction_history.remove(&tx);
+        // Evicted ids are never added back, drop them once they make up half of the sequence
+        if self.sequence.len() > 2 * self.transaction_history.len() {
+            let history = &self.transaction_history;
+            self.sequence.retain(|tx| history.contains_key(tx));
+        }
+        let funds = match removed {
+            Some(Transaction::Deposit(_, amount, _)) => match self.charged_back(tx, amount) {
+                Some(charged_back) => amount.checked_sub(charged_back),
+                None => Some(amount),
+            },
+            Some(Transaction::Withdrawal(_, amount, _)) => match self.charged_back(tx, amount) {
+                Some(charged_back) => charged_back.checked_sub(amount),
+                None => Some(-amount),
+            },
+            Some(Transaction::Interest(_, amount)) => Some(amount),
+            Some(Transaction::Transfer(md, _, amount)) if md.1 == self.account.id() => {
+                Some(-amount)
+            }
+            Some(Transaction::Transfer(_, _, amount)) => Some(amount),
+            _ => Some(Amount::ZERO),
+        };
+        if let Some(
+            Transaction::Deposit(..)
+            | Transaction::Withdrawal(..)
+            | Transaction::Interest(..)
+            | Transaction::Transfer(..),
+        ) = removed
+        {
+            self.tracked -= 1;
+            self.evicted.insert(tx);
+        }
+        self.partial_charge_backs.remove(&tx);
+        tracing::debug!(
+            client = self.account.id(),
+            tx,
+            "transaction evicted from history"
+        );
+        self.settled = funds
+            .and_then(|funds| self.settled.checked_add(funds))
+            .expect("settled funds are part of the account total");
+    }
+
+    /// Amount held by deposit or withdrawal `tx` of `amount` while under dispute.
+    fn held_amount(&self, tx: TransactionId, amount: Amount) -> Amount {
+        self.held
+            .get(&tx)
+            .copied()
+            .unwrap_or_else(|| self.config.disputed_amount(amount))
+    }
+
+    /// Amount charged back from deposit or withdrawal `tx` of `amount`, if it was charged back.
+    fn charged_back(&self, tx: TransactionId, amount: Amount) -> Option<Amount> {
+        self.charge_backs.contains(&tx).then(|| {
+            self.partial_charge_backs
+                .get(&tx)
+                .copied()
+                .unwrap_or_else(|| self.config.disputed_amount(amount))
+        })
+    }
+
+    /// Whether transaction `tx` was applied to the state and is still in its history.
+    pub fn has_transaction(&self, tx: TransactionId) -> bool {
+        self.transaction_history.contains_key(&tx)
+    }
+
+    /// Deposits and withdrawals currently under dispute, with the amount each of them holds.
+    #[cfg(test)]
+    pub fn held_transactions(&self) -> &HashMap<TransactionId, Amount> {
+        &self.held
+    }
+
+    /// Rebuild the account from scratch out of the transaction history, along with the funds of
+    /// the transactions evicted from it.
+    ///
+    /// The history is replayed in the order it was applied, each transaction moving the funds it
+    /// moves for good once its disputes are settled, so that the funds go through the same range
+    /// as the account did. Returns `None` if they do not fit in an `Amount` nonetheless, i.e. the
+    /// history does not match any account.
+    pub fn recompute_balance(&self) -> Option<Account> {
+        // Evicted transactions are older than all those in the history
+        let mut total = self.settled;
+        let mut held = Amount::ZERO;
+        // Charge backs cleared by the latest unlock
+        let mut unlocked = 0;
+        for transaction in self.applied() {
+            let funds = match *transaction {
+                Transaction::Deposit(md, amount, is_disputed) => {
+                    if is_disputed {
+                        held = held.checked_add(self.held_amount(md.0, amount))?;
+                    }
+                    match self.charged_back(md.0, amount) {
+                        Some(charged_back) => amount.checked_sub(charged_back)?,
+                        None => amount,
+                    }
+                }
+                Transaction::Withdrawal(md, amount, is_disputed) => {
+                    // Disputed withdrawals are credited back while held, charged back ones for
+                    // good
+                    let mut credited = self.charged_back(md.0, amount).unwrap_or(Amount::ZERO);
+                    if is_disputed {
+                        let disputed = self.held_amount(md.0, amount);
+                        held = held.checked_add(disputed)?;
+                        credited = credited.checked_add(disputed)?;
+                    }
+                    credited.checked_sub(amount)?
+                }
+                Transaction::Interest(_, amount) => amount,
+                // The sender and receiver keep the same transfer in their histories
+                Transaction::Transfer(md, _, amount) if md.1 == self.account.id() => -amount,
+                Transaction::Transfer(_, _, amount) => amount,
+                Transaction::Unlock(_, charge_backs) => {
+                    unlocked = unlocked.max(charge_backs);
+                    Amount::ZERO
+                }
+                _ => Amount::ZERO,
+            };
+            total = total.checked_add(funds)?;
+        }
+        let available = total.checked_sub(held)?;
+
+        Account::builder()
+            .id(self.account.id())
+            .available(available)
+            .held(held)
+            .total(total)
+            .locked(self.charge_backs.len() > unlocked)
+            .tag(self.account.tag().map(str::to_string))
+            .overdraft_limit(self.account.overdraft_limit())
+            .min_balance(self.account.min_balance())
+            // Evicted transactions raised it as well
+            .last_tx_id(self.account.last_tx_id())
+            .build()
+            .ok()
+    }
+
+    /// Compare the stored account against the one recomputed from the transaction history,
+    /// replacing the stored one if `repair` is set and the history adds up to an account.
+    ///
+    /// Returns the drift between the two, if any.
+    pub fn check_balance(&mut self, repair: bool) -> Option<Drift> {
+        let recomputed = self.recompute_balance();
+        if recomputed.as_ref() == Some(&self.account) {
+            return None;
+        }
+
+        let drift = Drift {
+            stored: self.account.clone(),
+            recomputed: recomputed.clone(),
+        };
+        if let (true, Some(recomputed)) = (repair, recomputed) {
+            self.account = recomputed;
+        }
+        Some(drift)
+    }
+
+    /// Tag the account with the `tag` of one of its transactions.
+    ///
+    /// Only the first transaction establishes the tag, even if it has none. Afterwards the tag is
+    /// kept and an error is returned if `tag` is set and differs from it.
+    pub fn set_tag(&mut self, tag: Option<&str>) -> Result<()> {
+        if !self.tag_established {
+            self.tag_established = true;
+            self.account.set_tag(tag.map(str::to_string));
+            return Ok(());
+        }
+        match tag {
+            Some(tag) if self.account.tag() != Some(tag) => Err(Error::TagConflict),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::account::Error as AccountError;
+
+    #[test]
+    fn test_state_default() {
+        assert_eq!(State::default().account, Account::default());
+        assert!(State::default().transaction_history.is_empty());
+    }
+
+    #[test]
+    fn test_transaction_tryfrom() {
+        assert_eq!(
+            Transaction::try_from(TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client: 1,
+                id: 2,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+            .unwrap(),
+            Transaction::Deposit(
+                TransactionMetadata(2, 1),
+                Amount::from_f64(1.0).unwrap(),
+                false
+            )
+        );
+        assert_eq!(
+            Transaction::try_from(TransactionRecord {
+                transaction_type: TransactionType::Withdrawal,
+                client: 1,
+                id: 2,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+            .unwrap(),
+            Transaction::Withdrawal(
+                TransactionMetadata(2, 1),
+                Amount::from_f64(1.0).unwrap(),
+                false
+            )
+        );
+        assert_eq!(
+            Transaction::try_from(TransactionRecord {
+                transaction_type: TransactionType::Dispute,
+                client: 1,
+                id: 2,
+                amount: None,
+                tag: None,
+                counterparty: None,
+            })
+            .unwrap(),
+            Transaction::Dispute(TransactionMetadata(2, 1), None)
+        );
+        assert_eq!(
+            Transaction::try_from(TransactionRecord {
+                transaction_type: TransactionType::Resolve,
+                client: 1,
+                id: 2,
+                amount: None,
+                tag: None,
+                counterparty: None,
+            })
+            .unwrap(),
+            Transaction::Resolve(TransactionMetadata(2, 1))
+        );
+        assert_eq!(
+            Transaction::try_from(TransactionRecord {
+                transaction_type: TransactionType::ChargeBack,
+                client: 1,
+                id: 2,
+                amount: None,
+                tag: None,
+                counterparty: None,
+            })
+            .unwrap(),
+            Transaction::ChargeBack(TransactionMetadata(2, 1))
+        );
+        assert!(Transaction::try_from(TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            id: 2,
+            amount: None,
+            tag: None,
+            counterparty: None,
+        })
+        .is_err());
+        assert!(Transaction::try_from(TransactionRecord {
+            transaction_type: TransactionType::Withdrawal,
+            client: 1,
+            id: 2,
+            amount: None,
+            tag: None,
+            counterparty: None,
+        })
+        .is_err());
+        assert_eq!(
+            Transaction::try_from(TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client: 1,
+                id: 2,
+                amount: Some(Err(crate::model::amount::ParseAmountError::NonFinite)),
+                tag: None,
+                counterparty: None,
+            })
+            .unwrap_err(),
+            Error::NonFiniteAmount
+        );
+    }
+
+    #[test]
+    fn test_transaction_tryfrom_negative() {
+        let try_from = |transaction_type, amount: &str| {
+            Transaction::try_from(TransactionRecord {
+                transaction_type,
+                client: 1,
+                id: 2,
+                amount: amount.parse().ok().map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+        };
+
+        for transaction_type in [TransactionType::Deposit, TransactionType::Withdrawal] {
+            let error = try_from(transaction_type, "-1.0").unwrap_err();
+            assert_eq!(error, Error::NegativeAmount("-1.0".parse().unwrap()));
+            assert_eq!(error.to_string(), "Negative amount -1.0");
+        }
+
+        // Zero amounts go through, to be rejected by the account
+        for amount in ["0.0", "-0.0"] {
+            let mut state = State::new(1);
+            let deposit = try_from(TransactionType::Deposit, amount).unwrap();
+            assert_eq!(
+                deposit.apply(&mut state).unwrap_err(),
+                Error::Account(AccountError::InvalidInput)
+            );
+        }
+    }
+
+    #[test]
+    fn test_transaction_tryfrom_ref() {
+        let record = TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client: 1,
+            id: 2,
+            amount: Amount::from_f64(1.0).map(Ok),
+            tag: Some("eu".to_string()),
+            counterparty: None,
+        };
+
+        assert_eq!(
+            Transaction::try_from(&record).unwrap(),
+            Transaction::Deposit(
+                TransactionMetadata(2, 1),
+                Amount::from_f64(1.0).unwrap(),
+                false
+            )
+        );
+        // The record is still usable after the conversion
+        assert_eq!(record.tag.as_deref(), Some("eu"));
+        assert_eq!(
+            Transaction::try_from(&record),
+            Transaction::try_from(record)
+        );
+        assert_eq!(
+            Transaction::try_from(&TransactionRecord {
+                transaction_type: TransactionType::Withdrawal,
+                client: 1,
+                id: 2,
+                amount: None,
+                tag: None,
+                counterparty: None,
+            }),
+            Err(Error::Withdrawal)
+        );
+    }
+
+    #[test]
+    fn test_set_tag() {
+        let mut state = State::new(1);
+
+        state.set_tag(Some("eu")).unwrap();
+        state.set_tag(Some("eu")).unwrap();
+        state.set_tag(None).unwrap();
+        assert_eq!(state.set_tag(Some("us")).err().unwrap(), Error::TagConflict);
+        assert_eq!(state.account.tag(), Some("eu"));
+
+        // An untagged first transaction leaves the account untagged
+        let mut state = State::new(2);
+        state.set_tag(None).unwrap();
+        assert_eq!(state.set_tag(Some("us")).err().unwrap(), Error::TagConflict);
+        assert_eq!(state.account.tag(), None);
+    }
+
+    /// Records the name and fields, except the message, of every event.
+    #[derive(Clone, Default)]
+    struct EventRecorder(std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for EventRecorder {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Fields(Vec<String>);
+            impl tracing::field::Visit for Fields {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() != "message" {
+                        self.0.push(format!("{}={:?}", field.name(), value));
+                    }
+                }
+            }
+
+            let mut fields = Fields(Vec::new());
+            event.record(&mut fields);
+            self.0
+                .lock()
+                .unwrap()
+                .push((event.metadata().name().to_string(), fields.0.join(" ")));
+        }
+    }
+
+    #[test]
+    fn test_dispute_events() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let recorder = EventRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let mut state = State::new(1);
+            let transactions = [
+                Transaction::Deposit(
+                    TransactionMetadata(1, 1),
+                    Amount::from_f64(2.5).unwrap(),
+                    false,
+                ),
+                Transaction::Dispute(TransactionMetadata(1, 1), None),
+                Transaction::Resolve(TransactionMetadata(1, 1)),
+                Transaction::Dispute(TransactionMetadata(1, 1), None),
+                Transaction::ChargeBack(TransactionMetadata(1, 1)),
+            ];
+            for transaction in transactions {
+                transaction.apply(&mut state).unwrap();
+            }
+            // Failed transitions emit nothing
+            Transaction::Resolve(TransactionMetadata(1, 1))
+                .apply(&mut state)
+                .unwrap_err();
+        });
+
+        let fields = "client=1 tx=1 amount=2.5".to_string();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                ("dispute_opened".to_string(), fields.clone()),
+                ("dispute_resolved".to_string(), fields.clone()),
+                ("dispute_opened".to_string(), fields.clone()),
+                ("charged_back".to_string(), fields),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recompute_balance() {
+        let mut state = State::new(1);
+        let transactions = [
+            Transaction::Deposit(
+                TransactionMetadata(1, 1),
+                Amount::from_f64(10.0).unwrap(),
+                false,
+            ),
+            Transaction::Deposit(
+                TransactionMetadata(2, 1),
+                Amount::from_f64(5.0).unwrap(),
+                false,
+            ),
+            Transaction::Deposit(
+                TransactionMetadata(3, 1),
+                Amount::from_f64(2.0).unwrap(),
+                false,
+            ),
+            Transaction::Withdrawal(
+                TransactionMetadata(4, 1),
+                Amount::from_f64(3.0).unwrap(),
+                false,
+            ),
+            Transaction::Dispute(TransactionMetadata(2, 1), None),
+            Transaction::Dispute(TransactionMetadata(3, 1), None),
+            Transaction::ChargeBack(TransactionMetadata(3, 1)),
+        ];
+        for transaction in transactions {
+            transaction.apply(&mut state).unwrap();
+        }
+        assert_eq!(state.recompute_balance(), Some(state.account.clone()));
+        assert_eq!(state.check_balance(true), None);
+
+        // Corrupt the stored account, as a stale snapshot would
+        let expected = state.account.clone();
+        state.account = Account::with_balances(
+            1,
+            Amount::from_f64(1.0).unwrap(),
+            Amount::ZERO,
+            Amount::from_f64(1.0).unwrap(),
+            false,
+        );
+        state.account.set_last_tx_id(expected.last_tx_id());
+
+        let drift = state.check_balance(false).unwrap();
+        assert_eq!(drift.recomputed, Some(expected.clone()));
+        assert_ne!(drift.stored, expected);
+        assert_ne!(state.account, expected);
+
+        assert!(state.check_balance(true).is_some());
+        assert_eq!(state.account, expected);
+        assert_eq!(state.account.available(), Amount::from_f64(7.0).unwrap());
+        assert_eq!(state.account.held(), Amount::from_f64(5.0).unwrap());
+        assert_eq!(state.account.total(), Amount::from_f64(12.0).unwrap());
+        assert!(state.account.locked());
+        assert_eq!(state.check_balance(true), None);
+    }
+
+    #[test]
+    fn test_recompute_balance_in_order() {
+        // The deposits alone add up to more than Amount::MAX
+        let mut state = State::new(1);
+        let transactions = [
+            Transaction::Deposit(TransactionMetadata(1, 1), Amount::MAX, false),
+            Transaction::Withdrawal(TransactionMetadata(2, 1), Amount::MAX, false),
+            Transaction::Deposit(TransactionMetadata(3, 1), Amount::MAX, false),
+        ];
+        for transaction in transactions {
+            transaction.apply(&mut state).unwrap();
+        }
+
+        assert_eq!(state.recompute_balance(), Some(state.account.clone()));
+        assert_eq!(state.check_balance(false), None);
+    }
+
+    #[test]
+    fn test_flows() {
+        let amount = |amount| Amount::from_f64(amount).unwrap();
+        let md = |id| TransactionMetadata(id, 1);
+        let mut state = State::with_config(
+            1,
+            Config {
+                duplicate_policy: DuplicatePolicy::Overwrite,
+                ..Config::default()
+            },
+        );
+        for transaction in [
+            Transaction::Deposit(md(1), amount(10.0), false),
+            Transaction::Deposit(md(2), amount(4.0), false),
+            Transaction::Withdrawal(md(3), amount(3.0), false),
+            // Overwrites are reversed first
+            Transaction::Withdrawal(md(3), amount(2.0), false),
+            Transaction::Withdrawal(md(4), amount(1.0), false),
+            Transaction::Dispute(md(4), None),
+            Transaction::Resolve(md(4)),
+            Transaction::Dispute(md(3), None),
+            Transaction::Dispute(md(2), None),
+            Transaction::ChargeBack(md(3)),
+            Transaction::ChargeBack(md(2)),
+        ] {
+            transaction.apply(&mut state).unwrap();
+        }
+        // Rejected transactions move nothing
+        Transaction::Deposit(md(5), amount(1.0), false)
+            .apply(&mut state)
+            .unwrap_err();
+
+        let flows = state.flows().unwrap();
+        assert_eq!(
+            flows,
+            Flows {
+                opening: Amount::ZERO,
+                deposited: amount(14.0),
+                withdrawn: amount(3.0),
+                charged_back: amount(4.0),
+                reversed: amount(2.0),
+                interest: Amount::ZERO,
+            }
+        );
+        assert_eq!(flows.total(), Some(state.account.total()));
+
+        // Restored states start from their total
+        let restored = State::restore(state.snapshot(), state.config).unwrap();
+        assert_eq!(
+            restored.flows().unwrap(),
+            Flows {
+                opening: amount(9.0),
+                ..Flows::default()
+            }
+        );
+
+        // Flows which no longer fit are given up on
+        let mut state = State::new(1);
+        Transaction::Deposit(md(1), Amount::MAX, false)
+            .apply(&mut state)
+            .unwrap();
+        Transaction::Withdrawal(md(2), Amount::MAX, false)
+            .apply(&mut state)
+            .unwrap();
+        Transaction::Deposit(md(3), Amount::MAX, false)
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(state.flows(), None);
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let deposit = |id, amount| {
+            Transaction::Deposit(
+                TransactionMetadata(id, 1),
+                Amount::from_f64(amount).unwrap(),
+                false,
+            )
+        };
+        let run = |duplicate_policy| {
+            let mut state = State::with_config(
+                1,
+                Config {
+                    duplicate_policy,
+                    ..Config::default()
+                },
+            );
+            deposit(1, 10.0).apply(&mut state).unwrap();
+            deposit(2, 5.0).apply(&mut state).unwrap();
+            let result = deposit(1, 3.0).apply(&mut state);
+            (state, result)
+        };
+
+        let (state, result) = run(DuplicatePolicy::Error);
+        assert_eq!(result.unwrap_err(), Error::DuplicateTransactionId);
+        assert_eq!(state.account.total(), Amount::from_f64(15.0).unwrap());
+
+        let (state, result) = run(DuplicatePolicy::Ignore);
+        result.unwrap();
+        assert_eq!(state.account.total(), Amount::from_f64(15.0).unwrap());
+        assert_eq!(state.transaction_history[&1], deposit(1, 10.0));
+
+        let (mut state, result) = run(DuplicatePolicy::Overwrite);
+        result.unwrap();
+        assert_eq!(state.account.total(), Amount::from_f64(8.0).unwrap());
+        assert_eq!(state.account.available(), Amount::from_f64(8.0).unwrap());
+        assert_eq!(state.transaction_history[&1], deposit(1, 3.0));
+        assert_eq!(state.recompute_balance(), Some(state.account.clone()));
+
+        // Overwriting a withdrawal refunds it first
+        let withdrawal = |amount| {
+            Transaction::Withdrawal(
+                TransactionMetadata(3, 1),
+                Amount::from_f64(amount).unwrap(),
+                false,
+            )
+        };
+        withdrawal(8.0).apply(&mut state).unwrap();
+        withdrawal(2.0).apply(&mut state).unwrap();
+        assert_eq!(state.account.total(), Amount::from_f64(6.0).unwrap());
+
+        // Corrections which cannot be applied leave the account untouched
+        assert_eq!(
+            withdrawal(9.0).apply(&mut state).unwrap_err(),
+            Error::Account(AccountError::InsufficientFunds)
+        );
+        assert_eq!(state.account.total(), Amount::from_f64(6.0).unwrap());
+        assert_eq!(state.transaction_history[&3], withdrawal(2.0));
+
+        // Disputed deposits cannot be overwritten
+        Transaction::Dispute(TransactionMetadata(2, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(
+            deposit(2, 1.0).apply(&mut state).unwrap_err(),
+            Error::DuplicateTransactionId
+        );
+        assert_eq!(state.account.held(), Amount::from_f64(5.0).unwrap());
+    }
+
+    #[test]
+    fn test_unlock() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let deposit = |id, n| Transaction::Deposit(TransactionMetadata(id, 1), amount(n), false);
+        let mut state = State::new(1);
+
+        assert_eq!(
+            Transaction::Unlock(TransactionMetadata(1, 1), 0).apply(&mut state),
+            Err(Error::Account(AccountError::NotLocked))
+        );
+
+        for transaction in [
+            deposit(1, 10),
+            deposit(2, 5),
+            deposit(3, 2),
+            Transaction::Dispute(TransactionMetadata(1, 1), None),
+            Transaction::Dispute(TransactionMetadata(3, 1), None),
+            Transaction::ChargeBack(TransactionMetadata(1, 1)),
+        ] {
+            transaction.apply(&mut state).unwrap();
+        }
+        assert_eq!(
+            deposit(4, 1).apply(&mut state),
+            Err(Error::Account(AccountError::Locked))
+        );
+
+        // Unlocks need an id of their own
+        assert_eq!(
+            Transaction::Unlock(TransactionMetadata(3, 1), 0).apply(&mut state),
+            Err(Error::DuplicateTransactionId)
+        );
+        Transaction::Unlock(TransactionMetadata(4, 1), 0)
+            .apply(&mut state)
+            .unwrap();
+        assert!(!state.account.locked());
+        assert_eq!(
+            state.transaction_history.get(&4),
+            Some(&Transaction::Unlock(TransactionMetadata(4, 1), 1))
+        );
+        deposit(5, 1).apply(&mut state).unwrap();
+        assert_eq!(state.account.total(), amount(8));
+        assert_eq!(state.check_balance(false), None);
+
+        let restored = State::restore(state.snapshot(), Config::default()).unwrap();
+        assert_eq!(restored.account, state.account);
+
+        // A later charge back locks the account again
+        Transaction::ChargeBack(TransactionMetadata(3, 1))
+            .apply(&mut state)
+            .unwrap();
+        assert!(state.account.locked());
+        assert_eq!(state.check_balance(false), None);
+        Transaction::Unlock(TransactionMetadata(6, 1), 0)
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(state.check_balance(false), None);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_snapshot() {
+        let mut state = State::new(1);
+        for transaction in [
+            Transaction::Deposit(
+                TransactionMetadata(1, 1),
+                Amount::from_int(10).unwrap(),
+                false,
+            ),
+            Transaction::Withdrawal(
+                TransactionMetadata(2, 1),
+                Amount::from_int(4).unwrap(),
+                false,
+            ),
+            Transaction::Dispute(TransactionMetadata(2, 1), None),
+        ] {
+            transaction.apply(&mut state).unwrap();
+        }
+        assert_eq!(state.check_balance(false), None);
+
+        let snapshot = serde_json::to_string(&state.snapshot()).unwrap();
+        let restored =
+            State::restore(serde_json::from_str(&snapshot).unwrap(), Config::default()).unwrap();
+        assert_eq!(restored.account, state.account);
+        assert_eq!(restored.held_transactions(), state.held_transactions());
+
+        // Snapshots taken before withdrawals could be disputed have no dispute flag
+        let snapshot: Snapshot = serde_json::from_str(
+            r#"{"client":1,"tag":null,"last_tx_id":2,"highest_tx_id":2,"transactions":[
+                {"type":"deposit","tx":1,"amount":"10","disputed":false},
+                {"type":"withdrawal","tx":2,"amount":"4"}
+            ],"charge_backs":[]}"#,
+        )
+        .unwrap();
+        let restored = State::restore(snapshot, Config::default()).unwrap();
+        assert_eq!(restored.account.total(), Amount::from_int(6).unwrap());
+        assert!(restored.held_transactions().is_empty());
+    }
+
+    #[test]
+    fn test_restore_in_order() {
+        // Sorted by id, the deposits would add up to more than Amount::MAX
+        let mut state = State::new(1);
+        for transaction in [
+            Transaction::Deposit(TransactionMetadata(2, 1), Amount::MAX, false),
+            Transaction::Withdrawal(TransactionMetadata(3, 1), Amount::MAX, false),
+            Transaction::Deposit(TransactionMetadata(1, 1), Amount::MAX, false),
+        ] {
+            transaction.apply(&mut state).unwrap();
+        }
+
+        let snapshot = state.snapshot();
+        assert_eq!(
+            snapshot
+                .transactions
+                .iter()
+                .map(SnapshotTransaction::tx)
+                .collect::<Vec<_>>(),
+            [2, 3, 1]
+        );
+        let restored = State::restore(snapshot, Config::default()).unwrap();
+        assert_eq!(restored.account, state.account);
+    }
+
+    #[test]
+    fn test_restore_corrupt_snapshot() {
+        let restore = |transactions: &str| {
+            let snapshot: Snapshot = serde_json::from_str(&format!(
+                r#"{{"client":1,"tag":null,"last_tx_id":2,"highest_tx_id":2,
+                    "transactions":[{transactions}],"charge_backs":[]}}"#
+            ))
+            .unwrap();
+            State::restore(snapshot, Config::default())
+        };
+        assert!(matches!(
+            restore(r#"{"type":"deposit","tx":1,"amount":"-10","disputed":false}"#),
+            Err(SnapshotError::Corrupt(1))
+        ));
+        // Deposits adding up to more than `Amount::MAX`
+        let max = Amount::MAX.to_string();
+        assert!(matches!(
+            restore(&format!(
+                r#"{{"type":"deposit","tx":1,"amount":"{max}","disputed":false}},
+                   {{"type":"deposit","tx":2,"amount":"{max}","disputed":false}}"#
+            )),
+            Err(SnapshotError::Corrupt(1))
+        ));
+        // The same transaction twice
+        assert!(matches!(
+            restore(
+                r#"{"type":"deposit","tx":1,"amount":"10","disputed":false},
+                   {"type":"withdrawal","tx":1,"amount":"10","disputed":false}"#
+            ),
+            Err(SnapshotError::Corrupt(1))
+        ));
+
+        let mut state = State::new(1);
+        state
+            .account
+            .set_overdraft_limit(Amount::from_int(100).unwrap())
+            .unwrap();
+        let mut snapshot = state.snapshot();
+        let restored = State::restore(snapshot.clone(), Config::default()).unwrap();
+        assert_eq!(
+            restored.account.overdraft_limit(),
+            Amount::from_int(100).unwrap()
+        );
+        snapshot.overdraft_limit = Amount::from_int(-100).unwrap();
+        assert!(matches!(
+            State::restore(snapshot, Config::default()),
+            Err(SnapshotError::Corrupt(1))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_envelope() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let mut state = State::new(1);
+        Transaction::Deposit(TransactionMetadata(1, 1), amount(10), false)
+            .apply(&mut state)
+            .unwrap();
+        Transaction::Deposit(TransactionMetadata(2, 1), amount(5), false)
+            .apply(&mut state)
+            .unwrap();
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+
+        let json = serde_json::to_vec(&SnapshotV1::new(vec![state.snapshot()])).unwrap();
+        let envelope = SnapshotV1::from_json(&json).unwrap();
+        assert_eq!(envelope.version, SNAPSHOT_VERSION);
+        let [snapshot] = <[Snapshot; 1]>::try_from(envelope.clients).unwrap();
+        let mut restored = State::restore(snapshot, Config::default()).unwrap();
+        assert_eq!(restored.account, state.account);
+        assert_eq!(restored.account.held(), amount(10));
+
+        // The dispute carries on after the restore
+        Transaction::Resolve(TransactionMetadata(1, 1))
+            .apply(&mut restored)
+            .unwrap();
+        assert_eq!(restored.account.held(), Amount::ZERO);
+        assert_eq!(restored.account.available(), amount(15));
+
+        // Snapshots saved before the envelope are a bare list
+        let legacy = serde_json::to_vec(&[state.snapshot()]).unwrap();
+        assert_eq!(
+            SnapshotV1::from_json(&legacy).unwrap(),
+            SnapshotV1::new(vec![state.snapshot()])
+        );
+
+        assert!(matches!(
+            SnapshotV1::from_json(br#"{"version":2,"clients":[]}"#),
+            Err(SnapshotError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            SnapshotV1::from_json(br#"{"clients":[]}"#),
+            Err(SnapshotError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_dispute_exceeds_original() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let dispute = |n| Transaction::Dispute(TransactionMetadata(1, 1), Some(amount(n)));
+        let config = Config {
+            partial_disputes: true,
+            ..Config::default()
+        };
+        let mut state = State::with_config(1, config);
+        Transaction::Deposit(TransactionMetadata(1, 1), amount(100), false)
+            .apply(&mut state)
+            .unwrap();
+
+        // 60% then 50% of the deposit
+        dispute(60).apply(&mut state).unwrap();
+        let before = state.account.clone();
+        assert_eq!(
+            dispute(50).apply(&mut state),
+            Err(Error::DisputeExceedsOriginal)
+        );
+        assert_eq!(state.account, before);
+        assert_eq!(state.held_transactions().get(&1), Some(&amount(60)));
+
+        // Up to the whole deposit can be held
+        dispute(40).apply(&mut state).unwrap();
+        assert_eq!(state.held_transactions().get(&1), Some(&amount(100)));
+        assert_eq!(state.account.available(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_held_ledger() {
+        let mut state = State::new(1);
+        for (id, amount) in [(1, 10.0), (2, 3.0), (3, 1.0)] {
+            Transaction::Deposit(
+                TransactionMetadata(id, 1),
+                Amount::from_f64(amount).unwrap(),
+                false,
+            )
+            .apply(&mut state)
+            .unwrap();
+        }
+        assert!(state.held_transactions().is_empty());
+
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        Transaction::Dispute(TransactionMetadata(2, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(
+            *state.held_transactions(),
+            HashMap::from([
+                (1, Amount::from_f64(10.0).unwrap()),
+                (2, Amount::from_f64(3.0).unwrap())
+            ])
+        );
+        assert_eq!(
+            state.held_transactions().values().copied().sum::<Amount>(),
+            state.account.held()
+        );
+
+        Transaction::Resolve(TransactionMetadata(1, 1))
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(
+            *state.held_transactions(),
+            HashMap::from([(2, Amount::from_f64(3.0).unwrap())])
+        );
+        assert_eq!(state.account.held(), Amount::from_f64(3.0).unwrap());
+
+        // Only held transactions can be resolved or charged back
+        assert_eq!(
+            Transaction::Resolve(TransactionMetadata(1, 1))
+                .apply(&mut state)
+                .unwrap_err(),
+            Error::Resolve
+        );
+        assert_eq!(
+            Transaction::ChargeBack(TransactionMetadata(3, 1))
+                .apply(&mut state)
+                .unwrap_err(),
+            Error::ChargeBack
+        );
+
+        Transaction::ChargeBack(TransactionMetadata(2, 1))
+            .apply(&mut state)
+            .unwrap();
+        assert!(state.held_transactions().is_empty());
+        assert_eq!(state.account.held(), Amount::ZERO);
+        assert_eq!(state.account.total(), Amount::from_f64(11.0).unwrap());
+    }
+
+    #[test]
+    fn test_monotonic_tx_ids() {
+        let deposit = |id| {
+            Transaction::Deposit(
+                TransactionMetadata(id, 1),
+                Amount::from_f64(1.0).unwrap(),
+                false,
+            )
+        };
+        let run = |monotonic_tx_ids| {
+            let mut state = State::with_config(
+                1,
+                Config {
+                    monotonic_tx_ids,
+                    ..Config::default()
+                },
+            );
+            deposit(1).apply(&mut state).unwrap();
+            deposit(5).apply(&mut state).unwrap();
+            let result = deposit(3).apply(&mut state);
+            (state, result)
+        };
+
+        let (state, result) = run(false);
+        result.unwrap();
+        assert_eq!(state.account.total(), Amount::from_f64(3.0).unwrap());
+
+        let (mut state, result) = run(true);
+        assert_eq!(result, Err(Error::OutOfOrderTransactionId));
+        assert_eq!(state.account.total(), Amount::from_f64(2.0).unwrap());
+        assert_eq!(
+            Transaction::Withdrawal(
+                TransactionMetadata(4, 1),
+                Amount::from_f64(1.0).unwrap(),
+                false
+            )
+            .apply(&mut state),
+            Err(Error::OutOfOrderTransactionId)
+        );
+        // Disputes refer to previous transactions, thus are not checked
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        deposit(6).apply(&mut state).unwrap();
+        // Rejected transactions do not move the highest id
+        assert_eq!(
+            Transaction::Withdrawal(
+                TransactionMetadata(10, 1),
+                Amount::from_f64(100.0).unwrap(),
+                false
+            )
+            .apply(&mut state),
+            Err(Error::Account(AccountError::InsufficientFunds))
+        );
+        deposit(7).apply(&mut state).unwrap();
+    }
+
+    #[test]
+    fn test_last_tx_id_is_highest() {
+        let mut state = State::new(1);
+        for id in [5, 3] {
+            Transaction::Deposit(
+                TransactionMetadata(id, 1),
+                Amount::from_f64(1.0).unwrap(),
+                false,
+            )
+            .apply(&mut state)
+            .unwrap();
+        }
+        assert_eq!(state.account.last_tx_id(), Some(5));
+    }
+
+    #[test]
+    fn test_max_open_disputes() {
+        let max = 3;
+        let mut state = State::with_config(
+            1,
+            Config {
+                max_open_disputes: Some(max),
+                ..Config::default()
+            },
+        );
+        for id in 0..=max as TransactionId {
+            Transaction::Deposit(
+                TransactionMetadata(id, 1),
+                Amount::from_f64(1.0).unwrap(),
+                false,
+            )
+            .apply(&mut state)
+            .unwrap();
+        }
+
+        for id in 0..max as TransactionId {
+            Transaction::Dispute(TransactionMetadata(id, 1), None)
+                .apply(&mut state)
+                .unwrap();
+        }
+        assert_eq!(
+            Transaction::Dispute(TransactionMetadata(max as TransactionId, 1), None)
+                .apply(&mut state),
+            Err(Error::TooManyOpenDisputes)
+        );
+        assert_eq!(state.held_transactions().len(), max);
+        assert_eq!(state.account.held(), Amount::from_f64(max as f64).unwrap());
 
-                match disputed_transaction {
-                    Self::Deposit(md, amount, is_disputed) => {
-                        if !*is_disputed {
-                            return Err(Error::Resolve);
-                        }
-                        state.account.resolve(*amount).map_err(Error::Account)?;
-                        state
-                            .transaction_history
-                            .insert(md.0, Self::Deposit(*md, *amount, false));
+        // Closing a dispute makes room for another one
+        Transaction::Resolve(TransactionMetadata(0, 1))
+            .apply(&mut state)
+            .unwrap();
+        Transaction::Dispute(TransactionMetadata(max as TransactionId, 1), None)
+            .apply(&mut state)
+            .unwrap();
+    }
 
-                        Ok(())
-                    }
-                    _ => Err(Error::Resolve),
-                }
-            }
-            _ => Err(Error::Resolve),
+    #[test]
+    fn test_max_history() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let config = Config {
+            max_history: Some(2),
+            ..Config::default()
+        };
+        let mut state = State::with_config(1, config);
+        let deposit = |id, n| Transaction::Deposit(TransactionMetadata(id, 1), amount(n), false);
+        deposit(1, 10).apply(&mut state).unwrap();
+        deposit(2, 1).apply(&mut state).unwrap();
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        for id in 3..=5 {
+            deposit(id, 1).apply(&mut state).unwrap();
         }
-    }
+        Transaction::Withdrawal(TransactionMetadata(6, 1), amount(2), false)
+            .apply(&mut state)
+            .unwrap();
 
-    fn charge_back(&self, state: &mut State) -> Result<()> {
-        match self {
-            Self::ChargeBack(md) => {
-                if state.account.id() != md.1 {
-                    return Err(Error::InvalidAccountId);
-                }
+        // The disputed deposit is kept beyond the cap, the oldest undisputed ones are dropped
+        let mut history = state
+            .transaction_history
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        history.sort_unstable();
+        assert_eq!(history, [1, 6]);
+        assert_eq!(
+            Transaction::Dispute(TransactionMetadata(2, 1), None).apply(&mut state),
+            Err(Error::Dispute)
+        );
+        assert_eq!(state.account.total(), amount(12));
+        assert_eq!(state.account.held(), amount(10));
+        assert_eq!(state.check_balance(false), None);
 
-                let disputed_transaction = state
-                    .transaction_history
-                    .get(&md.0)
-                    .ok_or(Error::ChargeBack)?;
+        // Once charged back, a deposit is dropped right away
+        Transaction::ChargeBack(TransactionMetadata(1, 1))
+            .apply(&mut state)
+            .unwrap();
+        assert!(!state.transaction_history.contains_key(&1));
+        assert!(state.account.locked());
+        assert_eq!(state.account.total(), amount(2));
+        assert_eq!(state.check_balance(false), None);
 
-                match disputed_transaction {
-                    Self::Deposit(md, amount, is_disputed) => {
-                        if !*is_disputed {
-                            return Err(Error::ChargeBack);
-                        }
-                        state.account.charge_back(*amount).map_err(Error::Account)?;
-                        state
-                            .transaction_history
-                            .insert(md.0, Self::Deposit(*md, *amount, false));
+        let snapshot = serde_json::to_string(&state.snapshot()).unwrap();
+        let restored = State::restore(serde_json::from_str(&snapshot).unwrap(), config).unwrap();
+        assert_eq!(restored.account, state.account);
+        assert_eq!(restored.settled, state.settled);
 
-                        Ok(())
-                    }
-                    _ => Err(Error::ChargeBack),
-                }
-            }
-            _ => Err(Error::ChargeBack),
+        // Histories are not capped by default
+        let mut state = State::new(1);
+        for id in 1..=5 {
+            deposit(id, 1).apply(&mut state).unwrap();
         }
+        assert_eq!(state.transaction_history.len(), 5);
+        assert!(state.order.is_empty());
     }
-}
 
-impl TryFrom<TransactionRecord> for Transaction {
-    type Error = crate::engine::state::Error;
+    #[test]
+    fn test_max_history_duplicates() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let config = Config {
+            max_history: Some(1),
+            ..Config::default()
+        };
+        let mut state = State::with_config(1, config);
+        let deposit = |id, n| Transaction::Deposit(TransactionMetadata(id, 1), amount(n), false);
+        deposit(1, 5).apply(&mut state).unwrap();
+        deposit(2, 1).apply(&mut state).unwrap();
+        deposit(3, 1).apply(&mut state).unwrap();
+        assert!(!state.transaction_history.contains_key(&1));
 
-    fn try_from(tx: TransactionRecord) -> Result<Self> {
-        match tx.transaction_type {
-            TransactionType::Deposit => Ok(Self::Deposit(
-                TransactionMetadata(tx.id, tx.client),
-                Amount::from_f64(tx.amount.ok_or(Error::Deposit)?).ok_or(Error::Deposit)?,
-                false,
-            )),
-            TransactionType::Withdrawal => Ok(Self::Withdrawal(
-                TransactionMetadata(tx.id, tx.client),
-                Amount::from_f64(tx.amount.ok_or(Error::Withdrawal)?).ok_or(Error::Withdrawal)?,
-            )),
-            TransactionType::Dispute => Ok(Self::Dispute(TransactionMetadata(tx.id, tx.client))),
-            TransactionType::Resolve => Ok(Self::Resolve(TransactionMetadata(tx.id, tx.client))),
-            TransactionType::ChargeBack => {
-                Ok(Self::ChargeBack(TransactionMetadata(tx.id, tx.client)))
-            }
-        }
+        // Evicted ids are still duplicates, whatever the policy but ignoring them
+        assert_eq!(
+            deposit(1, 5).apply(&mut state),
+            Err(Error::DuplicateTransactionId)
+        );
+        state.config.duplicate_policy = DuplicatePolicy::Overwrite;
+        assert_eq!(
+            deposit(1, 5).apply(&mut state),
+            Err(Error::DuplicateTransactionId)
+        );
+        state.config.duplicate_policy = DuplicatePolicy::Ignore;
+        deposit(1, 5).apply(&mut state).unwrap();
+        assert_eq!(state.account.total(), amount(7));
+
+        // Also once restored
+        let mut restored = State::restore(state.snapshot(), config).unwrap();
+        assert_eq!(
+            deposit(2, 1).apply(&mut restored),
+            Err(Error::DuplicateTransactionId)
+        );
+        assert_eq!(restored.account.total(), amount(7));
     }
-}
 
-impl std::fmt::Display for Transaction {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match *self {
-            Transaction::Deposit(md, amount, is_disputed) => write!(
-                f,
-                "Deposit id {} client {} amount {} is_disputed {}",
-                md.0, md.1, amount, is_disputed
-            ),
-            Transaction::Withdrawal(md, amount) => {
-                write!(f, "Withdraw id {} client {} amount {}", md.0, md.1, amount)
-            }
-            Transaction::Dispute(md) => write!(f, "Dispute id {}", md.0),
-            Transaction::Resolve(md) => write!(f, "Resolve id {}", md.0),
-            Transaction::ChargeBack(md) => write!(f, "Charge back id {}", md.0),
+    #[test]
+    fn test_max_history_pinned() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let config = Config {
+            max_history: Some(2),
+            ..Config::default()
+        };
+        let mut state = State::with_config(1, config);
+        let deposit = |id| Transaction::Deposit(TransactionMetadata(id, 1), amount(1), false);
+        deposit(1).apply(&mut state).unwrap();
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        for id in 2..=100 {
+            deposit(id).apply(&mut state).unwrap();
         }
+        // The disputed deposit is set aside rather than scanned past on each eviction
+        assert_eq!(state.pinned, HashSet::from([1]));
+        assert_eq!(state.order, [100]);
+        assert_eq!(state.transaction_history.len(), 2);
+
+        // Once resolved, it is the first one evicted
+        Transaction::Resolve(TransactionMetadata(1, 1))
+            .apply(&mut state)
+            .unwrap();
+        deposit(101).apply(&mut state).unwrap();
+        let mut history = state
+            .transaction_history
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        history.sort_unstable();
+        assert_eq!(history, [100, 101]);
+        assert!(state.pinned.is_empty());
+        assert_eq!(state.account.total(), amount(101));
+        assert_eq!(state.check_balance(false), None);
     }
-}
 
-/// State of all a client account.
-#[derive(Default)]
-pub struct State {
-    /// Account
-    pub account: Account,
-    /// History of deposits and withdrawals.
-    pub transaction_history: HashMap<TransactionId, Transaction>,
-}
+    #[test]
+    fn test_partial_dispute() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let dispute = |n| Transaction::Dispute(TransactionMetadata(1, 1), Some(amount(n)));
+        let config = Config {
+            partial_disputes: true,
+            ..Config::default()
+        };
+        let mut state = State::with_config(1, config);
+        Transaction::Deposit(TransactionMetadata(1, 1), amount(100), false)
+            .apply(&mut state)
+            .unwrap();
 
-impl State {
-    pub fn new(id: AccountId) -> Self {
-        Self {
-            account: Account::new(id),
-            transaction_history: HashMap::new(),
-        }
+        dispute(30).apply(&mut state).unwrap();
+        assert_eq!(state.account.available(), amount(70));
+        assert_eq!(state.account.held(), amount(30));
+        // Partial disputes add up to at most the deposit amount
+        assert_eq!(
+            dispute(71).apply(&mut state),
+            Err(Error::DisputeExceedsOriginal)
+        );
+        assert_eq!(dispute(0).apply(&mut state), Err(Error::Dispute));
+        assert_eq!(
+            Transaction::Dispute(TransactionMetadata(1, 1), None).apply(&mut state),
+            Err(Error::Dispute)
+        );
+        assert_eq!(state.account.held(), amount(30));
+        assert_eq!(state.check_balance(false), None);
+
+        // Saved along with the snapshot
+        let restored = State::restore(state.snapshot(), Config::default()).unwrap();
+        assert_eq!(restored.account, state.account);
+        assert_eq!(restored.held_transactions(), state.held_transactions());
+
+        Transaction::ChargeBack(TransactionMetadata(1, 1))
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(state.account.available(), amount(70));
+        assert_eq!(state.account.held(), Amount::ZERO);
+        assert_eq!(state.account.total(), amount(70));
+        assert!(state.account.locked());
+        assert_eq!(state.flows().unwrap().charged_back, amount(30));
+        assert_eq!(state.check_balance(false), None);
+        let restored = State::restore(state.snapshot(), Config::default()).unwrap();
+        assert_eq!(restored.account, state.account);
+
+        // Resolves release the disputed portion only
+        let mut state = State::with_config(1, config);
+        Transaction::Deposit(TransactionMetadata(1, 1), amount(100), false)
+            .apply(&mut state)
+            .unwrap();
+        dispute(30).apply(&mut state).unwrap();
+        dispute(20).apply(&mut state).unwrap();
+        assert_eq!(state.account.held(), amount(50));
+        Transaction::Resolve(TransactionMetadata(1, 1))
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(state.account.available(), amount(100));
+        assert_eq!(state.account.held(), Amount::ZERO);
+        assert_eq!(state.check_balance(false), None);
+
+        // Without partial disputes, the amount of dispute rows is ignored
+        let mut state = State::new(1);
+        Transaction::Deposit(TransactionMetadata(1, 1), amount(100), false)
+            .apply(&mut state)
+            .unwrap();
+        dispute(30).apply(&mut state).unwrap();
+        assert_eq!(state.account.held(), amount(100));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::model::account::Error as AccountError;
+    #[test]
+    fn test_transfer() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let transfer = |id, n| Transaction::Transfer(TransactionMetadata(id, 1), 2, amount(n));
+        let (mut sender, mut receiver) = (State::new(1), State::new(2));
+        Transaction::Deposit(TransactionMetadata(1, 1), amount(10), false)
+            .apply(&mut sender)
+            .unwrap();
+
+        transfer(2, 4).transfer(&mut sender, &mut receiver).unwrap();
+        assert_eq!(sender.account.available(), amount(6));
+        assert_eq!(receiver.account.available(), amount(4));
+        assert_eq!(receiver.account.total(), amount(4));
+        assert_eq!(sender.check_balance(false), None);
+        assert_eq!(receiver.check_balance(false), None);
+
+        // Insufficient funds leave both sides unchanged
+        let (before_sender, before_receiver) = (sender.account.clone(), receiver.account.clone());
+        assert_eq!(
+            transfer(3, 7).transfer(&mut sender, &mut receiver),
+            Err(Error::Account(AccountError::InsufficientFunds))
+        );
+        assert_eq!(sender.account, before_sender);
+        assert_eq!(receiver.account, before_receiver);
+        // The id of an applied transfer cannot be reused
+        assert_eq!(
+            transfer(2, 1).transfer(&mut sender, &mut receiver),
+            Err(Error::DuplicateTransactionId)
+        );
+        // Transfers need the states of both clients
+        assert_eq!(transfer(4, 1).apply(&mut sender), Err(Error::Transfer));
+        assert_eq!(
+            transfer(4, 1).transfer(&mut receiver, &mut sender),
+            Err(Error::InvalidAccountId)
+        );
+
+        // Neither side of a transfer can be disputed
+        assert_eq!(
+            Transaction::Dispute(TransactionMetadata(2, 2), None).apply(&mut receiver),
+            Err(Error::Dispute)
+        );
+        assert_eq!(
+            Transaction::Dispute(TransactionMetadata(2, 1), None).apply(&mut sender),
+            Err(Error::Dispute)
+        );
+        assert_eq!(sender.account.total(), amount(6));
+        assert_eq!(receiver.account.total(), amount(4));
+
+        // Both sides survive a snapshot
+        let restored = State::restore(sender.snapshot(), Config::default()).unwrap();
+        assert_eq!(restored.account, sender.account);
+        let restored = State::restore(receiver.snapshot(), Config::default()).unwrap();
+        assert_eq!(restored.account, receiver.account);
+    }
 
     #[test]
-    fn test_state_default() {
-        assert_eq!(State::default().account, Account::default());
-        assert!(State::default().transaction_history.is_empty());
+    fn test_accrue_interest() {
+        let amount = |n: &str| n.parse::<Amount>().unwrap();
+        let mut state = State::new(1);
+        Transaction::Deposit(TransactionMetadata(1, 1), amount("100"), false)
+            .apply(&mut state)
+            .unwrap();
+
+        assert_eq!(state.accrue_interest(2, amount("0.05")), Ok(amount("5")));
+        assert_eq!(state.account.available(), amount("105"));
+        assert_eq!(state.account.total(), amount("105"));
+        assert_eq!(state.flows().unwrap().interest, amount("5"));
+        assert_eq!(state.check_balance(false), None);
+        // Rounded to the output scale
+        assert_eq!(
+            state.accrue_interest(3, amount("0.0000001")),
+            Ok(amount("0"))
+        );
+        assert_eq!(
+            state.accrue_interest(3, amount("0.000011")),
+            Ok(amount("0.0012"))
+        );
+        assert_eq!(
+            state.accrue_interest(3, amount("0.01")),
+            Err(Error::DuplicateTransactionId)
+        );
+        assert_eq!(
+            state.accrue_interest(4, amount("-0.01")),
+            Err(Error::Interest)
+        );
+        // Interest cannot be disputed
+        assert_eq!(
+            Transaction::Dispute(TransactionMetadata(2, 1), None).apply(&mut state),
+            Err(Error::Dispute)
+        );
+
+        // Saved along with the snapshot
+        let restored = State::restore(state.snapshot(), Config::default()).unwrap();
+        assert_eq!(restored.account, state.account);
+
+        // Locked accounts are skipped
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        Transaction::ChargeBack(TransactionMetadata(1, 1))
+            .apply(&mut state)
+            .unwrap();
+        let account = state.account.clone();
+        assert_eq!(state.accrue_interest(5, amount("0.05")), Ok(Amount::ZERO));
+        assert_eq!(state.account, account);
     }
 
     #[test]
-    fn test_transaction_tryfrom() {
+    fn test_min_balance() {
+        let config = Config {
+            min_balance: Some(Amount::from_int(10).unwrap()),
+            ..Config::default()
+        };
+        let mut state = State::with_config(1, config);
+        Transaction::Deposit(
+            TransactionMetadata(1, 1),
+            Amount::from_int(50).unwrap(),
+            false,
+        )
+        .apply(&mut state)
+        .unwrap();
         assert_eq!(
-            Transaction::try_from(TransactionRecord {
-                transaction_type: TransactionType::Deposit,
-                client: 1,
-                id: 2,
-                amount: Some(1.0)
-            })
-            .unwrap(),
-            Transaction::Deposit(
+            Transaction::Withdrawal(
                 TransactionMetadata(2, 1),
-                Amount::from_f64(1.0).unwrap(),
+                Amount::from_int(45).unwrap(),
                 false
             )
+            .apply(&mut state),
+            Err(Error::Account(
+                crate::model::account::Error::BelowMinimumBalance
+            ))
         );
-        assert_eq!(
-            Transaction::try_from(TransactionRecord {
-                transaction_type: TransactionType::Withdrawal,
-                client: 1,
-                id: 2,
-                amount: Some(1.0)
-            })
-            .unwrap(),
-            Transaction::Withdrawal(TransactionMetadata(2, 1), Amount::from_f64(1.0).unwrap())
+        Transaction::Withdrawal(
+            TransactionMetadata(3, 1),
+            Amount::from_int(40).unwrap(),
+            false,
+        )
+        .apply(&mut state)
+        .unwrap();
+
+        // The floor survives a restore
+        let restored = State::restore(state.snapshot(), config).unwrap();
+        assert_eq!(restored.account.min_balance(), config.min_balance);
+        assert_eq!(restored.account.available(), Amount::from_int(10).unwrap());
+    }
+
+    #[test]
+    fn test_held_exceeds_total() {
+        let mut state = State::new(1);
+        Transaction::Deposit(
+            TransactionMetadata(1, 1),
+            Amount::from_f64(10.0).unwrap(),
+            false,
+        )
+        .apply(&mut state)
+        .unwrap();
+
+        // Corrupt the balances, so the available funds cover a dispute the total funds do not
+        let corrupted = Account::with_balances(
+            1,
+            Amount::from_f64(10.0).unwrap(),
+            Amount::ZERO,
+            Amount::from_f64(5.0).unwrap(),
+            false,
         );
+        state.account = corrupted.clone();
+
         assert_eq!(
-            Transaction::try_from(TransactionRecord {
-                transaction_type: TransactionType::Dispute,
-                client: 1,
-                id: 2,
-                amount: None
-            })
-            .unwrap(),
-            Transaction::Dispute(TransactionMetadata(2, 1))
+            Transaction::Dispute(TransactionMetadata(1, 1), None).apply(&mut state),
+            Err(Error::InvalidDispute)
+        );
+        assert_eq!(state.account, corrupted);
+        assert!(state.held_transactions().is_empty());
+    }
+
+    #[test]
+    fn test_round_disputes() {
+        let mut state = State::new(1);
+        let deposit = Transaction::Deposit(
+            TransactionMetadata(1, 1),
+            Amount::from_f64(1.123456).unwrap(),
+            false,
+        );
+        deposit.apply(&mut state).unwrap();
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(state.account.held(), Amount::from_f64(1.123456).unwrap());
+        assert_eq!(state.account.available(), Amount::ZERO);
+
+        let mut state = State::with_config(
+            1,
+            Config {
+                round_disputes: true,
+                ..Config::default()
+            },
         );
+        deposit.apply(&mut state).unwrap();
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(state.account.held(), Amount::from_f64(1.1234).unwrap());
         assert_eq!(
-            Transaction::try_from(TransactionRecord {
-                transaction_type: TransactionType::Resolve,
-                client: 1,
-                id: 2,
-                amount: None
-            })
-            .unwrap(),
-            Transaction::Resolve(TransactionMetadata(2, 1))
+            state.account.available(),
+            Amount::from_f64(0.000056).unwrap()
         );
+        assert_eq!(state.account.total(), Amount::from_f64(1.123456).unwrap());
+        Transaction::Resolve(TransactionMetadata(1, 1))
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(state.account.held(), Amount::ZERO);
         assert_eq!(
-            Transaction::try_from(TransactionRecord {
-                transaction_type: TransactionType::ChargeBack,
-                client: 1,
-                id: 2,
-                amount: None
-            })
-            .unwrap(),
-            Transaction::ChargeBack(TransactionMetadata(2, 1))
+            state.account.available(),
+            Amount::from_f64(1.123456).unwrap()
         );
-        assert!(Transaction::try_from(TransactionRecord {
-            transaction_type: TransactionType::Deposit,
-            client: 1,
-            id: 2,
-            amount: None
-        })
-        .is_err());
-        assert!(Transaction::try_from(TransactionRecord {
-            transaction_type: TransactionType::Withdrawal,
-            client: 1,
-            id: 2,
-            amount: None
-        })
-        .is_err());
+
+        // Truncated to the output scale of the config
+        let mut state = State::with_config(
+            1,
+            Config {
+                scale: 2,
+                round_disputes: true,
+                ..Config::default()
+            },
+        );
+        deposit.apply(&mut state).unwrap();
+        Transaction::Dispute(TransactionMetadata(1, 1), None)
+            .apply(&mut state)
+            .unwrap();
+        assert_eq!(state.account.held(), Amount::from_f64(1.12).unwrap());
     }
 
     #[test]
@@ -359,7 +2850,7 @@ mod tests {
         );
 
         // Same transaction id withdrawal test-case
-        let withdrawal = Transaction::Withdrawal(TransactionMetadata(3, 1), Amount::MAX);
+        let withdrawal = Transaction::Withdrawal(TransactionMetadata(3, 1), Amount::MAX, false);
         withdrawal.apply(&mut state).unwrap();
         assert_eq!(
             withdrawal.apply(&mut state).err().unwrap(),
@@ -367,7 +2858,7 @@ mod tests {
         );
 
         // Withdrawal insufficient funds test-case
-        let withdrawal = Transaction::Withdrawal(TransactionMetadata(4, 1), Amount::MAX);
+        let withdrawal = Transaction::Withdrawal(TransactionMetadata(4, 1), Amount::MAX, false);
         assert_eq!(
             withdrawal.apply(&mut state).err().unwrap(),
             Error::Account(AccountError::InsufficientFunds)
@@ -380,7 +2871,7 @@ mod tests {
             false,
         );
         deposit.apply(&mut state).unwrap();
-        let dispute = Transaction::Dispute(TransactionMetadata(5, 1));
+        let dispute = Transaction::Dispute(TransactionMetadata(5, 1), None);
         dispute.apply(&mut state).unwrap();
         assert_eq!(dispute.apply(&mut state).err().unwrap(), Error::Dispute);
 
@@ -396,7 +2887,7 @@ mod tests {
             false,
         );
         deposit.apply(&mut state).unwrap();
-        let dispute = Transaction::Dispute(TransactionMetadata(6, 1));
+        let dispute = Transaction::Dispute(TransactionMetadata(6, 1), None);
         dispute.apply(&mut state).unwrap();
         let charge_back = Transaction::ChargeBack(TransactionMetadata(6, 1));
         charge_back.apply(&mut state).unwrap();
@@ -413,7 +2904,7 @@ mod tests {
             false,
         );
         deposit.apply(&mut state).unwrap();
-        let dispute = Transaction::Dispute(TransactionMetadata(1234, 2));
+        let dispute = Transaction::Dispute(TransactionMetadata(1234, 2), None);
         assert_eq!(dispute.apply(&mut state).err().unwrap(), Error::Dispute);
         let resolve = Transaction::Resolve(TransactionMetadata(1234, 2));
         assert_eq!(resolve.apply(&mut state).err().unwrap(), Error::Resolve);
@@ -439,7 +2930,7 @@ mod tests {
             false,
         );
         deposit.apply(&mut state).unwrap();
-        let dispute = Transaction::Dispute(TransactionMetadata(8, 2));
+        let dispute = Transaction::Dispute(TransactionMetadata(8, 2), None);
         dispute.apply(&mut state).unwrap();
 
         let deposit = Transaction::Deposit(
@@ -455,7 +2946,7 @@ mod tests {
             false,
         );
         deposit.apply(&mut state).unwrap();
-        let dispute = Transaction::Dispute(TransactionMetadata(10, 2));
+        let dispute = Transaction::Dispute(TransactionMetadata(10, 2), None);
         dispute.apply(&mut state).unwrap();
 
         let deposit = Transaction::Deposit(
@@ -464,7 +2955,7 @@ mod tests {
             false,
         );
         deposit.apply(&mut state).unwrap();
-        let dispute = Transaction::Dispute(TransactionMetadata(11, 2));
+        let dispute = Transaction::Dispute(TransactionMetadata(11, 2), None);
         dispute.apply(&mut state).unwrap();
 
         let charge_back = Transaction::ChargeBack(TransactionMetadata(8, 2));
@@ -479,27 +2970,30 @@ mod tests {
             deposit.apply(&mut state).err().unwrap(),
             Error::Account(AccountError::Locked)
         );
-        let dispute = Transaction::Dispute(TransactionMetadata(9, 2));
+        let dispute = Transaction::Dispute(TransactionMetadata(9, 2), None);
         assert_eq!(
             dispute.apply(&mut state).err().unwrap(),
             Error::Account(AccountError::Locked)
         );
-        let resolve = Transaction::Resolve(TransactionMetadata(10, 2));
-        assert_eq!(
-            resolve.apply(&mut state).err().unwrap(),
-            Error::Account(AccountError::Locked)
+        let withdrawal = Transaction::Withdrawal(
+            TransactionMetadata(12, 2),
+            Amount::from_f64(1.0).unwrap(),
+            false,
         );
-        let withdrawal =
-            Transaction::Withdrawal(TransactionMetadata(12, 2), Amount::from_f64(1.0).unwrap());
         assert_eq!(
             withdrawal.apply(&mut state).err().unwrap(),
             Error::Account(AccountError::Locked)
         );
+        // Disputes opened before the account got locked can still be closed
+        let resolve = Transaction::Resolve(TransactionMetadata(10, 2));
+        resolve.apply(&mut state).unwrap();
         let charge_back = Transaction::ChargeBack(TransactionMetadata(11, 2));
-        assert_eq!(
-            charge_back.apply(&mut state).err().unwrap(),
-            Error::Account(AccountError::Locked)
-        );
+        charge_back.apply(&mut state).unwrap();
+        assert_eq!(state.account.held(), Amount::ZERO);
+        assert_eq!(state.account.available(), state.account.total());
+        assert!(state.account.locked());
+        let resolve = Transaction::Resolve(TransactionMetadata(10, 2));
+        assert_eq!(resolve.apply(&mut state).err().unwrap(), Error::Resolve);
 
         // Dispute/Resolve/ChargeBack on withdrawal
         let mut state = State::new(3);
@@ -509,18 +3003,45 @@ mod tests {
             false,
         );
         deposit.apply(&mut state).unwrap();
-        let withdrawal =
-            Transaction::Withdrawal(TransactionMetadata(2, 3), Amount::from_f64(1.0).unwrap());
+        let withdrawal = Transaction::Withdrawal(
+            TransactionMetadata(2, 3),
+            Amount::from_f64(1.0).unwrap(),
+            false,
+        );
         withdrawal.apply(&mut state).unwrap();
-        let dispute = Transaction::Dispute(TransactionMetadata(2, 3));
+        let one = Amount::from_f64(1.0).unwrap();
+        // Disputing holds the withdrawn funds
+        let dispute = Transaction::Dispute(TransactionMetadata(2, 3), None);
+        dispute.apply(&mut state).unwrap();
+        assert_eq!(state.account.available(), Amount::ZERO);
+        assert_eq!(state.account.held(), one);
+        assert_eq!(state.account.total(), one);
         assert_eq!(dispute.apply(&mut state).err().unwrap(), Error::Dispute);
+        // Resolving lets the withdrawal stand
         let resolve = Transaction::Resolve(TransactionMetadata(2, 3));
+        resolve.apply(&mut state).unwrap();
+        assert_eq!(state.account.held(), Amount::ZERO);
+        assert_eq!(state.account.total(), Amount::ZERO);
         assert_eq!(resolve.apply(&mut state).err().unwrap(), Error::Resolve);
+        // Charging back reverses the withdrawal and locks the account
         let charge_back = Transaction::ChargeBack(TransactionMetadata(2, 3));
         assert_eq!(
             charge_back.apply(&mut state).err().unwrap(),
             Error::ChargeBack
         );
+        dispute.apply(&mut state).unwrap();
+        charge_back.apply(&mut state).unwrap();
+        assert_eq!(state.account.available(), one);
+        assert_eq!(state.account.held(), Amount::ZERO);
+        assert_eq!(state.account.total(), one);
+        assert!(state.account.locked());
+        assert_eq!(
+            charge_back.apply(&mut state).err().unwrap(),
+            Error::ChargeBack
+        );
+        // A charged back withdrawal cannot be disputed again
+        assert_eq!(dispute.apply(&mut state).err().unwrap(), Error::Dispute);
+        assert_eq!(state.check_balance(false), None);
 
         // Deposit/Withdrawal invalid amount
         let mut state = State::new(4);
@@ -533,8 +3054,11 @@ mod tests {
             deposit.apply(&mut state).err().unwrap(),
             Error::Account(AccountError::InvalidInput)
         );
-        let withdrawal =
-            Transaction::Withdrawal(TransactionMetadata(2, 4), Amount::from_f64(-1.0).unwrap());
+        let withdrawal = Transaction::Withdrawal(
+            TransactionMetadata(2, 4),
+            Amount::from_f64(-1.0).unwrap(),
+            false,
+        );
         assert_eq!(
             withdrawal.apply(&mut state).err().unwrap(),
             Error::Account(AccountError::InvalidInput)
@@ -554,12 +3078,13 @@ mod tests {
         let withdrawal = Transaction::Withdrawal(
             TransactionMetadata(2, 1234),
             Amount::from_f64(-1.0).unwrap(),
+            false,
         );
         assert_eq!(
             withdrawal.apply(&mut state).err().unwrap(),
             Error::InvalidAccountId
         );
-        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234));
+        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234), None);
         assert_eq!(
             dispute.apply(&mut state).err().unwrap(),
             Error::InvalidAccountId
@@ -578,13 +3103,16 @@ mod tests {
         // Test private functions
         let mut state = State::new(6);
         // Deposit fn called on non-deposit transactions
-        let withdrawal =
-            Transaction::Withdrawal(TransactionMetadata(1, 6), Amount::from_f64(1.0).unwrap());
+        let withdrawal = Transaction::Withdrawal(
+            TransactionMetadata(1, 6),
+            Amount::from_f64(1.0).unwrap(),
+            false,
+        );
         assert_eq!(
             withdrawal.deposit(&mut state).err().unwrap(),
             Error::Deposit
         );
-        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234));
+        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234), None);
         assert_eq!(dispute.deposit(&mut state).err().unwrap(), Error::Deposit);
         let resolve = Transaction::Resolve(TransactionMetadata(1, 1234));
         assert_eq!(resolve.deposit(&mut state).err().unwrap(), Error::Deposit);
@@ -603,7 +3131,7 @@ mod tests {
             deposit.withdrawal(&mut state).err().unwrap(),
             Error::Withdrawal
         );
-        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234));
+        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234), None);
         assert_eq!(
             dispute.withdrawal(&mut state).err().unwrap(),
             Error::Withdrawal
@@ -625,8 +3153,11 @@ mod tests {
             false,
         );
         assert_eq!(deposit.dispute(&mut state).err().unwrap(), Error::Dispute);
-        let withdrawal =
-            Transaction::Withdrawal(TransactionMetadata(1, 1234), Amount::from_f64(1.0).unwrap());
+        let withdrawal = Transaction::Withdrawal(
+            TransactionMetadata(1, 1234),
+            Amount::from_f64(1.0).unwrap(),
+            false,
+        );
         assert_eq!(
             withdrawal.dispute(&mut state).err().unwrap(),
             Error::Dispute
@@ -645,13 +3176,16 @@ mod tests {
             false,
         );
         assert_eq!(deposit.resolve(&mut state).err().unwrap(), Error::Resolve);
-        let withdrawal =
-            Transaction::Withdrawal(TransactionMetadata(1, 1234), Amount::from_f64(1.0).unwrap());
+        let withdrawal = Transaction::Withdrawal(
+            TransactionMetadata(1, 1234),
+            Amount::from_f64(1.0).unwrap(),
+            false,
+        );
         assert_eq!(
             withdrawal.resolve(&mut state).err().unwrap(),
             Error::Resolve
         );
-        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234));
+        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234), None);
         assert_eq!(dispute.resolve(&mut state).err().unwrap(), Error::Resolve);
         let charge_back = Transaction::ChargeBack(TransactionMetadata(1, 1234));
         assert_eq!(
@@ -668,13 +3202,16 @@ mod tests {
             deposit.charge_back(&mut state).err().unwrap(),
             Error::ChargeBack
         );
-        let withdrawal =
-            Transaction::Withdrawal(TransactionMetadata(1, 1234), Amount::from_f64(1.0).unwrap());
+        let withdrawal = Transaction::Withdrawal(
+            TransactionMetadata(1, 1234),
+            Amount::from_f64(1.0).unwrap(),
+            false,
+        );
         assert_eq!(
             withdrawal.charge_back(&mut state).err().unwrap(),
             Error::ChargeBack
         );
-        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234));
+        let dispute = Transaction::Dispute(TransactionMetadata(1, 1234), None);
         assert_eq!(
             dispute.charge_back(&mut state).err().unwrap(),
             Error::ChargeBack
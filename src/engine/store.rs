@@ -0,0 +1,282 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use dashmap::DashMap;
+use std::collections::HashMap;
+
+use crate::engine::state::{Snapshot, State};
+use crate::model::account::{Account, Id as AccountId};
+
+/// Storage of client account states, shared by the listener and all handlers.
+///
+/// Implementations are expected to lock as little as possible, as every transaction of every
+/// client goes through the store.
+pub trait Store: Send + Sync + 'static {
+    /// Insert `state` for client `id`, unless the client already has one.
+    ///
+    /// Returns whether `state` was inserted.
+    fn insert(&self, id: AccountId, state: State) -> bool;
+
+    /// Run `f` on the state of client `id`, possibly on another thread.
+    ///
+    /// Returns `None` if the client has no state.
+    fn update<R: Send + 'static>(
+        &self,
+        id: AccountId,
+        f: impl FnOnce(&mut State) -> R + Send + 'static,
+    ) -> Option<R>;
+
+    /// Ids of all clients, in no particular order.
+    fn clients(&self) -> Vec<AccountId>;
+
+    /// Copy of the accounts of all clients, in no particular order.
+    fn accounts(&self) -> Vec<Account>;
+
+    /// Snapshots of the states of all clients, in no particular order.
+    fn snapshots(&self) -> Vec<Snapshot>;
+}
+
+impl Store for DashMap<AccountId, State> {
+    fn insert(&self, id: AccountId, state: State) -> bool {
+        match self.entry(id) {
+            dashmap::mapref::entry::Entry::Vacant(e) => {
+                e.insert(state);
+                true
+            }
+            dashmap::mapref::entry::Entry::Occupied(_) => false,
+        }
+    }
+
+    fn update<R: Send + 'static>(
+        &self,
+        id: AccountId,
+        f: impl FnOnce(&mut State) -> R + Send + 'static,
+    ) -> Option<R> {
+        self.get_mut(&id).map(|mut state| f(state.value_mut()))
+    }
+
+    fn clients(&self) -> Vec<AccountId> {
+        self.iter().map(|r| *r.key()).collect()
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.iter().map(|r| r.value().account.clone()).collect()
+    }
+
+    fn snapshots(&self) -> Vec<Snapshot> {
+        self.iter().map(|r| r.value().snapshot()).collect()
+    }
+}
+
+/// Implementations of `Store` the engine can run with.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Kind {
+    /// `DashMap`, shared by the listener and all handlers.
+    #[default]
+    Dashmap,
+    /// `ShardedStore`, with a worker per available core.
+    Sharded,
+}
+
+/// States of the clients assigned to a worker.
+type Shard = HashMap<AccountId, State>;
+
+/// Work run by a worker on its shard.
+type Job = Box<dyn FnOnce(&mut Shard) + Send>;
+
+/// Store made of a fixed number of workers, each a thread owning the `HashMap` of the clients
+/// assigned to it by id.
+///
+/// Shards are never shared: operations are sent to the worker owning the client, which runs them
+/// while the caller waits for the outcome. Alternative to `DashMap`, selected with `--store`.
+pub struct ShardedStore {
+    workers: Vec<std::sync::mpsc::Sender<Job>>,
+}
+
+impl ShardedStore {
+    /// Create a store with `workers` workers.
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: (0..workers.max(1))
+                .map(|worker| {
+                    let (tx, rx) = std::sync::mpsc::channel::<Job>();
+                    std::thread::Builder::new()
+                        .name(format!("store-{worker}"))
+                        .spawn(move || {
+                            let mut shard = Shard::new();
+                            for job in rx {
+                                job(&mut shard);
+                            }
+                        })
+                        .expect("unable to spawn store worker");
+                    tx
+                })
+                .collect(),
+        }
+    }
+
+    /// Send `job` to `worker` without waiting for it to run.
+    fn send<R: Send + 'static>(
+        &self,
+        worker: usize,
+        job: impl FnOnce(&mut Shard) -> R + Send + 'static,
+    ) -> std::sync::mpsc::Receiver<R> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        self.workers[worker]
+            .send(Box::new(move |shard| {
+                let _ = tx.send(job(shard));
+            }))
+            .expect("store worker stopped");
+        rx
+    }
+
+    /// Run `job` on the worker owning client `id`.
+    fn run<R: Send + 'static>(
+        &self,
+        id: AccountId,
+        job: impl FnOnce(&mut Shard) -> R + Send + 'static,
+    ) -> R {
+        self.send(id as usize % self.workers.len(), job)
+            .recv()
+            .expect("store worker stopped")
+    }
+
+    /// Run `job` on every worker at once, collecting what they return.
+    fn run_all<T: Send + 'static>(&self, job: fn(&Shard) -> Vec<T>) -> Vec<T> {
+        let pending: Vec<_> = (0..self.workers.len())
+            .map(|worker| self.send(worker, move |shard| job(shard)))
+            .collect();
+        pending
+            .into_iter()
+            .flat_map(|rx| rx.recv().expect("store worker stopped"))
+            .collect()
+    }
+}
+
+impl Default for ShardedStore {
+    fn default() -> Self {
+        Self::new(
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+        )
+    }
+}
+
+impl Store for ShardedStore {
+    fn insert(&self, id: AccountId, state: State) -> bool {
+        self.run(id, move |shard| match shard.entry(id) {
+            std::collections::hash_map::Entry::Vacant(e) => {
+                e.insert(state);
+                true
+            }
+            std::collections::hash_map::Entry::Occupied(_) => false,
+        })
+    }
+
+    fn update<R: Send + 'static>(
+        &self,
+        id: AccountId,
+        f: impl FnOnce(&mut State) -> R + Send + 'static,
+    ) -> Option<R> {
+        self.run(id, move |shard| shard.get_mut(&id).map(f))
+    }
+
+    fn clients(&self) -> Vec<AccountId> {
+        self.run_all(|shard| shard.keys().copied().collect())
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.run_all(|shard| shard.values().map(|state| state.account.clone()).collect())
+    }
+
+    fn snapshots(&self) -> Vec<Snapshot> {
+        self.run_all(|shard| shard.values().map(State::snapshot).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::server::{Command, Listener};
+    use crate::engine::state::Config;
+    use crate::model::amount::Amount;
+    use crate::model::transaction::{TransactionRecord, TransactionType};
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_util::sync::CancellationToken;
+
+    fn test_store<S: Store>(store: S) {
+        assert!(store.insert(1, State::new(1)));
+        assert!(store.insert(2, State::new(2)));
+        assert!(!store.insert(1, State::new(1)));
+
+        store
+            .update(1, |state| state.account.deposit(Amount::MAX))
+            .unwrap()
+            .unwrap();
+        assert!(store.update(3, |_| ()).is_none());
+
+        let mut clients = store.clients();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![1, 2]);
+
+        let mut accounts = store.accounts();
+        accounts.sort_unstable_by_key(|account| account.id());
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].total(), Amount::MAX);
+        assert_eq!(accounts[1].total(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_dashmap_store() {
+        test_store(DashMap::new());
+    }
+
+    #[test]
+    fn test_sharded_store() {
+        test_store(ShardedStore::new(3));
+        test_store(ShardedStore::new(0));
+    }
+
+    /// Run `records` through a listener backed by `store` and return the accounts state.
+    async fn run<S: Store>(store: S, records: &[TransactionRecord]) -> Vec<Account> {
+        let (tx, rx) = mpsc::channel(32);
+        let mut listener = Listener::with_store(rx, Config::default(), Arc::new(store));
+        tokio::spawn(async move { listener.run(CancellationToken::new()).await });
+
+        for record in records {
+            tx.send(Command::ExecuteTransaction(record.clone()))
+                .await
+                .unwrap();
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    /// Deposits spread over `clients` clients, in round-robin.
+    fn fan_out(transactions: u32, clients: u16) -> Vec<TransactionRecord> {
+        (0..transactions)
+            .map(|id| TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client: (id % clients as u32) as u16,
+                id,
+                amount: Amount::from_f64(1.0).map(Ok),
+                tag: None,
+                counterparty: None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_stores_agree() {
+        let records = fan_out(1000, 100);
+
+        let dashmap = run(DashMap::new(), &records).await;
+        let sharded = run(ShardedStore::new(4), &records).await;
+
+        assert_eq!(dashmap.len(), 100);
+        assert_eq!(dashmap, sharded);
+    }
+}
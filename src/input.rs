@@ -0,0 +1,914 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use async_compression::tokio::bufread::GzipDecoder;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::engine::server::Command;
+use crate::model::amount::{Amount, ParseAmountError};
+use crate::model::transaction::{TransactionRecord, TransactionType};
+
+/// Error conditions that may arise when reading transactions.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Failure to read from a file or stream.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Failure to open a transactions file.
+    #[error("unable to open {}: {source}", path.display())]
+    Open {
+        /// Path of the file.
+        path: std::path::PathBuf,
+        /// Cause of the failure.
+        source: std::io::Error,
+    },
+    /// Failure to read or deserialize CSV records.
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv_async::Error),
+    /// Failure to deserialize JSON.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Malformed amount of a CSV record.
+    #[error("invalid amount {amount:?} on line {line}: {source}")]
+    Amount {
+        /// Line of the record.
+        line: u64,
+        /// Amount as read.
+        amount: String,
+        /// Cause of the failure.
+        source: crate::model::amount::ParseAmountError,
+    },
+    /// The engine stopped accepting transactions.
+    #[error("engine stopped")]
+    EngineStopped,
+    /// The engine rejected a transaction, see `execute_all`.
+    #[error("transaction {key} rejected: {}", source.reason())]
+    Rejected {
+        /// Transaction rejected.
+        key: crate::model::transaction::TxKey,
+        /// Reason of the rejection.
+        source: crate::engine::state::Error,
+    },
+}
+
+/// Result of input operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Path standing for the standard input.
+pub const STDIN: &str = "-";
+
+/// Transactions file, either read as is or decompressed as it is read.
+pub enum InputFile {
+    /// Uncompressed file.
+    Plain(tokio::fs::File),
+    /// Uncompressed standard input.
+    Stdin(tokio::io::Stdin),
+    /// File or standard input hashed as it is read, before any decompression.
+    Hashed(Box<crate::manifest::HashReader<InputFile>>),
+    /// Gzip file or standard input, decompressed as it is read.
+    Gzip(Box<GzipDecoder<tokio::io::BufReader<InputFile>>>),
+}
+
+impl InputFile {
+    /// Open the transactions file at `path`, or the standard input if `path` is `STDIN`,
+    /// decompressing it if `gzip` is set or its extension is `.gz`.
+    ///
+    /// Compressed files are decompressed as they are read, one or more gzip members after the
+    /// other, thus memory use does not depend on their size.
+    pub async fn open(path: &Path, gzip: bool) -> Result<Self> {
+        Self::open_with(path, gzip, None).await
+    }
+
+    /// Same as `open`, hashing the content of the file, before any decompression, into `digest`
+    /// as it is read, so that it is only read once.
+    pub async fn open_hashed(
+        path: &Path,
+        gzip: bool,
+        digest: crate::manifest::Digest,
+    ) -> Result<Self> {
+        Self::open_with(path, gzip, Some(digest)).await
+    }
+
+    /// Open the file at `path`, see `open`, hashing it into `digest` if given.
+    async fn open_with(
+        path: &Path,
+        gzip: bool,
+        digest: Option<crate::manifest::Digest>,
+    ) -> Result<Self> {
+        let mut file = if path == Path::new(STDIN) {
+            Self::Stdin(tokio::io::stdin())
+        } else {
+            Self::Plain(
+                tokio::fs::File::open(path)
+                    .await
+                    .map_err(|source| Error::Open {
+                        path: path.to_path_buf(),
+                        source,
+                    })?,
+            )
+        };
+        if let Some(digest) = digest {
+            file = Self::Hashed(Box::new(crate::manifest::HashReader::new(file, digest)));
+        }
+        if !(gzip || path.extension().is_some_and(|extension| extension == "gz")) {
+            return Ok(file);
+        }
+
+        let mut decoder = GzipDecoder::new(tokio::io::BufReader::new(file));
+        decoder.multiple_members(true);
+        Ok(Self::Gzip(Box::new(decoder)))
+    }
+}
+
+impl AsyncRead for InputFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(file) => Pin::new(file).poll_read(cx, buf),
+            Self::Stdin(stdin) => Pin::new(stdin).poll_read(cx, buf),
+            Self::Hashed(reader) => Pin::new(reader).poll_read(cx, buf),
+            Self::Gzip(decoder) => Pin::new(decoder).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Origin of the transactions executed by the engine.
+///
+/// Records are yielded in the order they must be executed in, at least for each client.
+pub trait InputSource {
+    /// Next transaction record, `None` once the source is exhausted.
+    fn next_record(
+        &mut self,
+    ) -> impl std::future::Future<Output = Option<Result<TransactionRecord>>> + Send;
+}
+
+/// Number of records left to read, shared by all the sources of a run, so that a limit applies to
+/// all of them together rather than to each one.
+///
+/// Clones share the same budget. Unlimited if created without a maximum.
+#[derive(Clone, Debug, Default)]
+pub struct Limit(Option<std::sync::Arc<std::sync::atomic::AtomicUsize>>);
+
+impl Limit {
+    /// Create a budget of `max` records, unlimited if `None`.
+    pub fn new(max: Option<usize>) -> Self {
+        Self(max.map(|max| std::sync::Arc::new(max.into())))
+    }
+
+    /// Whether no record is left to read.
+    pub fn is_exhausted(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|left| left.load(std::sync::atomic::Ordering::Acquire) == 0)
+    }
+
+    /// Take one record out of the budget, returning whether there was any left.
+    pub fn take(&self) -> bool {
+        self.0.as_ref().is_none_or(|left| {
+            left.fetch_update(
+                std::sync::atomic::Ordering::AcqRel,
+                std::sync::atomic::Ordering::Acquire,
+                |left| left.checked_sub(1),
+            )
+            .is_ok()
+        })
+    }
+}
+
+/// Next record of `source` taken out of `limit`, `None` once either is exhausted.
+///
+/// Concurrent readers sharing `limit` may read a record past it, which is then dropped.
+pub async fn next_within<S: InputSource>(
+    source: &mut S,
+    limit: &Limit,
+) -> Option<Result<TransactionRecord>> {
+    if limit.is_exhausted() {
+        return None;
+    }
+    let record = source.next_record().await?;
+
+    limit.take().then_some(record)
+}
+
+/// Send the records of `source` to the engine one by one, stopping once `limit` is exhausted.
+///
+/// Returns the number of records sent.
+pub async fn send_all<S: InputSource>(
+    source: &mut S,
+    tx: &mpsc::Sender<Command>,
+    limit: &Limit,
+) -> Result<usize> {
+    let mut count = 0;
+    while let Some(record) = next_within(source, limit).await {
+        tx.send(Command::ExecuteTransaction(record?))
+            .await
+            .map_err(|_| Error::EngineStopped)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Execute the records of `source` through `engine` one at a time, waiting for each of them to be
+/// applied or rejected, stopping once `limit` is exhausted.
+///
+/// Fails on the first rejected record, leaving the following ones unread. Returns the number of
+/// records executed.
+pub async fn execute_all<S: InputSource>(
+    source: &mut S,
+    engine: &crate::engine::Engine,
+    limit: &Limit,
+) -> Result<usize> {
+    let mut count = 0;
+    while let Some(record) = next_within(source, limit).await {
+        let record = record?;
+        let key = record.key();
+        match engine.process_result(record).await {
+            Ok(()) => count += 1,
+            Err(source) => return Err(Error::Rejected { key, source }),
+        }
+    }
+
+    Ok(count)
+}
+
+/// Character separating the integer part of an amount from its fractional part.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DecimalSeparator {
+    /// `12.34`
+    #[default]
+    Dot,
+    /// `12,34`, as found in some European exports; use a different field delimiter, e.g. `;`
+    Comma,
+}
+
+impl DecimalSeparator {
+    /// The separator itself.
+    pub fn char(self) -> char {
+        match self {
+            Self::Dot => '.',
+            Self::Comma => ',',
+        }
+    }
+}
+
+/// Column names of the transaction fields, for files not using the default ones.
+///
+/// Fields which are not mapped keep their default column name.
+#[derive(serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    /// Column of the transaction type.
+    #[serde(rename = "type")]
+    pub transaction_type: Option<String>,
+    /// Column of the client id.
+    pub client: Option<String>,
+    /// Column of the transaction id.
+    pub tx: Option<String>,
+    /// Column of the amount.
+    pub amount: Option<String>,
+}
+
+impl Schema {
+    /// Rename the mapped columns of `headers` to the name of their transaction field.
+    fn apply(&self, headers: &csv_async::StringRecord) -> csv_async::StringRecord {
+        let fields = [
+            ("type", &self.transaction_type),
+            ("client", &self.client),
+            ("tx", &self.tx),
+            ("amount", &self.amount),
+        ];
+        headers
+            .iter()
+            .map(|header| {
+                fields
+                    .iter()
+                    .find(|(_, column)| column.as_deref() == Some(header))
+                    .map_or(header, |(field, _)| field)
+            })
+            .collect()
+    }
+}
+
+/// Encoding of the transaction records.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// CSV with a header
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// Layout of the transactions files.
+///
+/// Only the allowed types apply to JSON lines, the other settings are specific to CSV.
+#[derive(Clone, Debug)]
+pub struct InputFormat {
+    /// Encoding of the records.
+    pub encoding: Encoding,
+    /// Field delimiter.
+    pub delimiter: u8,
+    /// Decimal separator of the amounts.
+    pub decimal_separator: DecimalSeparator,
+    /// Maximum length of a line, unlimited if not set.
+    pub max_record_bytes: Option<usize>,
+    /// Column names of the transaction fields, the default ones if not set.
+    pub schema: Option<std::sync::Arc<Schema>>,
+    /// Transaction types to read, all if not set; records of other types are skipped.
+    pub allowed_types: Option<Vec<TransactionType>>,
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Csv,
+            delimiter: b',',
+            decimal_separator: DecimalSeparator::Dot,
+            max_record_bytes: None,
+            schema: None,
+            allowed_types: None,
+        }
+    }
+}
+
+/// Reader failing on the first line longer than `max` bytes.
+///
+/// The CSV reader buffers whole records, thus a huge line must be caught before reaching it.
+struct MaxLineLength<R> {
+    inner: R,
+    max: usize,
+    line: usize,
+    len: usize,
+}
+
+impl<R> MaxLineLength<R> {
+    fn new(inner: R, max: Option<usize>) -> Self {
+        Self {
+            inner,
+            max: max.unwrap_or(usize::MAX),
+            line: 1,
+            len: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MaxLineLength<R> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        for byte in &buf.filled()[start..] {
+            if *byte == b'\n' {
+                this.line += 1;
+                this.len = 0;
+                continue;
+            }
+            this.len += 1;
+            if this.len > this.max {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {} is longer than {} bytes", this.line, this.max),
+                )));
+            }
+        }
+
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Whether `record` is of one of the `allowed` types, logging it if not.
+fn is_allowed(allowed: &Option<Vec<TransactionType>>, record: &TransactionRecord) -> bool {
+    match allowed {
+        Some(allowed) if !allowed.contains(&record.transaction_type) => {
+            tracing::debug!(%record, "skipping transaction of a type which is not allowed");
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Reads transaction records from CSV laid out as an `InputFormat`.
+///
+/// Amounts are parsed from the text of their field rather than through the deserialization of the
+/// record, which would infer them to be floating point numbers and lose digits. Non-finite amounts,
+/// e.g. `inf` or `NaN`, are kept invalid on their record for the engine to reject it. Reading fails on
+/// the first line longer than the maximum record size, if any. Records of types which are not
+/// allowed are skipped.
+pub struct CsvSource<R> {
+    reader: csv_async::AsyncReader<MaxLineLength<R>>,
+    headers: csv_async::StringRecord,
+    amount: Option<usize>,
+    decimal_point: char,
+    allowed_types: Option<Vec<TransactionType>>,
+}
+
+impl<R: AsyncRead + Unpin + Send> CsvSource<R> {
+    /// Create a source reading from `reader`, starting with its header.
+    pub async fn new(reader: R, format: &InputFormat) -> Result<Self> {
+        let mut reader = csv_async::AsyncReaderBuilder::new()
+            .flexible(true)
+            .trim(csv_async::Trim::All)
+            .delimiter(format.delimiter)
+            .create_reader(MaxLineLength::new(reader, format.max_record_bytes));
+        let mut headers = reader.headers().await?.clone();
+        if let Some(schema) = &format.schema {
+            headers = schema.apply(&headers);
+        }
+        // Hide the amount column from the deserialization of the record, it is parsed here
+        let amount = headers.iter().position(|header| header == "amount");
+        if let Some(amount) = amount {
+            headers = headers
+                .iter()
+                .enumerate()
+                .map(|(i, header)| if i == amount { "" } else { header })
+                .collect();
+        }
+
+        Ok(Self {
+            reader,
+            headers,
+            amount,
+            decimal_point: format.decimal_separator.char(),
+            allowed_types: format.allowed_types.clone(),
+        })
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> InputSource for CsvSource<R> {
+    async fn next_record(&mut self) -> Option<Result<TransactionRecord>> {
+        loop {
+            let mut record = csv_async::StringRecord::new();
+            match self.reader.read_record(&mut record).await {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e.into())),
+            }
+
+            // The hidden amount column cannot be told missing like the others, thus records missing
+            // trailing fields are deserialized with the headers of the fields they have
+            let headers = if record.len() < self.headers.len() {
+                std::borrow::Cow::Owned(self.headers.iter().take(record.len()).collect())
+            } else {
+                std::borrow::Cow::Borrowed(&self.headers)
+            };
+            let mut transaction: TransactionRecord = match record.deserialize(Some(&headers)) {
+                Ok(transaction) => transaction,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if let Some(field) = self.amount.and_then(|amount| record.get(amount)) {
+                transaction.amount = match field {
+                    "" => None,
+                    field => match Amount::parse(field, self.decimal_point) {
+                        Ok(amount) => Some(Ok(amount)),
+                        // Left to the engine to reject, rather than failing the whole input
+                        Err(ParseAmountError::NonFinite) => Some(Err(ParseAmountError::NonFinite)),
+                        Err(source) => {
+                            return Some(Err(Error::Amount {
+                                line: record.position().map_or(0, |position| position.line()),
+                                amount: field.to_owned(),
+                                source,
+                            }))
+                        }
+                    },
+                };
+            }
+            if is_allowed(&self.allowed_types, &transaction) {
+                return Some(Ok(transaction));
+            }
+        }
+    }
+}
+
+/// Reads transaction records from JSON objects, one per line, skipping blank lines. Records of
+/// types which are not allowed are skipped.
+pub struct JsonlSource<R> {
+    lines: tokio::io::Lines<tokio::io::BufReader<R>>,
+    allowed_types: Option<Vec<TransactionType>>,
+}
+
+impl<R: AsyncRead + Unpin> JsonlSource<R> {
+    /// Create a source reading from `reader`.
+    pub fn new(reader: R, format: &InputFormat) -> Self {
+        Self {
+            lines: tokio::io::BufReader::new(reader).lines(),
+            allowed_types: format.allowed_types.clone(),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> InputSource for JsonlSource<R> {
+    async fn next_record(&mut self) -> Option<Result<TransactionRecord>> {
+        loop {
+            let line = match self.lines.next_line().await {
+                Ok(Some(line)) if line.trim().is_empty() => continue,
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let record: TransactionRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if is_allowed(&self.allowed_types, &record) {
+                return Some(Ok(record));
+            }
+        }
+    }
+}
+
+/// Number of records parsed ahead of their execution.
+const PARSE_QUEUE: usize = 1024;
+
+/// Transaction records parsed from a reader in a dedicated task, so parsing overlaps the execution
+/// of the records already parsed.
+///
+/// Records are yielded in the order of the reader. Parsing stops once the source is dropped.
+pub struct ParsedSource {
+    records: mpsc::Receiver<Result<TransactionRecord>>,
+    parse: tokio::task::AbortHandle,
+}
+
+impl ParsedSource {
+    /// Start parsing the records of `reader` laid out as `format`.
+    ///
+    /// The CSV header is read before returning, thus a missing or unreadable one fails here.
+    pub async fn new<R>(reader: R, format: &InputFormat) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        Ok(match format.encoding {
+            Encoding::Csv => Self::spawn(CsvSource::new(reader, format).await?),
+            Encoding::Jsonl => Self::spawn(JsonlSource::new(reader, format)),
+        })
+    }
+
+    fn spawn<S: InputSource + Send + 'static>(mut source: S) -> Self {
+        let (tx, records) = mpsc::channel(PARSE_QUEUE);
+        let parse = tokio::spawn(async move {
+            while let Some(record) = source.next_record().await {
+                if tx.send(record).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            records,
+            parse: parse.abort_handle(),
+        }
+    }
+}
+
+impl Drop for ParsedSource {
+    fn drop(&mut self) {
+        self.parse.abort();
+    }
+}
+
+impl InputSource for ParsedSource {
+    async fn next_record(&mut self) -> Option<Result<TransactionRecord>> {
+        self.records.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine;
+    use crate::model::amount::Amount;
+    use crate::model::transaction::TransactionType;
+    use tokio::sync::oneshot;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn test_parsed_source() {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    deposit,2,2,3.0\n\
+                    withdrawal,1,3,1.5\n";
+        let (tx, rx) = mpsc::channel(32);
+        let handle = tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+        let mut source = ParsedSource::new(data.as_bytes(), &InputFormat::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            send_all(&mut source, &tx, &Limit::default()).await.unwrap(),
+            3
+        );
+        assert!(source.next_record().await.is_none());
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
+        let accounts = resp_rx.await.unwrap();
+        drop(tx);
+        assert_eq!(handle.await.unwrap().processed, 3);
+
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].total(), Amount::from_f64(3.5).unwrap());
+        assert_eq!(accounts[1].total(), Amount::from_f64(3.0).unwrap());
+
+        let data = r#"{"type":"deposit","client":1,"tx":1,"amount":"5.0"}"#;
+        let format = InputFormat {
+            encoding: Encoding::Jsonl,
+            ..InputFormat::default()
+        };
+        let mut source = ParsedSource::new(data.as_bytes(), &format).await.unwrap();
+        assert_eq!(source.next_record().await.unwrap().unwrap().id, 1);
+        assert!(source.next_record().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_parsed_source_reads_ahead() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        writer.write_all(b"type,client,tx,amount\n").await.unwrap();
+        let source = ParsedSource::new(reader, &InputFormat::default())
+            .await
+            .unwrap();
+
+        // Records well beyond the capacity of the pipe are read while none is asked for
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            for id in 0..200 {
+                writer
+                    .write_all(format!("deposit,1,{id},1.0\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        // Dropping the source stops parsing, thus closes the reader
+        drop(source);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while writer.write_all(b"deposit,1,3,1.0\n").await.is_ok() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_source() {
+        let data = r#"{"type":"deposit","client":1,"tx":1,"amount":"5.0"}
+
+{"type":"dispute","client":1,"tx":1}
+"#;
+        let mut source = JsonlSource::new(data.as_bytes(), &InputFormat::default());
+
+        let first = source.next_record().await.unwrap().unwrap();
+        assert_eq!(first.transaction_type, TransactionType::Deposit);
+        assert_eq!(first.amount, Amount::from_f64(5.0).map(Ok));
+        let second = source.next_record().await.unwrap().unwrap();
+        assert_eq!(second.transaction_type, TransactionType::Dispute);
+        assert_eq!(second.amount, None);
+        assert!(source.next_record().await.is_none());
+
+        let mut source =
+            JsonlSource::new(&b"{\"type\":\"deposit\"}\n"[..], &InputFormat::default());
+        assert!(matches!(
+            source.next_record().await,
+            Some(Err(Error::Json(_)))
+        ));
+
+        let format = InputFormat {
+            allowed_types: Some(vec![TransactionType::Dispute]),
+            ..InputFormat::default()
+        };
+        let mut source = JsonlSource::new(data.as_bytes(), &format);
+        let record = source.next_record().await.unwrap().unwrap();
+        assert_eq!(record.transaction_type, TransactionType::Dispute);
+        assert!(source.next_record().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_csv_source_allowed_types() {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,5.0\n\
+                    dispute,1,1,\n\
+                    withdrawal,1,2,1.0\n\
+                    resolve,1,1,\n\
+                    chargeback,1,1,\n\
+                    deposit,2,3,2.0\n";
+        let format = InputFormat {
+            allowed_types: Some(vec![TransactionType::Deposit, TransactionType::Withdrawal]),
+            ..InputFormat::default()
+        };
+        let mut source = CsvSource::new(data.as_bytes(), &format).await.unwrap();
+        let mut ids = Vec::new();
+        while let Some(record) = source.next_record().await {
+            ids.push(record.unwrap().id);
+        }
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        // Only the deposit and withdrawal apply, the charge back would have locked the account
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+        let mut source = CsvSource::new(data.as_bytes(), &format).await.unwrap();
+        assert_eq!(
+            send_all(&mut source, &tx, &Limit::default()).await.unwrap(),
+            3
+        );
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(Command::GetAccountsState(resp_tx)).await.unwrap();
+        let accounts = resp_rx.await.unwrap();
+        assert_eq!(accounts[0].total(), Amount::from_f64(4.0).unwrap());
+        assert_eq!(accounts[0].held(), Amount::ZERO);
+        assert!(!accounts[0].locked());
+        assert_eq!(accounts[1].total(), Amount::from_f64(2.0).unwrap());
+
+        let format = InputFormat {
+            allowed_types: Some(vec![TransactionType::Dispute]),
+            ..InputFormat::default()
+        };
+        let mut source = CsvSource::new(data.as_bytes(), &format).await.unwrap();
+        let record = source.next_record().await.unwrap().unwrap();
+        assert_eq!(record.transaction_type, TransactionType::Dispute);
+        assert!(source.next_record().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_csv_source_amounts() {
+        let data = "type,client,tx,amount
+deposit,1,1,
+deposit,1,2,  
+deposit,1,3
+deposit,1,4,1.5
+deposit,1,5,2
+deposit,1,6,1.23400
+deposit,1,7,0.123456789
+deposit,1,8,+12.34
+deposit,1,9,12345678901234.5678
+";
+        let mut source = CsvSource::new(data.as_bytes(), &InputFormat::default())
+            .await
+            .unwrap();
+        let mut amounts = Vec::new();
+        while let Some(record) = source.next_record().await {
+            amounts.push(
+                record
+                    .unwrap()
+                    .amount
+                    .map(|amount| amount.unwrap().to_string()),
+            );
+        }
+
+        assert_eq!(amounts[..3], [None, None, None]);
+        // Every digit is kept, none is lost to floating point
+        let amounts = amounts[3..].iter().flatten().collect::<Vec<_>>();
+        assert_eq!(
+            amounts,
+            [
+                "1.5",
+                "2",
+                "1.23400",
+                "0.123456789",
+                "12.34",
+                "12345678901234.5678"
+            ]
+        );
+
+        // Kept invalid for the engine to reject rather than taken for a missing amount
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,inf\n\
+                    deposit,1,2,NaN\n\
+                    withdrawal,1,3,-Infinity\n\
+                    deposit,1,4,abc\n";
+        let mut source = CsvSource::new(data.as_bytes(), &InputFormat::default())
+            .await
+            .unwrap();
+        for id in 1..=3 {
+            let record = source.next_record().await.unwrap().unwrap();
+            assert_eq!(record.id, id);
+            assert_eq!(record.amount, Some(Err(ParseAmountError::NonFinite)));
+        }
+        match source.next_record().await {
+            Some(Err(Error::Amount { amount, .. })) => assert_eq!(amount, "abc"),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_csv_source_decimal_comma() {
+        let data = "type;client;tx;amount\n\
+                    deposit;1;1;12,34\n\
+                    dispute;1;1;\n\
+                    deposit;1;2;1.5\n";
+        let format = InputFormat {
+            delimiter: b';',
+            decimal_separator: DecimalSeparator::Comma,
+            ..InputFormat::default()
+        };
+        let mut source = CsvSource::new(data.as_bytes(), &format).await.unwrap();
+
+        let deposit = source.next_record().await.unwrap().unwrap();
+        assert_eq!(deposit.amount, Some(Ok("12.34".parse().unwrap())));
+        let dispute = source.next_record().await.unwrap().unwrap();
+        assert_eq!(dispute.amount, None);
+        match source.next_record().await {
+            Some(Err(Error::Amount {
+                line,
+                amount,
+                source,
+            })) => {
+                assert_eq!(line, 4);
+                assert_eq!(amount, "1.5");
+                assert_eq!(
+                    source,
+                    crate::model::amount::ParseAmountError::InvalidCharacter('.')
+                );
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_input_file_gzip() {
+        use async_compression::tokio::write::GzipEncoder;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        async fn gzip(data: &str) -> Vec<u8> {
+            let mut encoder = GzipEncoder::new(Vec::new());
+            encoder.write_all(data.as_bytes()).await.unwrap();
+            encoder.shutdown().await.unwrap();
+            encoder.into_inner()
+        }
+
+        let dir = std::env::temp_dir().join(format!("input-file-gzip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Members are decompressed one after the other, like `cat a.gz b.gz`
+        let members = dir.join("transactions.csv.gz");
+        let mut compressed = gzip("type,client,tx,amount\ndeposit,1,1,1.0\n").await;
+        compressed.extend(gzip("deposit,1,2,2.0\n").await);
+        std::fs::write(&members, compressed).unwrap();
+        let corrupt = dir.join("corrupt.csv");
+        std::fs::write(&corrupt, b"type,client,tx,amount\n").unwrap();
+
+        let mut data = String::new();
+        InputFile::open(&members, false)
+            .await
+            .unwrap()
+            .read_to_string(&mut data)
+            .await
+            .unwrap();
+        let mut file = InputFile::open(&corrupt, true).await.unwrap();
+        let corrupt = file.read_to_end(&mut Vec::new()).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            data,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n"
+        );
+        assert!(corrupt.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_all_limit() {
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,1.0\n\
+                    deposit,1,2,1.0\n\
+                    deposit,1,3,1.0\n";
+        let (tx, mut rx) = mpsc::channel(32);
+        let mut source = ParsedSource::new(data.as_bytes(), &InputFormat::default())
+            .await
+            .unwrap();
+
+        let limit = Limit::new(Some(4));
+        assert_eq!(send_all(&mut source, &tx, &limit).await.unwrap(), 3);
+        // The budget left is shared with the next source
+        let mut source = ParsedSource::new(data.as_bytes(), &InputFormat::default())
+            .await
+            .unwrap();
+        assert_eq!(send_all(&mut source, &tx, &limit.clone()).await.unwrap(), 1);
+        assert!(limit.is_exhausted());
+        drop(tx);
+        let mut ids = Vec::new();
+        while let Some(Command::ExecuteTransaction(record)) = rx.recv().await {
+            ids.push(record.id);
+        }
+        assert_eq!(ids, vec![1, 2, 3, 1]);
+    }
+}
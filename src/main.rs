@@ -1,81 +1,1982 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use std::num::NonZeroUsize;
 use tokio::fs::File;
-use tokio::select;
+use tokio::io::AsyncRead;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
 
 mod engine;
+mod input;
+mod manifest;
 mod model;
+mod output;
+mod statsd;
+
+use input::{DecimalSeparator, InputFile, InputFormat, ParsedSource};
+use output::{CsvSink, JsonSink, OutputSink, SqliteSink};
 
 /// Input for the transaction processing engine
 #[derive(Parser, Debug)]
 struct Args {
-    /// Path to the transactions file to read
-    file_path: std::path::PathBuf,
+    /// Paths of the transactions files to read one after the other, as a single stream, `-` for
+    /// the standard input
+    #[arg(
+        value_name = "FILE_PATH",
+        required_unless_present_any = ["list_types", "shards", "inline", "restore"]
+    )]
+    file_paths: Vec<std::path::PathBuf>,
+    /// Decompress the transactions files with gzip, which is done for files ending in `.gz`
+    /// regardless
+    #[arg(long)]
+    gzip: bool,
+    /// Path to a transactions file read concurrently with the other inputs; a client must not
+    /// appear in more than one file
+    #[arg(long = "shard", value_name = "PATH")]
+    shards: Vec<std::path::PathBuf>,
+    /// Transaction given as a CSV row, e.g. `deposit,1,1,5.0`, executed after the transactions
+    /// files
+    #[arg(long, value_name = "ROW")]
+    inline: Vec<String>,
+    /// Start from the client states saved with `--snapshot` at PATH, executing the transactions on
+    /// top of them
+    #[arg(long, value_name = "PATH", conflicts_with = "round_trip_check")]
+    restore: Option<std::path::PathBuf>,
+    /// Save the client states at PATH once all transactions are executed, to be resumed with
+    /// `--restore`
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<std::path::PathBuf>,
+    /// Skip deposits, withdrawals and unlocks whose id is recorded in the JSON file at PATH,
+    /// recording the ids applied by this run into it; along with `--restore` from the snapshot of
+    /// the previous run, re-running an input is a no-op
+    #[arg(long, value_name = "PATH", conflicts_with = "round_trip_check")]
+    applied_ids: Option<std::path::PathBuf>,
+    /// Write every rejected transaction into the CSV file at PATH, with the `tx`, `client`, `type`
+    /// and `reason` columns
+    #[arg(long, value_name = "PATH")]
+    rejects_file: Option<std::path::PathBuf>,
+    /// Write a JSON manifest of the run at PATH, with the SHA-256 of the inputs and outputs, the
+    /// number of records and accounts, the engine version and a timestamp; inputs are hashed as
+    /// they are read, thus they must be read whole
+    #[arg(long, value_name = "PATH", conflicts_with = "limit")]
+    manifest: Option<std::path::PathBuf>,
+    /// Print the supported transaction types and exit
+    #[arg(long)]
+    list_types: bool,
+    /// Only execute transactions of the given types, e.g. `deposit,withdrawal`, skipping the others
+    #[arg(long, value_enum, value_delimiter = ',', alias = "transaction-types")]
+    allow_types: Option<Vec<model::transaction::TransactionType>>,
+    /// Stop reading transactions after the first N of all the inputs together, files first, then
+    /// inline transactions; which transactions of concurrently read shards are part of them is
+    /// arbitrary
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+    /// Write accounts into files named `accounts.000.csv`, `accounts.001.csv`, etc. holding at most
+    /// N accounts each, instead of standard output
+    #[arg(long, value_name = "N")]
+    max_output_rows: Option<std::num::NonZeroUsize>,
+    /// Write accounts into the `accounts` table of the SQLite database at PATH, replacing the
+    /// table, instead of standard output
+    #[arg(long, value_name = "PATH", conflicts_with = "max_output_rows")]
+    sqlite: Option<std::path::PathBuf>,
+    /// Upsert the balances of each client into the `accounts` table of the SQLite database at PATH
+    /// as its transactions are committed, keeping the rows of clients absent from the run
+    #[arg(long, value_name = "PATH")]
+    persist: Option<std::path::PathBuf>,
+    /// Add a `tag` column with the tag of each account, set by its first transaction
+    #[arg(long, conflicts_with = "sqlite")]
+    tags: bool,
+    /// Add a `last_tx_id` column with the highest id of the deposits and withdrawals applied to
+    /// each account, the one `--since` compares against
+    #[arg(long, conflicts_with = "sqlite")]
+    last_tx_id: bool,
+    /// Only output the accounts with a deposit or withdrawal with an id greater than N, e.g. those
+    /// changed since a previous run resumed with `--restore`
+    #[arg(long, value_name = "N")]
+    since: Option<model::transaction::Id>,
+    /// Number of decimal places amounts are rounded to in the output, and truncated to by
+    /// `--round-disputes`
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = model::amount::Amount::PRECISION,
+        value_parser = clap::value_parser!(u32).range(..=model::amount::Amount::MAX_SCALE as i64)
+    )]
+    scale: u32,
+    /// Format of the transactions files; rows given with `--inline` are always CSV
+    #[arg(long, value_enum, default_value_t = input::Encoding::Csv)]
+    input_format: input::Encoding,
+    /// Order of the accounts in the output
+    #[arg(long, value_enum, default_value_t = OutputOrder::Client)]
+    output_order: OutputOrder,
+    /// Format of the accounts written to standard output
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Csv,
+        conflicts_with_all = ["sqlite", "max_output_rows"]
+    )]
+    output_format: OutputFormat,
+    /// Write accounts to standard output as the engine sends them instead of collecting them first
+    #[arg(
+        long,
+        conflicts_with_all = ["sqlite", "max_output_rows", "round_trip_check", "output_order"]
+    )]
+    stream: bool,
+    /// Only write the account of client ID out, may be given several times; unknown clients are
+    /// skipped
+    #[arg(
+        long = "client",
+        value_name = "ID",
+        conflicts_with_all = ["stream", "round_trip_check"]
+    )]
+    clients: Vec<model::account::Id>,
+    /// Omit accounts with a zero total balance which are not locked from the output
+    #[arg(long)]
+    suppress_zero: bool,
+    /// Truncate amounts moved to and from held funds by disputes to the output precision
+    #[arg(long)]
+    round_disputes: bool,
+    /// Dispute only the amount given on dispute rows, adding up over several disputes of the same
+    /// transaction; without it, the amount of dispute rows is ignored and the whole transaction is
+    /// disputed
+    #[arg(long)]
+    partial_disputes: bool,
+    /// How to handle deposits and withdrawals reusing a transaction id
+    #[arg(long, value_enum, default_value_t = engine::state::DuplicatePolicy::Error)]
+    duplicate_policy: engine::state::DuplicatePolicy,
+    /// Check the inputs before executing anything and fail the whole run if a deposit or withdrawal
+    /// reuses the id of a previous one of the same client
+    #[arg(long)]
+    reject_on_duplicate: bool,
+    /// Reject deposits and withdrawals with a lower id than a previous one of the same client
+    #[arg(long)]
+    monotonic_tx_ids: bool,
+    /// Treat transaction ids as unique across clients, rejecting transactions reusing the id of a
+    /// transaction applied to another client and still in its history
+    #[arg(long)]
+    unique_tx_ids: bool,
+    /// Reject disputes on an account which already has N disputes open
+    #[arg(long, value_name = "N")]
+    max_open_disputes: Option<usize>,
+    /// Keep at most N deposits and withdrawals per account for disputes, evicting the oldest ones
+    /// not under dispute and charged back ones
+    #[arg(long, value_name = "N")]
+    max_history: Option<usize>,
+    /// Reject withdrawals which would leave the available funds of an account below AMOUNT
+    #[arg(long, value_name = "AMOUNT")]
+    min_balance: Option<model::amount::Amount>,
+    /// Credit interest at RATE, e.g. 0.05 for 5%, on the available funds of every unlocked
+    /// account once all transactions are executed, recorded as transaction `--interest-tx`
+    #[arg(long, value_name = "RATE", requires = "interest_tx")]
+    accrue_interest: Option<model::amount::Amount>,
+    /// Id of the interest transaction recorded by `--accrue-interest`
+    #[arg(long, value_name = "ID", requires = "accrue_interest")]
+    interest_tx: Option<model::transaction::Id>,
+    /// Execute transactions without spawning a task per client until transactions of more than N
+    /// clients are received
+    #[arg(long, value_name = "N")]
+    adaptive_clients: Option<usize>,
+    /// With `--adaptive-clients`, also spawn a task per client once a client receives more than N
+    /// transactions
+    #[arg(long, value_name = "N", requires = "adaptive_clients")]
+    adaptive_client_transactions: Option<u64>,
+    /// Number of transactions queued for the engine and for each client before reading the input
+    /// blocks; a low capacity increases backpressure, a high one memory usage
+    #[arg(long, value_name = "N", default_value_t = NonZeroUsize::new(engine::server::DEFAULT_CHANNEL_CAPACITY).unwrap())]
+    channel_capacity: NonZeroUsize,
+    /// Push the transaction, rejection and account metrics of the run to the StatsD server at
+    /// ADDR, e.g. `127.0.0.1:8125`
+    #[arg(long, value_name = "ADDR")]
+    statsd_addr: Option<String>,
+    /// Exit with an error unless the totals of all accounts add up to the funds deposited,
+    /// withdrawn and charged back
+    #[arg(long)]
+    assert_balanced: bool,
+    /// Exit with an error unless the available and held funds of every account add up to its
+    /// total
+    #[arg(long)]
+    verify: bool,
+    /// Exit with an error unless every account matches the one recomputed from its transaction
+    /// history
+    #[arg(long)]
+    check_balances: bool,
+    /// Only parse and validate the transactions without executing them, printing the invalid ones
+    /// and how many records are valid; exit with an error if any is invalid
+    #[arg(long)]
+    check: bool,
+    /// Exit with an error if any transaction was rejected
+    #[arg(long)]
+    strict: bool,
+    /// Execute transactions one at a time and stop at the first rejected one, exiting with its
+    /// error
+    #[arg(long, conflicts_with = "shards")]
+    fail_fast: bool,
+    /// Field delimiter of the transactions files, must be an ASCII character
+    #[arg(long, default_value_t = ',')]
+    delimiter: char,
+    /// Inject failures in the engine at the given rate, between 0 and 1; debug builds only
+    #[arg(long, value_name = "RATE", hide = true)]
+    chaos: Option<f64>,
+    /// Seed of the injected failures, random by default
+    #[arg(long, value_name = "SEED", hide = true, requires = "chaos")]
+    chaos_seed: Option<u64>,
+    /// Storage of the account states, for benchmarking
+    #[arg(long, value_enum, hide = true, default_value_t = engine::store::Kind::Dashmap)]
+    store: engine::store::Kind,
+    /// Decimal separator of the amounts in the transactions files
+    #[arg(long, value_enum, default_value_t = DecimalSeparator::Dot)]
+    decimal_separator: DecimalSeparator,
+    /// Check that the output, read back, matches replaying the transactions on a single thread
+    #[arg(long)]
+    round_trip_check: bool,
+    /// Colorize log output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// JSON file mapping the transaction fields (`type`, `client`, `tx`, `amount`) to the column
+    /// names of the transactions files, e.g. `{"type": "kind", "tx": "reference"}`
+    #[arg(long, value_name = "PATH")]
+    schema: Option<std::path::PathBuf>,
+    /// Abort reading a transactions file on a line longer than N bytes
+    #[arg(long, value_name = "N")]
+    max_record_bytes: Option<usize>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let subscriber = tracing_subscriber::fmt()
+/// Order of the accounts in the output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputOrder {
+    /// Ascending client id
+    Client,
+    /// Order in which clients first appeared in the transactions
+    FirstSeen,
+}
+
+/// Format of the accounts written to standard output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// CSV with a header
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// When to colorize log output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    /// Colorize if logging to a terminal and `NO_COLOR` is not set
+    Auto,
+    /// Always colorize
+    Always,
+    /// Never colorize
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to colorize, given whether logs go to a terminal and the `NO_COLOR` environment
+    /// variable (see https://no-color.org, where an empty value does not count).
+    fn use_ansi(self, is_terminal: bool, no_color: Option<std::ffi::OsString>) -> bool {
+        match self {
+            Self::Auto => is_terminal && no_color.is_none_or(|value| value.is_empty()),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Build the log subscriber writing into `writer`, with ANSI colors if `ansi` is set.
+fn log_subscriber<W>(ansi: bool, writer: W) -> impl tracing::Subscriber + Send + Sync
+where
+    W: for<'writer> tracing_subscriber::fmt::MakeWriter<'writer> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
         .compact()
+        .with_ansi(ansi)
+        .with_writer(writer)
         .with_file(true)
         .with_line_number(true)
         .with_thread_ids(true)
         .with_target(false)
         .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+        .finish()
+}
+
+/// Describe the values accepted in the `type` column of the transactions file, one per line.
+fn supported_types() -> String {
+    model::transaction::TransactionType::value_variants()
+        .iter()
+        .filter_map(|transaction_type| transaction_type.to_possible_value())
+        .map(|value| {
+            let names = value.get_name_and_aliases().collect::<Vec<_>>().join(", ");
+            match value.get_help() {
+                Some(help) => format!("{names}: {help}\n"),
+                None => format!("{names}\n"),
+            }
+        })
+        .collect()
+}
+
+/// Deserialize transaction records laid out as `format` from `reader` and send them to the engine
+/// one by one, stopping once `limit` is exhausted.
+///
+/// Returns the number of records sent.
+async fn send_records<R>(
+    reader: R,
+    tx: &mpsc::Sender<engine::server::Command>,
+    limit: &input::Limit,
+    format: InputFormat,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut source = ParsedSource::new(reader, &format).await?;
+
+    Ok(input::send_all(&mut source, tx, limit).await?)
+}
+
+/// Deserialize transaction records laid out as `format` from `reader` and execute them through
+/// `engine` one at a time, failing on the first rejected one, see `input::execute_all`.
+///
+/// Returns the number of records executed.
+async fn execute_records<R>(
+    reader: R,
+    engine: &engine::Engine,
+    limit: &input::Limit,
+    format: InputFormat,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut source = ParsedSource::new(reader, &format).await?;
+
+    // Show which transaction was rejected and why rather than the error's debug representation
+    Ok(input::execute_all(&mut source, engine, limit)
+        .await
+        .map_err(|e| e.to_string())?)
+}
+
+/// Open the transactions files at `paths`, decompressing them according to `gzip`, and hashing
+/// each of them as it is read into a digest added to `digests`, if given.
+///
+/// Every file is opened before any record is sent, so a missing one fails the run before the
+/// engine changes any state.
+async fn open_files(
+    paths: &[std::path::PathBuf],
+    gzip: bool,
+    mut digests: Option<&mut Vec<manifest::Digest>>,
+) -> input::Result<Vec<InputFile>> {
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let file = match digests.as_deref_mut() {
+            Some(digests) => {
+                let digest = manifest::Digest::default();
+                digests.push(digest.clone());
+                InputFile::open_hashed(path, gzip, digest).await?
+            }
+            None => InputFile::open(path, gzip).await?,
+        };
+        files.push(file);
+    }
+
+    Ok(files)
+}
+
+/// Send the records of `files` to the engine, one file after the other, see `send_records`.
+///
+/// Returns the number of records sent.
+async fn send_files(
+    files: Vec<InputFile>,
+    tx: &mpsc::Sender<engine::server::Command>,
+    limit: &input::Limit,
+    format: &InputFormat,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let mut count = 0;
+    for file in files {
+        count += send_records(file, tx, limit, format.clone()).await?;
+    }
+
+    Ok(count)
+}
+
+/// Send the records of every reader to the engine, each reader in its own task.
+///
+/// Records of one reader are sent in order, but records of different readers interleave
+/// arbitrarily, thus a client must not appear in more than one reader.
+///
+/// Returns the total number of records sent.
+async fn send_records_concurrently<R>(
+    readers: Vec<R>,
+    tx: &mpsc::Sender<engine::server::Command>,
+    limit: &input::Limit,
+    format: InputFormat,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut tasks = tokio::task::JoinSet::new();
+    for reader in readers {
+        let tx = tx.clone();
+        let format = format.clone();
+        let limit = limit.clone();
+        tasks.spawn(async move { send_records(reader, &tx, &limit, format).await });
+    }
+
+    let mut count = 0;
+    while let Some(result) = tasks.join_next().await {
+        count += result??;
+    }
+
+    Ok(count)
+}
+
+/// Turn transactions given as CSV rows into a transactions file, with the default header fields
+/// separated by `delimiter`.
+fn inline_transactions(rows: &[String], delimiter: u8) -> String {
+    let header = ["type", "client", "tx", "amount"].join(&char::from(delimiter).to_string());
+    std::iter::once(header.as_str())
+        .chain(rows.iter().map(String::as_str))
+        .map(|row| format!("{row}\n"))
+        .collect()
+}
+
+/// Fail on the first deposit or withdrawal of `reader` reusing the id of a previous one of the same
+/// client, either from `reader` or already in `seen`, reading records laid out as `format` until
+/// `limit` is exhausted.
+///
+/// Only the identity of the transactions is kept in `seen`, not the records themselves.
+async fn check_duplicates<R>(
+    reader: R,
+    seen: &mut std::collections::HashSet<model::transaction::TxKey>,
+    limit: &input::Limit,
+    format: &InputFormat,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    use model::transaction::TransactionType;
+
+    let mut source = ParsedSource::new(reader, format).await?;
+    while let Some(record) = input::next_within(&mut source, limit).await {
+        let record = record?;
+        if matches!(
+            record.transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+        ) && !seen.insert(record.key())
+        {
+            return Err(format!(
+                "duplicate transaction id ({}), rejecting the whole batch",
+                record.key()
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Records found valid and invalid by `check_records`.
+#[derive(Debug, Default)]
+struct CheckReport {
+    /// Number of valid records.
+    valid: usize,
+    /// Reason each invalid record was rejected, in reading order.
+    invalid: Vec<String>,
+}
+
+impl std::fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for reason in &self.invalid {
+            writeln!(f, "invalid record: {reason}")?;
+        }
+        writeln!(
+            f,
+            "{} records checked: {} valid, {} invalid",
+            self.valid + self.invalid.len(),
+            self.valid,
+            self.invalid.len()
+        )
+    }
+}
+
+/// Deserialize the transaction records laid out as `format` from `reader` and convert them into
+/// transactions without executing them, stopping once `limit` is exhausted, adding the
+/// outcome to `report` with invalid records prefixed by `name`.
+///
+/// Only failures to read `reader` abort the check, malformed records are reported and skipped.
+async fn check_records<R>(
+    reader: R,
+    name: &str,
+    limit: &input::Limit,
+    format: &InputFormat,
+    report: &mut CheckReport,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut source = ParsedSource::new(reader, format).await?;
+    while let Some(record) = input::next_within(&mut source, limit).await {
+        let reason = match record {
+            Ok(record) => match engine::state::Transaction::try_from(&record) {
+                Ok(_) => {
+                    report.valid += 1;
+                    continue;
+                }
+                Err(e) => format!("{record}: {e}"),
+            },
+            Err(input::Error::Csv(e)) if !e.is_io_error() => e.to_string(),
+            Err(e @ input::Error::Amount { .. }) => e.to_string(),
+            Err(e) => return Err(e.into()),
+        };
+        report.invalid.push(format!("{name}: {reason}"));
+    }
+
+    Ok(())
+}
+
+/// Deserialize all transaction records laid out as `format` from `reader`, stopping once `limit` is
+/// exhausted, the same way `send_records` does.
+async fn read_records<R>(
+    reader: R,
+    limit: &input::Limit,
+    format: InputFormat,
+) -> Result<Vec<model::transaction::TransactionRecord>, Box<dyn std::error::Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(32);
+    let collect = tokio::spawn(async move {
+        let mut records = Vec::new();
+        while let Some(engine::server::Command::ExecuteTransaction(record)) = rx.recv().await {
+            records.push(record);
+        }
+        records
+    });
+    send_records(reader, &tx, limit, format).await?;
+    drop(tx);
+
+    Ok(collect.await?)
+}
+
+/// Account balances as read back from the output.
+#[derive(serde::Deserialize)]
+struct AccountRow {
+    client: model::account::Id,
+    #[serde(with = "model::amount::exact")]
+    available: model::amount::Amount,
+    #[serde(with = "model::amount::exact")]
+    held: model::amount::Amount,
+    #[serde(with = "model::amount::exact")]
+    total: model::amount::Amount,
+    locked: bool,
+}
+
+/// Read back the accounts serialized in `output` and compare them against replaying `records`, then
+/// accruing `interest` if any, on a fresh engine, amounts rounded to the output precision.
+///
+/// Returns an error describing the first mismatch found, if any.
+async fn round_trip_check(
+    output: &[u8],
+    records: &[model::transaction::TransactionRecord],
+    config: engine::state::Config,
+    interest: Option<(model::amount::Amount, model::transaction::Id)>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let rows = csv_async::AsyncReaderBuilder::new()
+        .create_deserializer(output)
+        .deserialize::<AccountRow>()
+        .collect::<Result<Vec<_>, _>>()
+        .await?;
+    let expected = engine::replay(records, config, interest).await;
+    if rows.len() != expected.len() {
+        return Err(format!(
+            "round-trip check failed: {} accounts in output, {} expected",
+            rows.len(),
+            expected.len()
+        )
+        .into());
+    }
+
+    let rounded = |amount: model::amount::Amount| amount.round_dp(config.scale);
+    for (row, account) in rows.iter().zip(&expected) {
+        let found = (row.client, row.available, row.held, row.total, row.locked);
+        let expected = (
+            account.id(),
+            rounded(account.available()),
+            rounded(account.held()),
+            rounded(account.total()),
+            account.locked(),
+        );
+        if found != expected {
+            return Err(format!(
+                "round-trip check failed: (client, available, held, total, locked) is {:?} in \
+                 output, {:?} expected",
+                found, expected
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `account` has a zero total balance and is not locked.
+fn is_zero_balance(account: &model::account::Account) -> bool {
+    !account.locked() && account.total().is_zero()
+}
+
+/// Remove the accounts with a zero total balance, unless they are locked.
+fn suppress_zero_balances(accounts: &mut Vec<model::account::Account>) {
+    accounts.retain(|account| !is_zero_balance(account));
+}
+
+/// Sort `accounts` in the order of their clients in `order`, e.g. the order they first appeared.
+///
+/// Accounts of clients missing from `order` go last, by client id.
+fn sort_first_seen(accounts: &mut [model::account::Account], order: &[model::account::Id]) {
+    let position = order
+        .iter()
+        .enumerate()
+        .map(|(position, client)| (*client, position))
+        .collect::<std::collections::HashMap<_, _>>();
+    accounts.sort_by_key(|account| {
+        (
+            position.get(&account.id()).copied().unwrap_or(usize::MAX),
+            account.id(),
+        )
+    });
+}
+
+/// Whether `account` had a deposit or withdrawal with an id greater than `since`.
+fn changed_since(account: &model::account::Account, since: model::transaction::Id) -> bool {
+    account.last_tx_id().is_some_and(|id| id > since)
+}
+
+/// Remove the accounts without a deposit or withdrawal with an id greater than `since`.
+fn retain_changed_since(
+    accounts: &mut Vec<model::account::Account>,
+    since: model::transaction::Id,
+) {
+    accounts.retain(|account| changed_since(account, since));
+}
+
+/// Check that the account totals of `ledger` add up to the funds moved by the transactions.
+fn check_ledger(ledger: Option<engine::server::Ledger>) -> Result<(), String> {
+    let ledger = ledger.ok_or("balance check failed: amounts overflow")?;
+    if ledger.is_balanced() {
+        return Ok(());
+    }
+
+    let flows = ledger.flows;
+    Err(format!(
+        "balance check failed: accounts add up to {}, but opening {} + deposited {} - withdrawn {} \
+         - charged back {} + reversed withdrawals {} + interest {} = {}",
+        ledger.total,
+        flows.opening,
+        flows.deposited,
+        flows.withdrawn,
+        flows.charged_back,
+        flows.reversed,
+        flows.interest,
+        flows
+            .total()
+            .map_or_else(|| "overflow".to_string(), |total| total.to_string())
+    ))
+}
+
+/// Write the accounts streamed by the engine into `sink` as they arrive, skipping those `keep`
+/// rejects, and finish it.
+///
+/// Returns the number of accounts written.
+async fn stream_accounts<S: OutputSink>(
+    tx: &mpsc::Sender<engine::server::Command>,
+    sink: &mut S,
+    keep: impl Fn(&model::account::Account) -> bool,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let (accounts_tx, mut accounts_rx) = mpsc::channel(1024);
+    tx.send(engine::server::Command::StreamAccountsState(accounts_tx))
+        .await?;
+    let mut count = 0;
+    while let Some(account) = accounts_rx.recv().await {
+        if keep(&account) {
+            sink.write_account(&account).await?;
+            count += 1;
+        }
+    }
+    sink.finish().await?;
+
+    Ok(count)
+}
+
+/// Write `accounts` as CSV into files named `accounts.000.csv`, `accounts.001.csv`, etc. in
+/// `dir`, each with its own header and at most `max_rows` accounts, adding the `tag` and
+/// `last_tx_id` columns if `tag` and `last_tx_id` are set.
+///
+/// Returns the paths of the files written, in order.
+async fn write_accounts_split(
+    dir: &std::path::Path,
+    accounts: &[model::account::Account],
+    max_rows: std::num::NonZeroUsize,
+    tag: bool,
+    last_tx_id: bool,
+    scale: u32,
+) -> output::Result<Vec<std::path::PathBuf>> {
+    let mut paths = Vec::new();
+    for chunk in accounts.chunks(max_rows.get()) {
+        let path = dir.join(format!("accounts.{:03}.csv", paths.len()));
+        let file = File::create(&path).await?;
+        let mut sink = CsvSink::new(file)
+            .with_scale(scale)
+            .with_tag(tag)
+            .with_last_tx_id(last_tx_id);
+        output::write_all(&mut sink, chunk).await?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
+    let ansi = args.color.use_ansi(
+        std::io::IsTerminal::is_terminal(&std::io::stdout()),
+        std::env::var_os("NO_COLOR"),
+    );
+    tracing::subscriber::set_global_default(log_subscriber(ansi, std::io::stdout))?;
+    if args.list_types {
+        print!("{}", supported_types());
+        return Ok(());
+    }
+    if args.last_tx_id && args.output_format == OutputFormat::Jsonl {
+        return Err("--last-tx-id is only supported by the CSV output".into());
+    }
+    // The standard input can only be read once, while some options read the inputs twice
+    let stdin = std::path::Path::new(input::STDIN);
+    let stdin_inputs = args
+        .file_paths
+        .iter()
+        .chain(&args.shards)
+        .filter(|path| *path == stdin)
+        .count();
+    if stdin_inputs > 1 {
+        return Err("the standard input can only be given once".into());
+    }
+    if stdin_inputs > 0 && (args.reject_on_duplicate || args.round_trip_check) {
+        return Err(
+            "--reject-on-duplicate and --round-trip-check do not support reading from the \
+             standard input"
+                .into(),
+        );
+    }
 
     // Start the engine in its own task
     //
     // Unwrap on engine run as there is not much to do in case of failure
-    let (tx, rx) = mpsc::channel(32);
+    let (tx, rx) = mpsc::channel(args.channel_capacity.get());
+    let config = engine::state::Config {
+        scale: args.scale,
+        round_disputes: args.round_disputes,
+        duplicate_policy: args.duplicate_policy,
+        monotonic_tx_ids: args.monotonic_tx_ids,
+        max_open_disputes: args.max_open_disputes,
+        min_balance: args.min_balance,
+        unique_tx_ids: args.unique_tx_ids,
+        max_history: args.max_history,
+        partial_disputes: args.partial_disputes,
+    };
+    let format = InputFormat {
+        encoding: args.input_format,
+        delimiter: u8::try_from(args.delimiter)
+            .ok()
+            .filter(u8::is_ascii)
+            .ok_or("delimiter must be an ASCII character")?,
+        decimal_separator: args.decimal_separator,
+        max_record_bytes: args.max_record_bytes,
+        schema: match &args.schema {
+            Some(path) => Some(std::sync::Arc::new(serde_json::from_slice(
+                &tokio::fs::read(path).await?,
+            )?)),
+            None => None,
+        },
+        allowed_types: args.allow_types.clone(),
+    };
+    let inline_format = InputFormat {
+        encoding: input::Encoding::Csv,
+        ..format.clone()
+    };
+    if args.check {
+        let limit = input::Limit::new(args.limit);
+        let mut report = CheckReport::default();
+        for path in args.file_paths.iter().chain(&args.shards) {
+            let file = InputFile::open(path, args.gzip).await?;
+            let name = path.display().to_string();
+            check_records(file, &name, &limit, &format, &mut report).await?;
+        }
+        let rows = inline_transactions(&args.inline, format.delimiter);
+        check_records(
+            std::io::Cursor::new(rows),
+            "--inline",
+            &limit,
+            &inline_format,
+            &mut report,
+        )
+        .await?;
+        print!("{report}");
+        if !report.invalid.is_empty() {
+            return Err(format!("{} records are invalid", report.invalid.len()).into());
+        }
+        return Ok(());
+    }
+    let chaos = match args.chaos {
+        Some(_) if !cfg!(debug_assertions) => {
+            return Err("chaos mode is only available in debug builds".into());
+        }
+        Some(rate) if !(0.0..=1.0).contains(&rate) => {
+            return Err("chaos rate must be between 0 and 1".into());
+        }
+        Some(rate) => {
+            let seed = args.chaos_seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+            });
+            tracing::warn!(rate, seed, "chaos mode enabled");
+            Some(engine::chaos::Config { rate, seed })
+        }
+        None => None,
+    };
+    let persistence = match &args.persist {
+        Some(path) => Some(std::sync::Arc::new(engine::persistence::AccountsDb::open(
+            path,
+        )?)),
+        None => None,
+    };
     let token = CancellationToken::new();
-    let cloned_token = token.clone();
-    let engine_handle = tokio::spawn(async move {
-        select! {
-            _ = cloned_token.cancelled() => {}
-            _ = engine::run(rx) => {}
+    let adaptive = args
+        .adaptive_clients
+        .map(|max_clients| engine::server::Adaptive {
+            max_clients,
+            max_client_transactions: args.adaptive_client_transactions,
+        });
+    let options = engine::Options {
+        config,
+        chaos,
+        adaptive,
+        channel_capacity: args.channel_capacity.get(),
+        persistence,
+        store: args.store,
+    };
+    let engine_handle = tokio::spawn(engine::run(rx, options, token.clone()));
+    // Rejections are written as they come, the engine waits for them to be consumed
+    let rejects_handle = match &args.rejects_file {
+        Some(path) => {
+            let file = File::create(path).await?;
+            let (rejections_tx, mut rejections_rx) = mpsc::channel(1024);
+            tx.send(engine::server::Command::SubscribeRejections(rejections_tx))
+                .await?;
+            Some(tokio::spawn(async move {
+                output::write_rejections(file, &mut rejections_rx).await
+            }))
         }
-    });
+        None => None,
+    };
+    // Missing on the first run
+    let applied = match &args.applied_ids {
+        Some(path) => Some(std::sync::Arc::new(match tokio::fs::read(path).await {
+            Ok(json) => engine::applied::AppliedIds::from_json(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        })),
+        None => None,
+    };
+    if let Some(applied) = &applied {
+        tx.send(engine::server::Command::TrackAppliedIds(applied.clone()))
+            .await?;
+    }
+    if let Some(path) = &args.restore {
+        let snapshots = engine::state::SnapshotV1::from_json(&tokio::fs::read(path).await?)?;
+        let (resp_tx, resp_rx) = tokio::sync::oneshot::channel();
+        tx.send(engine::server::Command::Restore(snapshots.clients, resp_tx))
+            .await?;
+        resp_rx.await??;
+    }
 
-    // Process and send transaction records to the engine in main thread, one by one as they
+    // Read and send transaction records to the engine from the main task, one by one as they
     // contain transaction ids which need to be processed in chronological order (similar to
     // receiving messages on a TCP socket; processing each transaction in it's own task would lead
     // to out of order transactions which is not the expected output of the program - though it's a
     // good testing scenario).
-    let mut rdr = csv_async::AsyncReaderBuilder::new()
-        .flexible(true)
-        .trim(csv_async::Trim::All)
-        .create_deserializer(File::open(args.file_path).await.unwrap());
-    let mut records = rdr.deserialize::<model::transaction::TransactionRecord>();
-    while let Some(record) = records.next().await {
-        let record = record?;
+    // When a limit is given, ingestion stops early and whatever was sent so far is committed below.
+    //
+    // Shards hold disjoint sets of clients, thus they can be read concurrently without breaking the
+    // order of each client's transactions.
+    let inputs = args
+        .file_paths
+        .iter()
+        .chain(&args.shards)
+        .cloned()
+        .collect::<Vec<_>>();
+    if args.reject_on_duplicate {
+        // Nothing is executed until all inputs are checked
+        let limit = input::Limit::new(args.limit);
+        let mut seen = std::collections::HashSet::new();
+        for path in &inputs {
+            let file = InputFile::open(path, args.gzip).await?;
+            check_duplicates(file, &mut seen, &limit, &format).await?;
+        }
+        let rows = inline_transactions(&args.inline, format.delimiter);
+        check_duplicates(
+            std::io::Cursor::new(rows),
+            &mut seen,
+            &limit,
+            &inline_format,
+        )
+        .await?;
+    }
+    // Inputs are hashed for the manifest as they are read, so they are only read once
+    let mut digests = args.manifest.as_ref().map(|_| Vec::new());
+    let files = open_files(&args.file_paths, args.gzip, digests.as_mut()).await?;
+    let shards = open_files(&args.shards, args.gzip, digests.as_mut()).await?;
+    let limit = input::Limit::new(args.limit);
+    let mut records_read = 0;
+    let facade = engine::Engine::new(tx.clone());
+    if args.fail_fast {
+        for file in files {
+            records_read += execute_records(file, &facade, &limit, format.clone()).await?;
+        }
+    } else if shards.is_empty() {
+        records_read += send_files(files, &tx, &limit, &format).await?;
+    } else {
+        // The files given as positional arguments are one more shard
+        let (files, shards) = tokio::join!(
+            send_files(files, &tx, &limit, &format),
+            send_records_concurrently(shards, &tx, &limit, format.clone()),
+        );
+        records_read += files? + shards?;
+    }
+    if !args.inline.is_empty() {
+        let rows = std::io::Cursor::new(inline_transactions(&args.inline, format.delimiter));
+        records_read += if args.fail_fast {
+            execute_records(rows, &facade, &limit, inline_format.clone()).await?
+        } else {
+            send_records(rows, &tx, &limit, inline_format.clone()).await?
+        };
+    }
 
-        tx.send(engine::server::Command::ExecuteTransaction(record))
+    if let (Some(rate), Some(interest_tx)) = (args.accrue_interest, args.interest_tx) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::AccrueInterest {
+            rate,
+            tx: interest_tx,
+            resp: resp_tx,
+        })
+        .await?;
+        let accruals = resp_rx.await?;
+        if let Some((client, e)) = accruals
+            .iter()
+            .find_map(|(client, result)| Some((client, result.as_ref().err()?)))
+        {
+            return Err(format!("unable to accrue interest for client {client}: {e}").into());
+        }
+        let credited = accruals
+            .iter()
+            .filter(|(_, interest)| {
+                interest
+                    .as_ref()
+                    .is_ok_and(|interest| interest.is_positive())
+            })
+            .count();
+        tracing::info!(credited, "interest accrued");
+    }
+    if let Some(path) = &args.snapshot {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetSnapshot(resp_tx))
             .await?;
+        let snapshots = engine::state::SnapshotV1::new(resp_rx.await?);
+        tokio::fs::write(path, serde_json::to_vec(&snapshots)?).await?;
     }
+    // Standard output is hashed on the fly for the manifest, output files once written
+    let stdout_hash = |stdout: &manifest::HashWriter<tokio::io::Stdout>| manifest::FileHash {
+        path: "-".into(),
+        sha256: stdout.sha256(),
+    };
+    let (accounts_written, output_files, stdout_output) = if args.stream {
+        let mut stdout = manifest::HashWriter::new(tokio::io::stdout());
+        let keep = |account: &model::account::Account| {
+            !(args.suppress_zero && is_zero_balance(account))
+                && args.since.is_none_or(|since| changed_since(account, since))
+        };
+        let count = match args.output_format {
+            OutputFormat::Csv => {
+                let mut sink = CsvSink::new(&mut stdout)
+                    .with_scale(args.scale)
+                    .with_tag(args.tags)
+                    .with_last_tx_id(args.last_tx_id);
+                stream_accounts(&tx, &mut sink, keep).await?
+            }
+            OutputFormat::Jsonl => {
+                let mut sink = JsonSink::new(&mut stdout)
+                    .with_scale(args.scale)
+                    .with_tag(args.tags);
+                stream_accounts(&tx, &mut sink, keep).await?
+            }
+        };
+        (count, Vec::new(), Some(stdout_hash(&stdout)))
+    } else {
+        // Request the state of account balances
+        let mut result = if args.clients.is_empty() {
+            facade.accounts().await.ok_or("the engine stopped early")?
+        } else {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(engine::server::Command::GetAccountsFiltered(
+                args.clients.clone(),
+                resp_tx,
+            ))
+            .await?;
+            resp_rx.await?
+        };
+        if args.round_trip_check {
+            let limit = input::Limit::new(args.limit);
+            let mut records = Vec::new();
+            for path in &inputs {
+                records.extend(
+                    read_records(
+                        InputFile::open(path, args.gzip).await?,
+                        &limit,
+                        format.clone(),
+                    )
+                    .await?,
+                );
+            }
+            let rows = inline_transactions(&args.inline, format.delimiter);
+            records.extend(
+                read_records(std::io::Cursor::new(rows), &limit, inline_format.clone()).await?,
+            );
 
-    // Request the state of account balances
-    let (resp_tx, resp_rx) = oneshot::channel();
-    tx.send(engine::server::Command::GetAccountsState(resp_tx))
-        .await?;
-    let result = resp_rx.await?;
+            let mut output = Vec::new();
+            output::write_all(
+                &mut CsvSink::new(&mut output).with_scale(args.scale),
+                &result,
+            )
+            .await?;
+            let interest = args.accrue_interest.zip(args.interest_tx);
+            round_trip_check(&output, &records, config, interest).await?;
+            tracing::info!("round-trip check passed");
+        }
+        if args.suppress_zero {
+            suppress_zero_balances(&mut result);
+        }
+        if let Some(since) = args.since {
+            retain_changed_since(&mut result, since);
+        }
+        if args.output_order == OutputOrder::FirstSeen {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(engine::server::Command::GetClientOrder(resp_tx))
+                .await?;
+            sort_first_seen(&mut result, &resp_rx.await?);
+        }
 
-    // Fetch account records from engine state and process them fully and in order as there is not
-    // use-case for partial results at this point.
-    // Could be an optimization  for another day. Maybe.
-    let mut wri = csv_async::AsyncSerializer::from_writer(tokio::io::stdout());
-    for account_record in result {
-        wri.serialize(account_record).await?;
+        // Accounts are collected first, as the round-trip check, the output order and the sinks
+        // other than standard output need all of them; `--stream` skips this for plain CSV output.
+        let (files, stdout_output) = match (args.sqlite, args.max_output_rows) {
+            (Some(path), _) => {
+                let mut sink = SqliteSink::new(&path).with_scale(args.scale);
+                output::write_all(&mut sink, &result).await?;
+                (vec![path], None)
+            }
+            (None, Some(max_rows)) => {
+                let dir = std::path::Path::new(".");
+                let files = write_accounts_split(
+                    dir,
+                    &result,
+                    max_rows,
+                    args.tags,
+                    args.last_tx_id,
+                    args.scale,
+                )
+                .await?;
+                (files, None)
+            }
+            (None, None) => {
+                let mut stdout = manifest::HashWriter::new(tokio::io::stdout());
+                match args.output_format {
+                    OutputFormat::Csv => {
+                        let mut sink = CsvSink::new(&mut stdout)
+                            .with_scale(args.scale)
+                            .with_tag(args.tags)
+                            .with_last_tx_id(args.last_tx_id);
+                        output::write_all(&mut sink, &result).await?;
+                    }
+                    OutputFormat::Jsonl => {
+                        let mut sink = JsonSink::new(&mut stdout)
+                            .with_scale(args.scale)
+                            .with_tag(args.tags);
+                        output::write_all(&mut sink, &result).await?;
+                    }
+                }
+                (Vec::new(), Some(stdout_hash(&stdout)))
+            }
+        };
+        (result.len(), files, stdout_output)
+    };
+    if args.assert_balanced {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetLedger(resp_tx)).await?;
+        check_ledger(resp_rx.await?)?;
+    }
+    if args.verify {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::VerifyInvariants(resp_tx))
+            .await?;
+        resp_rx
+            .await?
+            .map_err(|e| format!("invariant check failed: {e}"))?;
     }
-    wri.flush().await?;
+    if args.check_balances {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetClientOrder(resp_tx))
+            .await?;
+        for client in resp_rx.await? {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(engine::server::Command::CheckBalance {
+                client,
+                repair: false,
+                resp: resp_tx,
+            })
+            .await?;
+            if let Some(drift) = resp_rx.await? {
+                return Err(match drift.recomputed {
+                    Some(recomputed) => format!(
+                        "account of client {client} drifted from its history: total {} instead \
+                         of {}",
+                        drift.stored.total(),
+                        recomputed.total()
+                    ),
+                    None => format!("history of client {client} does not add up to an account"),
+                }
+                .into());
+            }
+        }
+    }
+    let (resp_tx, resp_rx) = oneshot::channel();
+    tx.send(engine::server::Command::GetMetrics(resp_tx))
+        .await?;
+    let metrics = resp_rx.await?;
     token.cancel();
-    engine_handle.await?;
+    let summary = engine_handle.await?;
+    if let Some(handle) = rejects_handle {
+        handle.await??;
+    }
+    if let (Some(path), Some(applied)) = (&args.applied_ids, &applied) {
+        tokio::fs::write(path, applied.to_json()?).await?;
+    }
+    if let Some(addr) = &args.statsd_addr {
+        // Metrics are best effort, they never fail the run
+        let sent = match statsd::StatsdClient::connect(addr.as_str()).await {
+            Ok(client) => client.send_summary(&summary).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            tracing::warn!(addr, "unable to send metrics to StatsD, err: {}", e);
+        }
+    }
+    if let Some(path) = &args.manifest {
+        let input_hashes = inputs
+            .iter()
+            .zip(digests.iter().flatten())
+            .map(|(input, digest)| manifest::FileHash::of_read(input, digest))
+            .collect();
+        let mut outputs = stdout_output.into_iter().collect::<Vec<_>>();
+        for file in &output_files {
+            outputs.push(manifest::FileHash::of_file(file).await?);
+        }
+        let records = manifest::Records {
+            read: records_read as u64,
+            processed: summary.processed,
+            rejected: summary.rejected,
+            skipped: summary.skipped,
+        };
+        let manifest = manifest::Manifest::new(input_hashes, records, accounts_written, outputs);
+        tokio::fs::write(path, serde_json::to_vec_pretty(&manifest)?).await?;
+    }
+    eprint!("{metrics}");
+    if summary.persistence_failures > 0 {
+        return Err(format!(
+            "{} account updates could not be persisted",
+            summary.persistence_failures
+        )
+        .into());
+    }
+    if args.strict && summary.rejected > 0 {
+        return Err(format!(
+            "{} of {} transactions were rejected",
+            summary.rejected,
+            summary.processed + summary.rejected + summary.skipped
+        )
+        .into());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Schema;
+    use crate::model::account::Account;
+    use crate::model::amount::Amount;
+    use crate::model::transaction::{TransactionRecord, TransactionType};
+
+    #[test]
+    fn test_color_choice() {
+        assert!(ColorChoice::Auto.use_ansi(true, None));
+        assert!(ColorChoice::Auto.use_ansi(true, Some("".into())));
+        assert!(!ColorChoice::Auto.use_ansi(true, Some("1".into())));
+        assert!(!ColorChoice::Auto.use_ansi(false, None));
+        assert!(ColorChoice::Always.use_ansi(false, Some("1".into())));
+        assert!(!ColorChoice::Never.use_ansi(true, None));
+    }
+
+    #[test]
+    fn test_log_colors() {
+        /// Log writer capturing everything written into a shared buffer.
+        #[derive(Clone, Default)]
+        struct Buffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for (ansi, colored) in [(false, false), (true, true)] {
+            let buffer = Buffer::default();
+            let writer = buffer.clone();
+            tracing::subscriber::with_default(log_subscriber(ansi, move || writer.clone()), || {
+                tracing::error!("something went wrong");
+            });
+
+            let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+            assert!(output.contains("something went wrong"));
+            assert_eq!(output.contains('\x1b'), colored);
+        }
+    }
+
+    #[test]
+    fn test_supported_types() {
+        let types = supported_types();
+
+        assert_eq!(types.lines().count(), 7);
+        for name in [
+            "deposit",
+            "withdrawal",
+            "dispute",
+            "resolve",
+            "chargeback",
+            "transfer",
+        ] {
+            assert!(types.lines().any(|line| line.starts_with(name)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limit() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,2.0
+deposit,1,3,3.0
+deposit,1,4,4.0
+deposit,1,5,5.0
+";
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+
+        assert_eq!(
+            send_records(
+                data.as_bytes(),
+                &tx,
+                &input::Limit::new(Some(2)),
+                InputFormat::default()
+            )
+            .await
+            .unwrap(),
+            2
+        );
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetAccountsState(resp_tx))
+            .await
+            .unwrap();
+        let result = resp_rx.await.unwrap();
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].total(), Amount::from_f64(3.0).unwrap());
+        assert_eq!(result[0].available(), Amount::from_f64(3.0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_parsing_overlaps_processing() {
+        use tokio::io::AsyncWriteExt;
+
+        let timeout = std::time::Duration::from_secs(5);
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+        let send = tokio::spawn({
+            let tx = tx.clone();
+            async move {
+                send_records(
+                    reader,
+                    &tx,
+                    &input::Limit::default(),
+                    InputFormat::default(),
+                )
+                .await
+                .unwrap()
+            }
+        });
+        let total = |tx: mpsc::Sender<engine::server::Command>| async move {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            tx.send(engine::server::Command::GetAccountsState(resp_tx))
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().first().map(Account::total)
+        };
+
+        writer
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\n")
+            .await
+            .unwrap();
+        // The engine applies the first record while the rest of the file is still to be parsed
+        tokio::time::timeout(timeout, async {
+            while total(tx.clone()).await != Amount::from_f64(1.0) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!send.is_finished());
+
+        writer.write_all(b"deposit,1,2,2.0\n").await.unwrap();
+        drop(writer);
+        assert_eq!(send.await.unwrap(), 2);
+        assert_eq!(total(tx).await, Amount::from_f64(3.0));
+
+        // Records are parsed ahead while the engine does not accept any, well beyond the capacity
+        // of the pipe
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let (tx, _rx) = mpsc::channel(1);
+        let send = tokio::spawn(async move {
+            send_records(
+                reader,
+                &tx,
+                &input::Limit::default(),
+                InputFormat::default(),
+            )
+            .await
+        });
+        tokio::time::timeout(timeout, async {
+            writer.write_all(b"type,client,tx,amount\n").await.unwrap();
+            for id in 0..200 {
+                writer
+                    .write_all(format!("deposit,1,{id},1.0\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!send.is_finished());
+        send.abort();
+    }
+
+    #[tokio::test]
+    async fn test_inline() {
+        let rows = inline_transactions(
+            &[
+                "deposit,1,1,5.0".to_string(),
+                "withdrawal,1,2,2.0".to_string(),
+            ],
+            b',',
+        );
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+
+        assert_eq!(
+            send_records(
+                std::io::Cursor::new(rows),
+                &tx,
+                &input::Limit::default(),
+                InputFormat::default()
+            )
+            .await
+            .unwrap(),
+            2
+        );
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetAccountsState(resp_tx))
+            .await
+            .unwrap();
+        let result = resp_rx.await.unwrap();
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].total(), Amount::from_f64(3.0).unwrap());
+        assert_eq!(result[0].available(), Amount::from_f64(3.0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_decimal_comma() {
+        let data = "type;client;tx;amount
+deposit;1;1;12,34
+withdrawal;1;2;0,3
+deposit;2;3;5
+dispute;2;3;
+";
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+        let format = InputFormat {
+            delimiter: b';',
+            decimal_separator: DecimalSeparator::Comma,
+            ..InputFormat::default()
+        };
+
+        assert_eq!(
+            send_records(data.as_bytes(), &tx, &input::Limit::default(), format)
+                .await
+                .unwrap(),
+            4
+        );
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetAccountsState(resp_tx))
+            .await
+            .unwrap();
+        let result = resp_rx.await.unwrap();
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].total(), Amount::from_f64(12.04).unwrap());
+        assert_eq!(result[1].total(), Amount::from_f64(5.0).unwrap());
+        assert_eq!(result[1].held(), Amount::from_f64(5.0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_max_record_bytes() {
+        let data = format!(
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,{}\n",
+            "9".repeat(1 << 20)
+        );
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+        let format = InputFormat {
+            max_record_bytes: Some(64),
+            ..InputFormat::default()
+        };
+
+        let err = send_records(
+            std::io::Cursor::new(data),
+            &tx,
+            &input::Limit::default(),
+            format.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("line 3 is longer than 64 bytes"));
+
+        // Records up to the limit are read as usual
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let format = InputFormat {
+            max_record_bytes: Some(24),
+            ..InputFormat::default()
+        };
+        assert_eq!(
+            send_records(data.as_bytes(), &tx, &input::Limit::default(), format)
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_schema() {
+        let data = "Reference,Kind,Value,Notes,CustomerId
+1,deposit,10.0,first,1
+2,withdrawal,2.5,,1
+3,deposit,1.0,other client,2
+";
+        let schema: Schema = serde_json::from_str(
+            r#"{"type": "Kind", "client": "CustomerId", "tx": "Reference", "amount": "Value"}"#,
+        )
+        .unwrap();
+        let format = InputFormat {
+            schema: Some(std::sync::Arc::new(schema)),
+            ..InputFormat::default()
+        };
+
+        let records = read_records(data.as_bytes(), &input::Limit::default(), format)
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.key())
+                .collect::<Vec<_>>(),
+            vec![
+                model::transaction::TxKey { client: 1, tx: 1 },
+                model::transaction::TxKey { client: 1, tx: 2 },
+                model::transaction::TxKey { client: 2, tx: 3 },
+            ]
+        );
+        assert_eq!(
+            records[1].transaction_type,
+            model::transaction::TransactionType::Withdrawal
+        );
+        assert_eq!(records[1].amount, Amount::from_f64(2.5).map(Ok));
+
+        // Unmapped fields keep their default column name
+        let data = "type,customer,tx,amount\ndeposit,7,1,1.0\n";
+        let schema: Schema = serde_json::from_str(r#"{"client": "customer"}"#).unwrap();
+        let format = InputFormat {
+            schema: Some(std::sync::Arc::new(schema)),
+            ..InputFormat::default()
+        };
+        let records = read_records(data.as_bytes(), &input::Limit::default(), format)
+            .await
+            .unwrap();
+        assert_eq!(records[0].client, 7);
+
+        assert!(serde_json::from_str::<Schema>(r#"{"kind": "type"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_check() {
+        let data = "type,client,tx,amount
+deposit,1,1,1.23456
+deposit,2,2,2.0
+withdrawal,1,3,0.5
+deposit,3,4,3.0
+dispute,3,4,
+";
+        let records = read_records(
+            data.as_bytes(),
+            &input::Limit::default(),
+            InputFormat::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(records.len(), 5);
+        let config = engine::state::Config::default();
+        let accounts = engine::process(&records).await;
+
+        let mut output = Vec::new();
+        output::write_all(&mut CsvSink::new(&mut output), &accounts)
+            .await
+            .unwrap();
+        round_trip_check(&output, &records, config, None)
+            .await
+            .unwrap();
+
+        // Serialization dropping digits is caught
+        let lossy = String::from_utf8(output).unwrap().replace("0.7346", "0.73");
+        let err = round_trip_check(lossy.as_bytes(), &records, config, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("round-trip check failed"));
+
+        // So are missing accounts
+        let truncated = "client,available,held,total,locked\n";
+        assert!(
+            round_trip_check(truncated.as_bytes(), &records, config, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shards() {
+        let first = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+withdrawal,1,3,0.5
+";
+        let second = "type,client,tx,amount
+deposit,3,4,3.0
+deposit,4,5,4.0
+dispute,3,4,
+";
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+
+        assert_eq!(
+            send_records_concurrently(
+                vec![first.as_bytes(), second.as_bytes()],
+                &tx,
+                &input::Limit::default(),
+                InputFormat::default(),
+            )
+            .await
+            .unwrap(),
+            6
+        );
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetAccountsState(resp_tx))
+            .await
+            .unwrap();
+        let result = resp_rx.await.unwrap();
+
+        assert_eq!(
+            result.iter().map(|acc| acc.id()).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(result[0].total(), Amount::from_f64(0.5).unwrap());
+        assert_eq!(result[1].total(), Amount::from_f64(2.0).unwrap());
+        assert_eq!(result[2].held(), Amount::from_f64(3.0).unwrap());
+        assert_eq!(result[2].available(), Amount::ZERO);
+        assert_eq!(result[3].total(), Amount::from_f64(4.0).unwrap());
+    }
+
+    #[test]
+    fn test_suppress_zero_balances() {
+        let one = Amount::from_f64(1.0).unwrap();
+        let mut zeroed = Account::new(1);
+        zeroed.deposit(one).unwrap();
+        zeroed.withdrawal(one).unwrap();
+        let mut locked = Account::new(2);
+        locked.deposit(one).unwrap();
+        locked.dispute(one).unwrap();
+        locked.charge_back(one).unwrap();
+        let mut funded = Account::new(3);
+        funded.deposit(one).unwrap();
+        let mut accounts = vec![zeroed, locked, funded, Account::new(4)];
+
+        suppress_zero_balances(&mut accounts);
+
+        assert_eq!(
+            accounts.iter().map(|acc| acc.id()).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(accounts[0].total(), Amount::ZERO);
+        assert!(accounts[0].locked());
+    }
+
+    #[tokio::test]
+    async fn test_write_accounts_split() {
+        let dir = std::env::temp_dir().join(format!(
+            "transaction-processing-split-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let accounts = (1..=5).map(Account::new).collect::<Vec<_>>();
+
+        let paths = write_accounts_split(&dir, &accounts, 2.try_into().unwrap(), false, false, 4)
+            .await
+            .unwrap();
+
+        assert_eq!(paths.len(), 3);
+        let mut clients = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            assert_eq!(path, &dir.join(format!("accounts.{i:03}.csv")));
+            let content = std::fs::read_to_string(path).unwrap();
+            let mut lines = content.lines();
+            assert_eq!(lines.next(), Some("client,available,held,total,locked"));
+            let rows = lines.collect::<Vec<_>>();
+            assert!(!rows.is_empty() && rows.len() <= 2);
+            clients.extend(
+                rows.iter()
+                    .map(|row| row.split(',').next().unwrap().to_string()),
+            );
+        }
+        assert_eq!(clients, vec!["1", "2", "3", "4", "5"]);
+        assert!(!dir.join("accounts.003.csv").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_output_first_seen() {
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+        let data = "type,client,tx,amount\n\
+                    deposit,4,1,1.0\n\
+                    deposit,2,2,1.0\n\
+                    deposit,4,3,1.0\n\
+                    withdrawal,9,4,1.0\n\
+                    deposit,1,5,1.0\n";
+        send_records(
+            data.as_bytes(),
+            &tx,
+            &input::Limit::default(),
+            InputFormat::default(),
+        )
+        .await
+        .unwrap();
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetAccountsState(resp_tx))
+            .await
+            .unwrap();
+        let mut accounts = resp_rx.await.unwrap();
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetClientOrder(resp_tx))
+            .await
+            .unwrap();
+        sort_first_seen(&mut accounts, &resp_rx.await.unwrap());
+
+        let mut output = Vec::new();
+        output::write_all(&mut CsvSink::new(&mut output), &accounts)
+            .await
+            .unwrap();
+        let clients = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec!["4", "2", "9", "1"]);
+
+        // Unknown clients go last
+        let mut accounts = (1..=4).map(Account::new).collect::<Vec<_>>();
+        sort_first_seen(&mut accounts, &[3, 1]);
+        assert_eq!(
+            accounts.iter().map(|acc| acc.id()).collect::<Vec<_>>(),
+            vec![3, 1, 2, 4]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_accounts() {
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(engine::run(
+            rx,
+            engine::Options::default(),
+            CancellationToken::new(),
+        ));
+        let data = "type,client,tx,amount\n\
+                    deposit,3,1,1.0\n\
+                    deposit,1,2,2.0\n\
+                    withdrawal,1,3,2.0\n\
+                    deposit,2,4,3.0\n";
+        send_records(
+            data.as_bytes(),
+            &tx,
+            &input::Limit::default(),
+            InputFormat::default(),
+        )
+        .await
+        .unwrap();
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        tx.send(engine::server::Command::GetAccountsState(resp_tx))
+            .await
+            .unwrap();
+        let mut collected = Vec::new();
+        output::write_all(&mut CsvSink::new(&mut collected), &resp_rx.await.unwrap())
+            .await
+            .unwrap();
+        let mut streamed = Vec::new();
+        let count = stream_accounts(&tx, &mut CsvSink::new(&mut streamed), |_| true)
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            String::from_utf8(streamed).unwrap(),
+            String::from_utf8(collected).unwrap()
+        );
+
+        let mut streamed = Vec::new();
+        let count = stream_accounts(&tx, &mut CsvSink::new(&mut streamed), |account| {
+            !is_zero_balance(account) && changed_since(account, 1)
+        })
+        .await
+        .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            String::from_utf8(streamed).unwrap(),
+            "client,available,held,total,locked\n2,3,0,3,false\n"
+        );
+    }
+
+    #[test]
+    fn test_check_ledger() {
+        let amount = |amount| Amount::from_f64(amount).unwrap();
+        let mut ledger = engine::server::Ledger {
+            flows: engine::state::Flows {
+                opening: amount(1.0),
+                deposited: amount(10.0),
+                withdrawn: amount(3.0),
+                charged_back: amount(2.0),
+                reversed: amount(0.5),
+                interest: amount(0.25),
+            },
+            total: amount(6.75),
+        };
+        check_ledger(Some(ledger)).unwrap();
+
+        ledger.total = amount(7.0);
+        assert_eq!(
+            check_ledger(Some(ledger)).unwrap_err(),
+            "balance check failed: accounts add up to 7, but opening 1 + deposited 10 - withdrawn 3 \
+             - charged back 2 + reversed withdrawals 0.5 + interest 0.25 = 6.75"
+        );
+        assert!(check_ledger(None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_duplicates() {
+        let format = InputFormat::default();
+        let mut seen = std::collections::HashSet::new();
+        let data = "type,client,tx,amount\n\
+                    deposit,1,1,1.0\n\
+                    deposit,2,1,1.0\n\
+                    dispute,1,1,\n\
+                    withdrawal,1,2,1.0\n";
+        check_duplicates(
+            data.as_bytes(),
+            &mut seen,
+            &input::Limit::default(),
+            &format,
+        )
+        .await
+        .unwrap();
+        assert_eq!(seen.len(), 3);
+
+        // Against the ids of previous inputs as well
+        let data = "type,client,tx,amount\n\
+                    deposit,3,1,1.0\n\
+                    withdrawal,1,2,1.0\n\
+                    deposit,1,1,1.0\n";
+        let err = check_duplicates(
+            data.as_bytes(),
+            &mut seen,
+            &input::Limit::default(),
+            &format,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "duplicate transaction id (client 1 tx 2), rejecting the whole batch"
+        );
+
+        // Records beyond the limit are not checked
+        let mut seen = std::collections::HashSet::new();
+        check_duplicates(
+            data.as_bytes(),
+            &mut seen,
+            &input::Limit::new(Some(1)),
+            &format,
+        )
+        .await
+        .unwrap();
+        check_duplicates(
+            data.as_bytes(),
+            &mut seen,
+            &input::Limit::new(Some(1)),
+            &format,
+        )
+        .await
+        .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_retain_changed_since() {
+        let record = |transaction_type, client, id, amount: Option<f64>| TransactionRecord {
+            transaction_type,
+            client,
+            id,
+            amount: amount.and_then(Amount::from_f64).map(Ok),
+            tag: None,
+            counterparty: None,
+        };
+        let mut accounts = engine::process(&[
+            record(TransactionType::Deposit, 1, 1, Some(5.0)),
+            record(TransactionType::Deposit, 2, 2, Some(5.0)),
+            record(TransactionType::Deposit, 3, 3, Some(5.0)),
+            // Only deposits and withdrawals count as changes
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Withdrawal, 2, 4, Some(1.0)),
+            record(TransactionType::Deposit, 3, 5, Some(1.0)),
+        ])
+        .await;
+
+        retain_changed_since(&mut accounts, 3);
+
+        assert_eq!(
+            accounts.iter().map(|acc| acc.id()).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(accounts[0].last_tx_id(), Some(4));
+        assert_eq!(accounts[1].last_tx_id(), Some(5));
+
+        retain_changed_since(&mut accounts, 5);
+        assert!(accounts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_last_tx_id() {
+        let record = |transaction_type, client, id, amount: Option<f64>| TransactionRecord {
+            transaction_type,
+            client,
+            id,
+            amount: amount.and_then(Amount::from_f64).map(Ok),
+            tag: None,
+            counterparty: None,
+        };
+        let accounts = engine::process(&[
+            record(TransactionType::Deposit, 1, 1, Some(5.0)),
+            record(TransactionType::Withdrawal, 1, 2, Some(1.0)),
+            record(TransactionType::Withdrawal, 1, 3, Some(10.0)),
+            record(TransactionType::Deposit, 2, 4, None),
+        ])
+        .await;
+
+        let mut output = Vec::new();
+        output::write_all(
+            &mut CsvSink::new(&mut output).with_last_tx_id(true),
+            &accounts,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_tx_id\n\
+             1,4,0,4,false,2\n\
+             2,0,0,0,false,\n"
+        );
+    }
+}
@@ -0,0 +1,264 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use sha2::{Digest as _, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+/// File read or written by a run, along with the SHA-256 of its content.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct FileHash {
+    /// Path of the file, `-` for standard output.
+    pub path: PathBuf,
+    /// SHA-256 of the content, as lowercase hexadecimal.
+    pub sha256: String,
+}
+
+impl FileHash {
+    /// Hash the file at `path`, reading it in chunks.
+    pub async fn of_file(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            sha256: hash_reader(tokio::fs::File::open(path).await?).await?,
+        })
+    }
+
+    /// Hash of the file at `path` from what was read of it through a `HashReader`, e.g. for the
+    /// standard input or a file which may change once read.
+    pub fn of_read(path: &Path, digest: &Digest) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            sha256: digest.sha256(),
+        }
+    }
+}
+
+/// Number of transaction records of a run.
+#[derive(serde::Serialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Records {
+    /// Records read from the inputs and sent to the engine.
+    pub read: u64,
+    /// Transactions successfully applied.
+    pub processed: u64,
+    /// Transactions rejected.
+    pub rejected: u64,
+    /// Transactions skipped as already applied, see `--applied-ids`.
+    pub skipped: u64,
+}
+
+/// Provenance of a run: what went in, what came out and which version produced it.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// Version of the engine.
+    pub version: &'static str,
+    /// End of the run, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Transactions files read.
+    pub inputs: Vec<FileHash>,
+    /// Transaction records read and executed.
+    pub records: Records,
+    /// Number of accounts written.
+    pub accounts: usize,
+    /// Files the accounts were written into.
+    pub outputs: Vec<FileHash>,
+}
+
+impl Manifest {
+    /// Create a manifest for a run ending now.
+    pub fn new(
+        inputs: Vec<FileHash>,
+        records: Records,
+        accounts: usize,
+        outputs: Vec<FileHash>,
+    ) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            inputs,
+            records,
+            accounts,
+            outputs,
+        }
+    }
+}
+
+/// Lowercase hexadecimal of the SHA-256 computed by `hasher`.
+fn hex(hasher: Sha256) -> String {
+    format!("{:x}", hasher.finalize())
+}
+
+/// SHA-256 of everything read from `reader`, as lowercase hexadecimal.
+pub async fn hash_reader<R: AsyncRead + Unpin>(mut reader: R) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf).await? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+
+    Ok(hex(hasher))
+}
+
+/// SHA-256 of everything read through a `HashReader`, shared with it so that the hash is still
+/// available once the reader moved away, e.g. into the task parsing it.
+#[derive(Clone, Default)]
+pub struct Digest(std::sync::Arc<std::sync::Mutex<Sha256>>);
+
+impl Digest {
+    /// SHA-256 of everything read so far, as lowercase hexadecimal.
+    pub fn sha256(&self) -> String {
+        hex(self.0.lock().unwrap().clone())
+    }
+}
+
+/// Reader hashing everything read through it into a `Digest`.
+pub struct HashReader<R> {
+    inner: R,
+    digest: Digest,
+}
+
+impl<R> HashReader<R> {
+    /// Create a reader hashing what is read from `inner` into `digest`.
+    pub fn new(inner: R, digest: Digest) -> Self {
+        Self { inner, digest }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashReader<R> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.digest
+            .0
+            .lock()
+            .unwrap()
+            .update(&buf.filled()[filled..]);
+
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Writer hashing everything written through it.
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashWriter<W> {
+    /// Create a writer hashing what is written into `inner`.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// SHA-256 of everything written so far, as lowercase hexadecimal.
+    pub fn sha256(&self) -> String {
+        hex(self.hasher.clone())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashWriter<W> {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let n = std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.hasher.update(&buf[..n]);
+
+        std::task::Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    const DATA: &[u8] = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+    const DATA_SHA256: &str = "68ac5d35be999f0f0e372a8a58d602e259f27cad650db4aa2755a37772558129";
+
+    #[tokio::test]
+    async fn test_hash_writer() {
+        let mut writer = HashWriter::new(Vec::new());
+        writer.write_all(&DATA[..5]).await.unwrap();
+        writer.write_all(&DATA[5..]).await.unwrap();
+        assert_eq!(writer.sha256(), DATA_SHA256);
+        assert_eq!(writer.inner, DATA);
+        assert_eq!(hash_reader(DATA).await.unwrap(), DATA_SHA256);
+        assert_eq!(
+            hex(Sha256::new()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_reader() {
+        let digest = Digest::default();
+        let mut reader = HashReader::new(DATA, digest.clone());
+        let mut buf = [0; 7];
+        let mut read = Vec::new();
+        loop {
+            match reader.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => read.extend_from_slice(&buf[..n]),
+            }
+        }
+        drop(reader);
+
+        assert_eq!(read, DATA);
+        assert_eq!(digest.sha256(), DATA_SHA256);
+        assert_eq!(
+            FileHash::of_read(Path::new("-"), &digest),
+            FileHash {
+                path: "-".into(),
+                sha256: DATA_SHA256.to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_hash() {
+        let path = std::env::temp_dir().join(format!(
+            "transaction-processing-manifest-{}.csv",
+            std::process::id()
+        ));
+        tokio::fs::write(&path, DATA).await.unwrap();
+        let hash = FileHash::of_file(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(
+            hash,
+            FileHash {
+                path,
+                sha256: DATA_SHA256.to_string(),
+            }
+        );
+    }
+}
@@ -1,11 +1,12 @@
 #![deny(missing_docs)]
 #![deny(warnings)]
 
-use crate::model::amount::Amount;
-use serde::Serialize;
+use crate::model::amount::{Amount, Rounded};
+use crate::model::transaction::Id as TransactionId;
+use serde::{Deserialize, Serialize};
 
 /// Error conditions that may arise when creating a new `Account` objects.
-#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
 pub enum Error {
     #[error("Account balance overflow")]
     Overflow,
@@ -15,6 +16,12 @@ pub enum Error {
     InsufficientFunds,
     #[error("Account operation has invalid input")]
     InvalidInput,
+    #[error("Account is not locked")]
+    NotLocked,
+    #[error("Account total does not match available plus held funds")]
+    Unbalanced,
+    #[error("Account available funds would fall below the minimum balance")]
+    BelowMinimumBalance,
 }
 
 /// Result of account operations.
@@ -24,7 +31,14 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub type Id = u16;
 
 /// Used to express client account balances.
-#[derive(Copy, Clone, Default, Debug, Serialize, PartialEq)]
+///
+/// A locked account rejects deposits, withdrawals and new disputes, but still allows resolves and
+/// charge backs, so disputes opened before the account got locked can still be closed.
+///
+/// Deserializes from its own serialized form, e.g. a previous CSV output, checking that the total
+/// is the available plus held funds.
+#[derive(Clone, Default, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(try_from = "AccountRecord")]
 pub struct Account {
     #[serde(rename = "client")]
     id: Id,
@@ -32,6 +46,15 @@ pub struct Account {
     held: Amount,
     total: Amount,
     locked: bool,
+    tag: Option<String>,
+    #[serde(skip)]
+    last_tx_id: Option<TransactionId>,
+    /// How far below zero withdrawals may take the available and total funds (credit line).
+    #[serde(skip)]
+    overdraft_limit: Amount,
+    /// Floor withdrawals may not take the available funds below, if any.
+    #[serde(skip)]
+    min_balance: Option<Amount>,
 }
 
 impl Account {
@@ -42,9 +65,41 @@ impl Account {
             held: Amount::default(),
             total: Amount::default(),
             locked: false,
+            tag: None,
+            last_tx_id: None,
+            overdraft_limit: Amount::ZERO,
+            min_balance: None,
         }
     }
 
+    /// Create an account with the given balances, e.g. when rebuilding it from its history.
+    #[cfg(test)]
+    pub fn with_balances(
+        id: Id,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+        locked: bool,
+    ) -> Self {
+        Self {
+            id,
+            available,
+            held,
+            total,
+            locked,
+            tag: None,
+            last_tx_id: None,
+            overdraft_limit: Amount::ZERO,
+            min_balance: None,
+        }
+    }
+
+    /// Start building an account with the given fields, e.g. for test setup or when rebuilding it
+    /// from a snapshot, without going through a sequence of operations.
+    pub fn builder() -> AccountBuilder {
+        AccountBuilder::default()
+    }
+
     #[allow(dead_code)]
     pub fn available(&self) -> Amount {
         self.available
@@ -60,6 +115,39 @@ impl Account {
         self.total
     }
 
+    /// Funds the client can withdraw right now, excluding held funds and including the overdraft
+    /// limit.
+    ///
+    /// This is the single place deciding whether a withdrawal is covered.
+    pub fn available_for_withdrawal(&self) -> Amount {
+        self.available
+            .checked_add(self.overdraft_limit)
+            .unwrap_or(Amount::MAX)
+    }
+
+    /// How far below zero withdrawals may take the available and total funds.
+    pub fn overdraft_limit(&self) -> Amount {
+        self.overdraft_limit
+    }
+
+    /// Allow withdrawals to go `overdraft_limit` below zero, which must not be negative.
+    pub fn set_overdraft_limit(&mut self, overdraft_limit: Amount) -> Result<()> {
+        if overdraft_limit.is_negative() {
+            return Err(Error::InvalidInput);
+        }
+        self.overdraft_limit = overdraft_limit;
+        Ok(())
+    }
+
+    /// Floor withdrawals may not take the available funds below, if any.
+    pub fn min_balance(&self) -> Option<Amount> {
+        self.min_balance
+    }
+
+    pub fn set_min_balance(&mut self, min_balance: Option<Amount>) {
+        self.min_balance = min_balance;
+    }
+
     pub fn locked(&self) -> bool {
         self.locked
     }
@@ -68,13 +156,49 @@ impl Account {
         self.id
     }
 
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    pub fn set_tag(&mut self, tag: Option<String>) {
+        self.tag = tag;
+    }
+
+    /// Highest id of the deposits and withdrawals applied to the account, if any.
+    pub fn last_tx_id(&self) -> Option<TransactionId> {
+        self.last_tx_id
+    }
+
+    /// The account serialized with its amounts rounded to `dp` decimal places rather than
+    /// `Amount::PRECISION`, followed by its tag if `tag` is set.
+    pub fn rounded(&self, dp: u32, tag: bool) -> RoundedAccount<'_> {
+        RoundedAccount {
+            client: self.id,
+            available: Rounded(self.available, dp),
+            held: Rounded(self.held, dp),
+            total: Rounded(self.total, dp),
+            locked: self.locked,
+            tag: tag.then(|| self.tag()),
+        }
+    }
+
+    /// Raise `last_tx_id` to `id`, keeping it if it is already higher, e.g. for out of order
+    /// feeds.
+    pub fn raise_last_tx_id(&mut self, id: TransactionId) {
+        self.last_tx_id = self.last_tx_id.max(Some(id));
+    }
+
+    pub fn set_last_tx_id(&mut self, last_tx_id: Option<TransactionId>) {
+        self.last_tx_id = last_tx_id;
+    }
+
     #[allow(dead_code)]
     pub fn set_locked(&mut self, locked: bool) {
         self.locked = locked;
     }
 
     pub fn deposit(&mut self, amount: Amount) -> Result<()> {
-        if amount <= Amount::ZERO {
+        if !amount.is_positive() {
             return Err(Error::InvalidInput);
         }
 
@@ -90,7 +214,7 @@ impl Account {
 
     #[allow(dead_code)]
     pub fn dispute(&mut self, amount: Amount) -> Result<()> {
-        if amount <= Amount::ZERO {
+        if !amount.is_positive() {
             return Err(Error::InvalidInput);
         }
 
@@ -100,7 +224,7 @@ impl Account {
 
         let avail_diff = self.available.checked_sub(amount).ok_or(Error::Overflow)?;
 
-        if avail_diff < Amount::ZERO {
+        if avail_diff.is_negative() {
             return Err(Error::InsufficientFunds);
         }
 
@@ -111,7 +235,7 @@ impl Account {
     }
 
     pub fn withdrawal(&mut self, amount: Amount) -> Result<()> {
-        if amount <= Amount::ZERO {
+        if !amount.is_positive() {
             return Err(Error::InvalidInput);
         }
 
@@ -119,27 +243,42 @@ impl Account {
             return Err(Error::Locked);
         }
 
-        let avail_diff = self.available.checked_sub(amount).ok_or(Error::Overflow)?;
-
-        if avail_diff < Amount::ZERO {
+        if amount > self.available_for_withdrawal() {
             return Err(Error::InsufficientFunds);
         }
 
+        let avail_diff = self.available.checked_sub(amount).ok_or(Error::Overflow)?;
         let total_diff = self.total.checked_sub(amount).ok_or(Error::Overflow)?;
 
-        if total_diff < Amount::ZERO {
+        if total_diff < -self.overdraft_limit {
             return Err(Error::InsufficientFunds);
         }
 
+        if self.min_balance.is_some_and(|floor| avail_diff < floor) {
+            return Err(Error::BelowMinimumBalance);
+        }
+
         self.available = avail_diff;
         self.total = total_diff;
 
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn resolve(&mut self, amount: Amount) -> Result<()> {
-        if amount <= Amount::ZERO {
+    /// Reactivate a locked account, allowing all operations again.
+    pub fn unlock(&mut self) -> Result<()> {
+        if !self.locked() {
+            return Err(Error::NotLocked);
+        }
+
+        self.set_locked(false);
+
+        Ok(())
+    }
+
+    /// Hold `amount` of withdrawn funds disputed by the client, crediting them back to the total
+    /// without making them available until the dispute is settled.
+    pub fn dispute_withdrawal(&mut self, amount: Amount) -> Result<()> {
+        if !amount.is_positive() {
             return Err(Error::InvalidInput);
         }
 
@@ -147,37 +286,83 @@ impl Account {
             return Err(Error::Locked);
         }
 
+        let held = self.held.checked_add(amount).ok_or(Error::Overflow)?;
+        let total = self.total.checked_add(amount).ok_or(Error::Overflow)?;
+
+        self.held = held;
+        self.total = total;
+
+        Ok(())
+    }
+
+    /// Remove `amount` of held funds from the account, the disputed withdrawal standing.
+    pub fn resolve_withdrawal(&mut self, amount: Amount) -> Result<()> {
+        if !amount.is_positive() {
+            return Err(Error::InvalidInput);
+        }
+
         let held_diff = self.held.checked_sub(amount).ok_or(Error::Overflow)?;
 
-        if held_diff < Amount::ZERO {
+        if held_diff.is_negative() {
+            return Err(Error::InsufficientFunds);
+        }
+
+        let total_diff = self.total.checked_sub(amount).ok_or(Error::Overflow)?;
+
+        if total_diff.is_negative() {
             return Err(Error::InsufficientFunds);
         }
 
-        self.available = self.available.checked_add(amount).ok_or(Error::Overflow)?;
         self.held = held_diff;
+        self.total = total_diff;
 
         Ok(())
     }
 
+    /// Release `amount` of held funds back to the available funds, reversing the disputed
+    /// withdrawal, and lock the account.
+    pub fn charge_back_withdrawal(&mut self, amount: Amount) -> Result<()> {
+        self.resolve(amount)?;
+        self.set_locked(true);
+
+        Ok(())
+    }
+
+    /// Release `amount` of held funds back to the available funds.
     #[allow(dead_code)]
-    pub fn charge_back(&mut self, amount: Amount) -> Result<()> {
-        if amount <= Amount::ZERO {
+    pub fn resolve(&mut self, amount: Amount) -> Result<()> {
+        if !amount.is_positive() {
             return Err(Error::InvalidInput);
         }
 
-        if self.locked() {
-            return Err(Error::Locked);
+        let held_diff = self.held.checked_sub(amount).ok_or(Error::Overflow)?;
+
+        if held_diff.is_negative() {
+            return Err(Error::InsufficientFunds);
+        }
+
+        self.available = self.available.checked_add(amount).ok_or(Error::Overflow)?;
+        self.held = held_diff;
+
+        Ok(())
+    }
+
+    /// Remove `amount` of held funds from the account and lock it.
+    #[allow(dead_code)]
+    pub fn charge_back(&mut self, amount: Amount) -> Result<()> {
+        if !amount.is_positive() {
+            return Err(Error::InvalidInput);
         }
 
         let held_diff = self.held.checked_sub(amount).ok_or(Error::Overflow)?;
 
-        if held_diff < Amount::ZERO {
+        if held_diff.is_negative() {
             return Err(Error::InsufficientFunds);
         }
 
         let total_diff = self.total.checked_sub(amount).ok_or(Error::Overflow)?;
 
-        if total_diff < Amount::ZERO {
+        if total_diff.is_negative() {
             return Err(Error::InsufficientFunds);
         }
 
@@ -189,6 +374,128 @@ impl Account {
     }
 }
 
+/// Serialized form of an `Account` with its amounts rounded, see `Account::rounded`.
+#[derive(Serialize)]
+pub struct RoundedAccount<'a> {
+    client: Id,
+    available: Rounded,
+    held: Rounded,
+    total: Rounded,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<Option<&'a str>>,
+}
+
+/// Serialized form of an `Account`, validated into one by `TryFrom`.
+///
+/// Amounts are read as text, so no digit is lost to CSV inferring them to be floating point.
+#[derive(Deserialize)]
+struct AccountRecord {
+    client: Id,
+    #[serde(deserialize_with = "crate::model::amount::exact::deserialize")]
+    available: Amount,
+    #[serde(deserialize_with = "crate::model::amount::exact::deserialize")]
+    held: Amount,
+    #[serde(deserialize_with = "crate::model::amount::exact::deserialize")]
+    total: Amount,
+    locked: bool,
+    #[serde(default)]
+    tag: Option<String>,
+    #[serde(default)]
+    last_tx_id: Option<TransactionId>,
+}
+
+impl TryFrom<AccountRecord> for Account {
+    type Error = Error;
+
+    fn try_from(record: AccountRecord) -> Result<Self> {
+        Account::builder()
+            .id(record.client)
+            .available(record.available)
+            .held(record.held)
+            .total(record.total)
+            .locked(record.locked)
+            .tag(record.tag.filter(|tag| !tag.is_empty()))
+            .last_tx_id(record.last_tx_id)
+            .build()
+    }
+}
+
+/// Builder of an `Account`, see `Account::builder`.
+///
+/// Balances default to zero; the total defaults to the available plus held funds.
+#[derive(Clone, Default, Debug)]
+pub struct AccountBuilder {
+    account: Account,
+    total: Option<Amount>,
+}
+
+impl AccountBuilder {
+    pub fn id(mut self, id: Id) -> Self {
+        self.account.id = id;
+        self
+    }
+
+    pub fn available(mut self, available: Amount) -> Self {
+        self.account.available = available;
+        self
+    }
+
+    pub fn held(mut self, held: Amount) -> Self {
+        self.account.held = held;
+        self
+    }
+
+    pub fn total(mut self, total: Amount) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    pub fn locked(mut self, locked: bool) -> Self {
+        self.account.locked = locked;
+        self
+    }
+
+    pub fn tag(mut self, tag: Option<String>) -> Self {
+        self.account.tag = tag;
+        self
+    }
+
+    pub fn last_tx_id(mut self, last_tx_id: Option<TransactionId>) -> Self {
+        self.account.last_tx_id = last_tx_id;
+        self
+    }
+
+    pub fn overdraft_limit(mut self, overdraft_limit: Amount) -> Self {
+        self.account.overdraft_limit = overdraft_limit;
+        self
+    }
+
+    pub fn min_balance(mut self, min_balance: Option<Amount>) -> Self {
+        self.account.min_balance = min_balance;
+        self
+    }
+
+    /// Build the account, checking that its total is the available plus held funds and that its
+    /// overdraft limit is not negative.
+    pub fn build(self) -> Result<Account> {
+        let mut account = self.account;
+        if account.overdraft_limit.is_negative() {
+            return Err(Error::InvalidInput);
+        }
+        let sum = account
+            .available
+            .checked_add(account.held)
+            .ok_or(Error::Overflow)?;
+        if self.total.is_some_and(|total| total != sum) {
+            return Err(Error::Unbalanced);
+        }
+        account.total = sum;
+
+        Ok(account)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,6 +509,7 @@ mod tests {
         assert_eq!(account.total(), Amount::ZERO);
         assert_eq!(account.held(), Amount::ZERO);
         assert!(!account.locked());
+        assert_eq!(account.tag(), None);
     }
 
     #[test]
@@ -262,9 +570,241 @@ mod tests {
 
         assert!(account.deposit(Amount::MAX).unwrap_err() == Error::Locked);
         assert!(account.dispute(Amount::MAX).unwrap_err() == Error::Locked);
-        assert!(account.resolve(Amount::MAX).unwrap_err() == Error::Locked);
         assert!(account.withdrawal(Amount::MAX).unwrap_err() == Error::Locked);
-        assert!(account.charge_back(Amount::MAX).unwrap_err() == Error::Locked);
+        // Closing disputes is not blocked by the lock, only by the funds held
+        assert!(account.resolve(Amount::MAX).unwrap_err() == Error::InsufficientFunds);
+        assert!(account.charge_back(Amount::MAX).unwrap_err() == Error::InsufficientFunds);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_ops() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let mut account = Account::new(1);
+        account.deposit(amount(10)).unwrap();
+        account.withdrawal(amount(4)).unwrap();
+
+        account.dispute_withdrawal(amount(4)).unwrap();
+        assert_eq!(account.available(), amount(6));
+        assert_eq!(account.held(), amount(4));
+        assert_eq!(account.total(), amount(10));
+        // The withdrawn funds are not available until the dispute is settled
+        assert_eq!(
+            account.withdrawal(amount(7)).unwrap_err(),
+            Error::InsufficientFunds
+        );
+
+        account.resolve_withdrawal(amount(4)).unwrap();
+        assert_eq!(account.available(), amount(6));
+        assert_eq!(account.held(), Amount::ZERO);
+        assert_eq!(account.total(), amount(6));
+        assert!(!account.locked());
+        assert_eq!(
+            account.resolve_withdrawal(amount(4)).unwrap_err(),
+            Error::InsufficientFunds
+        );
+
+        account.dispute_withdrawal(amount(4)).unwrap();
+        account.charge_back_withdrawal(amount(4)).unwrap();
+        assert_eq!(account.available(), amount(10));
+        assert_eq!(account.held(), Amount::ZERO);
+        assert_eq!(account.total(), amount(10));
+        assert!(account.locked());
+
+        assert_eq!(
+            account.dispute_withdrawal(amount(1)).unwrap_err(),
+            Error::Locked
+        );
+        assert_eq!(
+            account.dispute_withdrawal(Amount::ZERO).unwrap_err(),
+            Error::InvalidInput
+        );
+        assert_eq!(
+            account.resolve_withdrawal(Amount::ZERO).unwrap_err(),
+            Error::InvalidInput
+        );
+        assert_eq!(
+            account.charge_back_withdrawal(Amount::ZERO).unwrap_err(),
+            Error::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_unlock() {
+        let one = Amount::from_int(1).unwrap();
+        let mut account = Account::new(1);
+        assert_eq!(account.unlock().unwrap_err(), Error::NotLocked);
+
+        account.deposit(one).unwrap();
+        account.dispute(one).unwrap();
+        account.charge_back(one).unwrap();
+        assert_eq!(account.deposit(one).unwrap_err(), Error::Locked);
+
+        account.unlock().unwrap();
+        assert!(!account.locked());
+        account.deposit(one).unwrap();
+        assert_eq!(account.total(), one);
+        assert_eq!(account.unlock().unwrap_err(), Error::NotLocked);
+    }
+
+    #[test]
+    fn test_available_for_withdrawal() {
+        let mut account = Account::new(1);
+        assert_eq!(account.available_for_withdrawal(), Amount::ZERO);
+
+        account.deposit(Amount::from_f64(10.0).unwrap()).unwrap();
+        assert_eq!(
+            account.available_for_withdrawal(),
+            Amount::from_f64(10.0).unwrap()
+        );
+
+        // Held funds cannot be withdrawn
+        account.dispute(Amount::from_f64(4.0).unwrap()).unwrap();
+        assert_eq!(
+            account.available_for_withdrawal(),
+            Amount::from_f64(6.0).unwrap()
+        );
+        assert_eq!(
+            account
+                .withdrawal(Amount::from_f64(7.0).unwrap())
+                .unwrap_err(),
+            Error::InsufficientFunds
+        );
+        account.withdrawal(Amount::from_f64(6.0).unwrap()).unwrap();
+        assert_eq!(account.available_for_withdrawal(), Amount::ZERO);
+        assert_eq!(account.total(), Amount::from_f64(4.0).unwrap());
+    }
+
+    #[test]
+    fn test_builder() {
+        let amount = |n| Amount::from_int(n).unwrap();
+
+        let account = Account::builder()
+            .id(7)
+            .available(amount(5))
+            .held(amount(2))
+            .total(amount(7))
+            .locked(true)
+            .tag(Some("eu".to_string()))
+            .last_tx_id(Some(3))
+            .build()
+            .unwrap();
+        assert_eq!(account.id(), 7);
+        assert_eq!(account.available(), amount(5));
+        assert_eq!(account.held(), amount(2));
+        assert_eq!(account.total(), amount(7));
+        assert!(account.locked());
+        assert_eq!(account.tag(), Some("eu"));
+        assert_eq!(account.last_tx_id(), Some(3));
+
+        // The total defaults to the available plus held funds
+        let mut expected = Account::new(1);
+        expected.deposit(amount(5)).unwrap();
+        expected.dispute(amount(2)).unwrap();
+        assert_eq!(
+            Account::builder()
+                .id(1)
+                .available(amount(3))
+                .held(amount(2))
+                .build(),
+            Ok(expected)
+        );
+        assert_eq!(Account::builder().build(), Ok(Account::default()));
+    }
+
+    #[test]
+    fn test_builder_unbalanced() {
+        let amount = |n| Amount::from_int(n).unwrap();
+
+        assert_eq!(
+            Account::builder()
+                .available(amount(5))
+                .held(amount(2))
+                .total(amount(8))
+                .build(),
+            Err(Error::Unbalanced)
+        );
+        assert_eq!(
+            Account::builder()
+                .available(Amount::MAX)
+                .held(amount(1))
+                .build(),
+            Err(Error::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_overdraft() {
+        let amount = |n| Amount::from_int(n).unwrap();
+
+        let mut account = Account::new(1);
+        account.deposit(amount(50)).unwrap();
+        assert_eq!(
+            account.withdrawal(amount(100)).unwrap_err(),
+            Error::InsufficientFunds
+        );
+        assert_eq!(account.available(), amount(50));
+
+        let mut account = Account::new(1);
+        assert_eq!(
+            account.set_overdraft_limit(amount(-1)),
+            Err(Error::InvalidInput)
+        );
+        account.set_overdraft_limit(amount(100)).unwrap();
+        account.deposit(amount(50)).unwrap();
+        assert_eq!(account.available_for_withdrawal(), amount(150));
+        account.withdrawal(amount(100)).unwrap();
+        assert_eq!(account.available(), amount(-50));
+        assert_eq!(account.total(), amount(-50));
+        assert_eq!(account.available_for_withdrawal(), amount(50));
+
+        // Up to the limit, not beyond
+        assert_eq!(
+            account.withdrawal(amount(51)).unwrap_err(),
+            Error::InsufficientFunds
+        );
+        account.withdrawal(amount(50)).unwrap();
+        assert_eq!(account.total(), amount(-100));
+        assert_eq!(account.available_for_withdrawal(), Amount::ZERO);
+
+        account.deposit(amount(100)).unwrap();
+        assert_eq!(account.total(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_min_balance() {
+        let amount = |n| Amount::from_int(n).unwrap();
+        let account = || {
+            let mut account = Account::new(1);
+            account.set_min_balance(Some(amount(20)));
+            account.deposit(amount(100)).unwrap();
+            account
+        };
+
+        // Above the floor
+        let mut above = account();
+        above.withdrawal(amount(70)).unwrap();
+        assert_eq!(above.available(), amount(30));
+
+        // Exactly on the floor
+        let mut on = account();
+        on.withdrawal(amount(80)).unwrap();
+        assert_eq!(on.available(), amount(20));
+        assert_eq!(on.total(), amount(20));
+
+        // Below the floor, while still covered by the funds
+        let mut below = account();
+        assert_eq!(
+            below.withdrawal(amount(81)).unwrap_err(),
+            Error::BelowMinimumBalance
+        );
+        assert_eq!(below.available(), amount(100));
+        assert_eq!(below.total(), amount(100));
+
+        // Below zero is still insufficient funds
+        assert_eq!(
+            below.withdrawal(amount(101)).unwrap_err(),
+            Error::InsufficientFunds
+        );
     }
 
     #[test]
@@ -273,7 +813,67 @@ mod tests {
 
         account.deposit(Amount::MAX).unwrap();
 
-        let expected = r#"{"client":123,"available":"79228162514264337593543950335","held":"0","total":"79228162514264337593543950335","locked":false}"#;
+        let expected = r#"{"client":123,"available":"79228162514264337593543950335","held":"0","total":"79228162514264337593543950335","locked":false,"tag":null}"#;
+        assert_eq!(serde_json::to_string(&account).unwrap(), expected);
+
+        account.set_tag(Some("eu".to_string()));
+        let expected = r#"{"client":123,"available":"79228162514264337593543950335","held":"0","total":"79228162514264337593543950335","locked":false,"tag":"eu"}"#;
         assert_eq!(serde_json::to_string(&account).unwrap(), expected);
     }
+
+    #[tokio::test]
+    async fn test_deserialize_csv_round_trip() {
+        use tokio_stream::StreamExt;
+
+        let mut tagged = Account::builder()
+            .id(7)
+            // More digits than a floating point number holds
+            .available("12345678901234.5678".parse().unwrap())
+            .held("0.25".parse().unwrap())
+            .locked(true)
+            .build()
+            .unwrap();
+        tagged.set_tag(Some("eu".to_string()));
+        let accounts = vec![Account::new(1), tagged];
+
+        let mut writer = csv_async::AsyncSerializer::from_writer(Vec::new());
+        for account in &accounts {
+            writer.serialize(account).await.unwrap();
+        }
+        let output = writer.into_inner().await.unwrap();
+
+        let read = csv_async::AsyncReaderBuilder::new()
+            .create_deserializer(output.as_slice())
+            .deserialize::<Account>()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(read, accounts);
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let account: Account = serde_json::from_str(
+            r#"{"client":3,"available":"-1.5","held":"2","total":"0.5","locked":false,"tag":null}"#,
+        )
+        .unwrap();
+        assert_eq!(account.id(), 3);
+        assert_eq!(account.available(), "-1.5".parse().unwrap());
+        assert_eq!(account.held(), Amount::from_int(2).unwrap());
+        assert_eq!(account.total(), "0.5".parse().unwrap());
+        assert!(!account.locked());
+        assert_eq!(account.tag(), None);
+
+        // The total must match the available plus held funds
+        let err = serde_json::from_str::<Account>(
+            r#"{"client":3,"available":"1","held":"2","total":"4","locked":false}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains(&Error::Unbalanced.to_string()));
+
+        assert!(serde_json::from_str::<Account>(
+            r#"{"client":3,"available":"x","held":"2","total":"2","locked":false}"#
+        )
+        .is_err());
+    }
 }
@@ -2,7 +2,28 @@
 #![deny(warnings)]
 
 use rust_decimal::prelude::*;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Error conditions that may arise when parsing an `Amount` from a string.
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
+pub enum ParseAmountError {
+    /// The string is empty.
+    #[error("Amount is empty")]
+    Empty,
+    /// The string has a character other than a leading sign, digits and a single decimal point,
+    /// or has no digits at all, in which case the last character is reported.
+    #[error("Amount has invalid character {0:?}")]
+    InvalidCharacter(char),
+    /// The number has more than `Amount::MAX_SCALE` fractional digits.
+    #[error("Amount has more than {} decimal places", Amount::MAX_SCALE)]
+    TooManyDecimals,
+    /// The number is outside of `Amount::MIN..=Amount::MAX`.
+    #[error("Amount is out of range")]
+    OutOfRange,
+    /// The string spells a number which is not finite, e.g. `inf`, `-Infinity` or `NaN`.
+    #[error("Amount is not a finite number")]
+    NonFinite,
+}
 
 /// Used to express currency amounts
 ///
@@ -19,8 +40,9 @@ use serde::{Serialize, Serializer};
 /// future. Most standard mathematical operations are not implemented as they are not needed at
 /// this point in time, thus they are left as an exercise for the reader.
 ///
-/// Serialization is done by rounding the amount to 4 decimal points, thus serialized data is
-/// suitable only for human inspection, not for sending it over a write protocol.
+/// Serialization is done by rounding the amount to 4 decimal points, without trailing zeros, thus
+/// serialized data is suitable only for human inspection, not for sending it over a write protocol.
+/// `Rounded` serializes it to another number of decimal points.
 ///
 /// Amount::ZERO, Amount::MIN, Amount::MAX are declared to make it clear what are the bounds of the
 /// amount, even though they are not used except in tests.
@@ -37,6 +59,37 @@ impl Amount {
     /// The maximum value of an amount.
     #[allow(dead_code)]
     pub const MAX: Amount = Amount(Decimal::MAX);
+    /// Number of decimal places amounts are rounded to when serialized.
+    pub const PRECISION: u32 = 4;
+    /// Highest number of decimal places an amount can have.
+    pub const MAX_SCALE: u32 = 28;
+
+    /// Whether the amount is zero, of either sign.
+    pub const fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Whether the amount is greater than zero.
+    pub const fn is_positive(&self) -> bool {
+        self.0.is_sign_positive() && !self.0.is_zero()
+    }
+
+    /// Whether the amount is less than zero.
+    pub const fn is_negative(&self) -> bool {
+        self.0.is_sign_negative() && !self.0.is_zero()
+    }
+
+    /// The lesser of `a` and `b`.
+    #[allow(dead_code)]
+    pub fn min(a: Amount, b: Amount) -> Amount {
+        std::cmp::min(a, b)
+    }
+
+    /// The greater of `a` and `b`.
+    #[allow(dead_code)]
+    pub fn max(a: Amount, b: Amount) -> Amount {
+        std::cmp::max(a, b)
+    }
 
     /// Checked addition.
     /// Returns `None` if overflow occurred.
@@ -50,8 +103,96 @@ impl Amount {
         self.0.checked_sub(rhs.0).map(Amount)
     }
 
+    /// Checked multiplication.
+    /// Returns `None` if overflow occurred.
+    pub fn checked_mul(&self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_mul(rhs.0).map(Amount)
+    }
+
+    /// Checked division.
+    /// Returns `None` if `rhs` is zero or if overflow occurred.
+    #[allow(dead_code)]
+    pub fn checked_div(&self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_div(rhs.0).map(Amount)
+    }
+
+    /// Truncates to `dp` decimal places, rounding towards zero.
+    pub fn trunc_dp(&self, dp: u32) -> Amount {
+        Amount(self.0.round_dp_with_strategy(dp, RoundingStrategy::ToZero))
+    }
+
+    /// Rounds to `dp` decimal places, the way amounts are serialized.
+    pub fn round_dp(&self, dp: u32) -> Amount {
+        Amount(self.0.round_dp(dp))
+    }
+
+    /// Displays the amount rounded to `dp` decimal places, without trailing zeros, the way it is
+    /// serialized by `Rounded`. `Display` shows all the digits.
+    pub fn display_dp(&self, dp: u32) -> impl std::fmt::Display {
+        self.0.round_dp(dp).normalize()
+    }
+
+    /// Creates an amount of `n` whole currency units. Returns `None` if `n` is out of the
+    /// `Amount::MIN..=Amount::MAX` range, which no `i64` is at the moment.
+    #[allow(dead_code)]
+    pub fn from_int(n: i64) -> Option<Self> {
+        Decimal::from_i64(n).map(Amount)
+    }
+
+    /// Parses amounts like `"123.4567"` or `"-5"` using `decimal_point` to separate the integer
+    /// part from the fractional part, e.g. `,` for `"12,34"`, keeping every digit given.
+    ///
+    /// Only an optional sign, digits and a single decimal point are accepted; digit separators and
+    /// surrounding whitespace are not. A well-formed number with more than `Amount::MAX_SCALE`
+    /// fractional digits has too many decimals, one outside of `Amount::MIN..=Amount::MAX` is out
+    /// of range. Infinities and NaN are told apart from other invalid strings.
+    pub fn parse(s: &str, decimal_point: char) -> Result<Self, ParseAmountError> {
+        if s.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+
+        let mut point = false;
+        let mut digits = false;
+        let mut decimals = 0;
+        for (i, c) in s.char_indices() {
+            match c {
+                '+' | '-' if i == 0 => {}
+                c if c == decimal_point && !point => point = true,
+                '0'..='9' => {
+                    digits = true;
+                    if point {
+                        decimals += 1;
+                    }
+                }
+                _ if s.parse::<f64>().is_ok_and(|amount| !amount.is_finite()) => {
+                    return Err(ParseAmountError::NonFinite)
+                }
+                c => return Err(ParseAmountError::InvalidCharacter(c)),
+            }
+        }
+        if !digits {
+            // Only a sign and/or a decimal point, blame the last of them
+            return Err(ParseAmountError::InvalidCharacter(
+                s.chars().next_back().unwrap_or_default(),
+            ));
+        }
+        if decimals > Self::MAX_SCALE {
+            return Err(ParseAmountError::TooManyDecimals);
+        }
+
+        // The input is well formed, thus rust_decimal can only reject it for its magnitude. Not all
+        // of its versions accept a leading `+`, which some feeds put on positive amounts.
+        let s = s.strip_prefix('+').unwrap_or(s);
+        let parsed = match decimal_point {
+            '.' => Decimal::from_str(s),
+            _ => Decimal::from_str(&s.replace(decimal_point, ".")),
+        };
+        parsed.map(Amount).map_err(|_| ParseAmountError::OutOfRange)
+    }
+
     /// Converts a `f64` to return an optional value of this type. If the value cannot be
     /// represented by this type, then `None` is returned.
+    #[cfg(test)]
     pub fn from_f64(amount: f64) -> Option<Self> {
         Decimal::from_f64(amount).map(Amount)
     }
@@ -62,7 +203,127 @@ impl Serialize for Amount {
     where
         S: Serializer,
     {
-        serializer.serialize_newtype_struct("Amount", &self.0.round_dp(4))
+        Rounded(*self, Self::PRECISION).serialize(serializer)
+    }
+}
+
+/// Amount serialized rounded to a number of decimal places, without trailing zeros, e.g. for an
+/// output scale other than `Amount::PRECISION`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rounded(pub Amount, pub u32);
+
+impl Serialize for Rounded {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&self.0.display_dp(self.1))
+    }
+}
+
+/// Parses amounts like `"123.4567"` or `"-5"`, keeping every digit given, see `Amount::parse`.
+impl std::str::FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, '.')
+    }
+}
+
+/// Deserializes amounts from their textual representation, so no digit is lost, e.g. from CSV
+/// fields or JSON strings.
+///
+/// Numbers are accepted too, e.g. from JSON, and converted through their shortest representation,
+/// so `0.1` is deserialized as `0.1` rather than the closest binary floating point value, though
+/// digits beyond the precision of `f64` are lost. Formats inferring the type of a field, like CSV,
+/// deliver numbers this way, thus amounts read from them should go through `exact` or `FromStr`.
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl serde::de::Visitor<'_> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a decimal amount")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.trim().parse().map_err(E::custom)
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                if !v.is_finite() {
+                    return Err(E::custom(format!("{v} is not a decimal amount")));
+                }
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(Amount(Decimal::from(v)))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(Amount(Decimal::from(v)))
+            }
+        }
+
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+/// Sums amounts with checked addition.
+///
+/// # Panics
+///
+/// Panics if the sum overflows, as silently saturating would misreport totals.
+impl std::iter::Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Self {
+        iter.fold(Amount::ZERO, |acc, amount| {
+            acc.checked_add(amount)
+                .expect("overflow when summing amounts")
+        })
+    }
+}
+
+/// Adds amounts, for tests and code where overflow is a bug.
+///
+/// # Panics
+///
+/// Panics on overflow, like integer addition. Applying transactions must use `checked_add`
+/// instead, so overflows are rejected rather than taking the engine down.
+impl std::ops::Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Amount) -> Self::Output {
+        self.checked_add(rhs).expect("overflow when adding amounts")
+    }
+}
+
+/// Subtracts amounts, for tests and code where overflow is a bug.
+///
+/// # Panics
+///
+/// Panics on overflow, like integer subtraction. Applying transactions must use `checked_sub`
+/// instead, so overflows are rejected rather than taking the engine down.
+impl std::ops::Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Amount) -> Self::Output {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting amounts")
+    }
+}
+
+/// Negates an amount.
+///
+/// Unlike `Add` and `Sub`, never panics: the range of amounts is symmetric, so `-Amount::MIN` is
+/// `Amount::MAX`.
+impl std::ops::Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Self::Output {
+        Amount(-self.0)
     }
 }
 
@@ -72,6 +333,28 @@ impl std::fmt::Display for Amount {
     }
 }
 
+/// Serialization of amounts as strings keeping all their digits, for data which is read back,
+/// unlike the rounded serialization of `Amount` itself.
+///
+/// Use with `#[serde(with = "crate::model::amount::exact")]`.
+pub mod exact {
+    use super::*;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    /// Serialize `amount` as a string with all its digits.
+    pub fn serialize<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(amount)
+    }
+
+    /// Deserialize an amount serialized by `serialize`.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        let amount = String::deserialize(deserializer)?;
+        Decimal::from_str_exact(&amount)
+            .map(Amount)
+            .map_err(D::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,6 +371,38 @@ mod tests {
         assert_eq!(Amount::ZERO.0, Decimal::ZERO);
     }
 
+    #[test]
+    fn test_predicates() {
+        let negative = -Amount::from_f64(0.0001).unwrap();
+        let negative_zero = -Amount::ZERO;
+
+        assert!(Amount::ZERO.is_zero());
+        assert!(!Amount::ZERO.is_positive());
+        assert!(!Amount::ZERO.is_negative());
+        assert!(negative_zero.is_zero());
+        assert!(!negative_zero.is_negative());
+
+        assert!(!Amount::MAX.is_zero());
+        assert!(Amount::MAX.is_positive());
+        assert!(!Amount::MAX.is_negative());
+
+        assert!(!negative.is_zero());
+        assert!(!negative.is_positive());
+        assert!(negative.is_negative());
+        assert!(Amount::MIN.is_negative());
+    }
+
+    #[test]
+    fn test_min_max() {
+        let negative = -Amount::from_f64(0.0001).unwrap();
+
+        assert_eq!(Amount::min(Amount::ZERO, Amount::MAX), Amount::ZERO);
+        assert_eq!(Amount::max(Amount::ZERO, Amount::MAX), Amount::MAX);
+        assert_eq!(Amount::min(negative, Amount::ZERO), negative);
+        assert_eq!(Amount::max(negative, Amount::ZERO), Amount::ZERO);
+        assert_eq!(Amount::min(Amount::MIN, negative), Amount::MIN);
+    }
+
     #[test]
     fn test_serialize() {
         let expected = r#""1.2346""#;
@@ -97,6 +412,301 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_serialize_rounded() {
+        let amount = Amount::from_f64(1.23456789).unwrap();
+        assert_eq!(
+            serde_json::to_string(&Rounded(amount, Amount::PRECISION)).unwrap(),
+            serde_json::to_string(&amount).unwrap()
+        );
+        assert_eq!(
+            serde_json::to_string(&Rounded(amount, 8)).unwrap(),
+            r#""1.23456789""#
+        );
+        assert_eq!(
+            serde_json::to_string(&Rounded(amount, 2)).unwrap(),
+            r#""1.23""#
+        );
+        assert_eq!(
+            serde_json::to_string(&Rounded(Amount::from_f64(2.5).unwrap(), 8)).unwrap(),
+            r#""2.5""#
+        );
+    }
+
+    #[test]
+    fn test_exact() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct Exact(#[serde(with = "exact")] Amount);
+
+        let amount = Exact(Amount::from_f64(1.23456789).unwrap());
+        let serialized = serde_json::to_string(&amount).unwrap();
+        assert_eq!(serialized, r#""1.23456789""#);
+        assert_eq!(serde_json::from_str::<Exact>(&serialized).unwrap(), amount);
+        assert!(serde_json::from_str::<Exact>(r#""1.2.3""#).is_err());
+    }
+
+    #[test]
+    fn test_from_str() {
+        let amount = |s: &str| s.parse::<Amount>();
+
+        assert_eq!(
+            amount("123.4567").unwrap(),
+            Amount(Decimal::from_str_exact("123.4567").unwrap())
+        );
+        assert_eq!(amount("123.4567").unwrap().to_string(), "123.4567");
+        assert_eq!(amount("-5").unwrap(), Amount::from_int(-5).unwrap());
+        assert_eq!(amount("+.5").unwrap(), amount("0.5").unwrap());
+        assert_eq!(amount(&Amount::MAX.to_string()).unwrap(), Amount::MAX);
+        assert_eq!(amount(&Amount::MIN.to_string()).unwrap(), Amount::MIN);
+
+        assert_eq!(amount(""), Err(ParseAmountError::Empty));
+        assert_eq!(amount("12a"), Err(ParseAmountError::InvalidCharacter('a')));
+        assert_eq!(amount(" 1"), Err(ParseAmountError::InvalidCharacter(' ')));
+        assert_eq!(
+            amount("1.2.3"),
+            Err(ParseAmountError::InvalidCharacter('.'))
+        );
+        assert_eq!(amount("1-"), Err(ParseAmountError::InvalidCharacter('-')));
+        assert_eq!(amount("1e5"), Err(ParseAmountError::InvalidCharacter('e')));
+        assert_eq!(amount("inf"), Err(ParseAmountError::NonFinite));
+        assert_eq!(amount("-Infinity"), Err(ParseAmountError::NonFinite));
+        assert_eq!(amount("NaN"), Err(ParseAmountError::NonFinite));
+        assert_eq!(amount("-"), Err(ParseAmountError::InvalidCharacter('-')));
+        assert_eq!(
+            amount("1_000"),
+            Err(ParseAmountError::InvalidCharacter('_'))
+        );
+        assert_eq!(
+            amount(&format!("0.{}", "1".repeat(28)))
+                .unwrap()
+                .to_string(),
+            format!("0.{}", "1".repeat(28))
+        );
+        assert_eq!(
+            amount(&format!("0.{}", "1".repeat(29))),
+            Err(ParseAmountError::TooManyDecimals)
+        );
+        assert_eq!(
+            amount("79228162514264337593543950336"),
+            Err(ParseAmountError::OutOfRange)
+        );
+        assert_eq!(
+            amount("-79228162514264337593543950336"),
+            Err(ParseAmountError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_parse_decimal_comma() {
+        assert_eq!(
+            Amount::parse("12,34", ','),
+            Ok(Amount(Decimal::from_str_exact("12.34").unwrap()))
+        );
+        assert_eq!(
+            Amount::parse("-,5", ','),
+            Ok(Amount(Decimal::from_str_exact("-0.5").unwrap()))
+        );
+        assert_eq!(Amount::parse("7", ','), Ok(Amount::from_int(7).unwrap()));
+        assert_eq!(
+            Amount::parse("12.34", ','),
+            Err(ParseAmountError::InvalidCharacter('.'))
+        );
+        assert_eq!(
+            Amount::parse("1,2,3", ','),
+            Err(ParseAmountError::InvalidCharacter(','))
+        );
+    }
+
+    #[test]
+    fn test_leading_plus() {
+        let expected = Amount(Decimal::from_str_exact("12.34").unwrap());
+
+        assert_eq!("+12.34".parse::<Amount>(), Ok(expected));
+        assert_eq!("12.34".parse::<Amount>(), Ok(expected));
+        assert_eq!("-12.34".parse::<Amount>(), Ok(Amount(-expected.0)));
+        assert_eq!(
+            serde_json::from_str::<Amount>(r#""+12.34""#).unwrap(),
+            expected
+        );
+        assert_eq!(
+            "+-12.34".parse::<Amount>(),
+            Err(ParseAmountError::InvalidCharacter('-'))
+        );
+        assert_eq!(
+            "++12.34".parse::<Amount>(),
+            Err(ParseAmountError::InvalidCharacter('+'))
+        );
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let amount = |data| serde_json::from_str::<Amount>(data).unwrap();
+
+        assert_eq!(amount(r#""1.23400""#), amount(r#""1.234""#));
+        assert_eq!(amount(r#""1.23400""#).to_string(), "1.23400");
+        // Digits beyond the output precision are kept
+        assert_eq!(amount(r#""1.23456789""#).to_string(), "1.23456789");
+        assert_eq!(amount(r#"" 2.5 ""#), amount("2.5"));
+        assert_eq!(amount("0.1").to_string(), "0.1");
+        assert_eq!(
+            amount("0.1").checked_add(amount("0.2")).unwrap(),
+            amount(r#""0.3""#)
+        );
+        assert_eq!(amount("7"), Amount::from_int(7).unwrap());
+        assert_eq!(amount("-7"), Amount::from_int(-7).unwrap());
+        assert!(serde_json::from_str::<Amount>(r#""abc""#).is_err());
+        assert!(serde_json::from_str::<Amount>(r#""""#).is_err());
+        assert!(serde_json::from_str::<Amount>("1e300").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_non_finite() {
+        use serde::de::IntoDeserializer;
+
+        for (value, name) in [
+            (f64::NAN, "NaN"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ] {
+            let deserializer: serde::de::value::F64Deserializer<serde::de::value::Error> =
+                value.into_deserializer();
+            assert_eq!(
+                Amount::deserialize(deserializer).unwrap_err().to_string(),
+                format!("{name} is not a decimal amount")
+            );
+            assert!(Amount::from_f64(value).is_none());
+        }
+    }
+
+    #[test]
+    fn test_checked_mul() {
+        let amount = |s: &str| s.parse::<Amount>().unwrap();
+
+        assert_eq!(
+            amount("100").checked_mul(amount("0.015")),
+            Some(amount("1.5"))
+        );
+        assert_eq!(amount("-2").checked_mul(amount("3")), Some(amount("-6")));
+        assert_eq!(Amount::MAX.checked_mul(Amount::ZERO), Some(Amount::ZERO));
+        assert_eq!(
+            Amount::MAX.checked_mul(Amount::from_f64(2.0).unwrap()),
+            None
+        );
+        assert_eq!(
+            Amount::MIN.checked_mul(Amount::from_f64(2.0).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_checked_div() {
+        let amount = |s: &str| s.parse::<Amount>().unwrap();
+
+        assert_eq!(amount("10").checked_div(amount("4")), Some(amount("2.5")));
+        assert_eq!(
+            amount("-1").checked_div(amount("8")),
+            Some(amount("-0.125"))
+        );
+        assert_eq!(amount("1").checked_div(Amount::ZERO), None);
+        assert_eq!(Amount::ZERO.checked_div(Amount::ZERO), None);
+        assert_eq!(Amount::MAX.checked_div(amount("0.5")), None);
+    }
+
+    #[test]
+    fn test_trunc_dp() {
+        assert_eq!(
+            Amount::from_f64(1.123456)
+                .unwrap()
+                .trunc_dp(Amount::PRECISION),
+            Amount::from_f64(1.1234).unwrap()
+        );
+        assert_eq!(
+            Amount::from_f64(-1.123456)
+                .unwrap()
+                .trunc_dp(Amount::PRECISION),
+            Amount::from_f64(-1.1234).unwrap()
+        );
+        assert_eq!(Amount::MAX.trunc_dp(Amount::PRECISION), Amount::MAX);
+    }
+
+    #[test]
+    fn test_display_dp() {
+        let amount = Amount(Decimal::from_str_exact("12.3456").unwrap());
+        assert_eq!(amount.display_dp(2).to_string(), "12.35");
+        assert_eq!(amount.display_dp(4).to_string(), "12.3456");
+        assert_eq!(amount.display_dp(6).to_string(), "12.3456");
+
+        let amount = Amount(Decimal::from_str_exact("12.340000").unwrap());
+        assert_eq!(amount.to_string(), "12.340000");
+        assert_eq!(amount.display_dp(4).to_string(), "12.34");
+        assert_eq!(Amount::ZERO.display_dp(4).to_string(), "0");
+    }
+
+    #[test]
+    fn test_sum() {
+        let amounts = [1.5, 2.25, -0.75]
+            .into_iter()
+            .map(|amount| Amount::from_f64(amount).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            amounts.into_iter().sum::<Amount>(),
+            Amount::from_f64(3.0).unwrap()
+        );
+        assert_eq!(std::iter::empty::<Amount>().sum::<Amount>(), Amount::ZERO);
+    }
+
+    #[test]
+    #[should_panic(expected = "overflow when summing amounts")]
+    fn test_sum_overflow() {
+        let _ = [Amount::MAX, Amount::from_f64(1.0).unwrap()]
+            .into_iter()
+            .sum::<Amount>();
+    }
+
+    #[test]
+    fn test_ops() {
+        let amount = |s: &str| s.parse::<Amount>().unwrap();
+
+        assert_eq!(amount("1.5") + amount("2.25"), amount("3.75"));
+        assert_eq!(amount("1.5") - amount("2.25"), amount("-0.75"));
+        assert_eq!(-amount("1.5"), amount("-1.5"));
+        assert_eq!(-Amount::MIN, Amount::MAX);
+        assert_eq!(-Amount::ZERO, Amount::ZERO);
+        assert_eq!(Amount::MAX - Amount::MAX, Amount::ZERO);
+    }
+
+    #[test]
+    #[should_panic(expected = "overflow when adding amounts")]
+    fn test_add_overflow() {
+        let _ = Amount::MAX + Amount::MAX;
+    }
+
+    #[test]
+    #[should_panic(expected = "overflow when subtracting amounts")]
+    fn test_sub_overflow() {
+        let _ = Amount::MIN - Amount::MAX;
+    }
+
+    #[test]
+    fn test_from_int() {
+        assert_eq!(Amount::from_int(0).unwrap(), Amount::ZERO);
+        assert_eq!(
+            Amount::from_int(100).unwrap(),
+            Amount::from_f64(100.0).unwrap()
+        );
+        assert_eq!(
+            Amount::from_int(i64::MAX).unwrap().to_string(),
+            i64::MAX.to_string()
+        );
+        assert_eq!(
+            Amount::from_int(i64::MIN).unwrap().to_string(),
+            i64::MIN.to_string()
+        );
+        // Every i64 is in range, the largest ones stay well below the bounds
+        assert!(Amount::from_int(i64::MAX).unwrap() < Amount::MAX);
+        assert!(Amount::from_int(i64::MIN).unwrap() > Amount::MIN);
+    }
+
     #[test]
     fn test_f64_conversion() {
         assert!(Amount::from_f64(f64::MAX).is_none());
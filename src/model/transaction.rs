@@ -3,50 +3,183 @@
 
 use serde::Deserialize;
 
+use crate::model::amount::{Amount, ParseAmountError};
+
 /// Transaction ID.
+///
+/// Ids are only unique per client, see `TxKey`.
 pub type Id = u32;
 
+/// Identity of a transaction.
+///
+/// Some feeds reuse transaction ids across clients, thus a transaction is only identified by the
+/// pair of client and transaction ids.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct TxKey {
+    /// Client owning the transaction.
+    pub client: crate::model::account::Id,
+    /// Transaction id, unique for `client`.
+    pub tx: Id,
+}
+
+impl std::fmt::Display for TxKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "client {} tx {}", self.client, self.tx)
+    }
+}
+
 /// Supported types of transactions.
-#[derive(Copy, Clone, Deserialize, PartialEq, Debug)]
+///
+/// The names accepted on the command line mirror the serde ones.
+#[derive(Copy, Clone, Deserialize, PartialEq, Debug, clap::ValueEnum)]
 pub enum TransactionType {
     /// Deposit transaction.
     #[serde(alias = "deposit")]
+    #[value(alias = "Deposit")]
     Deposit,
     #[serde(alias = "withdrawal")]
+    #[value(alias = "Withdrawal")]
     /// Withdrawal transaction.
     Withdrawal,
     #[serde(alias = "dispute")]
+    #[value(alias = "Dispute")]
     /// Dispute either a deposit or a withdrawal transaction.
     ///
     /// Clients dispute a deposit when their account was funded erroneously.
     /// Clients dispute a withdrawal when someone else took funds from their account without their
     /// consent (this is like a charge back but from exchange owner to client bank).
+    /// With an amount, only that part of the transaction is disputed.
     Dispute,
     #[serde(alias = "resolve")]
+    #[value(alias = "Resolve")]
     /// Resolve a dispute transaction.
     Resolve,
-    /// Charge back a disputed deposit transaction.
-    #[serde(alias = "chargeback")]
+    /// Charge back a disputed deposit or withdrawal transaction.
+    #[serde(alias = "chargeback", alias = "charge_back")]
+    #[value(name = "chargeback", alias = "ChargeBack", alias = "charge_back")]
     ChargeBack,
+    /// Unlock an account locked by a charge back, e.g. once support resolved the fraud.
+    #[serde(alias = "unlock")]
+    #[value(alias = "Unlock")]
+    Unlock,
+    /// Transfer funds from the account of the client to the account of the counterparty.
+    #[serde(alias = "transfer")]
+    #[value(alias = "Transfer")]
+    Transfer,
 }
 
 /// Transaction data structure used as API payload.
-#[derive(Copy, Clone, Deserialize, Debug)]
+///
+/// Fields are accepted both under their short CSV names (`type`, `tx`) and their REST style names
+/// (`transaction_type`, `transaction_id`).
+#[derive(Clone, Deserialize, Debug)]
 pub struct TransactionRecord {
     #[serde(alias = "type")]
     pub transaction_type: TransactionType,
     pub client: crate::model::account::Id,
-    #[serde(alias = "tx")]
+    #[serde(alias = "tx", alias = "transaction_id")]
     pub id: Id,
-    pub amount: Option<f64>,
+    /// Missing, null and empty amounts are all `None`, whatever the input format. Amounts which are
+    /// not finite numbers, e.g. `inf` or `NaN`, are kept as invalid for the engine to reject the
+    /// record, rather than taken for missing ones; other invalid amounts fail reading the record.
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount: Option<Result<Amount, ParseAmountError>>,
+    /// Cohort the client belongs to, carried through to the client's account.
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Client receiving the funds of a transfer.
+    #[serde(default)]
+    pub counterparty: Option<crate::model::account::Id>,
+}
+
+/// Deserialize an optional amount given either as a number or as a string, treating null and empty
+/// (or blank) strings as no amount, and infinities and NaN as an invalid one.
+///
+/// Formats inferring the type of a field, like CSV, would deliver numbers as floating point and
+/// lose digits, thus `input::CsvSource` parses the amount field as text instead.
+fn deserialize_amount<'de, D>(
+    deserializer: D,
+) -> Result<Option<Result<Amount, ParseAmountError>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::IntoDeserializer;
+
+    struct AmountVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for AmountVisitor {
+        type Value = Option<Result<Amount, ParseAmountError>>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "an optional decimal amount")
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: serde::Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+
+        fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+            if !v.is_finite() {
+                return Ok(Some(Err(ParseAmountError::NonFinite)));
+            }
+            Amount::deserialize(v.into_deserializer()).map(|amount| Some(Ok(amount)))
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+            Amount::deserialize(v.into_deserializer()).map(|amount| Some(Ok(amount)))
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            Amount::deserialize(v.into_deserializer()).map(|amount| Some(Ok(amount)))
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            match v.trim().parse::<Amount>() {
+                _ if v.trim().is_empty() => Ok(None),
+                Err(ParseAmountError::NonFinite) => Ok(Some(Err(ParseAmountError::NonFinite))),
+                result => result.map(|amount| Some(Ok(amount))).map_err(E::custom),
+            }
+        }
+    }
+
+    deserializer.deserialize_option(AmountVisitor)
+}
+
+impl TransactionRecord {
+    /// Identity of the transaction.
+    pub fn key(&self) -> TxKey {
+        TxKey {
+            client: self.client,
+            tx: self.id,
+        }
+    }
 }
 
 impl std::fmt::Display for TransactionRecord {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Record type {:?} client {} id {} amount {:?}",
-            self.transaction_type, self.client, self.id, self.amount
+            "Record type {:?} client {} id {} amount {} tag {:?}",
+            self.transaction_type,
+            self.client,
+            self.id,
+            match &self.amount {
+                None => "None".to_string(),
+                Some(Ok(amount)) => amount.to_string(),
+                Some(Err(e)) => e.to_string(),
+            },
+            self.tag
         )
     }
 }
@@ -62,6 +195,93 @@ mod tests {
         assert_eq!(transaction.transaction_type, TransactionType::Deposit);
         assert_eq!(transaction.client, 1234);
         assert_eq!(transaction.id, 5678);
-        assert_eq!(transaction.amount, Some(1.2));
+        assert_eq!(transaction.amount, Amount::from_f64(1.2).map(Ok));
+        assert_eq!(transaction.tag, None);
+
+        let data = r#"{"type":"deposit","client":1234,"tx":5678,"amount":1.2,"tag":"eu"}"#;
+        let transaction: TransactionRecord = serde_json::from_str(data).unwrap();
+        assert_eq!(transaction.tag.as_deref(), Some("eu"));
+    }
+
+    #[test]
+    fn test_deser_rest_style() {
+        let data =
+            r#"{"transaction_type":"withdrawal","client":1234,"transaction_id":5678,"amount":1.2}"#;
+        let transaction: TransactionRecord = serde_json::from_str(data).unwrap();
+        assert_eq!(transaction.transaction_type, TransactionType::Withdrawal);
+        assert_eq!(transaction.client, 1234);
+        assert_eq!(transaction.id, 5678);
+        assert_eq!(transaction.amount, Amount::from_f64(1.2).map(Ok));
+
+        let data = r#"{"transaction_type":"charge_back","client":1234,"transaction_id":5678}"#;
+        let transaction: TransactionRecord = serde_json::from_str(data).unwrap();
+        assert_eq!(transaction.transaction_type, TransactionType::ChargeBack);
+        assert_eq!(transaction.amount, None);
+
+        let data = r#"{"type":"chargeback","client":1234,"tx":5678}"#;
+        let transaction: TransactionRecord = serde_json::from_str(data).unwrap();
+        assert_eq!(transaction.transaction_type, TransactionType::ChargeBack);
+        assert_eq!(transaction.id, 5678);
+    }
+
+    #[test]
+    fn test_deser_absent_amount_json() {
+        for data in [
+            r#"{"type":"deposit","client":1,"tx":1}"#,
+            r#"{"type":"deposit","client":1,"tx":1,"amount":null}"#,
+            r#"{"type":"deposit","client":1,"tx":1,"amount":""}"#,
+            r#"{"type":"deposit","client":1,"tx":1,"amount":" "}"#,
+        ] {
+            let transaction: TransactionRecord = serde_json::from_str(data).unwrap();
+            assert_eq!(transaction.amount, None, "{data}");
+        }
+
+        for (data, amount) in [
+            (r#"{"type":"deposit","client":1,"tx":1,"amount":1.5}"#, 1.5),
+            (r#"{"type":"deposit","client":1,"tx":1,"amount":2}"#, 2.0),
+            (
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}"#,
+                1.5,
+            ),
+        ] {
+            let transaction: TransactionRecord = serde_json::from_str(data).unwrap();
+            assert_eq!(
+                transaction.amount,
+                Amount::from_f64(amount).map(Ok),
+                "{data}"
+            );
+        }
+        assert!(serde_json::from_str::<TransactionRecord>(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"abc"}"#
+        )
+        .is_err());
+
+        // Kept invalid for the engine to reject rather than taken for a missing amount
+        for data in [
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"inf"}"#,
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"NaN"}"#,
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"-Infinity"}"#,
+        ] {
+            let transaction: TransactionRecord = serde_json::from_str(data).unwrap();
+            assert_eq!(
+                transaction.amount,
+                Some(Err(ParseAmountError::NonFinite)),
+                "{data}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_value_names_deser() {
+        use clap::ValueEnum;
+
+        for transaction_type in TransactionType::value_variants() {
+            let value = transaction_type.to_possible_value().unwrap();
+            for name in value.get_name_and_aliases() {
+                let data = format!(r#"{{"type":"{name}","client":1,"tx":1}}"#);
+                let transaction: TransactionRecord = serde_json::from_str(&data).unwrap();
+                assert_eq!(transaction.transaction_type, *transaction_type);
+            }
+        }
     }
 }
@@ -0,0 +1,632 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::engine::handler::Rejection;
+use crate::model::account::{Account, Id as AccountId};
+use crate::model::amount::Amount;
+use crate::model::transaction::Id as TransactionId;
+
+/// Error conditions that may arise when writing accounts out.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Failure to write into a file or stream.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Failure to write CSV records.
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv_async::Error),
+    /// Failure to serialize JSON.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Failure to write into the SQLite database.
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Result of output operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Destination of the accounts once all transactions are executed.
+///
+/// Accounts are written one by one, then the sink is finished; nothing is guaranteed to be
+/// persisted before `finish` returns.
+pub trait OutputSink {
+    /// Write a single account.
+    async fn write_account(&mut self, account: &Account) -> Result<()>;
+
+    /// Flush or commit whatever was written so far.
+    async fn finish(&mut self) -> Result<()>;
+}
+
+/// Write all `accounts` into `sink`, in order, and finish it.
+pub async fn write_all<'a, S, I>(sink: &mut S, accounts: I) -> Result<()>
+where
+    S: OutputSink,
+    I: IntoIterator<Item = &'a Account>,
+{
+    for account in accounts {
+        sink.write_account(account).await?;
+    }
+
+    sink.finish().await
+}
+
+/// Extra output column with the id of the last deposit or withdrawal applied to an account.
+#[derive(serde::Serialize)]
+struct LastTxId {
+    last_tx_id: Option<TransactionId>,
+}
+
+/// Check whether `e` was caused by writing into a pipe whose reading end was closed.
+fn is_broken_pipe(e: &Error) -> bool {
+    let io = match e {
+        Error::Io(e) => Some(e),
+        Error::Csv(e) => match e.kind() {
+            csv_async::ErrorKind::Io(e) => Some(e),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    io.is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+}
+
+/// Turn a broken pipe into success, setting `closed` so that whatever is written afterwards is
+/// ignored.
+fn ignore_broken_pipe(closed: &mut bool, result: Result<()>) -> Result<()> {
+    match result {
+        Err(e) if is_broken_pipe(&e) => {
+            tracing::debug!("output closed early, err: {}", e);
+            *closed = true;
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Writes accounts as CSV records, with a header.
+///
+/// The reader of the output going away early (e.g. when piping into `head`) is not treated as a
+/// failure; the remaining accounts are dropped and writing succeeds.
+pub struct CsvSink<W: AsyncWrite + Unpin> {
+    writer: csv_async::AsyncSerializer<W>,
+    scale: u32,
+    tag: bool,
+    last_tx_id: bool,
+    closed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> CsvSink<W> {
+    /// Create a sink writing into `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: csv_async::AsyncSerializer::from_writer(writer),
+            scale: Amount::PRECISION,
+            tag: false,
+            last_tx_id: false,
+            closed: false,
+        }
+    }
+
+    /// Round amounts to `scale` decimal places rather than `Amount::PRECISION`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Add a `tag` column after the account columns if `tag` is set.
+    pub fn with_tag(mut self, tag: bool) -> Self {
+        self.tag = tag;
+        self
+    }
+
+    /// Add a `last_tx_id` column after the account columns if `last_tx_id` is set.
+    pub fn with_last_tx_id(mut self, last_tx_id: bool) -> Self {
+        self.last_tx_id = last_tx_id;
+        self
+    }
+
+    /// Turn a broken pipe into success, ignoring whatever is written afterwards.
+    fn check(&mut self, result: csv_async::Result<()>) -> Result<()> {
+        ignore_broken_pipe(&mut self.closed, result.map_err(Error::from))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> OutputSink for CsvSink<W> {
+    async fn write_account(&mut self, account: &Account) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let row = account.rounded(self.scale, self.tag);
+        let result = if self.last_tx_id {
+            let last_tx_id = account.last_tx_id();
+            self.writer.serialize((row, LastTxId { last_tx_id })).await
+        } else {
+            self.writer.serialize(row).await
+        };
+
+        self.check(result)
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let result = self.writer.flush().await.map_err(csv_async::Error::from);
+
+        self.check(result)
+    }
+}
+
+/// Writes accounts as JSON objects, one per line.
+///
+/// Like `CsvSink`, the reader of the output going away early is not treated as a failure.
+pub struct JsonSink<W: AsyncWrite + Unpin> {
+    writer: W,
+    scale: u32,
+    tag: bool,
+    closed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> JsonSink<W> {
+    /// Create a sink writing into `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            scale: Amount::PRECISION,
+            tag: false,
+            closed: false,
+        }
+    }
+
+    /// Round amounts to `scale` decimal places rather than `Amount::PRECISION`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Add a `tag` field after the account fields if `tag` is set.
+    pub fn with_tag(mut self, tag: bool) -> Self {
+        self.tag = tag;
+        self
+    }
+}
+
+impl<W: AsyncWrite + Unpin> OutputSink for JsonSink<W> {
+    async fn write_account(&mut self, account: &Account) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let mut line = serde_json::to_vec(&account.rounded(self.scale, self.tag))?;
+        line.push(b'\n');
+        let result = self.writer.write_all(&line).await.map_err(Error::from);
+
+        ignore_broken_pipe(&mut self.closed, result)
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let result = self.writer.flush().await.map_err(Error::from);
+
+        ignore_broken_pipe(&mut self.closed, result)
+    }
+}
+
+/// Writes accounts into the `accounts` table of a SQLite database, replacing the table.
+///
+/// Accounts are collected until `finish`, which writes all of them in a single SQL transaction on
+/// a blocking thread, so a failure leaves the database untouched. Amounts are stored as text,
+/// rounded like in the CSV output, so no precision is lost to floating point columns.
+pub struct SqliteSink {
+    path: std::path::PathBuf,
+    scale: u32,
+    rows: Vec<SqliteRow>,
+}
+
+/// Columns of a row of the `accounts` table.
+type SqliteRow = (AccountId, String, String, String, bool);
+
+impl SqliteSink {
+    /// Create a sink writing into the database at `path`, created if it does not exist.
+    pub fn new(path: impl Into<std::path::PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            scale: Amount::PRECISION,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Round amounts to `scale` decimal places rather than `Amount::PRECISION`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+}
+
+/// Replace the `accounts` table of the database at `path` with `rows`.
+fn write_sqlite(path: &std::path::Path, rows: &[SqliteRow]) -> rusqlite::Result<()> {
+    let mut conn = rusqlite::Connection::open(path)?;
+    let tx = conn.transaction()?;
+    tx.execute_batch(
+        "DROP TABLE IF EXISTS accounts;
+         CREATE TABLE accounts (
+             client INTEGER PRIMARY KEY,
+             available TEXT NOT NULL,
+             held TEXT NOT NULL,
+             total TEXT NOT NULL,
+             locked INTEGER NOT NULL
+         );",
+    )?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO accounts (client, available, held, total, locked)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for row in rows {
+            insert.execute(rusqlite::params![row.0, row.1, row.2, row.3, row.4])?;
+        }
+    }
+
+    tx.commit()
+}
+
+impl OutputSink for SqliteSink {
+    async fn write_account(&mut self, account: &Account) -> Result<()> {
+        let amount = |amount: Amount| amount.round_dp(self.scale).to_string();
+        self.rows.push((
+            account.id(),
+            amount(account.available()),
+            amount(account.held()),
+            amount(account.total()),
+            account.locked(),
+        ));
+
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        let path = self.path.clone();
+        let rows = std::mem::take(&mut self.rows);
+        tokio::task::spawn_blocking(move || write_sqlite(&path, &rows))
+            .await
+            .map_err(std::io::Error::other)??;
+
+        Ok(())
+    }
+}
+
+/// CSV record of a rejected transaction.
+#[derive(serde::Serialize)]
+struct RejectionRow<'a> {
+    tx: TransactionId,
+    client: crate::model::account::Id,
+    #[serde(rename = "type")]
+    transaction_type: &'a str,
+    reason: String,
+}
+
+/// Write the rejections received from `rejections` as CSV records with a header into `writer`,
+/// until all of its senders are dropped.
+///
+/// Returns the number of rejections written.
+pub async fn write_rejections<W: AsyncWrite + Unpin>(
+    writer: W,
+    rejections: &mut tokio::sync::mpsc::Receiver<Rejection>,
+) -> Result<u64> {
+    let mut writer = csv_async::AsyncWriterBuilder::new()
+        .has_headers(false)
+        .create_serializer(writer);
+    // Written separately so the header is there even without rejections
+    writer.serialize(("tx", "client", "type", "reason")).await?;
+    let mut count = 0;
+    while let Some(rejection) = rejections.recv().await {
+        let transaction_type = clap::ValueEnum::to_possible_value(&rejection.transaction_type)
+            .expect("transaction types are not skipped");
+        writer
+            .serialize(RejectionRow {
+                tx: rejection.key.tx,
+                client: rejection.key.client,
+                transaction_type: transaction_type.get_name(),
+                reason: rejection.error.reason(),
+            })
+            .await?;
+        count += 1;
+    }
+    writer.flush().await?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Sink keeping accounts in memory.
+    #[derive(Default)]
+    struct Collect {
+        accounts: Vec<Account>,
+        finished: bool,
+    }
+
+    impl OutputSink for Collect {
+        async fn write_account(&mut self, account: &Account) -> Result<()> {
+            self.accounts.push(account.clone());
+            Ok(())
+        }
+
+        async fn finish(&mut self) -> Result<()> {
+            self.finished = true;
+            Ok(())
+        }
+    }
+
+    /// Writer behaving like a pipe whose reading end was closed.
+    struct ClosedWriter;
+
+    impl AsyncWrite for ClosedWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_all() {
+        let accounts = (1..=5).map(Account::new).collect::<Vec<_>>();
+
+        let mut sink = Collect::default();
+        write_all(&mut sink, &accounts).await.unwrap();
+
+        assert_eq!(sink.accounts, accounts);
+        assert!(sink.finished);
+    }
+
+    #[tokio::test]
+    async fn test_csv_sink() {
+        let mut account = Account::new(1);
+        account.deposit(Amount::from_f64(1.23456).unwrap()).unwrap();
+        account.set_last_tx_id(Some(7));
+        account.set_tag(Some("eu".to_string()));
+        let accounts = [account, Account::new(2)];
+
+        let mut output = Vec::new();
+        write_all(&mut CsvSink::new(&mut output), &accounts)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,1.2346,0,1.2346,false\n\
+             2,0,0,0,false\n"
+        );
+
+        let mut output = Vec::new();
+        write_all(
+            &mut CsvSink::new(&mut output).with_last_tx_id(true),
+            &accounts,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,last_tx_id\n\
+             1,1.2346,0,1.2346,false,7\n\
+             2,0,0,0,false,\n"
+        );
+
+        let mut output = Vec::new();
+        write_all(
+            &mut CsvSink::new(&mut output)
+                .with_tag(true)
+                .with_last_tx_id(true),
+            &accounts,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,tag,last_tx_id\n\
+             1,1.2346,0,1.2346,false,eu,7\n\
+             2,0,0,0,false,,\n"
+        );
+
+        let mut output = Vec::new();
+        write_all(&mut CsvSink::new(&mut output).with_scale(2), &accounts)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,1.23,0,1.23,false\n\
+             2,0,0,0,false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_csv_sink_closed_pipe() {
+        let accounts = (1..=1000).map(Account::new).collect::<Vec<_>>();
+
+        write_all(&mut CsvSink::new(ClosedWriter), &accounts)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_json_sink_closed_pipe() {
+        let accounts = (1..=1000).map(Account::new).collect::<Vec<_>>();
+
+        write_all(&mut JsonSink::new(ClosedWriter), &accounts)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_json_sink() {
+        let mut account = Account::new(1);
+        account.deposit(Amount::from_f64(2.5).unwrap()).unwrap();
+
+        let mut output = Vec::new();
+        write_all(
+            &mut JsonSink::new(&mut output),
+            &[account.clone(), Account::new(2)],
+        )
+        .await
+        .unwrap();
+
+        let lines = String::from_utf8(output).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            r#"{"client":1,"available":"2.5","held":"0","total":"2.5","locked":false}"#
+        );
+        // Lines parse back into the same accounts
+        let parsed = lines
+            .iter()
+            .map(|line| serde_json::from_str::<Account>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(parsed, [account.clone(), Account::new(2)]);
+
+        account.set_tag(Some("eu".to_string()));
+        let mut output = Vec::new();
+        write_all(
+            &mut JsonSink::new(&mut output).with_tag(true),
+            &[account.clone(), Account::new(2)],
+        )
+        .await
+        .unwrap();
+        let lines = String::from_utf8(output).unwrap();
+        assert_eq!(
+            lines.lines().collect::<Vec<_>>(),
+            [
+                r#"{"client":1,"available":"2.5","held":"0","total":"2.5","locked":false,"tag":"eu"}"#,
+                r#"{"client":2,"available":"0","held":"0","total":"0","locked":false,"tag":null}"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_sink() {
+        let mut first = Account::new(1);
+        first.deposit(Amount::from_f64(1.23456).unwrap()).unwrap();
+        let mut second = Account::new(2);
+        second.deposit(Amount::from_f64(10.0).unwrap()).unwrap();
+        second.dispute(Amount::from_f64(4.0).unwrap()).unwrap();
+        second.charge_back(Amount::from_f64(4.0).unwrap()).unwrap();
+
+        let path = std::env::temp_dir().join(format!("sqlite-sink-{}.db", std::process::id()));
+        write_all(&mut SqliteSink::new(&path), &[Account::new(3)])
+            .await
+            .unwrap();
+        // Writing again replaces previous rows
+        write_all(&mut SqliteSink::new(&path), &[first, second])
+            .await
+            .unwrap();
+        // A failure, here a duplicate client, rolls the table back
+        assert!(write_all(
+            &mut SqliteSink::new(&path),
+            &[Account::new(4), Account::new(4)]
+        )
+        .await
+        .is_err());
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let rows = conn
+            .prepare("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, u16>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, bool>(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    1,
+                    "1.2346".to_string(),
+                    "0".to_string(),
+                    "1.2346".to_string(),
+                    false
+                ),
+                (2, "6".to_string(), "0".to_string(), "6".to_string(), true),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_rejections() {
+        use crate::engine::state::Error as StateError;
+        use crate::model::transaction::{TransactionType, TxKey};
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        tx.send(Rejection {
+            key: TxKey { client: 2, tx: 7 },
+            transaction_type: TransactionType::Dispute,
+            error: StateError::Dispute,
+        })
+        .await
+        .unwrap();
+        tx.send(Rejection {
+            key: TxKey { client: 1, tx: 3 },
+            transaction_type: TransactionType::ChargeBack,
+            error: StateError::ChargeBack,
+        })
+        .await
+        .unwrap();
+        tx.send(Rejection {
+            key: TxKey { client: 1, tx: 4 },
+            transaction_type: TransactionType::Withdrawal,
+            error: StateError::Account(crate::model::account::Error::InsufficientFunds),
+        })
+        .await
+        .unwrap();
+        drop(tx);
+
+        let mut output = Vec::new();
+        assert_eq!(write_rejections(&mut output, &mut rx).await.unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tx,client,type,reason\n\
+             7,2,dispute,Invalid dispute\n\
+             3,1,chargeback,Invalid charge back\n\
+             4,1,withdrawal,Account has insufficient funds\n"
+        );
+
+        // The header is written even without rejections
+        let (_, mut rx) = tokio::sync::mpsc::channel::<Rejection>(1);
+        rx.close();
+        let mut output = Vec::new();
+        assert_eq!(write_rejections(&mut output, &mut rx).await.unwrap(), 0);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tx,client,type,reason\n"
+        );
+    }
+}
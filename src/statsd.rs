@@ -0,0 +1,107 @@
+#![deny(missing_docs)]
+#![deny(warnings)]
+
+use tokio::net::{ToSocketAddrs, UdpSocket};
+
+use crate::engine::server::RunSummary;
+
+/// Prefix of all the metrics sent.
+const PREFIX: &str = "transaction_processing";
+
+/// Pushes engine metrics to a StatsD (or DogStatsD) server over UDP.
+///
+/// Metrics are sent one per packet, in the plain StatsD line format understood by both. Delivery
+/// is best effort, as with any StatsD client: nothing tells whether a packet was received.
+pub struct StatsdClient {
+    socket: UdpSocket,
+}
+
+impl StatsdClient {
+    /// Create a client sending metrics to the StatsD server at `addr`.
+    pub async fn connect(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no address to send metrics to",
+            )
+        })?;
+        let local = match addr {
+            std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+            std::net::SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+
+        Ok(Self { socket })
+    }
+
+    /// Send a counter increased by `value`.
+    async fn count(&self, name: &str, value: u64) -> std::io::Result<()> {
+        self.send(format!("{PREFIX}.{name}:{value}|c")).await
+    }
+
+    /// Send a gauge set to `value`.
+    async fn gauge(&self, name: &str, value: u64) -> std::io::Result<()> {
+        self.send(format!("{PREFIX}.{name}:{value}|g")).await
+    }
+
+    async fn send(&self, metric: String) -> std::io::Result<()> {
+        self.socket.send(metric.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    /// Send the transaction, rejection and account metrics of an engine run.
+    pub async fn send_summary(&self, summary: &RunSummary) -> std::io::Result<()> {
+        self.count("transactions.processed", summary.processed)
+            .await?;
+        self.count("transactions.rejected", summary.rejected)
+            .await?;
+        self.count("transactions.skipped", summary.skipped).await?;
+        self.count("accounts.created", summary.accounts_created)
+            .await?;
+        self.gauge("handlers.peak", summary.peak_handlers).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_summary() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = StatsdClient::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        client
+            .send_summary(&RunSummary {
+                processed: 10,
+                rejected: 2,
+                skipped: 1,
+                accounts_created: 3,
+                peak_handlers: 3,
+                persistence_failures: 0,
+            })
+            .await
+            .unwrap();
+
+        let mut packets = Vec::new();
+        let mut buf = [0; 512];
+        for _ in 0..5 {
+            let len = server.recv(&mut buf).await.unwrap();
+            packets.push(String::from_utf8(buf[..len].to_vec()).unwrap());
+        }
+        assert_eq!(
+            packets,
+            vec![
+                "transaction_processing.transactions.processed:10|c",
+                "transaction_processing.transactions.rejected:2|c",
+                "transaction_processing.transactions.skipped:1|c",
+                "transaction_processing.accounts.created:3|c",
+                "transaction_processing.handlers.peak:3|g",
+            ]
+        );
+    }
+}
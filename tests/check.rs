@@ -0,0 +1,31 @@
+mod common;
+
+#[test]
+fn test_check() {
+    let output = common::run(
+        "check",
+        "type,client,tx,amount\n\
+         deposit,1,1,5.0\n\
+         deposit,1,2,\n\
+         withdrawal,1,3,1.0\n\
+         dispute,1,1,\n",
+        &["--check"],
+    );
+
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // No accounts are written out
+    assert!(!stdout.contains("client,"), "{stdout}");
+    let invalid = stdout
+        .lines()
+        .filter(|line| line.starts_with("invalid record: "))
+        .collect::<Vec<_>>();
+    assert_eq!(invalid.len(), 1, "{stdout}");
+    assert!(invalid[0].contains("id 2"), "{stdout}");
+    assert!(invalid[0].ends_with("Invalid deposit"), "{stdout}");
+    assert!(
+        stdout.ends_with("4 records checked: 3 valid, 1 invalid\n"),
+        "{stdout}"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 records are invalid"));
+}
@@ -0,0 +1,47 @@
+//! Helpers shared by the integration tests.
+
+// Each test file is a crate of its own using only some of the helpers
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Command running the binary, with logs turned off so that standard error only holds the error
+/// the run fails with, if any.
+pub fn command() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_transaction-processing"));
+    command.env("RUST_LOG", "off");
+    command
+}
+
+/// Path named after `name` in the temporary directory, unique to the running tests.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "transaction-processing-{name}-{}",
+        std::process::id()
+    ))
+}
+
+/// Run the binary over `transactions`, written to a temporary file named after `name`, with the
+/// extra `args`.
+pub fn run(name: &str, transactions: &str, args: &[&str]) -> Output {
+    let path = temp_path(name);
+    std::fs::write(&path, transactions).unwrap();
+
+    let output = command().arg(&path).args(args).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    output
+}
+
+/// Standard output of a successful run.
+pub fn stdout(output: Output) -> String {
+    assert!(output.status.success(), "{output:?}");
+
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Standard output of a successful run over `transactions`, see `run`.
+pub fn run_ok(name: &str, transactions: &str, args: &[&str]) -> String {
+    stdout(run(name, transactions, args))
+}
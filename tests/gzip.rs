@@ -0,0 +1,57 @@
+mod common;
+
+/// Transactions of `TRANSACTIONS_GZ`.
+fn transactions() -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..25 {
+        let client = tx % 4 + 1;
+        if tx % 3 == 0 {
+            csv.push_str(&format!("withdrawal,{client},{tx},1.25\n"));
+        } else {
+            csv.push_str(&format!("deposit,{client},{tx},{tx}.5\n"));
+        }
+    }
+    csv.push_str("dispute,2,1,\n");
+    csv
+}
+
+/// `gzip -9n` of `transactions()`, compressed with dynamic Huffman codes.
+const TRANSACTIONS_GZ: [u8; 186] = [
+    0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x5d, 0x91, 0xdd, 0x0a, 0x83, 0x30,
+    0x0c, 0x46, 0xef, 0x7d, 0x96, 0x50, 0x4c, 0xda, 0xfa, 0xf3, 0x38, 0x32, 0x0b, 0x2b, 0x38, 0x95,
+    0x59, 0x71, 0x7b, 0x7b, 0x3f, 0xc6, 0xd8, 0x4c, 0x20, 0x37, 0xf9, 0xc8, 0x49, 0x73, 0x68, 0x79,
+    0xaf, 0x89, 0x6e, 0x53, 0x4e, 0x73, 0xa1, 0xf2, 0xa2, 0xe1, 0xb1, 0xec, 0x73, 0xa9, 0xc6, 0xb4,
+    0x2e, 0x5b, 0x2e, 0x24, 0xc4, 0xc4, 0x2e, 0xfe, 0x7a, 0x8f, 0x44, 0xd0, 0x1f, 0xb9, 0xdc, 0xc7,
+    0xe7, 0x70, 0x0c, 0x13, 0x05, 0x84, 0xec, 0xe4, 0x3f, 0xc3, 0x88, 0xc2, 0x85, 0x11, 0x8a, 0x14,
+    0x35, 0xe3, 0xa9, 0xd1, 0x4c, 0xa0, 0x96, 0x5a, 0x77, 0xdd, 0xd1, 0x51, 0xa7, 0x19, 0xa1, 0x5e,
+    0x33, 0x78, 0xb6, 0x46, 0xb9, 0xeb, 0x16, 0xc6, 0xb9, 0xac, 0x39, 0x24, 0xa2, 0x41, 0xb4, 0x60,
+    0xbd, 0xd2, 0x62, 0xb0, 0xc1, 0x8a, 0x71, 0xb4, 0x66, 0x8c, 0xbb, 0x1b, 0xe5, 0xc6, 0x2d, 0xca,
+    0xda, 0x71, 0x67, 0xf5, 0x18, 0xc7, 0xf7, 0x4a, 0x50, 0x6a, 0x94, 0x55, 0x14, 0xb6, 0x8e, 0x82,
+    0x50, 0x94, 0xa3, 0x20, 0xf4, 0xd6, 0x51, 0xc2, 0x17, 0xcc, 0xdb, 0xba, 0x97, 0xf4, 0xf9, 0xb8,
+    0xea, 0x04, 0x79, 0xdb, 0x83, 0x67, 0xdc, 0x01, 0x00, 0x00,
+];
+
+fn run(args: &[&std::path::Path]) -> String {
+    common::stdout(common::command().args(args).output().unwrap())
+}
+
+#[test]
+fn test_gzip_input() {
+    let dir = common::temp_path("gzip");
+    std::fs::create_dir_all(&dir).unwrap();
+    let plain = dir.join("transactions.csv");
+    std::fs::write(&plain, transactions()).unwrap();
+    let gz = dir.join("transactions.csv.gz");
+    std::fs::write(&gz, TRANSACTIONS_GZ).unwrap();
+    let unnamed = dir.join("transactions.bin");
+    std::fs::write(&unnamed, TRANSACTIONS_GZ).unwrap();
+
+    let expected = run(&[&plain]);
+    let from_gz = run(&[&gz]);
+    let forced = run(&[std::path::Path::new("--gzip"), &unnamed]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(expected.lines().count(), 5);
+    assert_eq!(from_gz, expected);
+    assert_eq!(forced, expected);
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use common::run;
+
+#[test]
+fn test_accrue_interest() {
+    let transactions = "type,client,tx,amount\n\
+                        deposit,1,1,100.0\n\
+                        deposit,2,2,10.0\n\
+                        dispute,2,2,\n\
+                        chargeback,2,2,\n\
+                        deposit,3,3,0.123456\n";
+
+    let output = run(
+        "interest",
+        transactions,
+        &["--accrue-interest", "0.05", "--interest-tx", "10"],
+    );
+    assert!(output.status.success(), "{output:?}");
+    // Rounded to the output scale, the locked account is skipped
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n\
+         1,105,0,105,false\n\
+         2,0,0,0,true\n\
+         3,0.1297,0,0.1297,false\n"
+    );
+
+    let output = run(
+        "interest-scale",
+        transactions,
+        &[
+            "--accrue-interest",
+            "0.05",
+            "--interest-tx",
+            "10",
+            "--scale",
+            "8",
+        ],
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .ends_with("3,0.1296288,0,0.1296288,false\n"));
+
+    // The reference run of the round-trip check accrues the same interest
+    let output = run(
+        "interest-round-trip",
+        transactions,
+        &[
+            "--accrue-interest",
+            "0.05",
+            "--interest-tx",
+            "10",
+            "--round-trip-check",
+        ],
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    // The interest transaction reuses the id of a deposit
+    let output = run(
+        "interest-duplicate",
+        transactions,
+        &["--accrue-interest", "0.05", "--interest-tx", "1"],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("client 1"), "{stderr}");
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use common::run_ok;
+
+const TRANSACTIONS: &str = "type,client,tx,amount\n\
+                            deposit,1,1,2.5\n\
+                            deposit,2,2,1.0\n\
+                            dispute,2,2,\n\
+                            deposit,3,3,4.0\n\
+                            dispute,3,3,\n\
+                            chargeback,3,3,\n";
+
+#[test]
+fn test_jsonl_output() {
+    let csv = run_ok("jsonl", TRANSACTIONS, &["--output-format", "csv"]);
+    let jsonl = run_ok("jsonl", TRANSACTIONS, &["--output-format", "jsonl"]);
+
+    // Same values as the CSV rows, column by column
+    let accounts = jsonl
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let rows = csv.lines().skip(1).collect::<Vec<_>>();
+    assert_eq!(accounts.len(), 3);
+    assert_eq!(rows.len(), accounts.len());
+    for (account, row) in accounts.iter().zip(rows) {
+        let fields = [
+            account["client"].to_string(),
+            account["available"].as_str().unwrap().to_string(),
+            account["held"].as_str().unwrap().to_string(),
+            account["total"].as_str().unwrap().to_string(),
+            account["locked"].to_string(),
+        ];
+        assert_eq!(fields.join(","), row);
+    }
+    assert_eq!(
+        accounts[2],
+        serde_json::json!({
+            "client": 3,
+            "available": "0",
+            "held": "0",
+            "total": "0",
+            "locked": true,
+        })
+    );
+}
+
+#[test]
+fn test_jsonl_input() {
+    let records = TRANSACTIONS
+        .lines()
+        .skip(1)
+        .map(|row| {
+            let fields = row.split(',').collect::<Vec<_>>();
+            let amount = match fields[3] {
+                "" => serde_json::Value::Null,
+                amount => amount.into(),
+            };
+            serde_json::json!({
+                "type": fields[0],
+                "client": fields[1].parse::<u16>().unwrap(),
+                "tx": fields[2].parse::<u32>().unwrap(),
+                "amount": amount,
+            })
+            .to_string()
+                + "\n"
+        })
+        .collect::<String>();
+
+    let jsonl = run_ok("jsonl-input", &records, &["--input-format", "jsonl"]);
+    let deposits = run_ok(
+        "jsonl-input",
+        &records,
+        &["--input-format", "jsonl", "--allow-types", "deposit"],
+    );
+
+    assert_eq!(
+        jsonl,
+        "client,available,held,total,locked\n\
+         1,2.5,0,2.5,false\n\
+         2,0,1,1,false\n\
+         3,0,0,0,true\n"
+    );
+    // Without disputes and charge backs, every deposit stands
+    assert_eq!(
+        deposits,
+        "client,available,held,total,locked\n\
+         1,2.5,0,2.5,false\n\
+         2,1,0,1,false\n\
+         3,4,0,4,false\n"
+    );
+}
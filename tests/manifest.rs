@@ -0,0 +1,86 @@
+mod common;
+
+const TRANSACTIONS: &str = "type,client,tx,amount\n\
+                            deposit,1,1,2.5\n\
+                            deposit,2,2,1.0\n\
+                            withdrawal,2,3,5.0\n";
+/// `sha256sum` of `TRANSACTIONS`.
+const TRANSACTIONS_SHA256: &str =
+    "af4eebd7552c2efbf7be638ad34a0d76a27aaa4b9388fbb334df4caf7f6e10e9";
+const OUTPUT: &str = "client,available,held,total,locked\n\
+                      1,2.5,0,2.5,false\n\
+                      2,1,0,1,false\n";
+/// `sha256sum` of `OUTPUT`.
+const OUTPUT_SHA256: &str = "e15d9992d5f4c7f17fea4a1900d74237567f66be544bc658dee296adb5da6461";
+
+#[test]
+fn test_manifest() {
+    let dir = common::temp_path("manifest");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("transactions.csv");
+    std::fs::write(&input, TRANSACTIONS).unwrap();
+    let manifest_path = dir.join("manifest.json");
+
+    let output = common::command()
+        .arg(&input)
+        .arg("--manifest")
+        .arg(&manifest_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), OUTPUT);
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(manifest["version"], env!("CARGO_PKG_VERSION"));
+    assert!(manifest["timestamp"].as_u64().unwrap() > 0);
+    assert_eq!(
+        manifest["inputs"],
+        serde_json::json!([{"path": input, "sha256": TRANSACTIONS_SHA256}])
+    );
+    assert_eq!(
+        manifest["records"],
+        serde_json::json!({"read": 3, "processed": 2, "rejected": 1, "skipped": 0})
+    );
+    assert_eq!(manifest["accounts"], 2);
+    assert_eq!(
+        manifest["outputs"],
+        serde_json::json!([{"path": "-", "sha256": OUTPUT_SHA256}])
+    );
+}
+
+#[test]
+fn test_manifest_stdin() {
+    use std::io::Write;
+
+    let manifest_path = common::temp_path("manifest-stdin.json");
+    let mut child = common::command()
+        .arg("-")
+        .arg("--manifest")
+        .arg(&manifest_path)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(TRANSACTIONS.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), OUTPUT);
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+    std::fs::remove_file(&manifest_path).unwrap();
+
+    // The standard input is hashed as it is read, as it cannot be read again
+    assert_eq!(
+        manifest["inputs"],
+        serde_json::json!([{"path": "-", "sha256": TRANSACTIONS_SHA256}])
+    );
+}
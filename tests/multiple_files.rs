@@ -0,0 +1,103 @@
+mod common;
+
+use std::process::Output;
+
+const HOUR_1: &str = "type,client,tx,amount\n\
+                      deposit,1,1,10.0\n\
+                      deposit,2,2,4.0\n\
+                      withdrawal,1,3,2.5\n";
+const HOUR_2: &str = "type,client,tx,amount\n\
+                      dispute,1,1,\n\
+                      deposit,2,4,1.0\n\
+                      resolve,1,1,\n\
+                      withdrawal,2,5,6.0\n";
+
+fn run<S: AsRef<std::ffi::OsStr>>(args: &[S]) -> Output {
+    common::command().args(args).output().unwrap()
+}
+
+#[test]
+fn test_multiple_files() {
+    let dir = common::temp_path("multiple-files");
+    std::fs::create_dir_all(&dir).unwrap();
+    let hour_1 = dir.join("hour-1.csv");
+    std::fs::write(&hour_1, HOUR_1).unwrap();
+    let hour_2 = dir.join("hour-2.csv");
+    std::fs::write(&hour_2, HOUR_2).unwrap();
+    let day = dir.join("day.csv");
+    let rows = HOUR_2.split_once('\n').unwrap().1;
+    std::fs::write(&day, format!("{HOUR_1}{rows}")).unwrap();
+    let missing = dir.join("hour-3.csv");
+
+    let split = run(&[&hour_1, &hour_2]);
+    let concatenated = run(&[&day]);
+    let with_missing = run(&[&hour_1, &missing, &hour_2]);
+    // The rejections of the first file would be written if it were executed
+    let rejects = dir.join("rejects.csv");
+    let missing_later = run(&[
+        hour_2.as_os_str(),
+        missing.as_os_str(),
+        "--rejects-file".as_ref(),
+        rejects.as_os_str(),
+    ]);
+    let rejected = std::fs::read_to_string(&rejects).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(split.status.success());
+    assert!(concatenated.status.success());
+    assert_eq!(
+        String::from_utf8(split.stdout).unwrap(),
+        String::from_utf8(concatenated.stdout).unwrap()
+    );
+
+    assert!(!with_missing.status.success());
+    assert!(with_missing.stdout.is_empty());
+    let stderr = String::from_utf8(with_missing.stderr).unwrap();
+    assert!(stderr.contains(missing.to_str().unwrap()), "{stderr}");
+
+    assert!(!missing_later.status.success());
+    assert_eq!(rejected.lines().skip(1).count(), 0, "{rejected}");
+}
+
+#[test]
+fn test_limit_across_files() {
+    let dir = common::temp_path("limit-across-files");
+    std::fs::create_dir_all(&dir).unwrap();
+    let hour_1 = dir.join("hour-1.csv");
+    std::fs::write(&hour_1, HOUR_1).unwrap();
+    let hour_2 = dir.join("hour-2.csv");
+    std::fs::write(&hour_2, HOUR_2).unwrap();
+    let day = dir.join("day.csv");
+    let rows = HOUR_2.split_once('\n').unwrap().1;
+    std::fs::write(&day, format!("{HOUR_1}{rows}")).unwrap();
+
+    // The limit is shared by all the files rather than applied to each one
+    let split = run(&[
+        hour_1.as_os_str(),
+        hour_2.as_os_str(),
+        "--limit".as_ref(),
+        "4".as_ref(),
+    ]);
+    let concatenated = run(&[day.as_os_str(), "--limit".as_ref(), "4".as_ref()]);
+    // Inline transactions come after the files, so none is left for them
+    let inline = run(&[
+        hour_1.as_os_str(),
+        hour_2.as_os_str(),
+        "--limit".as_ref(),
+        "7".as_ref(),
+        "--inline".as_ref(),
+        "deposit,3,6,1.0".as_ref(),
+    ]);
+    let all = run(&[&hour_1, &hour_2]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let split = common::stdout(split);
+    assert_eq!(split, common::stdout(concatenated));
+    assert_eq!(
+        split,
+        "client,available,held,total,locked\n\
+         1,7.5,0,7.5,false\n\
+         2,4,0,4,false\n"
+    );
+    assert_eq!(common::stdout(inline), common::stdout(all));
+}
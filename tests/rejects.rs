@@ -0,0 +1,74 @@
+mod common;
+
+#[test]
+fn test_rejects_file() {
+    let dir = common::temp_path("rejects");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("transactions.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,1,1,5.0\n\
+         dispute,1,42,\n\
+         withdrawal,1,2,1.0\n\
+         deposit,1,3,inf\n",
+    )
+    .unwrap();
+    let rejects = dir.join("rejects.csv");
+
+    let output = common::command()
+        .arg(&input)
+        .arg("--rejects-file")
+        .arg(&rejects)
+        .output()
+        .unwrap();
+    let written = std::fs::read_to_string(&rejects).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,4,0,4,false\n"
+    );
+    assert_eq!(
+        written,
+        "tx,client,type,reason\n42,1,dispute,Invalid dispute\n3,1,deposit,Non-finite amount\n"
+    );
+}
+
+#[test]
+fn test_rejects_file_jsonl() {
+    let dir = common::temp_path("rejects-jsonl");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("transactions.jsonl");
+    std::fs::write(
+        &input,
+        "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"5.0\"}\n\
+         {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"NaN\"}\n\
+         {\"type\":\"withdrawal\",\"client\":1,\"tx\":3,\"amount\":\"-inf\"}\n",
+    )
+    .unwrap();
+    let rejects = dir.join("rejects.csv");
+
+    let output = common::command()
+        .arg(&input)
+        .args(["--input-format", "jsonl", "--rejects-file"])
+        .arg(&rejects)
+        .output()
+        .unwrap();
+    let written = std::fs::read_to_string(&rejects).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Non-finite amounts are rejected like in CSV rather than failing the run
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,5,0,5,false\n"
+    );
+    assert_eq!(
+        written,
+        "tx,client,type,reason\n\
+         2,1,deposit,Non-finite amount\n\
+         3,1,withdrawal,Non-finite amount\n"
+    );
+}
@@ -0,0 +1,22 @@
+mod common;
+
+use common::run_ok;
+
+#[test]
+fn test_scale() {
+    let transactions = "type,client,tx,amount\n\
+                        deposit,1,1,1.23456789\n";
+
+    assert_eq!(
+        run_ok("scale-default", transactions, &[]),
+        "client,available,held,total,locked\n1,1.2346,0,1.2346,false\n"
+    );
+    assert_eq!(
+        run_ok("scale-8", transactions, &["--scale", "8"]),
+        "client,available,held,total,locked\n1,1.23456789,0,1.23456789,false\n"
+    );
+    assert_eq!(
+        run_ok("scale-2", transactions, &["--scale", "2"]),
+        "client,available,held,total,locked\n1,1.23,0,1.23,false\n"
+    );
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use common::run_ok;
+
+#[test]
+fn test_sorted_output() {
+    // Clients in shuffled order, each appearing several times
+    let clients = 1000;
+    let mut transactions = String::from("type,client,tx,amount\n");
+    for tx in 0..3 * clients {
+        let client = (tx * 7919) % clients;
+        transactions.push_str(&format!("deposit,{client},{tx},1.5\n"));
+    }
+
+    let outputs = [
+        run_ok("sorted", &transactions, &[]),
+        run_ok("sorted", &transactions, &[]),
+        run_ok("sorted", &transactions, &["--stream"]),
+        run_ok("sorted", &transactions, &["--adaptive-clients", "10"]),
+    ];
+
+    let ids = outputs[0]
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').next().unwrap().parse::<u32>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, (0..clients).collect::<Vec<_>>());
+    for output in &outputs[1..] {
+        assert_eq!(output, &outputs[0]);
+    }
+}
+
+#[test]
+fn test_client_filter() {
+    let output = run_ok(
+        "client-filter",
+        "type,client,tx,amount\n\
+         deposit,5,1,5.0\n\
+         deposit,1,2,1.0\n\
+         deposit,4,3,4.0\n\
+         deposit,2,4,2.0\n\
+         deposit,3,5,3.0\n",
+        &[
+            "--client", "4", "--client", "2", "--client", "9", "--client", "1",
+        ],
+    );
+
+    assert_eq!(
+        output,
+        "client,available,held,total,locked\n\
+         1,1,0,1,false\n\
+         2,2,0,2,false\n\
+         4,4,0,4,false\n"
+    );
+}
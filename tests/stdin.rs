@@ -0,0 +1,51 @@
+mod common;
+
+use common::command;
+use std::io::Write;
+use std::process::Stdio;
+
+const TRANSACTIONS: &str = "type,client,tx,amount\n\
+                            deposit,1,1,2.5\n\
+                            deposit,2,2,1.0\n\
+                            withdrawal,1,3,1.5\n\
+                            dispute,2,2,\n";
+
+#[test]
+fn test_stdin() {
+    let from_file = common::run("stdin", TRANSACTIONS, &[]);
+    assert!(from_file.status.success());
+
+    let mut child = command()
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(TRANSACTIONS.as_bytes())
+        .unwrap();
+    let from_stdin = child.wait_with_output().unwrap();
+
+    assert!(from_stdin.status.success());
+    assert_eq!(
+        String::from_utf8(from_stdin.stdout).unwrap(),
+        String::from_utf8(from_file.stdout).unwrap()
+    );
+}
+
+#[test]
+fn test_stdin_read_twice() {
+    let output = command()
+        .args(["-", "--round-trip-check"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("standard input"));
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use common::run;
+
+#[test]
+fn test_strict() {
+    let transactions = "type,client,tx,amount\n\
+                        deposit,1,1,5.0\n\
+                        withdrawal,1,2,10.0\n";
+
+    let output = run("lenient", transactions, &[]);
+    assert!(output.status.success());
+
+    let output = run("strict", transactions, &["--strict"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 of 2 transactions were rejected"));
+    // Accounts are still written out
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("client,"));
+
+    let output = run(
+        "strict-valid",
+        "type,client,tx,amount\ndeposit,1,1,5.0\n",
+        &["--strict"],
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_reject_on_duplicate() {
+    let transactions = "type,client,tx,amount\n\
+                        deposit,1,1,5.0\n\
+                        deposit,2,2,1.0\n\
+                        withdrawal,2,2,1.0\n";
+
+    // Without the flag the duplicate alone is rejected
+    let output = run("duplicate-lenient", transactions, &[]);
+    assert!(output.status.success());
+
+    let output = run("duplicate", transactions, &["--reject-on-duplicate"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("(client 2 tx 2)"));
+    // Nothing is executed nor written out
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_fail_fast() {
+    let transactions = "type,client,tx,amount\n\
+                        deposit,1,1,5.0\n\
+                        withdrawal,1,2,10.0\n\
+                        deposit,2,3,1.0\n";
+
+    let output = run("fail-fast", transactions, &["--fail-fast"]);
+    assert!(!output.status.success());
+    // The account error tells insufficient funds and locked accounts apart
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("transaction client 1 tx 2 rejected: Account has insufficient funds"));
+    // Nothing is written out past the rejected transaction
+    assert!(output.stdout.is_empty());
+
+    let output = run(
+        "fail-fast-valid",
+        "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.0\n",
+        &["--fail-fast"],
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,4,0,4,false\n"
+    );
+}
+
+#[test]
+fn test_check_balances() {
+    let transactions = "type,client,tx,amount\n\
+                        deposit,1,1,5.0\n\
+                        deposit,2,2,3.0\n\
+                        dispute,2,2,\n\
+                        chargeback,2,2,\n\
+                        withdrawal,1,3,1.5\n";
+
+    let output = run("check-balances", transactions, &["--check-balances"]);
+    assert!(output.status.success());
+
+    // Only applied in order do the transactions fit in an amount
+    let max = "79228162514264337593543950335";
+    let output = run(
+        "check-balances-max",
+        &format!(
+            "type,client,tx,amount\ndeposit,1,1,{max}\nwithdrawal,1,2,{max}\ndeposit,1,3,{max}\n"
+        ),
+        &["--check-balances"],
+    );
+    assert!(output.status.success(), "{output:?}");
+}
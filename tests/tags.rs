@@ -0,0 +1,36 @@
+mod common;
+
+use common::run_ok;
+
+#[test]
+fn test_tags() {
+    let transactions = "type,client,tx,amount,tag\n\
+                        deposit,1,1,1.0,eu\n\
+                        deposit,1,2,1.0,us\n\
+                        deposit,2,3,1.0,\n\
+                        deposit,2,4,1.0,us\n";
+
+    // The tag column is opt-in
+    assert_eq!(
+        run_ok("tags-default", transactions, &[]),
+        "client,available,held,total,locked\n\
+         1,2,0,2,false\n\
+         2,2,0,2,false\n"
+    );
+    // Only the first transaction of a client establishes its tag
+    assert_eq!(
+        run_ok("tags", transactions, &["--tags"]),
+        "client,available,held,total,locked,tag\n\
+         1,2,0,2,false,eu\n\
+         2,2,0,2,false,\n"
+    );
+    assert_eq!(
+        run_ok(
+            "tags-jsonl",
+            transactions,
+            &["--tags", "--output-format", "jsonl"]
+        ),
+        "{\"client\":1,\"available\":\"2\",\"held\":\"0\",\"total\":\"2\",\"locked\":false,\"tag\":\"eu\"}\n\
+         {\"client\":2,\"available\":\"2\",\"held\":\"0\",\"total\":\"2\",\"locked\":false,\"tag\":null}\n"
+    );
+}